@@ -0,0 +1,6 @@
+-- no operation is metered until it's in the schedule, i.e.
+-- {"upload": {"amount": 1, "min_bytes": 1000000}, "subscribe": {"amount": 1}, "transaction": {"amount": 1}}
+UPDATE crate_item SET data_json = data_json || '{"fees": {
+    "collector": "≈6D",
+    "schedule": {}}}'
+WHERE id = '00000000000000000000000000000000';
@@ -0,0 +1,51 @@
+-- items stored in files point at content addressed blobs through crate_item_chunk.sha2_hash
+ALTER TABLE crate_item_chunk DROP CONSTRAINT IF EXISTS crate_item_chunk_pkey;
+ALTER TABLE crate_item_chunk ALTER COLUMN size_hectobyte TYPE INTEGER;
+DROP INDEX IF EXISTS idx_crate_item_chunk_sha2_hash;
+CREATE INDEX idx_crate_item_chunk_sha2_hash ON crate_item_chunk(sha2_hash);
+
+DROP TABLE IF EXISTS blob CASCADE;
+CREATE TABLE blob (
+    sha2_hash BYTEA PRIMARY KEY,
+    size_hectobyte INTEGER NOT NULL,
+    ref_count BIGINT NOT NULL DEFAULT 0,
+    created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+DROP INDEX IF EXISTS idx_blob_unreferenced;
+CREATE INDEX idx_blob_unreferenced ON blob(sha2_hash) WHERE ref_count <= 0;
+
+INSERT INTO blob(sha2_hash, size_hectobyte, ref_count)
+    SELECT sha2_hash, MAX(size_hectobyte), COUNT(*) FROM crate_item_chunk GROUP BY sha2_hash
+ON CONFLICT DO NOTHING;
+
+-- reference counts follow the chunks, whatever deletes them
+CREATE OR REPLACE FUNCTION blob_ref() RETURNS TRIGGER AS $$
+BEGIN
+    IF TG_OP = 'INSERT' THEN
+        UPDATE blob SET ref_count = ref_count + 1, updated = CURRENT_TIMESTAMP
+        WHERE sha2_hash = NEW.sha2_hash;
+        RETURN NEW;
+    END IF;
+    UPDATE blob SET ref_count = ref_count - 1, updated = CURRENT_TIMESTAMP
+    WHERE sha2_hash = OLD.sha2_hash;
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trg_crate_item_chunk_blob_ref ON crate_item_chunk;
+CREATE TRIGGER trg_crate_item_chunk_blob_ref
+    AFTER INSERT OR DELETE ON crate_item_chunk
+    FOR EACH ROW EXECUTE FUNCTION blob_ref();
+
+CREATE OR REPLACE FUNCTION crate_item_drop_chunks() RETURNS TRIGGER AS $$
+BEGIN
+    DELETE FROM crate_item_chunk WHERE crate_item_id = OLD.id;
+    RETURN OLD;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trg_crate_item_drop_chunks ON crate_item;
+CREATE TRIGGER trg_crate_item_drop_chunks
+    AFTER DELETE ON crate_item
+    FOR EACH ROW EXECUTE FUNCTION crate_item_drop_chunks();
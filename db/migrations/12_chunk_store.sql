@@ -0,0 +1,11 @@
+-- chunks stay on the local filesystem until the backend is switched to "s3",
+-- `comn-broker migrate-store local s3` copies them over first
+UPDATE crate_item SET data_json = data_json || '{"store": {
+    "backend": "local",
+    "s3": {
+        "endpoint": "http://127.0.0.1:9000",
+        "bucket": "comn-chunks",
+        "region": "us-east-1",
+        "access_key": "",
+        "secret_key": ""}}}'
+WHERE id = '00000000000000000000000000000000';
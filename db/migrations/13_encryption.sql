@@ -0,0 +1,12 @@
+-- data keys of crates encrypted at rest, wrapped with a master key from COMN_MASTER_KEYS
+CREATE TABLE crate_key (
+    crate_id UUID PRIMARY KEY REFERENCES crate(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    master_key_id TEXT NOT NULL,
+    wrapped_key BYTEA NOT NULL,
+    created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX idx_crate_key_master_key_id ON crate_key(master_key_id);
+
+-- sealed item data is in data_bytes, or the blob, whatever item_storage says
+ALTER TABLE crate_item ADD COLUMN encrypted BOOL NOT NULL DEFAULT FALSE;
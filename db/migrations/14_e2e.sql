@@ -0,0 +1,9 @@
+-- crates whose items members encrypt themselves, each member's copy of the crate
+-- key is sealed to one of its keys and kept with its access, see `e2e`
+ALTER TABLE crate ADD COLUMN e2e BOOL NOT NULL DEFAULT FALSE;
+ALTER TABLE crate ADD COLUMN key_version INTEGER NOT NULL DEFAULT 0;
+-- a member was removed, nobody new gets in until the crate key is replaced
+ALTER TABLE crate ADD COLUMN rotate_key BOOL NOT NULL DEFAULT FALSE;
+
+ALTER TABLE crate_access ADD COLUMN envelope BYTEA;
+ALTER TABLE crate_access ADD COLUMN key_version INTEGER;
@@ -0,0 +1,14 @@
+-- item data and chunks compressed with zstd, size_hectobyte stays the size uploaded
+ALTER TABLE crate_item ADD COLUMN compression TEXT;
+ALTER TABLE crate_item ADD COLUMN stored_hectobyte INTEGER NOT NULL DEFAULT 0;
+UPDATE crate_item SET stored_hectobyte = size_hectobyte;
+
+ALTER TABLE blob ADD COLUMN compression TEXT;
+ALTER TABLE blob ADD COLUMN stored_hectobyte INTEGER NOT NULL DEFAULT 0;
+UPDATE blob SET stored_hectobyte = size_hectobyte;
+
+UPDATE crate_item SET data_json = data_json || '{"compression": {
+    "enabled": true,
+    "min_bytes": 1024,
+    "level": 3}}'
+WHERE id = '00000000000000000000000000000000';
@@ -0,0 +1,22 @@
+DROP INDEX IF EXISTS idx_item_type_media_type;
+CREATE UNIQUE INDEX idx_item_type_media_type ON item_type(media_type);
+
+-- a media type goes by any number of extensions, i.e. jpeg and jpg
+CREATE TABLE item_type_extension (
+    item_type_id UUID NOT NULL REFERENCES item_type(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    extension TEXT NOT NULL,
+    created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (item_type_id, extension)
+);
+CREATE INDEX idx_item_type_extension_extension ON item_type_extension(extension);
+INSERT INTO item_type_extension(item_type_id, extension)
+    SELECT id, extension FROM item_type WHERE extension <> '';
+
+-- writer access to a type lets an addr upload items of it. Everything seeded stays
+-- open to the public except html, which only addrs given access can upload
+UPDATE item_type_access SET type = 'writer'
+WHERE addr_id = 'ffffffffffffffffffffffffffffffff'
+AND item_type_id IN (SELECT id FROM item_type WHERE media_type <> 'text/html');
+
+INSERT INTO item_type_extension(item_type_id, extension)
+    SELECT id, 'jpg' FROM item_type WHERE media_type = 'image/jpeg';
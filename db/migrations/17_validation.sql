@@ -0,0 +1,4 @@
+UPDATE crate_item SET data_json = data_json || '{"validation": {
+    "sniff": true,
+    "sanitize": true}}'
+WHERE id = '00000000000000000000000000000000';
@@ -0,0 +1,9 @@
+-- json items at paths matching path_pattern have to satisfy the schema, see `update::json_schema`
+CREATE TABLE crate_schema (
+    crate_id UUID NOT NULL REFERENCES crate(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    path_pattern TEXT NOT NULL,
+    schema JSONB NOT NULL,
+    created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (crate_id, path_pattern)
+);
@@ -0,0 +1,20 @@
+-- bumped whenever item data changes, patches of json items are made against a version
+ALTER TABLE crate_item ADD COLUMN version INTEGER NOT NULL DEFAULT 1;
+
+-- the coin code updates json items in place too, so it's counted whatever updates them
+CREATE OR REPLACE FUNCTION crate_item_bump_version() RETURNS TRIGGER AS $$
+BEGIN
+    IF NEW.data_json IS DISTINCT FROM OLD.data_json
+        OR NEW.data_text IS DISTINCT FROM OLD.data_text
+        OR NEW.data_bytes IS DISTINCT FROM OLD.data_bytes THEN
+        NEW.version = OLD.version + 1;
+        NEW.updated = CURRENT_TIMESTAMP;
+    END IF;
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trg_crate_item_bump_version ON crate_item;
+CREATE TRIGGER trg_crate_item_bump_version
+    BEFORE UPDATE ON crate_item
+    FOR EACH ROW EXECUTE FUNCTION crate_item_bump_version();
@@ -0,0 +1,3 @@
+-- containment and jsonpath queries over json items, see `read::json_query`
+DROP INDEX IF EXISTS idx_crate_item_data_json;
+CREATE INDEX idx_crate_item_data_json ON crate_item USING GIN (data_json jsonb_path_ops);
@@ -0,0 +1,27 @@
+-- words of text kept compressed in data_bytes, set when the item is added since
+-- the db can't decompress it, see `add::crate_item`
+ALTER TABLE crate_item ADD COLUMN text_vector TSVECTOR;
+ALTER TABLE crate_item ADD COLUMN search_vector TSVECTOR;
+
+-- item paths weigh more than content. Only the start of long text is indexed,
+-- a tsvector can't hold more than 1MB
+CREATE OR REPLACE FUNCTION crate_item_search_vector() RETURNS TRIGGER AS $$
+BEGIN
+    NEW.search_vector =
+        setweight(to_tsvector('english', replace(NEW.item_path, '/', ' ')), 'A')
+        || setweight(to_tsvector('english', left(coalesce(NEW.data_text, ''), 262144)), 'B')
+        || setweight(coalesce(NEW.text_vector, ''::tsvector), 'B')
+        || setweight(jsonb_to_tsvector('english', coalesce(NEW.data_json, '{}'::jsonb), '["string"]'), 'B');
+    RETURN NEW;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS trg_crate_item_search_vector ON crate_item;
+CREATE TRIGGER trg_crate_item_search_vector
+    BEFORE INSERT OR UPDATE OF item_path, data_text, text_vector, data_json ON crate_item
+    FOR EACH ROW EXECUTE FUNCTION crate_item_search_vector();
+
+UPDATE crate_item SET item_path = item_path;
+
+DROP INDEX IF EXISTS idx_crate_item_search_vector;
+CREATE INDEX idx_crate_item_search_vector ON crate_item USING GIN (search_vector);
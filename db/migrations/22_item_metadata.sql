@@ -0,0 +1,6 @@
+-- free form key value metadata and tags of items, see `update::item_metadata`
+ALTER TABLE crate_item ADD COLUMN IF NOT EXISTS metadata JSONB NOT NULL DEFAULT '{}';
+ALTER TABLE crate_item ADD COLUMN IF NOT EXISTS tags TEXT[] NOT NULL DEFAULT '{}';
+
+CREATE INDEX IF NOT EXISTS idx_crate_item_metadata ON crate_item USING GIN (metadata jsonb_path_ops);
+CREATE INDEX IF NOT EXISTS idx_crate_item_tags ON crate_item USING GIN (tags);
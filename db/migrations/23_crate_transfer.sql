@@ -0,0 +1,33 @@
+-- ownership of a crate is handed over in two steps, see `update::crates`
+DROP TYPE IF EXISTS CRATE_TRANSFER_STATUS CASCADE;
+CREATE TYPE CRATE_TRANSFER_STATUS AS ENUM ('offered', 'accepted', 'cancelled');
+
+DROP TABLE IF EXISTS crate_transfer CASCADE;
+CREATE TABLE crate_transfer (
+    id UUID DEFAULT gen_random_uuid() PRIMARY KEY,
+    crate_id UUID NOT NULL REFERENCES crate(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    from_addr UUID NOT NULL REFERENCES addr(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    to_addr UUID NOT NULL REFERENCES addr(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    -- the crate key sealed to to_addr, for e2e crates
+    envelope BYTEA,
+    status CRATE_TRANSFER_STATUS NOT NULL DEFAULT 'offered',
+    expires TIMESTAMPTZ NOT NULL,
+    created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+-- a crate has one open offer at a time
+DROP INDEX IF EXISTS idx_crate_transfer_offered_unique;
+CREATE UNIQUE INDEX idx_crate_transfer_offered_unique ON crate_transfer(crate_id) WHERE status = 'offered';
+
+-- changes to crates and their ownership, by whom
+DROP TABLE IF EXISTS crate_audit CASCADE;
+CREATE TABLE crate_audit (
+    id BIGSERIAL PRIMARY KEY,
+    crate_id UUID NOT NULL REFERENCES crate(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    addr_id UUID REFERENCES addr(id) ON DELETE SET NULL ON UPDATE CASCADE,
+    action TEXT NOT NULL,
+    detail JSONB NOT NULL DEFAULT '{}',
+    created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+DROP INDEX IF EXISTS idx_crate_audit_crate;
+CREATE INDEX idx_crate_audit_crate ON crate_audit(crate_id, created);
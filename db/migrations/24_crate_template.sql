@@ -0,0 +1,12 @@
+-- declarative sets of crates, items and access grants, see `add::template`
+DROP TABLE IF EXISTS crate_template CASCADE;
+CREATE TABLE crate_template (
+    name TEXT PRIMARY KEY,
+    description TEXT,
+    spec JSONB NOT NULL,
+    created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO scope(scope_type, description) VALUES
+    ('template', 'Added with the crates of a template.');
@@ -0,0 +1,3 @@
+-- items of crates imported from archives whose scope isn't known here, see `archive`
+INSERT INTO scope(scope_type, description) VALUES
+    ('import', 'Imported from a crate archive.');
@@ -0,0 +1,18 @@
+-- fees held from the payer while the metered route runs, see `fee::Metered`
+DROP TYPE IF EXISTS FEE_STATUS CASCADE;
+CREATE TYPE FEE_STATUS AS ENUM ('held', 'charged', 'refunding', 'refunded');
+
+DROP TABLE IF EXISTS fee CASCADE;
+CREATE TABLE fee (
+    id UUID DEFAULT gen_random_uuid() PRIMARY KEY,
+    payer UUID NOT NULL REFERENCES addr(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    collector UUID NOT NULL REFERENCES addr(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    operation TEXT NOT NULL,
+    amount BIGINT NOT NULL CHECK (amount > 0),
+    nonce TEXT NOT NULL UNIQUE,
+    status FEE_STATUS NOT NULL DEFAULT 'held',
+    created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+DROP INDEX IF EXISTS idx_fee_status;
+CREATE INDEX idx_fee_status ON fee(status);
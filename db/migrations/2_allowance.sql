@@ -0,0 +1,6 @@
+INSERT INTO crate_item(id, added_by, crate_id, scope_id, item_path, item_storage, type_id, data_json, data_text, size_hectobyte) VALUES
+    ('000000000000000000000000000021cc',
+        '000000000000000000000000000000cc', '000000000000000000000000000000cc',
+        (SELECT id from scope where scope_type = 'system'),
+        '/allowance', 'Json', (SELECT id from item_type where media_type = 'application/json'),
+         '{}', null, 1);
@@ -0,0 +1,6 @@
+ALTER TABLE key ADD COLUMN eth_addr BYTEA;
+DROP INDEX IF EXISTS idx_key_eth_addr_unique;
+CREATE UNIQUE INDEX idx_key_eth_addr_unique ON key(eth_addr);
+
+UPDATE crate_item SET data_json = data_json || '{"chain_id": 1337}'
+WHERE id = '00000000000000000000000000000000';
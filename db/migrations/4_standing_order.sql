@@ -0,0 +1,25 @@
+DROP TYPE IF EXISTS STANDING_ORDER_STATUS CASCADE;
+CREATE TYPE STANDING_ORDER_STATUS AS ENUM ('active', 'cancelled', 'completed', 'failed');
+
+DROP TABLE IF EXISTS standing_order CASCADE;
+CREATE TABLE standing_order (
+    id UUID DEFAULT gen_random_uuid() PRIMARY KEY,
+    sender UUID NOT NULL REFERENCES addr(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    receiver UUID NOT NULL REFERENCES addr(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    amount BIGINT NOT NULL CHECK (amount > 0),
+    comment TEXT,
+    nonce TEXT NOT NULL,
+    interval_secs BIGINT NOT NULL CHECK (interval_secs > 0),
+    next_run TIMESTAMPTZ NOT NULL,
+    ends TIMESTAMPTZ,
+    max_count INTEGER,
+    run_count INTEGER NOT NULL DEFAULT 0,
+    failures INTEGER NOT NULL DEFAULT 0,
+    status STANDING_ORDER_STATUS NOT NULL DEFAULT 'active',
+    created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+DROP INDEX IF EXISTS idx_standing_order_sender_nonce_unique;
+CREATE UNIQUE INDEX idx_standing_order_sender_nonce_unique ON standing_order(sender, nonce);
+DROP INDEX IF EXISTS idx_standing_order_status_next_run;
+CREATE INDEX idx_standing_order_status_next_run ON standing_order(status, next_run);
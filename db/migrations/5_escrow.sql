@@ -0,0 +1,24 @@
+INSERT INTO addr(id, name) VALUES
+    ('000000000000000000000000000000ec', 'escrow');
+
+DROP TYPE IF EXISTS ESCROW_STATUS CASCADE;
+CREATE TYPE ESCROW_STATUS AS ENUM ('locked', 'released', 'refunded');
+
+DROP TABLE IF EXISTS escrow CASCADE;
+CREATE TABLE escrow (
+    id UUID DEFAULT gen_random_uuid() PRIMARY KEY,
+    sender UUID NOT NULL REFERENCES addr(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    receiver UUID NOT NULL REFERENCES addr(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    arbiter UUID REFERENCES addr(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    amount BIGINT NOT NULL CHECK (amount > 0),
+    comment TEXT,
+    nonce TEXT NOT NULL,
+    status ESCROW_STATUS NOT NULL DEFAULT 'locked',
+    expires TIMESTAMPTZ NOT NULL,
+    created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+DROP INDEX IF EXISTS idx_escrow_sender_nonce_unique;
+CREATE UNIQUE INDEX idx_escrow_sender_nonce_unique ON escrow(sender, nonce);
+DROP INDEX IF EXISTS idx_escrow_status_expires;
+CREATE INDEX idx_escrow_status_expires ON escrow(status, expires);
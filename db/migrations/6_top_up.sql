@@ -0,0 +1,23 @@
+DROP TABLE IF EXISTS top_up CASCADE;
+CREATE TABLE top_up (
+    id UUID DEFAULT gen_random_uuid() PRIMARY KEY,
+    provider TEXT NOT NULL,
+    payment_id TEXT NOT NULL,
+    charge_ref TEXT,
+    addr_id UUID NOT NULL REFERENCES addr(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    amount BIGINT NOT NULL,
+    clawed_back BIGINT NOT NULL DEFAULT 0,
+    created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+DROP INDEX IF EXISTS idx_top_up_provider_payment_id_unique;
+CREATE UNIQUE INDEX idx_top_up_provider_payment_id_unique ON top_up(provider, payment_id);
+DROP INDEX IF EXISTS idx_top_up_provider_charge_ref;
+CREATE INDEX idx_top_up_provider_charge_ref ON top_up(provider, charge_ref);
+
+UPDATE crate_item SET data_json = data_json || '{"stripe": {
+    "webhook_secret": "",
+    "treasury": "≈6D",
+    "comment": "Bought from Opus Website.",
+    "rates": {"usd": 100}}}'
+WHERE id = '00000000000000000000000000000000';
@@ -0,0 +1,7 @@
+-- the secret comes from COMN_WEBHOOK_SECRET, events are rejected while neither is set
+UPDATE crate_item SET data_json = data_json || '{"webhook": {
+    "webhook_secret": "",
+    "treasury": "≈6D",
+    "comment": "Bought from Opus billing.",
+    "rates": {"usd": 100}}}'
+WHERE id = '00000000000000000000000000000000';
@@ -0,0 +1,22 @@
+DROP TABLE IF EXISTS checkout CASCADE;
+CREATE TABLE checkout (
+    id UUID DEFAULT gen_random_uuid() PRIMARY KEY,
+    provider TEXT NOT NULL,
+    payment_id TEXT NOT NULL,
+    addr_id UUID NOT NULL REFERENCES addr(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    amount BIGINT NOT NULL,
+    url TEXT,
+    created TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+DROP INDEX IF EXISTS idx_checkout_provider_payment_id_unique;
+CREATE UNIQUE INDEX idx_checkout_provider_payment_id_unique ON checkout(provider, payment_id);
+
+-- the secret key comes from STRIPE_SECRET_KEY
+UPDATE crate_item SET data_json = data_json || '{"checkout": {
+    "secret_key": "",
+    "currency": "usd",
+    "product_name": "ComnCoin",
+    "success_url": "https://comn.opus.ai/coins?checkout={CHECKOUT_SESSION_ID}",
+    "cancel_url": "https://comn.opus.ai/coins",
+    "max_amount": 100000}}'
+WHERE id = '00000000000000000000000000000000';
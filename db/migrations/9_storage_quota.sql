@@ -0,0 +1,16 @@
+DROP TABLE IF EXISTS storage_quota CASCADE;
+CREATE TABLE storage_quota (
+    addr_id UUID PRIMARY KEY REFERENCES addr(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    extra_hectobyte BIGINT NOT NULL DEFAULT 0,
+    updated TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+DROP INDEX IF EXISTS idx_crate_item_added_by;
+CREATE INDEX idx_crate_item_added_by ON crate_item(added_by);
+
+UPDATE crate_item SET data_json = data_json || '{"quota": {
+    "free_hectobyte": 100000,
+    "block_hectobyte": 100000,
+    "block_price": 10,
+    "max_hectobyte": 100000000,
+    "treasury": "≈6D"}}'
+WHERE id = '00000000000000000000000000000000';
@@ -1 +1,2 @@
-pub mod crate_item;
\ No newline at end of file
+pub mod crate_item;
+pub mod template;
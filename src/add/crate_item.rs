@@ -4,13 +4,22 @@ use salvo::prelude::{handler, Depot, Response, Request};
 use secp256k1::PublicKey;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use sqlx::types::{Uuid};
-use sqlx::FromRow;
+use std::collections::HashMap;
+use sqlx::types::{Json, Uuid};
+use sqlx::{FromRow, PgConnection};
 use crate::{
-	AddCrateItemReq, write_file, CrateItemStorage,
+	AddCrateItemReq, CrateItemStorage,
 	CrateItem, CrateItemRes, AccessType, SpecialAddr,
-	read_file, verify_hash,
+	verify_hash,
 	comn_addr::ComnAddr, db::{db, get_config},
+	update::quota::{reserve, QuotaErr},
+	update::item_type::{check_upload, normalize_media_type, ItemTypeErr},
+	blob::{put_blob, lock_blob, read_blob, possession_proof},
+	encryption::{crate_key, seal},
+	compression::{pack, ZSTD},
+	validation::{validate, ValidationErr},
+	update::json_schema::{check_item, SchemaErr},
+	update::item_metadata::{check_metadata, normalize_tags, MetadataErr},
 	read::{
 		crate_item::CrateItemFilter,
 		crates::CrateFilter,
@@ -34,12 +43,28 @@ pub struct AddCrateItem {
 	pub sha2_hash: Vec<u8>,
 	pub data: Option<Vec<u8>>,
 	pub scope: String,
+	// see `blob::possession_proof`, stands in for data the broker already has
+	pub proof: Option<Vec<u8>>,
+	#[sqlx(skip)]
+	pub metadata: HashMap<String, String>,
+	pub tags: Vec<String>,
 }
 
 #[derive(Debug)]
 pub enum AddCrateItemErr {
 	InternalErr,
 	PayloadLarge,
+	QuotaExceeded,
+	StorageFull,
+	// no data with the hash, or a proof that isn't for it, which of them isn't
+	// told so the hash of data can't be confirmed without having it
+	UnknownBlob,
+	UnsupportedMediaType,
+	MediaTypeForbidden,
+	InvalidData(ValidationErr),
+	// errors of the schemas of the item path, see `update::json_schema`
+	SchemaViolation(Vec<String>),
+	BadMetadata(MetadataErr),
 }
 
 impl Error for AddCrateItemErr {}
@@ -49,52 +74,122 @@ impl fmt::Display for AddCrateItemErr {
         match self {
             AddCrateItemErr::InternalErr => write!(f, "internal error"),
             AddCrateItemErr::PayloadLarge => write!(f, "Payload is too large"),
+            AddCrateItemErr::QuotaExceeded => write!(f, "Storage quota is exceeded, buy more"),
+            AddCrateItemErr::StorageFull => write!(f, "Storage quota is at its max"),
+            AddCrateItemErr::UnknownBlob => write!(f, "No data with the hash and proof, upload it"),
+            AddCrateItemErr::UnsupportedMediaType => write!(f, "Media type is not registered"),
+            AddCrateItemErr::MediaTypeForbidden => write!(f, "Addr may not upload the media type"),
+            AddCrateItemErr::InvalidData(e) => write!(f, "Data is not valid, {}", e),
+            AddCrateItemErr::SchemaViolation(errors) => write!(f, "Data doesn't match the schema, {}", errors.join(", ")),
+            AddCrateItemErr::BadMetadata(e) => write!(f, "{}", e),
         }
     }
 }
 
+// json and text are validated before they get here, a bad one is refused rather than stored empty
+fn utf8(data: Vec<u8>) -> Result<String, AddCrateItemErr> {
+	String::from_utf8(data).map_err(|e| {
+		AddCrateItemErr::InvalidData(ValidationErr::BadUtf8(e.utf8_error().valid_up_to()))
+	})
+}
+
 impl AddCrateItem {
 	pub async fn add(self) -> Result<Uuid, AddCrateItemErr> {
+		let mut tx = db().await.begin().await.unwrap();
+		let id = self.add_in(&mut *tx).await?;
+		tx.commit().await.unwrap();
+		Ok(id)
+	}
+
+	/// Adds the item as part of the caller's transaction, nothing is kept if it's rolled back.
+	pub async fn add_in(mut self, tx: &mut PgConnection) -> Result<Uuid, AddCrateItemErr> {
 		let conf = get_config().await;
+		self.tags = normalize_tags(&self.tags).map_err(AddCrateItemErr::BadMetadata)?;
+		check_metadata(&self.metadata, &self.tags).map_err(AddCrateItemErr::BadMetadata)?;
+		match check_upload(&mut *tx, &self.media_type, &self.addr).await {
+			Ok(_) => {}
+			Err(ItemTypeErr::Forbidden) => return Err(AddCrateItemErr::MediaTypeForbidden),
+			Err(_) => return Err(AddCrateItemErr::UnsupportedMediaType),
+		}
+		// end to end encrypted data can't be looked into
+		let e2e = sqlx::query_scalar::<_, bool>("SELECT e2e FROM crate WHERE id = $1")
+			.bind(self.crate_id)
+			.fetch_optional(&mut *tx)
+			.await
+			.unwrap()
+			.unwrap_or(false);
+		self.media_type = normalize_media_type(&self.media_type).unwrap();
 
-		if let Some(data) = self.data {
+		if let Some(data) = self.data.take() {
 			if verify_hash(&*data, &*self.sha2_hash).await {
+				// only ciphertext goes unchecked, it's never stored as json or text
+				let data = if e2e {
+					data
+				} else {
+					match validate(&self.media_type, data).await {
+						Ok(valid) => valid,
+						Err(e) => return Err(AddCrateItemErr::InvalidData(e)),
+					}
+				};
+				// sanitized data is kept under its own hash
+				self.sha2_hash = Sha256::digest(&data).as_slice().to_vec();
+				if !e2e {
+					self.check_schema(&mut *tx, &data).await?;
+				}
 				let size_hectobyte = (data.len() / 100) as i32;
 				if size_hectobyte > conf.data_size.max {
 					return Err(AddCrateItemErr::PayloadLarge);
 				}
 
-				let mut tx = db().await.begin().await.unwrap();
+				match reserve(&mut *tx, self.crate_id, &self.addr, size_hectobyte as i64).await {
+					Ok(_) => {}
+					Err(QuotaErr::Full) => return Err(AddCrateItemErr::StorageFull),
+					Err(_) => return Err(AddCrateItemErr::QuotaExceeded),
+				}
 				let id = Uuid::new_v4();
+				match crate_key(&mut *tx, self.crate_id).await {
+					Ok(Some(data_key)) => return self.add_encrypted(tx, id, &data_key, &data, size_hectobyte, e2e).await,
+					Ok(None) => {}
+					Err(_) => return Err(AddCrateItemErr::InternalErr),
+				}
+				let mut stored_hectobyte = size_hectobyte;
 				let item_storage = if size_hectobyte > conf.data_size.max_db {
-					if let Ok(_fname) =
-						write_file(&format!("{}/{}_{}", conf.chunks_location, id, 0), &data).await
-					{
-						sqlx::query("INSERT INTO crate_item_chunk(id, crate_item_id, sha2_hash, size_hectobyte)
-							VALUES(0, $1, $2, $3)").bind(id)
-						.bind(self.sha2_hash).bind(size_hectobyte)
-						.fetch_optional(&mut *tx).await.unwrap();
+					if let Ok(stored) = put_blob(&mut *tx, &self.sha2_hash, &data, size_hectobyte).await {
+						stored_hectobyte = stored;
 						Some(CrateItemStorage::File)
 					} else {
 						return Err(AddCrateItemErr::InternalErr);
-						None
 					}
 				} else {
 					None
 				};
+				// end to end encrypted data is the client's ciphertext, it's kept as bytes
+				// whatever its media type says and isn't compressed
+				let kind = if e2e { None } else { self.media_type.split_once('/') };
+				// text and bytes kept in the db are compressed, json stays queryable
+				let packed = match (item_storage, kind) {
+					(None, Some(("application", "json"))) => None,
+					(None, Some(_)) => pack(&data).await,
+					_ => None,
+				};
+				if let Some(packed) = &packed {
+					stored_hectobyte = (packed.len() / 100) as i32;
+				}
+				let compression = packed.as_ref().map(|_| ZSTD);
 
-				let rr = match self.media_type.split_once('/').unwrap() {
-					("application", "json") => {
+				let rr = match kind {
+					Some(("application", "json")) => {
 						let tdata = if item_storage.is_some() {
 							None
 						} else {
-							Some(String::from_utf8(data).unwrap_or_default())
+							Some(utf8(data)?)
 						};
 						sqlx::query_scalar::<_, Uuid>(
 							"INSERT INTO
-						crate_item(id, crate_id, item_path, data_json, type_id, size_hectobyte, item_storage, complete, added_by, scope_id)
+						crate_item(id, crate_id, item_path, data_json, type_id, size_hectobyte, item_storage, complete, added_by, scope_id, stored_hectobyte,
+							metadata, tags)
 							VALUES($1, $2, $3, $4::json, (SELECT id from item_type where media_type = $5),
-							$6, $7, true, $8::uuid, (SELECT id from scope where scope_type = $9)) RETURNING id",
+							$6, $7, true, $8::uuid, (SELECT id from scope where scope_type = $9), $10, $11, $12) RETURNING id",
 						)
 						.bind(id)
 						.bind(self.crate_id)
@@ -105,22 +200,34 @@ impl AddCrateItem {
 						.bind(item_storage.unwrap_or(CrateItemStorage::Json))
 						.bind(self.addr.to_uuid())
 						.bind(self.scope)
+						.bind(stored_hectobyte)
+						.bind(Json(self.metadata))
+						.bind(self.tags)
 						.fetch_one(&mut *tx)
 						.await
 						.unwrap()
 					}
 
-					("text", ..) => {
-						let tdata = if item_storage.is_some() {
+					Some(("text", ..)) => {
+						// compressed text and text kept in files is indexed for search here,
+						// the db can't read it
+						let vector_text = if item_storage.is_some() || packed.is_some() {
+							String::from_utf8(data.clone()).ok()
+						} else {
+							None
+						};
+						let tdata = if item_storage.is_some() || packed.is_some() {
 							None
 						} else {
-							Some(String::from_utf8(data).unwrap_or_default())
+							Some(utf8(data)?)
 						};
 						sqlx::query_scalar::<_, Uuid>(
 							"INSERT INTO
-						crate_item(id, crate_id, item_path, data_text, type_id, size_hectobyte, item_storage, complete, added_by, scope_id)
+						crate_item(id, crate_id, item_path, data_text, type_id, size_hectobyte, item_storage, complete, added_by, scope_id,
+							data_bytes, compression, stored_hectobyte, text_vector, metadata, tags)
 							VALUES($1, $2, $3, $4, (SELECT id from item_type where media_type = $5),
-							$6, $7, true, $8::uuid, (SELECT id from scope where scope_type = $9)) RETURNING id",
+							$6, $7, true, $8::uuid, (SELECT id from scope where scope_type = $9), $10, $11, $12,
+							to_tsvector('english', left($13, 262144)), $14, $15) RETURNING id",
 						)
 						.bind(id)
 						.bind(self.crate_id)
@@ -131,22 +238,29 @@ impl AddCrateItem {
 						.bind(item_storage.unwrap_or(CrateItemStorage::Text))
 						.bind(self.addr.to_uuid())
 						.bind(self.scope)
+						.bind(packed)
+						.bind(compression)
+						.bind(stored_hectobyte)
+						.bind(vector_text)
+						.bind(Json(self.metadata))
+						.bind(self.tags)
 						.fetch_one(&mut *tx)
 						.await
 						.unwrap()
 					}
 
-					(_, _) => {
+					_ => {
 						let tdata = if item_storage.is_some() {
 							None
 						} else {
-							Some(data)
+							Some(packed.unwrap_or(data))
 						};
 						sqlx::query_scalar::<_, Uuid>(
 							"INSERT INTO
-						crate_item(id, crate_id, item_path, data_bytes, type_id, size_hectobyte, item_storage, complete, added_by, scope_id)
+						crate_item(id, crate_id, item_path, data_bytes, type_id, size_hectobyte, item_storage, complete, added_by, scope_id,
+							compression, stored_hectobyte, metadata, tags)
 							VALUES($1, $2, $3, $4, (SELECT id from item_type where media_type = $5),
-							$6, $7, true, $8::uuid, (SELECT id from scope where scope_type = $9)) RETURNING id",
+							$6, $7, true, $8::uuid, (SELECT id from scope where scope_type = $9), $10, $11, $12, $13) RETURNING id",
 						)
 						.bind(id)
 						.bind(self.crate_id)
@@ -157,21 +271,32 @@ impl AddCrateItem {
 						.bind(item_storage.unwrap_or(CrateItemStorage::Bytes))
 						.bind(self.addr.to_uuid())
 						.bind(self.scope)
+						.bind(compression)
+						.bind(stored_hectobyte)
+						.bind(Json(self.metadata))
+						.bind(self.tags)
 						.fetch_one(&mut *tx)
 						.await
 						.unwrap()
 					}
 				};
-				tx.commit().await.unwrap();
+				if item_storage.is_some() {
+					sqlx::query("INSERT INTO crate_item_chunk(id, crate_item_id, sha2_hash, size_hectobyte)
+						VALUES(0, $1, $2, $3)").bind(id)
+					.bind(self.sha2_hash).bind(size_hectobyte)
+					.execute(&mut *tx).await.unwrap();
+				}
 				return Ok(rr);
 			} else {
 				return Err(AddCrateItemErr::InternalErr);
 			}
+		} else if self.proof.is_some() {
+			self.add_by_reference(tx).await
 		} else {
 			let rr = sqlx::query_scalar::<_, Uuid>(
 				"INSERT INTO
-						crate_item(crate_id, item_path, data_bytes, media_type, size_hectobyte, item_storage, added_by, scope_id)
-							VALUES($1, $2, $3, $4, 0, 'File', $5::uuid, (SELECT id from scope where scope_type = $6)) RETURNING id",
+						crate_item(crate_id, item_path, data_bytes, media_type, size_hectobyte, item_storage, added_by, scope_id, metadata, tags)
+							VALUES($1, $2, $3, $4, 0, 'File', $5::uuid, (SELECT id from scope where scope_type = $6), $7, $8) RETURNING id",
 			)
 			.bind(self.crate_id)
 			.bind(self.item_path)
@@ -179,10 +304,139 @@ impl AddCrateItem {
 			.bind(self.media_type)
 			.bind(self.addr.to_uuid())
 			.bind(self.scope)
-			.fetch_one(db().await)
+			.bind(Json(self.metadata))
+			.bind(self.tags)
+			.fetch_one(&mut *tx)
 			.await
 			.unwrap();
 			return Ok(rr);
 		}
 	}
-}
\ No newline at end of file
+
+	// sealed data goes in data_bytes, or a blob of its own when it's too big for the db
+	async fn add_encrypted(
+		self,
+		tx: &mut PgConnection,
+		id: Uuid,
+		data_key: &[u8; 32],
+		data: &[u8],
+		size_hectobyte: i32,
+		e2e: bool,
+	) -> Result<Uuid, AddCrateItemErr> {
+		let conf = get_config().await;
+		// compressed before it's sealed, sealed data doesn't compress
+		let packed = pack(data).await;
+		let compression = packed.as_ref().map(|_| ZSTD);
+		let sealed = seal(data_key, id.as_bytes(), packed.as_deref().unwrap_or(data));
+		let stored_hectobyte = (sealed.len() / 100) as i32;
+		let (item_storage, data_bytes, blob_hash) = if size_hectobyte > conf.data_size.max_db {
+			let sealed_hash = Sha256::digest(&sealed).as_slice().to_vec();
+			if put_blob(&mut *tx, &sealed_hash, &sealed, stored_hectobyte).await.is_err() {
+				return Err(AddCrateItemErr::InternalErr);
+			}
+			(CrateItemStorage::File, None, Some(sealed_hash))
+		} else {
+			// unsealed end to end encrypted data is still ciphertext, not json or text
+			let item_storage = match self.media_type.split_once('/').unwrap() {
+				_ if e2e => CrateItemStorage::Bytes,
+				("application", "json") => CrateItemStorage::Json,
+				("text", ..) => CrateItemStorage::Text,
+				(_, _) => CrateItemStorage::Bytes,
+			};
+			(item_storage, Some(sealed), None)
+		};
+		let rr = sqlx::query_scalar::<_, Uuid>(
+			"INSERT INTO
+		crate_item(id, crate_id, item_path, data_bytes, type_id, size_hectobyte, item_storage, complete, added_by, scope_id, encrypted,
+			compression, stored_hectobyte, metadata, tags)
+			VALUES($1, $2, $3, $4, (SELECT id from item_type where media_type = $5),
+			$6, $7, true, $8::uuid, (SELECT id from scope where scope_type = $9), true, $10, $11, $12, $13) RETURNING id",
+		)
+		.bind(id)
+		.bind(self.crate_id)
+		.bind(self.item_path)
+		.bind(data_bytes)
+		.bind(self.media_type)
+		.bind(size_hectobyte)
+		.bind(item_storage)
+		.bind(self.addr.to_uuid())
+		.bind(self.scope)
+		.bind(compression)
+		.bind(stored_hectobyte)
+		.bind(Json(self.metadata))
+		.bind(self.tags)
+		.fetch_one(&mut *tx)
+		.await
+		.unwrap();
+		if let Some(sealed_hash) = blob_hash {
+			sqlx::query("INSERT INTO crate_item_chunk(id, crate_item_id, sha2_hash, size_hectobyte)
+				VALUES(0, $1, $2, $3)").bind(id)
+			.bind(sealed_hash).bind(stored_hectobyte)
+			.execute(&mut *tx).await.unwrap();
+		}
+		Ok(rr)
+	}
+
+	// json has to satisfy the schemas attached to the item path
+	async fn check_schema(&self, conn: &mut PgConnection, data: &[u8]) -> Result<(), AddCrateItemErr> {
+		if self.media_type != "application/json" {
+			return Ok(());
+		}
+		let data = serde_json::from_slice::<serde_json::Value>(data).map_err(|e| {
+			AddCrateItemErr::InvalidData(ValidationErr::BadJson(e.to_string()))
+		})?;
+		match check_item(conn, self.crate_id, &self.item_path, &data).await {
+			Ok(_) => Ok(()),
+			Err(SchemaErr::Invalid(errors)) => Err(AddCrateItemErr::SchemaViolation(errors)),
+			Err(_) => Err(AddCrateItemErr::InternalErr),
+		}
+	}
+
+	// item pointing at a known blob, for uploads that only prove they have the data
+	async fn add_by_reference(self, tx: &mut PgConnection) -> Result<Uuid, AddCrateItemErr> {
+		// blobs are plain data, encrypted crates only get data uploaded to them
+		if !matches!(crate_key(&mut *tx, self.crate_id).await, Ok(None)) {
+			return Err(AddCrateItemErr::UnknownBlob);
+		}
+		let blob = lock_blob(&mut *tx, &self.sha2_hash).await.ok_or(AddCrateItemErr::UnknownBlob)?;
+		let data = read_blob(&blob.sha2_hash).await.map_err(|_| AddCrateItemErr::InternalErr)?;
+		let proof = possession_proof(&self.crate_id, &self.item_path, &data);
+		if Some(proof) != self.proof {
+			return Err(AddCrateItemErr::UnknownBlob);
+		}
+		// the blob may have been uploaded as another type, it's not changed in place
+		match validate(&self.media_type, data.clone()).await {
+			Ok(valid) if valid == data => {}
+			Ok(_) => return Err(AddCrateItemErr::InvalidData(ValidationErr::ScriptContent)),
+			Err(e) => return Err(AddCrateItemErr::InvalidData(e)),
+		}
+		self.check_schema(&mut *tx, &data).await?;
+		match reserve(&mut *tx, self.crate_id, &self.addr, blob.size_hectobyte as i64).await {
+			Ok(_) => {}
+			Err(QuotaErr::Full) => return Err(AddCrateItemErr::StorageFull),
+			Err(_) => return Err(AddCrateItemErr::QuotaExceeded),
+		}
+		let id = sqlx::query_scalar::<_, Uuid>(
+			"INSERT INTO
+		crate_item(crate_id, item_path, type_id, size_hectobyte, item_storage, complete, added_by, scope_id, metadata, tags)
+			VALUES($1, $2, (SELECT id from item_type where media_type = $3),
+			$4, 'File', true, $5::uuid, (SELECT id from scope where scope_type = $6), $7, $8) RETURNING id",
+		)
+		.bind(self.crate_id)
+		.bind(self.item_path)
+		.bind(self.media_type)
+		.bind(blob.size_hectobyte)
+		.bind(self.addr.to_uuid())
+		.bind(self.scope)
+		.bind(Json(self.metadata))
+		.bind(self.tags)
+		.fetch_one(&mut *tx)
+		.await
+		.unwrap();
+		sqlx::query("INSERT INTO crate_item_chunk(id, crate_item_id, sha2_hash, size_hectobyte)
+			VALUES(0, $1, $2, $3)").bind(id)
+		.bind(blob.sha2_hash).bind(blob.size_hectobyte)
+		.execute(&mut *tx).await.unwrap();
+		Ok(id)
+	}
+}
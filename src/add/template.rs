@@ -0,0 +1,331 @@
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, HashSet};
+use std::{error::Error, fmt};
+use once_cell::sync::Lazy;
+use regex_lite::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::types::{Json, Uuid};
+use sqlx::{FromRow, PgConnection};
+use crate::{
+	AccessType, AddCrateReq, Crate, _add_crate_in,
+	comn_addr::ComnAddr, db::db,
+	encryption::master_keys,
+	add::crate_item::AddCrateItem,
+};
+
+// `{{addr}}` and the vars given when a template is applied
+static PLACEHOLDER: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{\{\s*([a-z_][a-z0-9_]*)\s*\}\}").unwrap());
+
+// most crates a template makes
+const MAX_CRATES: usize = 32;
+
+#[derive(Debug, PartialEq)]
+pub enum TemplateErr {
+	BadTemplate(String),
+	NotFound,
+	UnknownAddr,
+	// an item of the template couldn't be added, by its crate and path
+	BadItem(String, String),
+}
+
+impl Error for TemplateErr {}
+
+impl fmt::Display for TemplateErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateErr::BadTemplate(e) => write!(f, "template is bad, {}", e),
+            TemplateErr::NotFound => write!(f, "no template found"),
+            TemplateErr::UnknownAddr => write!(f, "addr is not registered"),
+            TemplateErr::BadItem(crate_name, e) => write!(f, "item of crate {} can't be added, {}", crate_name, e),
+        }
+    }
+}
+
+/// Item added to a crate made from a template.
+///
+/// Its data is `json`, `text` or `data` bytes, `media_type` defaults to
+/// application/json or text/plain for the first two.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TemplateItem {
+	pub item_path: String,
+	#[serde(default)]
+	pub media_type: Option<String>,
+	#[serde(default)]
+	pub json: Option<serde_json::Value>,
+	#[serde(default)]
+	pub text: Option<String>,
+	#[serde(default)]
+	pub data: Option<Vec<u8>>,
+	#[serde(default)]
+	pub metadata: HashMap<String, String>,
+	#[serde(default)]
+	pub tags: Vec<String>,
+}
+
+/// Access to a crate made from a template, `addr` is a comn address or a placeholder.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TemplateAccess {
+	pub addr: String,
+	pub access_type: AccessType,
+	#[serde(default)]
+	pub expires_in_days: Option<i64>,
+}
+
+/// Crate made from a template, owned by the addr the template is applied for.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TemplateCrate {
+	pub name: String,
+	#[serde(default)]
+	pub comment: String,
+	#[serde(default)]
+	pub expires_in_days: Option<i64>,
+	#[serde(default)]
+	pub encrypted: bool,
+	#[serde(default)]
+	pub items: Vec<TemplateItem>,
+	#[serde(default)]
+	pub access: Vec<TemplateAccess>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TemplateSpec {
+	pub crates: Vec<TemplateCrate>,
+}
+
+/// Registers a template under `name`, or replaces the one there, in JSON or TOML.
+///
+/// Strings anywhere in the crates can have placeholders, `{{addr}}` for the addr
+/// the template is applied for and `{{var}}` for the vars given with it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddTemplateReq {
+	pub name: String,
+	#[serde(default)]
+	pub description: Option<String>,
+	#[serde(flatten)]
+	pub spec: TemplateSpec,
+}
+
+/// Makes the crates of a template for `addr`, all of them or none.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ApplyTemplateReq {
+	pub name: String,
+	pub addr: ComnAddr,
+	#[serde(default)]
+	pub vars: HashMap<String, String>,
+}
+
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CrateTemplate {
+	pub name: String,
+	pub description: Option<String>,
+	pub spec: Json<serde_json::Value>,
+	pub created: DateTime<Utc>,
+	pub updated: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ProvisionedCrate {
+	#[serde(rename = "crate")]
+	pub crate_: Crate,
+	pub items: Vec<Uuid>,
+}
+
+impl TemplateSpec {
+	// what can be told before placeholders are filled in
+	fn check(&self) -> Result<(), TemplateErr> {
+		if self.crates.is_empty() || self.crates.len() > MAX_CRATES {
+			return Err(TemplateErr::BadTemplate(format!("a template has 1 to {} crates", MAX_CRATES)));
+		}
+		for template_crate in &self.crates {
+			let mut paths = HashSet::new();
+			for item in &template_crate.items {
+				if !paths.insert(&item.item_path) {
+					return Err(TemplateErr::BadTemplate(format!("{} is in {} twice", item.item_path, template_crate.name)));
+				}
+				let kinds = [item.json.is_some(), item.text.is_some(), item.data.is_some()];
+				if kinds.iter().filter(|kind| **kind).count() != 1 {
+					return Err(TemplateErr::BadTemplate(format!("{} has one of json, text or data", item.item_path)));
+				}
+				if item.data.is_some() && item.media_type.is_none() {
+					return Err(TemplateErr::BadTemplate(format!("{} has data without a media type", item.item_path)));
+				}
+			}
+		}
+		Ok(())
+	}
+}
+
+// every string in the value with its placeholders filled in
+fn fill(value: &mut serde_json::Value, vars: &HashMap<String, String>) -> Result<(), TemplateErr> {
+	match value {
+		serde_json::Value::String(s) => {
+			let mut unknown = None;
+			let filled = PLACEHOLDER.replace_all(s, |caps: &Captures| {
+				vars.get(&caps[1]).cloned().unwrap_or_else(|| {
+					unknown = Some(caps[1].to_string());
+					String::new()
+				})
+			}).to_string();
+			if let Some(var) = unknown {
+				return Err(TemplateErr::BadTemplate(format!("no value for {{{{{}}}}}", var)));
+			}
+			*s = filled;
+		}
+		serde_json::Value::Array(values) => {
+			for value in values {
+				fill(value, vars)?;
+			}
+		}
+		serde_json::Value::Object(map) => {
+			for value in map.values_mut() {
+				fill(value, vars)?;
+			}
+		}
+		_ => {}
+	}
+	Ok(())
+}
+
+fn days_from_now(days: Option<i64>) -> Option<DateTime<Utc>> {
+	days.map(|days| Utc::now() + Duration::days(days))
+}
+
+impl AddTemplateReq {
+	pub async fn register(&self) -> Result<CrateTemplate, TemplateErr> {
+		if self.name.trim().is_empty() {
+			return Err(TemplateErr::BadTemplate("name is missing".to_string()));
+		}
+		self.spec.check()?;
+		let template = sqlx::query_as::<_, CrateTemplate>(
+			"
+			INSERT INTO crate_template(name, description, spec)
+			VALUES($1, $2, $3)
+			ON CONFLICT (name) DO UPDATE
+			SET description = EXCLUDED.description, spec = EXCLUDED.spec, updated = now()
+			RETURNING *
+			"
+		)
+		.bind(self.name.trim())
+		.bind(self.description.clone())
+		.bind(Json(serde_json::to_value(&self.spec).unwrap()))
+		.fetch_one(db().await)
+		.await
+		.unwrap();
+		Ok(template)
+	}
+}
+
+pub async fn list_templates() -> Vec<CrateTemplate> {
+	sqlx::query_as::<_, CrateTemplate>("SELECT * FROM crate_template ORDER BY name")
+		.fetch_all(db().await)
+		.await
+		.unwrap()
+}
+
+pub async fn remove_template(name: &str) -> Result<(), TemplateErr> {
+	let removed = sqlx::query("DELETE FROM crate_template WHERE name = $1")
+		.bind(name)
+		.execute(db().await)
+		.await
+		.unwrap();
+	if removed.rows_affected() == 0 {
+		return Err(TemplateErr::NotFound);
+	}
+	Ok(())
+}
+
+impl ApplyTemplateReq {
+	pub async fn apply(&self) -> Result<Vec<ProvisionedCrate>, TemplateErr> {
+		let mut spec = sqlx::query_scalar::<_, Json<serde_json::Value>>("SELECT spec FROM crate_template WHERE name = $1")
+			.bind(&self.name)
+			.fetch_optional(db().await)
+			.await
+			.unwrap()
+			.ok_or(TemplateErr::NotFound)?
+			.0;
+		let mut vars = self.vars.clone();
+		vars.insert("addr".to_string(), self.addr.to_string());
+		fill(&mut spec, &vars)?;
+		let spec = serde_json::from_value::<TemplateSpec>(spec).map_err(|e| TemplateErr::BadTemplate(e.to_string()))?;
+		spec.check()?;
+		if spec.crates.iter().any(|template_crate| template_crate.encrypted) && master_keys().is_err() {
+			return Err(TemplateErr::BadTemplate("crates can't be encrypted here".to_string()));
+		}
+
+		// nothing is kept unless every crate, item and grant is
+		let mut tx = db().await.begin().await.unwrap();
+		let known = sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM addr WHERE id = $1::uuid)")
+			.bind(self.addr.to_uuid())
+			.fetch_one(&mut *tx)
+			.await
+			.unwrap();
+		if !known {
+			return Err(TemplateErr::UnknownAddr);
+		}
+		let mut provisioned = vec![];
+		for template_crate in spec.crates {
+			provisioned.push(self.provision(&mut *tx, template_crate).await?);
+		}
+		tx.commit().await.unwrap();
+		Ok(provisioned)
+	}
+
+	async fn provision(&self, tx: &mut PgConnection, template_crate: TemplateCrate) -> Result<ProvisionedCrate, TemplateErr> {
+		let crate_ = _add_crate_in(&mut *tx, AddCrateReq {
+			name: template_crate.name.clone(),
+			comment: template_crate.comment,
+			addr: self.addr.clone(),
+			expires: days_from_now(template_crate.expires_in_days),
+			encrypted: template_crate.encrypted,
+			envelope: None,
+		}).await;
+
+		for access in template_crate.access {
+			let addr = ComnAddr::parse(&access.addr).ok_or_else(|| {
+				TemplateErr::BadTemplate(format!("{} is not a comn address", access.addr))
+			})?;
+			sqlx::query(
+				"
+				INSERT INTO crate_access(crate_id, addr_id, type, expires)
+				VALUES($1, $2::uuid, $3, $4)
+				ON CONFLICT DO NOTHING
+				"
+			)
+			.bind(crate_.id)
+			.bind(addr.to_uuid())
+			.bind(access.access_type)
+			.bind(days_from_now(access.expires_in_days))
+			.execute(&mut *tx)
+			.await
+			.map_err(|_| TemplateErr::BadTemplate(format!("{} is not a known addr", addr)))?;
+		}
+
+		let mut items = vec![];
+		for item in template_crate.items {
+			let (media_type, data) = match (item.json, item.text, item.data) {
+				(Some(json), _, _) => ("application/json", serde_json::to_vec(&json).unwrap()),
+				(_, Some(text), _) => ("text/plain", text.into_bytes()),
+				(_, _, Some(data)) => ("application/octet-stream", data),
+				_ => unreachable!(),
+			};
+			let add_item = AddCrateItem {
+				crate_id: crate_.id,
+				addr: self.addr.clone(),
+				item_path: item.item_path.clone(),
+				media_type: item.media_type.unwrap_or(media_type.to_string()),
+				sha2_hash: Sha256::digest(&data).as_slice().to_vec(),
+				data: Some(data),
+				scope: "template".to_string(),
+				proof: None,
+				metadata: item.metadata,
+				tags: item.tags,
+			};
+			match add_item.add_in(&mut *tx).await {
+				Ok(id) => items.push(id),
+				Err(e) => return Err(TemplateErr::BadItem(template_crate.name, format!("{}, {}", item.item_path, e))),
+			}
+		}
+		Ok(ProvisionedCrate { crate_, items })
+	}
+}
@@ -0,0 +1,407 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Deserializer, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::types::Uuid;
+use std::collections::HashMap;
+use std::io::Read;
+use std::{error::Error, fmt};
+use crate::{
+	AccessType, AddCrateReq, Crate, CrateItem, CrateItemStorage, _add_crate_in,
+	add::crate_item::AddCrateItem,
+	blob::read_item_blob,
+	comn_addr::ComnAddr,
+	compression::unpack_item,
+	db::db,
+	encryption::master_keys,
+	update::crates::{audit, has_access},
+};
+
+// bumped when the manifest changes in a way older brokers can't read
+pub const FORMAT_VERSION: u32 = 1;
+pub const MANIFEST: &str = "manifest.json";
+pub const MEDIA_TYPE: &str = "application/x-tar";
+// archives are read into memory to be imported, exports are held to it too
+pub const MAX_ARCHIVE_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum ArchiveErr {
+	BadArchive(String),
+	// data of the item doesn't match the hash in the manifest, by item path
+	HashMismatch(String),
+	NotFound,
+	Unauthorized,
+	EndToEnd,
+	TooLarge,
+	// an item couldn't be exported or imported, by its path
+	BadItem(String, String),
+}
+
+impl Error for ArchiveErr {}
+
+impl fmt::Display for ArchiveErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveErr::BadArchive(e) => write!(f, "archive is bad, {}", e),
+            ArchiveErr::HashMismatch(item_path) => write!(f, "data of {} doesn't match its hash", item_path),
+            ArchiveErr::NotFound => write!(f, "no crate found"),
+            ArchiveErr::Unauthorized => write!(f, "addr doesn't have access to the crate"),
+            ArchiveErr::EndToEnd => write!(f, "end to end encrypted crates can't be exported or imported into"),
+            ArchiveErr::TooLarge => write!(f, "archive would be more than {} bytes", MAX_ARCHIVE_BYTES),
+            ArchiveErr::BadItem(item_path, e) => write!(f, "item {} can't be moved, {}", item_path, e),
+        }
+    }
+}
+
+/// Everything in an archive but the item data, `manifest.json` at its root.
+///
+/// Item data is plain, decrypted and decompressed, in the file named by the
+/// item and checked against its SHA-256 when the archive is imported.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ArchiveManifest {
+	pub version: u32,
+	pub exported: DateTime<Utc>,
+	#[serde(rename = "crate")]
+	pub crate_: Crate,
+	// items are encrypted at rest, an imported crate is too
+	pub encrypted: bool,
+	pub access: Vec<ArchiveAccess>,
+	pub items: Vec<ArchiveItem>,
+}
+
+// manifests are written anywhere, their addrs are checked rather than trusted
+fn parse_addr<'de, D: Deserializer<'de>>(deserializer: D) -> Result<ComnAddr, D::Error> {
+	let addr = String::deserialize(deserializer)?;
+	ComnAddr::parse(&addr).ok_or_else(|| serde::de::Error::custom(format!("{} is not a comn address", addr)))
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ArchiveAccess {
+	#[serde(deserialize_with = "parse_addr")]
+	pub addr: ComnAddr,
+	pub access_type: AccessType,
+	pub expires: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ArchiveItem {
+	// name of the item's data in the archive
+	pub file: String,
+	pub item_path: String,
+	pub media_type: String,
+	pub scope: String,
+	#[serde(deserialize_with = "parse_addr")]
+	pub added_by: ComnAddr,
+	// hex of the SHA-256 of the data
+	pub sha2_hash: String,
+	pub size: u64,
+	#[serde(default)]
+	pub metadata: HashMap<String, String>,
+	#[serde(default)]
+	pub tags: Vec<String>,
+	pub expires: Option<DateTime<Utc>>,
+	pub created: DateTime<Utc>,
+}
+
+/// Exports a crate an addr owns or administers, with its items and access list.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ExportCrateReq {
+	pub crate_id: Uuid,
+	pub addr: ComnAddr,
+}
+
+/// Imports an archive as a new crate owned by `addr`, or into `crate_id` when
+/// the addr can edit it. Only `addr` gets access to a new crate, the archive's
+/// access list is handed back for it to grant again.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ImportCrateReq {
+	pub addr: ComnAddr,
+	#[serde(default)]
+	pub crate_id: Option<Uuid>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ImportedCrate {
+	#[serde(rename = "crate")]
+	pub crate_: Crate,
+	pub items: Vec<Uuid>,
+	// addrs of the archive's access list other than the importer, none get access
+	pub skipped_access: Vec<ComnAddr>,
+}
+
+// data of an unpacked item as it was uploaded, None for items without any
+fn item_data(item: &mut CrateItem) -> Option<Vec<u8>> {
+	match item.item_storage {
+		CrateItemStorage::File => item.data_file.take(),
+		CrateItemStorage::Json => item.data_json.take().map(|json| serde_json::to_vec(&json.0).unwrap()),
+		CrateItemStorage::Text => item.data_text.take().map(String::into_bytes),
+		CrateItemStorage::Bytes => item.data_bytes.take(),
+	}
+}
+
+// bytes a file takes in a tar archive, its header and data padded to blocks
+fn entry_size(data: &[u8]) -> usize {
+	512 + (data.len() + 511) / 512 * 512
+}
+
+fn append(builder: &mut tar::Builder<Vec<u8>>, name: &str, data: &[u8]) {
+	let mut header = tar::Header::new_gnu();
+	header.set_size(data.len() as u64);
+	header.set_mode(0o644);
+	header.set_mtime(Utc::now().timestamp() as u64);
+	// writing to memory doesn't fail
+	builder.append_data(&mut header, name, data).unwrap();
+}
+
+// the manifest and data of every item in it, checked against their hashes
+fn read_archive(archive: &[u8]) -> Result<(ArchiveManifest, HashMap<String, Vec<u8>>), ArchiveErr> {
+	let bad = |e: std::io::Error| ArchiveErr::BadArchive(e.to_string());
+	let mut files = HashMap::new();
+	let mut archive = tar::Archive::new(archive);
+	for entry in archive.entries().map_err(bad)? {
+		let mut entry = entry.map_err(bad)?;
+		if !entry.header().entry_type().is_file() {
+			continue;
+		}
+		let name = entry.path().map_err(bad)?.to_string_lossy().to_string();
+		let mut data = vec![];
+		entry.read_to_end(&mut data).map_err(bad)?;
+		files.insert(name, data);
+	}
+	let manifest = files
+		.remove(MANIFEST)
+		.ok_or_else(|| ArchiveErr::BadArchive(format!("{} is missing", MANIFEST)))?;
+	let manifest = serde_json::from_slice::<ArchiveManifest>(&manifest)
+		.map_err(|e| ArchiveErr::BadArchive(e.to_string()))?;
+	if manifest.version != FORMAT_VERSION {
+		return Err(ArchiveErr::BadArchive(format!("version {} is not supported", manifest.version)));
+	}
+	for item in &manifest.items {
+		let data = files
+			.get(&item.file)
+			.ok_or_else(|| ArchiveErr::BadArchive(format!("data of {} is missing", item.item_path)))?;
+		if hex::encode(Sha256::digest(data)) != item.sha2_hash.to_lowercase() {
+			return Err(ArchiveErr::HashMismatch(item.item_path.clone()));
+		}
+	}
+	Ok((manifest, files))
+}
+
+impl ExportCrateReq {
+	/// The crate as a tar archive, the manifest first and then a file per item.
+	pub async fn export(&self) -> Result<Vec<u8>, ArchiveErr> {
+		let mut conn = db().await.acquire().await.unwrap();
+		let crate_ = sqlx::query_as::<_, Crate>("SELECT * FROM crate WHERE id = $1")
+			.bind(self.crate_id)
+			.fetch_optional(&mut *conn)
+			.await
+			.unwrap()
+			.ok_or(ArchiveErr::NotFound)?;
+		if !has_access(&mut *conn, self.crate_id, &self.addr, &[AccessType::Owner, AccessType::Admin]).await {
+			return Err(ArchiveErr::Unauthorized);
+		}
+		// its data can only be read with the members' keys
+		if crate_.e2e {
+			return Err(ArchiveErr::EndToEnd);
+		}
+		let encrypted = sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM crate_key WHERE crate_id = $1)")
+			.bind(self.crate_id)
+			.fetch_one(&mut *conn)
+			.await
+			.unwrap();
+		let access = sqlx::query_as::<_, (Uuid, AccessType, Option<DateTime<Utc>>)>(
+			"SELECT addr_id, type, expires FROM crate_access WHERE crate_id = $1 ORDER BY created"
+		)
+		.bind(self.crate_id)
+		.fetch_all(&mut *conn)
+		.await
+		.unwrap()
+		.into_iter()
+		.map(|(addr_id, access_type, expires)| ArchiveAccess {
+			addr: ComnAddr::from_uuid(&addr_id.to_string()).unwrap(),
+			access_type,
+			expires,
+		})
+		.collect();
+		// oldest first, so the latest of a path is the latest again once imported
+		let crate_items = sqlx::query_as::<_, CrateItem>(
+			"
+			SELECT
+			ci.*, it.media_type, NULL as data_file, s.scope_type as scope,
+			(SELECT COUNT(*) FROM crate_item_chunk WHERE crate_item_id = ci.id)::SMALLINT AS chunk_count
+			FROM crate_item ci
+			JOIN item_type it ON it.id = ci.type_id
+			JOIN scope s ON s.id = ci.scope_id
+			WHERE ci.crate_id = $1 AND ci.complete
+			ORDER BY ci.created, ci.id
+			"
+		)
+		.bind(self.crate_id)
+		.fetch_all(&mut *conn)
+		.await
+		.unwrap();
+
+		let mut items = vec![];
+		let mut files = vec![];
+		let mut archive_bytes = 0;
+		for mut item in crate_items {
+			if item.chunk_count > 0 {
+				item.data_file = Some(read_item_blob(item.id).await.ok_or_else(|| {
+					ArchiveErr::BadItem(item.item_path.clone(), "data is missing".to_string())
+				})?);
+			}
+			if let Err(e) = unpack_item(&mut item).await {
+				return Err(ArchiveErr::BadItem(item.item_path, e.to_string()));
+			}
+			let Some(data) = item_data(&mut item) else {
+				continue;
+			};
+			archive_bytes += entry_size(&data);
+			if archive_bytes > MAX_ARCHIVE_BYTES {
+				return Err(ArchiveErr::TooLarge);
+			}
+			let file = format!("items/{}", item.id.simple());
+			items.push(ArchiveItem {
+				file: file.clone(),
+				item_path: item.item_path,
+				media_type: item.media_type,
+				scope: item.scope,
+				added_by: ComnAddr::from_uuid(&item.added_by.to_string()).unwrap(),
+				sha2_hash: hex::encode(Sha256::digest(&data)),
+				size: data.len() as u64,
+				metadata: item.metadata.0,
+				tags: item.tags,
+				expires: item.expires,
+				created: item.created,
+			});
+			files.push((file, data));
+		}
+		let manifest = ArchiveManifest {
+			version: FORMAT_VERSION,
+			exported: Utc::now(),
+			crate_,
+			encrypted,
+			access,
+			items,
+		};
+
+		let mut builder = tar::Builder::new(vec![]);
+		append(&mut builder, MANIFEST, &serde_json::to_vec_pretty(&manifest).unwrap());
+		for (file, data) in &files {
+			append(&mut builder, file, data);
+		}
+		let archive = builder.into_inner().unwrap();
+		// so it can be imported again
+		if archive.len() > MAX_ARCHIVE_BYTES {
+			return Err(ArchiveErr::TooLarge);
+		}
+		audit(&mut *conn, self.crate_id, &self.addr, "export", serde_json::json!({
+			"items": manifest.items.len(),
+		})).await;
+		Ok(archive)
+	}
+}
+
+impl ImportCrateReq {
+	/// Adds the crate and items of the archive, all of them or none.
+	///
+	/// Items are added like uploads by `addr`, so they're validated and count
+	/// towards its quota, they get new ids.
+	pub async fn import(&self, archive: &[u8]) -> Result<ImportedCrate, ArchiveErr> {
+		let (manifest, files) = read_archive(archive)?;
+		if self.crate_id.is_none() && manifest.encrypted && master_keys().is_err() {
+			return Err(ArchiveErr::BadArchive("crates can't be encrypted here".to_string()));
+		}
+
+		let mut tx = db().await.begin().await.unwrap();
+		let mut skipped_access = vec![];
+		let crate_ = match self.crate_id {
+			Some(crate_id) => {
+				let crate_ = sqlx::query_as::<_, Crate>("SELECT * FROM crate WHERE id = $1")
+					.bind(crate_id)
+					.fetch_optional(&mut *tx)
+					.await
+					.unwrap()
+					.ok_or(ArchiveErr::NotFound)?;
+				let writers = [AccessType::Owner, AccessType::Admin, AccessType::Editor];
+				if !has_access(&mut *tx, crate_id, &self.addr, &writers).await {
+					return Err(ArchiveErr::Unauthorized);
+				}
+				// plain data can't go where only ciphertext is kept
+				if crate_.e2e {
+					return Err(ArchiveErr::EndToEnd);
+				}
+				crate_
+			}
+			None => {
+				let crate_ = _add_crate_in(&mut *tx, AddCrateReq {
+					name: manifest.crate_.name.clone(),
+					comment: manifest.crate_.comment.clone().unwrap_or_default(),
+					addr: self.addr.clone(),
+					expires: manifest.crate_.expires,
+					encrypted: manifest.encrypted,
+					envelope: None,
+				}).await;
+				// the importer owns it, granting the others would let anyone make
+				// any addr an owner and fill its quota
+				skipped_access = manifest.access.iter()
+					.filter(|access| access.addr != self.addr)
+					.map(|access| access.addr.clone())
+					.collect();
+				crate_
+			}
+		};
+
+		let mut items = vec![];
+		for item in manifest.items {
+			// read_archive checked every item has its data
+			let data = files[&item.file].clone();
+			let scope = sqlx::query_scalar::<_, String>("SELECT scope_type FROM scope WHERE scope_type = $1 LIMIT 1")
+				.bind(&item.scope)
+				.fetch_optional(&mut *tx)
+				.await
+				.unwrap()
+				.unwrap_or("import".to_string());
+			// importing over existing items would silently fork their history
+			let exists = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM crate_item WHERE crate_id = $1 AND item_path = $2")
+				.bind(crate_.id)
+				.bind(&item.item_path)
+				.fetch_one(&mut *tx)
+				.await
+				.unwrap();
+			if exists > 0 {
+				return Err(ArchiveErr::BadItem(item.item_path, "it is already in the crate".to_string()));
+			}
+			let add_item = AddCrateItem {
+				crate_id: crate_.id,
+				addr: self.addr.clone(),
+				item_path: item.item_path.clone(),
+				media_type: item.media_type,
+				sha2_hash: Sha256::digest(&data).as_slice().to_vec(),
+				data: Some(data),
+				scope,
+				proof: None,
+				metadata: item.metadata,
+				tags: item.tags,
+			};
+			let id = match add_item.add_in(&mut *tx).await {
+				Ok(id) => id,
+				Err(e) => return Err(ArchiveErr::BadItem(item.item_path, e.to_string())),
+			};
+			if item.expires.is_some() {
+				sqlx::query("UPDATE crate_item SET expires = $2 WHERE id = $1")
+					.bind(id)
+					.bind(item.expires)
+					.execute(&mut *tx)
+					.await
+					.unwrap();
+			}
+			items.push(id);
+		}
+		audit(&mut *tx, crate_.id, &self.addr, "import", serde_json::json!({
+			"source": manifest.crate_.id,
+			"items": items.len(),
+		})).await;
+		tx.commit().await.unwrap();
+		Ok(ImportedCrate { crate_, items, skipped_access })
+	}
+}
@@ -0,0 +1,175 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::types::Uuid;
+use sqlx::{FromRow, PgConnection};
+use std::time::Duration;
+use tokio::time::interval;
+use crate::{
+	compression::{pack, unpack, ZSTD},
+	db::{db, get_config},
+	store::{chunk_store, StoreErr},
+};
+
+/// Data of file stored items, kept once per SHA-256 however many items point at it.
+///
+/// `ref_count` is the number of `crate_item_chunk` rows with the hash and is kept
+/// by triggers, blobs nobody references are removed by `collect_garbage`.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Blob {
+	pub sha2_hash: Vec<u8>,
+	pub size_hectobyte: i32,
+	pub ref_count: i64,
+	pub compression: Option<String>,
+	pub stored_hectobyte: i32,
+	pub created: DateTime<Utc>,
+	pub updated: DateTime<Utc>,
+}
+
+// name of the blob's data in the chunk store
+pub fn blob_key(sha2_hash: &[u8]) -> String {
+	hex::encode(sha2_hash)
+}
+
+/// Stores data under its hash unless it's already there, compressed when
+/// it's worth it. Returns the hectobytes it takes in the chunk store.
+///
+/// The blob row stays locked until `conn`'s transaction ends, so garbage
+/// collection can't remove it before the chunk referencing it is added.
+pub async fn put_blob(
+	conn: &mut PgConnection,
+	sha2_hash: &[u8],
+	data: &[u8],
+	size_hectobyte: i32,
+) -> Result<i32, StoreErr> {
+	let blob = sqlx::query_as::<_, (bool, Option<String>, i32)>(
+		"
+		INSERT INTO blob(sha2_hash, size_hectobyte) VALUES($1, $2)
+		ON CONFLICT (sha2_hash) DO UPDATE SET updated = CURRENT_TIMESTAMP
+		RETURNING (xmax = 0), compression, stored_hectobyte
+		"
+	)
+	.bind(sha2_hash)
+	.bind(size_hectobyte)
+	.fetch_one(&mut *conn)
+	.await
+	.unwrap();
+	let (inserted, mut compression, mut stored_hectobyte) = blob;
+	let store = chunk_store().await;
+	let key = blob_key(sha2_hash);
+	if inserted || !store.exists(&key).await? {
+		let packed = pack(data).await;
+		compression = packed.as_ref().map(|_| ZSTD.to_string());
+		stored_hectobyte = (packed.as_ref().map_or(data.len(), |p| p.len()) / 100) as i32;
+		store.put(&key, packed.as_deref().unwrap_or(data)).await?;
+		sqlx::query("UPDATE blob SET compression = $2, stored_hectobyte = $3 WHERE sha2_hash = $1")
+			.bind(sha2_hash)
+			.bind(&compression)
+			.bind(stored_hectobyte)
+			.execute(&mut *conn)
+			.await
+			.unwrap();
+	}
+	Ok(stored_hectobyte)
+}
+
+// locks the blob like put_blob, None if it's unknown or about to be collected
+pub async fn lock_blob(conn: &mut PgConnection, sha2_hash: &[u8]) -> Option<Blob> {
+	sqlx::query_as::<_, Blob>(
+		"SELECT * FROM blob WHERE sha2_hash = $1 AND ref_count > 0 FOR UPDATE"
+	)
+	.bind(sha2_hash)
+	.fetch_optional(conn)
+	.await
+	.unwrap()
+}
+
+pub async fn get_blob(sha2_hash: &[u8]) -> Option<Blob> {
+	sqlx::query_as::<_, Blob>("SELECT * FROM blob WHERE sha2_hash = $1")
+		.bind(sha2_hash)
+		.fetch_optional(db().await)
+		.await
+		.unwrap()
+}
+
+pub async fn read_blob(sha2_hash: &[u8]) -> Result<Vec<u8>, StoreErr> {
+	let blob = get_blob(sha2_hash).await.ok_or(StoreErr::NotFound)?;
+	let data = chunk_store().await.get(&blob_key(sha2_hash)).await?;
+	unpack(blob.compression.as_deref(), data).map_err(|_| StoreErr::Failed)
+}
+
+// data of a file stored item
+pub async fn read_item_blob(crate_item_id: Uuid) -> Option<Vec<u8>> {
+	let sha2_hash = sqlx::query_scalar::<_, Vec<u8>>(
+		"SELECT sha2_hash FROM crate_item_chunk WHERE crate_item_id = $1 AND id = 0"
+	)
+	.bind(crate_item_id)
+	.fetch_optional(db().await)
+	.await
+	.unwrap()?;
+	read_blob(&sha2_hash).await.ok()
+}
+
+/// What an uploader sends instead of data it claims the broker already has.
+///
+/// Knowing the hash isn't enough to make it, the data is needed, and it's bound
+/// to where the item goes so it can't be replayed elsewhere.
+pub fn possession_proof(crate_id: &Uuid, item_path: &str, data: &[u8]) -> Vec<u8> {
+	let mut hasher = Sha256::new();
+	hasher.update(format!("{}:{}:", crate_id.simple(), item_path).as_bytes());
+	hasher.update(data);
+	hasher.finalize().as_slice().to_vec()
+}
+
+/// Removes blobs no chunk references anymore, with their data.
+///
+/// Data goes before the rows are committed, so a concurrent `put_blob` of the
+/// same data waits and stores it again.
+pub async fn collect_garbage() -> Vec<Vec<u8>> {
+	let mut tx = db().await.begin().await.unwrap();
+	let hashes = sqlx::query_scalar::<_, Vec<u8>>(
+		"DELETE FROM blob WHERE ref_count <= 0 RETURNING sha2_hash"
+	)
+	.fetch_all(&mut *tx)
+	.await
+	.unwrap();
+	let store = chunk_store().await;
+	for sha2_hash in &hashes {
+		if let Err(e) = store.delete(&blob_key(sha2_hash)).await {
+			println!("blob {} not deleted {}", blob_key(sha2_hash), e);
+		}
+	}
+	tx.commit().await.unwrap();
+	hashes
+}
+
+/// Moves data files of the local store's older layouts into the chunk store,
+/// per item id before blobs and unsharded by hash before `store::local`.
+pub async fn backfill_blob_files() {
+	let chunks = sqlx::query_as::<_, (Uuid, Vec<u8>)>(
+		"SELECT crate_item_id, sha2_hash FROM crate_item_chunk WHERE id = 0"
+	)
+	.fetch_all(db().await)
+	.await
+	.unwrap();
+	let location = &get_config().await.chunks_location;
+	let store = chunk_store().await;
+	for (crate_item_id, sha2_hash) in chunks {
+		let key = blob_key(&sha2_hash);
+		for legacy in [format!("{}/{}_{}", location, crate_item_id, 0), format!("{}/{}", location, key)] {
+			if let Ok(data) = tokio::fs::read(&legacy).await {
+				if store.exists(&key).await == Ok(true) || store.put(&key, &data).await.is_ok() {
+					let _ = tokio::fs::remove_file(&legacy).await;
+				}
+			}
+		}
+	}
+}
+
+pub async fn worker(period: Duration) {
+	let mut ticker = interval(period);
+	loop {
+		ticker.tick().await;
+		collect_garbage().await;
+	}
+}
@@ -98,6 +98,19 @@ impl ComnAddr {
 
 		Ok(Self { addr: chars.collect::<String>() })
 	}
+	// like `new`, but None for strings that aren't comn addresses or don't fit a uuid
+	pub fn parse(addr: &str) -> Option<Self> {
+		let rest = addr.strip_prefix('≈')?;
+		if rest.is_empty() {
+			return None;
+		}
+		let radix = COMN_DIGITS.len() as u128;
+		rest.chars().try_fold(0u128, |x, c| {
+			let modulo = COMN_DIGITS.find(*_COMN_DIGITS.get(&c)?)? as u128;
+			x.checked_mul(radix)?.checked_add(modulo)
+		})?;
+		Some(Self { addr: rest.to_string() })
+	}
 
 	pub fn to_u128(&self) -> u128 {
 		let mut x: u128 = 0;
@@ -245,4 +258,13 @@ mod tests {
 			1_000_000_000_000_000_000_000_000_000
 		);
 	}
+	#[test]
+	fn parse() {
+		assert_eq!(ComnAddr::parse("≈6d"), ComnAddr::new("≈6d").ok());
+		assert_eq!(ComnAddr::parse("≈7ZZZZZZZZZZZZZZZZZZZZZZZZZ").unwrap().to_u128(), u128::MAX);
+		assert_eq!(ComnAddr::parse("≈8ZZZZZZZZZZZZZZZZZZZZZZZZZ"), None);
+		assert_eq!(ComnAddr::parse("≈6!"), None);
+		assert_eq!(ComnAddr::parse("≈"), None);
+		assert_eq!(ComnAddr::parse("6D"), None);
+	}
 }
@@ -0,0 +1,88 @@
+use salvo::compression::{Compression, CompressionLevel};
+use salvo::http::mime;
+use sqlx::types::Json;
+use std::{error::Error, fmt};
+use crate::{db::get_config, encryption::decrypt_item, CrateItem, CrateItemStorage};
+
+pub const ZSTD: &str = "zstd";
+
+#[derive(Debug, PartialEq)]
+pub enum CompressionErr {
+	Unsupported,
+	BadData,
+}
+
+impl Error for CompressionErr {}
+
+impl fmt::Display for CompressionErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompressionErr::Unsupported => write!(f, "data is compressed with an unknown algorithm"),
+            CompressionErr::BadData => write!(f, "stored data doesn't decompress"),
+        }
+    }
+}
+
+/// Data compressed with zstd to be stored in its place, None when compression
+/// is off, the data is under `min_bytes` or it doesn't get any smaller.
+pub async fn pack(data: &[u8]) -> Option<Vec<u8>> {
+	let conf = &get_config().await.compression;
+	if !conf.enabled || (data.len() as u64) < conf.min_bytes {
+		return None;
+	}
+	let packed = zstd::bulk::compress(data, conf.level).ok()?;
+	if packed.len() < data.len() {
+		Some(packed)
+	} else {
+		None
+	}
+}
+
+pub fn unpack(compression: Option<&str>, data: Vec<u8>) -> Result<Vec<u8>, CompressionErr> {
+	match compression {
+		None => Ok(data),
+		Some(ZSTD) => zstd::stream::decode_all(data.as_slice()).map_err(|_| CompressionErr::BadData),
+		Some(_) => Err(CompressionErr::Unsupported),
+	}
+}
+
+/// Turns an item read from the db back into what was uploaded.
+///
+/// Text and bytes that were compressed or sealed are kept in `data_bytes` and
+/// file data in the item's blob, they're decrypted, decompressed and put back
+/// in `data_json` or `data_text` when that's where they'd be otherwise.
+pub async fn unpack_item(item: &mut CrateItem) -> Result<(), CompressionErr> {
+	if let Err(e) = decrypt_item(item).await {
+		println!("item {} {}", item.id, e);
+		return Err(CompressionErr::BadData);
+	}
+	if let Some(data) = item.data_file.take() {
+		item.data_file = Some(unpack(item.compression.as_deref(), data)?);
+	}
+	if !item.encrypted && item.compression.is_none() {
+		return Ok(());
+	}
+	if let Some(data) = item.data_bytes.take() {
+		let data = unpack(item.compression.as_deref(), data)?;
+		match item.item_storage {
+			CrateItemStorage::Json => {
+				item.data_json = Some(Json(serde_json::from_slice(&data).map_err(|_| CompressionErr::BadData)?));
+			}
+			CrateItemStorage::Text => {
+				item.data_text = Some(String::from_utf8(data).map_err(|_| CompressionErr::BadData)?);
+			}
+			_ => item.data_bytes = Some(data),
+		}
+	}
+	Ok(())
+}
+
+// gzip, brotli or zstd for handler responses, whatever Accept-Encoding prefers
+pub fn response_compression() -> Compression {
+	Compression::new()
+		.enable_gzip(CompressionLevel::Default)
+		.enable_brotli(CompressionLevel::Default)
+		.enable_zstd(CompressionLevel::Default)
+		.min_length(1024)
+		.content_types(&[mime::APPLICATION_JSON, mime::TEXT_PLAIN_UTF_8, mime::TEXT_PLAIN])
+}
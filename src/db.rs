@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
 use sqlx::{FromRow, PgPool};
+use std::collections::HashMap;
 use tokio::sync::OnceCell;
+use crate::comn_addr::ComnAddr;
 
 static mut DB: OnceCell<PgPool> = OnceCell::const_new();
 
@@ -31,11 +33,105 @@ pub struct DataSize {
 	pub max_db: i32,
 }
 
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct PaymentConfig {
+	pub webhook_secret: String,
+	// coins are paid out of the treasury addr
+	pub treasury: ComnAddr,
+	pub comment: String,
+	// smallest currency unit per coin keyed by lowercase currency code, i.e. {"usd": 100}
+	pub rates: HashMap<String, i64>,
+}
+
+// checkout sessions the broker creates on stripe, the api base is STRIPE_API_BASE
+// and the secret key STRIPE_SECRET_KEY when they're set
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct CheckoutConfig {
+	pub secret_key: String,
+	pub currency: String,
+	pub product_name: String,
+	pub success_url: String,
+	pub cancel_url: String,
+	// most coins bought in one checkout
+	pub max_amount: i64,
+}
+
+// storage per addr across the crates it owns, extra blocks are bought with coins
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct QuotaConfig {
+	pub free_hectobyte: i64,
+	pub block_hectobyte: i64,
+	// coins per block
+	pub block_price: i64,
+	// no addr gets more storage than this, bought or not
+	pub max_hectobyte: i64,
+	pub treasury: ComnAddr,
+}
+
+// fee for an operation, charged only for request bodies of at least min_bytes
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct Fee {
+	pub amount: u64,
+	#[serde(default)]
+	pub min_bytes: u64,
+}
+
+// fees keyed by operation, see `fee::Metered`
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct FeeConfig {
+	pub collector: ComnAddr,
+	pub schedule: HashMap<String, Fee>,
+}
+
+// bucket of an S3 compatible service, addressed path style as {endpoint}/{bucket}/{key}
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct S3Config {
+	pub endpoint: String,
+	pub bucket: String,
+	pub region: String,
+	pub access_key: String,
+	pub secret_key: String,
+}
+
+// where chunks are kept, backend is "local" (under chunks_location) or "s3"
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct StoreConfig {
+	pub backend: String,
+	pub s3: S3Config,
+}
+
+// zstd for item data and chunks of at least min_bytes, see `compression::pack`
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct CompressionConfig {
+	pub enabled: bool,
+	pub min_bytes: u64,
+	pub level: i32,
+}
+
+// checks of uploaded data, see `validation::validate`
+#[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
+pub struct ValidationConfig {
+	// magic bytes have to match the media type
+	pub sniff: bool,
+	// script is stripped from html and svg
+	pub sanitize: bool,
+}
+
 #[derive(FromRow, Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
 	pub host_names: Vec<String>,
 	pub data_size: DataSize,
 	pub chunks_location: String,
+	pub chain_id: u64,
+	pub stripe: PaymentConfig,
+	// our own billing system, see `payment::webhook`
+	pub webhook: PaymentConfig,
+	pub checkout: CheckoutConfig,
+	pub quota: QuotaConfig,
+	pub fees: FeeConfig,
+	pub store: StoreConfig,
+	pub compression: CompressionConfig,
+	pub validation: ValidationConfig,
 }
 
 pub static CONFIG: OnceCell<Config> = OnceCell::const_new();
@@ -0,0 +1,251 @@
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use rand::prelude::*;
+use secp256k1::ecdh::SharedSecret;
+use secp256k1::{PublicKey, SecretKey, SECP256K1};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sqlx::types::Uuid;
+use sqlx::{FromRow, PgConnection};
+use std::collections::HashMap;
+use std::{error::Error, fmt};
+use crate::{comn_addr::ComnAddr, db::db, SpecialAddr};
+
+/// Crate key of an end to end encrypted crate sealed to one member's key.
+///
+/// ECIES over secp256k1: an ephemeral public key (33 bytes), a nonce (12) and
+/// the 32 byte crate key in AES-256-GCM (48), keyed with
+/// `sha256(ecdh(ephemeral, member) || ephemeral public key)`. Members seal
+/// and open them, the broker only checks the shape and hands them out.
+pub const ENVELOPE_LEN: usize = 33 + 12 + 32 + 16;
+
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct MemberEnvelope {
+	pub addr: ComnAddr,
+	pub key_version: i32,
+	pub envelope: Vec<u8>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CrateEnvelopes {
+	pub crate_id: Uuid,
+	pub key_version: i32,
+	// a member was removed and the crate key has to be replaced
+	pub rotate_key: bool,
+	pub envelopes: Vec<MemberEnvelope>,
+}
+
+/// A new crate key for everyone still in the crate, `key_version` is the next one
+/// and there's an envelope for each addr with access, keyed by addr.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RotateCrateKeyReq {
+	pub crate_id: String,
+	pub key_version: i32,
+	pub envelopes: HashMap<String, Vec<u8>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum E2eErr {
+	BadEnvelope,
+	NotE2e,
+	// envelopes of a rotation don't match the members
+	BadMembers,
+	// key_version isn't the next one
+	Conflict,
+}
+
+impl Error for E2eErr {}
+
+impl fmt::Display for E2eErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            E2eErr::BadEnvelope => write!(f, "envelope isn't an ECIES sealed crate key"),
+            E2eErr::NotE2e => write!(f, "crate isn't end to end encrypted"),
+            E2eErr::BadMembers => write!(f, "there has to be one envelope for each member"),
+            E2eErr::Conflict => write!(f, "crate key version has changed"),
+        }
+    }
+}
+
+pub fn check_envelope(envelope: &[u8]) -> Result<(), E2eErr> {
+	if envelope.len() != ENVELOPE_LEN || PublicKey::from_slice(&envelope[..33]).is_err() {
+		return Err(E2eErr::BadEnvelope);
+	}
+	Ok(())
+}
+
+fn envelope_cipher(shared: &SharedSecret, ephemeral: &PublicKey) -> Aes256Gcm {
+	let mut hasher = Sha256::new();
+	hasher.update(shared.secret_bytes());
+	hasher.update(ephemeral.serialize());
+	Aes256Gcm::new_from_slice(&hasher.finalize()).unwrap()
+}
+
+// what a member does to give another member the crate key
+pub fn seal_envelope(member: &PublicKey, crate_key: &[u8; 32]) -> Vec<u8> {
+	let mut rng = rand::thread_rng();
+	let ephemeral_secret = SecretKey::new(&mut rng);
+	let ephemeral = PublicKey::from_secret_key(SECP256K1, &ephemeral_secret);
+	let mut nonce = [0u8; 12];
+	rng.fill(&mut nonce);
+	let cipher = envelope_cipher(&SharedSecret::new(member, &ephemeral_secret), &ephemeral);
+	let mut envelope = ephemeral.serialize().to_vec();
+	envelope.extend(nonce);
+	envelope.extend(cipher.encrypt(Nonce::from_slice(&nonce), crate_key.as_slice()).unwrap());
+	envelope
+}
+
+pub fn open_envelope(secret_key: &SecretKey, envelope: &[u8]) -> Result<[u8; 32], E2eErr> {
+	check_envelope(envelope)?;
+	let ephemeral = PublicKey::from_slice(&envelope[..33]).unwrap();
+	let cipher = envelope_cipher(&SharedSecret::new(&ephemeral, secret_key), &ephemeral);
+	let crate_key = cipher
+		.decrypt(Nonce::from_slice(&envelope[33..45]), &envelope[45..])
+		.map_err(|_| E2eErr::BadEnvelope)?;
+	crate_key.try_into().map_err(|_| E2eErr::BadEnvelope)
+}
+
+async fn crate_key_state(conn: &mut PgConnection, crate_id: &str) -> Result<(i32, bool), E2eErr> {
+	sqlx::query_as::<_, (i32, bool)>(
+		"SELECT key_version, rotate_key FROM crate WHERE id = $1::uuid AND e2e FOR UPDATE"
+	)
+	.bind(crate_id)
+	.fetch_optional(conn)
+	.await
+	.unwrap()
+	.ok_or(E2eErr::NotE2e)
+}
+
+/// Checks access being given to addr on an e2e crate comes with its envelope,
+/// made with the current crate key. Public and registered addrs can't be members.
+///
+/// Returns the envelope's key version, None for crates that aren't e2e.
+pub async fn member_key_version(
+	conn: &mut PgConnection,
+	crate_id: &str,
+	addr: &ComnAddr,
+	envelope: &Option<Vec<u8>>,
+) -> Result<Option<i32>, E2eErr> {
+	let (key_version, rotate_key) = match crate_key_state(conn, crate_id).await {
+		Ok(state) => state,
+		Err(_) => return Ok(None),
+	};
+	if *addr == SpecialAddr::Public.value() || *addr == SpecialAddr::Registered.value() {
+		return Err(E2eErr::BadMembers);
+	}
+	if rotate_key {
+		return Err(E2eErr::Conflict);
+	}
+	check_envelope(envelope.as_deref().ok_or(E2eErr::BadEnvelope)?)?;
+	Ok(Some(key_version))
+}
+
+// the crate key has to be replaced once a member has no access left
+pub async fn member_removed(conn: &mut PgConnection, crate_id: &str, addr: &ComnAddr) {
+	sqlx::query(
+		"
+		UPDATE crate SET rotate_key = true WHERE id = $1::uuid AND e2e
+		AND NOT EXISTS (SELECT 1 FROM crate_access WHERE crate_id = $1::uuid AND addr_id = $2::uuid)
+		"
+	)
+	.bind(crate_id)
+	.bind(addr.to_uuid())
+	.execute(conn)
+	.await
+	.unwrap();
+}
+
+/// Envelopes of the addrs of `pub_key` in an e2e crate.
+pub async fn get_envelopes(crate_id: &str, pub_key: &PublicKey) -> Result<CrateEnvelopes, E2eErr> {
+	let mut conn = db().await.acquire().await.unwrap();
+	let (key_version, rotate_key) = crate_key_state(&mut conn, crate_id).await?;
+	let envelopes = sqlx::query_as::<_, (Uuid, i32, Vec<u8>)>(
+		"
+		SELECT DISTINCT ON (ca.addr_id) ca.addr_id, ca.key_version, ca.envelope
+		FROM crate_access ca
+		JOIN addr_key ak ON ak.addr_id = ca.addr_id
+		JOIN key k ON k.id = ak.key_id
+		WHERE ca.crate_id = $1::uuid AND k.pub_key = $2 AND ca.envelope IS NOT NULL
+		ORDER BY ca.addr_id, ca.key_version DESC
+		"
+	)
+	.bind(crate_id)
+	.bind(pub_key.serialize())
+	.fetch_all(&mut *conn)
+	.await
+	.unwrap();
+	Ok(CrateEnvelopes {
+		crate_id: Uuid::parse_str(crate_id).unwrap(),
+		key_version,
+		rotate_key,
+		envelopes: envelopes
+			.into_iter()
+			.map(|(addr_id, key_version, envelope)| MemberEnvelope {
+				addr: ComnAddr::from_uuid(&addr_id.to_string()).unwrap(),
+				key_version,
+				envelope,
+			})
+			.collect(),
+	})
+}
+
+impl RotateCrateKeyReq {
+	/// Replaces every member's envelope, in the caller's transaction.
+	pub async fn rotate(&self, conn: &mut PgConnection) -> Result<(), E2eErr> {
+		let (key_version, _) = crate_key_state(&mut *conn, &self.crate_id).await?;
+		if self.key_version != key_version + 1 {
+			return Err(E2eErr::Conflict);
+		}
+		let members = sqlx::query_scalar::<_, Uuid>(
+			"SELECT DISTINCT addr_id FROM crate_access WHERE crate_id = $1::uuid"
+		)
+		.bind(&self.crate_id)
+		.fetch_all(&mut *conn)
+		.await
+		.unwrap();
+		let mut envelopes = HashMap::new();
+		for (addr, envelope) in &self.envelopes {
+			let addr = ComnAddr::new(addr).map_err(|_| E2eErr::BadMembers)?;
+			check_envelope(envelope)?;
+			envelopes.insert(Uuid::parse_str(&addr.to_uuid()).unwrap(), envelope);
+		}
+		if envelopes.len() != members.len() || !members.iter().all(|m| envelopes.contains_key(m)) {
+			return Err(E2eErr::BadMembers);
+		}
+		for (addr_id, envelope) in envelopes {
+			sqlx::query(
+				"UPDATE crate_access SET envelope = $3, key_version = $4 WHERE crate_id = $1::uuid AND addr_id = $2"
+			)
+			.bind(&self.crate_id)
+			.bind(addr_id)
+			.bind(envelope)
+			.bind(self.key_version)
+			.execute(&mut *conn)
+			.await
+			.unwrap();
+		}
+		sqlx::query("UPDATE crate SET key_version = $2, rotate_key = false WHERE id = $1::uuid")
+			.bind(&self.crate_id)
+			.bind(self.key_version)
+			.execute(&mut *conn)
+			.await
+			.unwrap();
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn envelope_round_trip() {
+		let member_secret = SecretKey::new(&mut rand::thread_rng());
+		let member = PublicKey::from_secret_key(SECP256K1, &member_secret);
+		let envelope = seal_envelope(&member, &[5u8; 32]);
+		assert_eq!(envelope.len(), ENVELOPE_LEN);
+		assert_eq!(open_envelope(&member_secret, &envelope), Ok([5u8; 32]));
+		let other = SecretKey::new(&mut rand::thread_rng());
+		assert_eq!(open_envelope(&other, &envelope), Err(E2eErr::BadEnvelope));
+	}
+}
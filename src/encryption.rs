@@ -0,0 +1,181 @@
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use chrono::{DateTime, Utc};
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use sqlx::{FromRow, PgConnection};
+use std::{error::Error, fmt};
+use crate::{db::db, CrateItem};
+
+/// Data key of an encrypted crate, wrapped with the master key `master_key_id`.
+///
+/// Rotating the master key re-wraps these, item data stays encrypted with
+/// the same data key.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CrateKey {
+	pub crate_id: Uuid,
+	pub master_key_id: String,
+	pub wrapped_key: Vec<u8>,
+	pub created: DateTime<Utc>,
+	pub updated: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EncryptionErr {
+	NoMasterKey,
+	UnknownMasterKey,
+	Failed,
+}
+
+impl Error for EncryptionErr {}
+
+impl fmt::Display for EncryptionErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EncryptionErr::NoMasterKey => write!(f, "COMN_MASTER_KEYS is not set"),
+            EncryptionErr::UnknownMasterKey => write!(f, "data key is wrapped with a master key that isn't in COMN_MASTER_KEYS"),
+            EncryptionErr::Failed => write!(f, "data doesn't decrypt with its key"),
+        }
+    }
+}
+
+/// Master keys from COMN_MASTER_KEYS, `id:hex,id:hex` of 32 byte AES-256 keys.
+///
+/// New data keys are wrapped with the first, the rest are kept until nothing
+/// is wrapped with them anymore, see `rotate_master_key`.
+pub fn master_keys() -> Result<Vec<(String, [u8; 32])>, EncryptionErr> {
+	let env = std::env::var("COMN_MASTER_KEYS").map_err(|_| EncryptionErr::NoMasterKey)?;
+	let mut keys = vec![];
+	for entry in env.split(',').map(|e| e.trim()).filter(|e| !e.is_empty()) {
+		let (id, key) = entry.split_once(':').ok_or(EncryptionErr::NoMasterKey)?;
+		let key = hex::decode(key).map_err(|_| EncryptionErr::NoMasterKey)?;
+		keys.push((id.to_string(), key.try_into().map_err(|_| EncryptionErr::NoMasterKey)?));
+	}
+	if keys.is_empty() {
+		return Err(EncryptionErr::NoMasterKey);
+	}
+	Ok(keys)
+}
+
+fn master_key(id: &str) -> Result<[u8; 32], EncryptionErr> {
+	master_keys()?
+		.into_iter()
+		.find(|(key_id, _)| key_id == id)
+		.map(|(_, key)| key)
+		.ok_or(EncryptionErr::UnknownMasterKey)
+}
+
+// AES-256-GCM, the random nonce goes in front of the ciphertext
+pub fn seal(key: &[u8; 32], aad: &[u8], data: &[u8]) -> Vec<u8> {
+	let mut nonce = [0u8; 12];
+	rand::thread_rng().fill(&mut nonce);
+	let cipher = Aes256Gcm::new_from_slice(key).unwrap();
+	let mut sealed = nonce.to_vec();
+	sealed.extend(cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad }).unwrap());
+	sealed
+}
+
+pub fn open(key: &[u8; 32], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, EncryptionErr> {
+	if sealed.len() < 12 {
+		return Err(EncryptionErr::Failed);
+	}
+	let cipher = Aes256Gcm::new_from_slice(key).unwrap();
+	cipher
+		.decrypt(Nonce::from_slice(&sealed[..12]), Payload { msg: &sealed[12..], aad })
+		.map_err(|_| EncryptionErr::Failed)
+}
+
+/// Makes the crate encrypted, items added from now on are sealed with a new data key.
+pub async fn add_crate_key(conn: &mut PgConnection, crate_id: Uuid) -> Result<(), EncryptionErr> {
+	let (master_key_id, master) = master_keys()?.remove(0);
+	let mut data_key = [0u8; 32];
+	rand::thread_rng().fill(&mut data_key);
+	sqlx::query("INSERT INTO crate_key(crate_id, master_key_id, wrapped_key) VALUES($1, $2, $3)")
+		.bind(crate_id)
+		.bind(master_key_id)
+		.bind(seal(&master, crate_id.as_bytes(), &data_key))
+		.execute(conn)
+		.await
+		.unwrap();
+	Ok(())
+}
+
+// data key of the crate, None if it isn't encrypted
+pub async fn crate_key(conn: &mut PgConnection, crate_id: Uuid) -> Result<Option<[u8; 32]>, EncryptionErr> {
+	let row = sqlx::query_as::<_, CrateKey>("SELECT * FROM crate_key WHERE crate_id = $1")
+		.bind(crate_id)
+		.fetch_optional(conn)
+		.await
+		.unwrap();
+	match row {
+		Some(row) => {
+			let data_key = open(&master_key(&row.master_key_id)?, crate_id.as_bytes(), &row.wrapped_key)?;
+			Ok(Some(data_key.try_into().map_err(|_| EncryptionErr::Failed)?))
+		}
+		None => Ok(None),
+	}
+}
+
+/// Decrypts the sealed data of an item read from the db in place.
+///
+/// Sealed item data is kept in `data_bytes` or the item's blob, bound to the
+/// item id, `compression::unpack_item` puts it back where it'd be unencrypted.
+pub async fn decrypt_item(item: &mut CrateItem) -> Result<(), EncryptionErr> {
+	if !item.encrypted {
+		return Ok(());
+	}
+	let mut conn = db().await.acquire().await.unwrap();
+	let data_key = crate_key(&mut conn, item.crate_id).await?.ok_or(EncryptionErr::Failed)?;
+	if let Some(sealed) = item.data_file.take() {
+		item.data_file = Some(open(&data_key, item.id.as_bytes(), &sealed)?);
+	}
+	if let Some(sealed) = item.data_bytes.take() {
+		item.data_bytes = Some(open(&data_key, item.id.as_bytes(), &sealed)?);
+	}
+	Ok(())
+}
+
+/// Re-wraps the data keys of every encrypted crate with the current master key.
+///
+/// Item data isn't touched. Once this returns the older master keys can be
+/// dropped from COMN_MASTER_KEYS. Returns the number of keys re-wrapped.
+pub async fn rotate_master_key() -> Result<u64, EncryptionErr> {
+	let (current_id, current) = master_keys()?.remove(0);
+	let mut tx = db().await.begin().await.unwrap();
+	let rows = sqlx::query_as::<_, CrateKey>(
+		"SELECT * FROM crate_key WHERE master_key_id <> $1 FOR UPDATE"
+	)
+	.bind(&current_id)
+	.fetch_all(&mut *tx)
+	.await
+	.unwrap();
+	for row in &rows {
+		let data_key = open(&master_key(&row.master_key_id)?, row.crate_id.as_bytes(), &row.wrapped_key)?;
+		sqlx::query(
+			"UPDATE crate_key SET master_key_id = $2, wrapped_key = $3, updated = CURRENT_TIMESTAMP WHERE crate_id = $1"
+		)
+		.bind(row.crate_id)
+		.bind(&current_id)
+		.bind(seal(&current, row.crate_id.as_bytes(), &data_key))
+		.execute(&mut *tx)
+		.await
+		.unwrap();
+	}
+	tx.commit().await.unwrap();
+	Ok(rows.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn seal_open() {
+		let key = [3u8; 32];
+		let sealed = seal(&key, b"item", b"data");
+		assert_eq!(open(&key, b"item", &sealed), Ok(b"data".to_vec()));
+		assert_eq!(open(&key, b"other item", &sealed), Err(EncryptionErr::Failed));
+		assert_eq!(open(&[4u8; 32], b"item", &sealed), Err(EncryptionErr::Failed));
+	}
+}
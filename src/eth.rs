@@ -0,0 +1,178 @@
+use secp256k1::ecdsa::{RecoverableSignature, RecoveryId};
+use secp256k1::{Message, PublicKey, SECP256K1};
+use sha3::{Digest, Keccak256};
+use std::{error::Error, fmt};
+use crate::db;
+
+#[derive(Debug)]
+pub enum EthErr {
+	BadAddr,
+	BadChecksum,
+	BadSignature,
+}
+
+impl Error for EthErr {}
+
+impl fmt::Display for EthErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EthErr::BadAddr => write!(f, "not a 20 byte hex address."),
+            EthErr::BadChecksum => write!(f, "address doesn't match its EIP-55 checksum."),
+            EthErr::BadSignature => write!(f, "signature is not a valid 65 byte recoverable signature."),
+        }
+    }
+}
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+	Keccak256::new_with_prefix(data)
+		.finalize()
+		.as_slice()
+		.try_into()
+		.expect("Wrong length")
+}
+
+// last 20 bytes of keccak256 of the uncompressed key without the 0x04 prefix
+pub fn eth_addr(pub_key: &PublicKey) -> [u8; 20] {
+	let hash = keccak256(&pub_key.serialize_uncompressed()[1..]);
+	hash[12..].try_into().expect("Wrong length")
+}
+
+/// EIP-55 mixed case checksum encoding
+pub fn to_checksum(addr: &[u8; 20]) -> String {
+	let lower = hex::encode(addr);
+	let hash = hex::encode(keccak256(lower.as_bytes()));
+	let checksummed: String = lower
+		.chars()
+		.zip(hash.chars())
+		.map(|(c, h)| if h >= '8' { c.to_ascii_uppercase() } else { c })
+		.collect();
+	"0x".to_string() + &checksummed
+}
+
+// all lower or all upper case addresses are accepted as is, mixed case has to
+// be a valid EIP-55 checksum
+pub fn parse_eth_addr(addr: &str) -> Result<[u8; 20], EthErr> {
+	let digits = addr.trim_start_matches("0x");
+	let bytes: [u8; 20] = hex::decode(digits)
+		.map_err(|_| EthErr::BadAddr)?
+		.try_into()
+		.map_err(|_| EthErr::BadAddr)?;
+	let mixed_case = digits.chars().any(|c| c.is_ascii_lowercase())
+		&& digits.chars().any(|c| c.is_ascii_uppercase());
+	if mixed_case && to_checksum(&bytes)[2..] != *digits {
+		return Err(EthErr::BadChecksum);
+	}
+	Ok(bytes)
+}
+
+/// EIP-191 `personal_sign` digest of a message
+pub fn eip191_hash(msg: &[u8]) -> [u8; 32] {
+	let prefix = format!("\x19Ethereum Signed Message:\n{}", msg.len());
+	keccak256(&[prefix.as_bytes(), msg].concat())
+}
+
+fn encode_u64(x: u64) -> [u8; 32] {
+	let mut word = [0u8; 32];
+	word[24..].copy_from_slice(&x.to_be_bytes());
+	word
+}
+
+fn encode_addr(addr: &[u8; 20]) -> [u8; 32] {
+	let mut word = [0u8; 32];
+	word[12..].copy_from_slice(addr);
+	word
+}
+
+/// EIP-712 digest of `Transfer(address to,uint256 amount,string nonce)` under the
+/// `EIP712Domain(string name,string version,uint256 chainId)` domain
+pub fn eip712_transfer_hash(
+	domain_name: &str,
+	chain_id: u64,
+	to: &[u8; 20],
+	amount: u64,
+	nonce: &str,
+) -> [u8; 32] {
+	let domain_separator = keccak256(&[
+		keccak256(b"EIP712Domain(string name,string version,uint256 chainId)"),
+		keccak256(domain_name.as_bytes()),
+		keccak256(b"1"),
+		encode_u64(chain_id),
+	].concat());
+	let transfer_hash = keccak256(&[
+		keccak256(b"Transfer(address to,uint256 amount,string nonce)"),
+		encode_addr(to),
+		encode_u64(amount),
+		keccak256(nonce.as_bytes()),
+	].concat());
+	keccak256(&[&[0x19u8, 0x01][..], &domain_separator[..], &transfer_hash[..]].concat())
+}
+
+/// Recovers the signer of a 65 byte `r || s || v` signature, `v` may be 0/1 or 27/28
+pub fn recover(digest: &[u8; 32], signature: &str) -> Result<PublicKey, EthErr> {
+	let sig = hex::decode(signature.trim_start_matches("0x")).map_err(|_| EthErr::BadSignature)?;
+	if sig.len() != 65 {
+		return Err(EthErr::BadSignature);
+	}
+	let v = if sig[64] >= 27 { sig[64] - 27 } else { sig[64] };
+	let recid = RecoveryId::from_i32(v as i32).map_err(|_| EthErr::BadSignature)?;
+	let signr = RecoverableSignature::from_compact(&sig[..64], recid)
+		.map_err(|_| EthErr::BadSignature)?;
+	let msg = Message::from_slice(digest).unwrap();
+	SECP256K1.recover_ecdsa(&msg, &signr).map_err(|_| EthErr::BadSignature)
+}
+
+/// Fills `key.eth_addr` for keys registered before it existed
+pub async fn backfill_eth_addrs() {
+	let keys = sqlx::query_as::<_, (sqlx::types::Uuid, Vec<u8>)>(
+		"SELECT id, pub_key FROM key WHERE eth_addr IS NULL"
+	)
+	.fetch_all(db::db().await)
+	.await
+	.unwrap();
+	for (id, pub_key) in keys {
+		if let Ok(pub_key) = PublicKey::from_slice(&pub_key) {
+			sqlx::query("UPDATE key SET eth_addr = $1 WHERE id = $2")
+				.bind(eth_addr(&pub_key).to_vec())
+				.bind(id)
+				.execute(db::db().await)
+				.await
+				.unwrap();
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use secp256k1::SecretKey;
+	#[test]
+	fn eth_addr_from_key() {
+		let secret_key = SecretKey::from_slice(
+			&hex::decode("4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318").unwrap()
+		).unwrap();
+		let pub_key = PublicKey::from_secret_key_global(&secret_key);
+		assert_eq!(
+			hex::encode(eth_addr(&pub_key)),
+			"2c7536e3605d9c16a7a3d7b1898e529396a65c23"
+		);
+	}
+	#[test]
+	fn checksum() {
+		let addr = parse_eth_addr("0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed").unwrap();
+		assert_eq!(to_checksum(&addr), "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed");
+		assert!(parse_eth_addr("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed").is_ok());
+		assert!(parse_eth_addr("0x5AAeb6053F3E94C9b9A09f33669435E7Ef1BeAed").is_err());
+	}
+	#[test]
+	fn recover_eip191() {
+		let secret_key = SecretKey::from_slice(&[7u8; 32]).unwrap();
+		let pub_key = PublicKey::from_secret_key_global(&secret_key);
+		let digest = eip191_hash(b"hello");
+		let (recid, sig) = SECP256K1
+			.sign_ecdsa_recoverable(&Message::from_slice(&digest).unwrap(), &secret_key)
+			.serialize_compact();
+		let signature = [&sig[..], &[recid.to_i32() as u8 + 27]].concat();
+		assert_eq!(recover(&digest, &hex::encode(signature)).unwrap(), pub_key);
+		assert!(recover(&digest, "0x00").is_err());
+	}
+}
@@ -0,0 +1,270 @@
+use chrono::{DateTime, Utc};
+use std::time::Duration;
+use salvo::http::StatusCode;
+use salvo::prelude::{async_trait, Depot, FlowCtrl, Handler, Request, Response};
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sqlx::types::Uuid;
+use sqlx::FromRow;
+use tokio::time::interval;
+use crate::{
+	comn_addr::ComnAddr, db::{db, get_config},
+	read::addr::owns_addr,
+	update::coin::{Transaction, TransactionErr},
+};
+
+// fees held longer than this are of requests that never finished, i.e. the
+// broker stopped while the route ran
+const STALE_HELD_SECS: f64 = 60.0 * 60.0;
+
+/// Hoop charging the fee scheduled for `operation` in config to the addr the
+/// request is made for, which the caller's key has to own.
+///
+/// The fee is moved from the addr to the fee collector and recorded as held in
+/// one transaction before the route runs. It's charged when the route succeeds,
+/// otherwise it's paid back in the same transaction as the refund is recorded.
+/// `worker` retries refunds that fail and pays back fees left held by requests
+/// that never finished. Both show in the transfer history. Goes after the auth
+/// hoop, callers without a key are left to the route.
+pub struct Metered {
+	pub operation: &'static str,
+	pub payer: PayerField,
+}
+
+/// Where the request of a metered route names the addr paying for it.
+pub enum PayerField {
+	// `addr` of the JSON body
+	Body,
+	// `sender` of the signed request, see `auth_token::protected`
+	Signed,
+	// the `addr` query parameter
+	Query,
+}
+
+impl Metered {
+	pub fn new(operation: &'static str, payer: PayerField) -> Self {
+		Self { operation, payer }
+	}
+
+	async fn payer_addr(&self, req: &mut Request, depot: &Depot) -> Option<ComnAddr> {
+		let addr = match self.payer {
+			PayerField::Body => req.parse_json::<Value>().await.ok()?.get("addr")?.as_str()?.to_string(),
+			PayerField::Signed => serde_json::from_str::<Value>(depot.get::<String>("req").ok()?)
+				.ok()?
+				.get("sender")?
+				.as_str()?
+				.to_string(),
+			PayerField::Query => req.query::<String>("addr")?,
+		};
+		ComnAddr::parse(&addr)
+	}
+}
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[sqlx(type_name = "FEE_STATUS")]
+#[sqlx(rename_all = "lowercase")]
+pub enum FeeStatus {
+	Held,
+	Charged,
+	Refunding,
+	Refunded,
+}
+
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct HeldFee {
+	pub id: Uuid,
+	pub payer: Uuid,
+	pub collector: Uuid,
+	pub operation: String,
+	pub amount: i64,
+	pub nonce: String,
+	pub status: FeeStatus,
+	pub created: DateTime<Utc>,
+	pub updated: DateTime<Utc>,
+}
+
+impl HeldFee {
+	// settles a held fee, one `refund_pending` gave up on stays refunding
+	async fn set_status(&self, status: FeeStatus) {
+		sqlx::query("UPDATE fee SET status = $2, updated = CURRENT_TIMESTAMP WHERE id = $1 AND status = 'held'")
+			.bind(self.id)
+			.bind(status)
+			.execute(db().await)
+			.await
+			.unwrap();
+	}
+
+	// pays the fee back, the row stays refunding while the transfer fails
+	pub async fn refund(&self) -> Result<HeldFee, TransactionErr> {
+		let mut tx = db().await.begin().await.unwrap();
+		let fee = sqlx::query_as::<_, HeldFee>(
+			"
+			UPDATE fee
+			SET status = 'refunded', updated = CURRENT_TIMESTAMP
+			WHERE id = $1 AND status = 'refunding'
+			RETURNING *
+			"
+		)
+		.bind(self.id)
+		.fetch_optional(&mut *tx)
+		.await
+		.unwrap()
+		.ok_or(TransactionErr::BadData)?;
+
+		let refund = Transaction {
+			amount: self.amount as u64,
+			receiver: ComnAddr::from_uuid(&self.payer.to_string()).unwrap(),
+			sender: ComnAddr::from_uuid(&self.collector.to_string()).unwrap(),
+			comment: Some(format!("fee for {} refunded", self.operation)),
+			nonce: format!("{}:refund", self.nonce),
+		};
+		match refund.transfer_coins_in(&mut tx).await {
+			Ok(result) => {
+				tx.commit().await.unwrap();
+				refund.record_history(&result).await;
+				Ok(fee)
+			}
+			Err(TransactionErr::AlreadyReported) => {
+				tx.commit().await.unwrap();
+				Ok(fee)
+			}
+			Err(e) => Err(e),
+		}
+	}
+}
+
+// moves the fee to the collector and records it as held, both or neither
+async fn hold(operation: &str, amount: u64, payer: &ComnAddr, collector: &ComnAddr) -> Result<HeldFee, TransactionErr> {
+	let nonce = format!("fee:{}:{}", operation, Uuid::new_v4());
+	let transaction = Transaction {
+		amount,
+		receiver: collector.clone(),
+		sender: payer.clone(),
+		comment: Some(format!("fee for {}", operation)),
+		nonce: nonce.clone(),
+	};
+	let mut tx = db().await.begin().await.unwrap();
+	let result = transaction.transfer_coins_in(&mut tx).await?;
+	let fee = sqlx::query_as::<_, HeldFee>(
+		"
+		INSERT INTO fee (payer, collector, operation, amount, nonce)
+		VALUES ($1::uuid, $2::uuid, $3, $4, $5)
+		RETURNING *
+		"
+	)
+	.bind(payer.to_uuid())
+	.bind(collector.to_uuid())
+	.bind(operation)
+	// transfer_coins_in refused amounts that don't fit
+	.bind(amount as i64)
+	.bind(nonce)
+	.fetch_one(&mut *tx)
+	.await
+	.unwrap();
+	tx.commit().await.unwrap();
+	transaction.record_history(&result).await;
+	Ok(fee)
+}
+
+/// Pays back fees that are refunding, and fees still held by requests that
+/// never finished, it isn't known if their operation happened.
+pub async fn refund_pending() -> Vec<Result<HeldFee, TransactionErr>> {
+	sqlx::query(
+		"
+		UPDATE fee SET status = 'refunding', updated = CURRENT_TIMESTAMP
+		WHERE status = 'held' AND updated < CURRENT_TIMESTAMP - make_interval(secs => $1)
+		"
+	)
+	.bind(STALE_HELD_SECS)
+	.execute(db().await)
+	.await
+	.unwrap();
+	let fees = sqlx::query_as::<_, HeldFee>(
+		"SELECT * FROM fee WHERE status = 'refunding' ORDER BY updated"
+	)
+	.fetch_all(db().await)
+	.await
+	.unwrap();
+	let mut results = Vec::new();
+	for fee in fees {
+		results.push(fee.refund().await);
+	}
+	results
+}
+
+pub async fn worker(period: Duration) {
+	let mut ticker = interval(period);
+	loop {
+		ticker.tick().await;
+		for result in refund_pending().await {
+			if let Err(e) = result {
+				println!("fee refund failed {}", e);
+			}
+		}
+	}
+}
+
+#[async_trait]
+impl Handler for Metered {
+	async fn handle(&self, req: &mut Request, depot: &mut Depot, res: &mut Response, ctrl: &mut FlowCtrl) {
+		let conf = &get_config().await.fees;
+		let fee = match conf.schedule.get(self.operation) {
+			Some(fee) if fee.amount > 0 => fee,
+			_ => return,
+		};
+		if res.status_code.map_or(false, |s| !s.is_success()) {
+			return;
+		}
+		if fee.min_bytes > 0 {
+			// the body is read here and kept for the route, a body it can't
+			// read is left to the route to answer
+			let body_bytes = req.payload().await.map_or(0, |payload| payload.len() as u64);
+			if body_bytes < fee.min_bytes {
+				return;
+			}
+		}
+		let pub_key = match depot.get::<PublicKey>("public_key") {
+			Ok(pub_key) => *pub_key,
+			Err(_) => return,
+		};
+		// the route refuses requests for addrs the key doesn't own too, the
+		// fee can't be charged to them before it does
+		let payer = match self.payer_addr(req, depot).await {
+			Some(payer) if owns_addr(&payer, &pub_key).await => payer,
+			_ => {
+				res.render(StatusCode::BAD_REQUEST);
+				ctrl.skip_rest();
+				return;
+			}
+		};
+		if payer == conf.collector {
+			return;
+		}
+
+		let held = match hold(self.operation, fee.amount, &payer, &conf.collector).await {
+			Ok(held) => held,
+			Err(TransactionErr::LowAmount) => {
+				res.render(StatusCode::PAYMENT_REQUIRED);
+				ctrl.skip_rest();
+				return;
+			}
+			Err(_) => {
+				res.render(StatusCode::INTERNAL_SERVER_ERROR);
+				ctrl.skip_rest();
+				return;
+			}
+		};
+
+		ctrl.call_next(req, depot, res).await;
+
+		if res.status_code.map_or(true, |s| s.is_success()) {
+			held.set_status(FeeStatus::Charged).await;
+		} else {
+			held.set_status(FeeStatus::Refunding).await;
+			if let Err(e) = held.refund().await {
+				println!("fee refund failed {} {}", held.nonce, e);
+			}
+		}
+	}
+}
@@ -2,4 +2,10 @@ pub mod coin;
 pub mod addr;
 pub mod crates;
 pub mod crate_item;
-pub mod stripe;
\ No newline at end of file
+pub mod top_up;
+pub mod rpc;
+pub mod schedule;
+pub mod escrow;
+pub mod quota;
+pub mod item_type;
+pub mod template;
@@ -7,6 +7,7 @@ use sqlx::types::{Uuid};
 use sqlx::FromRow;
 use crate::{
 	comn_addr::ComnAddr, db::{db},
+	eth::{eth_addr, parse_eth_addr, to_checksum},
 	read::addr::AddrFilter,
 };
 use crate::print_current_db;
@@ -18,6 +19,9 @@ pub struct AddrKey {
 	pub pub_key: Vec<u8>,
 	pub key_type: KeyType,
 	pub created: DateTime<Utc>,
+	// EIP-55 checksummed, derived from pub_key
+	#[sqlx(default)]
+	pub eth_addr: Option<String>,
 }
 
 #[derive(sqlx::Type, Serialize, Deserialize, PartialEq, Eq, Debug)]
@@ -68,7 +72,7 @@ pub async fn get_keys(req: &mut Request, res: &mut Response) {
 	// print_current_db().await;
 	if let Some(param) = req.query::<&str>("addr") {
 		if let Ok(addr) = ComnAddr::new(param) {
-			let data = sqlx::query_as::<_, AddrKey>(
+			let mut data = sqlx::query_as::<_, AddrKey>(
 				"
 				SELECT a.id, k.pub_key, ak.key_type, k.created
 				FROM addr a JOIN addr_key ak ON ak.addr_id = a.id
@@ -79,6 +83,11 @@ pub async fn get_keys(req: &mut Request, res: &mut Response) {
 			.fetch_all(db().await)
 			.await
 			.unwrap();
+			for key in data.iter_mut() {
+				key.eth_addr = PublicKey::from_slice(&key.pub_key)
+					.ok()
+					.map(|pub_key| to_checksum(&eth_addr(&pub_key)));
+			}
 			res.render(serde_json::to_string(&data).unwrap());
 		} else {
 			res.render(StatusCode::BAD_REQUEST);
@@ -88,6 +97,35 @@ pub async fn get_keys(req: &mut Request, res: &mut Response) {
 	}
 }
 
+/// Gets the addr registered with the key behind an ethereum address
+///
+/// Accepts lower case or EIP-55 checksummed addresses, returns http status code
+/// BAD_REQUEST for a malformed one and NOT_FOUND if no key matches it.
+#[handler]
+pub async fn get_eth_addr(req: &mut Request, res: &mut Response) {
+	if let Some(param) = req.query::<&str>("eth_addr") {
+		if let Ok(eth) = parse_eth_addr(param) {
+			if let Ok(addr) = AddrFilter::from_eth_addr(&eth).await {
+				let mut addr_filter = AddrFilter {
+					name: None,
+					addr: Some(addr),
+					keys: None,
+					result: None,
+				};
+				if let Ok(_) = addr_filter.init().await {
+					res.render(serde_json::to_string(&addr_filter.to_res()).unwrap());
+					return;
+				}
+			}
+			res.render(StatusCode::NOT_FOUND);
+		} else {
+			res.render(StatusCode::BAD_REQUEST);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct RegResp {
 	pub addr: ComnAddr,
@@ -112,8 +150,9 @@ pub async fn register_key(req: &mut Request, res: &mut Response, depot: &mut Dep
 		.unwrap_or(
 			RegisterKeyReq { name: None }
 		);
-	let key_id = sqlx::query_scalar::<_, Uuid>("INSERT INTO key(pub_key) VALUES($1) RETURNING id")
+	let key_id = sqlx::query_scalar::<_, Uuid>("INSERT INTO key(pub_key, eth_addr) VALUES($1, $2) RETURNING id")
 		.bind(pub_key.serialize())
+		.bind(eth_addr(pub_key).to_vec())
 		.fetch_one(&mut *tx)
 		.await
 		.unwrap();
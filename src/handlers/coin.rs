@@ -1,10 +1,13 @@
 use salvo::http::{StatusCode};
-use salvo::prelude::{handler, Depot, Response};
+use salvo::prelude::{handler, Depot, Request, Response};
 use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
 use crate::{
+	comn_addr::ComnAddr,
 	db::{db},
 	read::addr::AddrFilter,
-	update::coin::{Transaction, TransactionErr},
+	update::coin::{Transaction, TransactionErr, Approve, TransferFrom, get_allowance, get_balance},
+	update::escrow::locked_amount,
 };
 use crate::print_current_db;
 
@@ -25,8 +28,8 @@ pub async fn transaction(res: &mut Response, depot: &mut Depot) {
 			match transaction_req.transfer_coins().await {
 				Ok(result) => res.render(serde_json::to_string(&result).unwrap()), 
 				Err(TransactionErr::AlreadyReported) => res.render(StatusCode::ALREADY_REPORTED),
-				Err(TransactionErr::LowAmount) | Err(TransactionErr::BadData) =>
-					res.render(StatusCode::BAD_REQUEST),
+				Err(TransactionErr::LowAmount) | Err(TransactionErr::BadData)
+					| Err(TransactionErr::LowAllowance) => res.render(StatusCode::BAD_REQUEST),
 			}
 		} else {
 			res.render(StatusCode::BAD_REQUEST);
@@ -65,4 +68,111 @@ pub async fn get_comn_coins(res: &mut Response, depot: &mut Depot) {
 		Err(..) => 0,
 	};
 	res.render(serde_json::to_string(&amount).unwrap());
-}
\ No newline at end of file
+}
+
+#[handler]
+pub async fn approve(res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	let req_json = depot.get::<String>("req").unwrap();
+
+	if let Ok(approve_req) = serde_json::from_str::<Approve>(req_json) {
+		let mut verify_owner = AddrFilter {
+			name: None,
+			addr: Some(approve_req.owner.clone()),
+			keys: Some(vec!(*pub_key)),
+			result: None,
+		};
+		if let Ok(_) = verify_owner.init().await {
+			match approve_req.approve().await {
+				Ok(result) => res.render(serde_json::to_string(&result).unwrap()),
+				Err(TransactionErr::AlreadyReported) => res.render(StatusCode::ALREADY_REPORTED),
+				Err(_) => res.render(StatusCode::BAD_REQUEST),
+			}
+		} else {
+			res.render(StatusCode::BAD_REQUEST);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+#[handler]
+pub async fn transfer_from(res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	let req_json = depot.get::<String>("req").unwrap();
+
+	if let Ok(transfer_req) = serde_json::from_str::<TransferFrom>(req_json) {
+		let mut verify_spender = AddrFilter {
+			name: None,
+			addr: Some(transfer_req.spender.clone()),
+			keys: Some(vec!(*pub_key)),
+			result: None,
+		};
+		if let Ok(_) = verify_spender.init().await {
+			match transfer_req.transfer_from().await {
+				Ok(result) => res.render(serde_json::to_string(&result).unwrap()),
+				Err(TransactionErr::AlreadyReported) => res.render(StatusCode::ALREADY_REPORTED),
+				Err(TransactionErr::LowAmount) | Err(TransactionErr::BadData)
+					| Err(TransactionErr::LowAllowance) => res.render(StatusCode::BAD_REQUEST),
+			}
+		} else {
+			res.render(StatusCode::BAD_REQUEST);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AllowanceReq {
+	pub owner: ComnAddr,
+	pub spender: ComnAddr,
+}
+
+#[handler]
+pub async fn allowance(req: &mut Request, res: &mut Response) {
+	if let Ok(allowance_req) = req.parse_queries::<AllowanceReq>() {
+		let result = get_allowance(&allowance_req.owner, &allowance_req.spender).await;
+		res.render(serde_json::to_string(&result).unwrap());
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BalanceReq {
+	pub addr: ComnAddr,
+}
+
+// available coins can be spent, locked coins are held in escrow until settled
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Balance {
+	pub addr: ComnAddr,
+	pub available: i64,
+	pub locked: i64,
+}
+
+#[handler]
+pub async fn balance(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Ok(balance_req) = req.parse_queries::<BalanceReq>() {
+		let mut verify_addr = AddrFilter {
+			name: None,
+			addr: Some(balance_req.addr.clone()),
+			keys: Some(vec!(*pub_key)),
+			result: None,
+		};
+		if let Ok(_) = verify_addr.init().await {
+			let result = Balance {
+				available: get_balance(&balance_req.addr).await,
+				locked: locked_amount(&balance_req.addr).await,
+				addr: balance_req.addr,
+			};
+			res.render(serde_json::to_string(&result).unwrap());
+		} else {
+			res.render(StatusCode::UNAUTHORIZED);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
@@ -5,19 +5,26 @@ use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use sqlx::types::{Uuid};
 use sqlx::FromRow;
+use std::collections::HashMap;
 use crate::{
-	AddCrateItemReq, write_file, CrateItemStorage,
+	AddCrateItemReq, CrateItemStorage,
 	CrateItem, CrateItemRes, AccessType, SpecialAddr,
-	read_file, verify_hash,
+	verify_hash,
 	comn_addr::ComnAddr, db::{db, get_config},
 	read::{
 		crate_item::CrateItemFilter,
+		json_query::{JsonQuery, JsonQueryErr, JsonQueryReq},
+		search::{ItemSearch, SearchErr, SearchReq},
 		crates::CrateFilter,
-		addr::AddrFilter
+		addr::owns_addr
 	},
 	add::{
 		crate_item::{AddCrateItem, AddCrateItemErr},
 	},
+	blob::{read_item_blob, collect_garbage},
+	compression::unpack_item,
+	update::json_patch::{PatchCrateItemReq, PatchErr},
+	update::item_metadata::{ItemMetadataReq, MetadataErr},
 };
 use std::convert::Infallible;
 use std::time::Duration;
@@ -33,12 +40,26 @@ pub async fn list_crate(req: &mut Request, res: &mut Response, depot: &mut Depot
 	print_current_db().await;
 	if let Some(crate_id) = req.query::<&str>("id") {
 		let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+		// tags=a,b and metadata={"k":"v"} narrow the list to items having all of them
+		let tags = req.query::<&str>("tags").map_or(vec![], |tags| {
+			tags.split(',').map(|tag| tag.trim().to_lowercase()).filter(|tag| !tag.is_empty()).collect()
+		});
+		let metadata = match req.query::<&str>("metadata").map(serde_json::from_str::<HashMap<String, String>>) {
+			None => HashMap::new(),
+			Some(Ok(metadata)) => metadata,
+			Some(Err(_)) => {
+				res.render(StatusCode::BAD_REQUEST);
+				return;
+			}
+		};
 		let mut filter = CrateItemFilter {
 			addr: None,
 			pub_key: Some(*pub_key),
 			crate_id: crate_id.to_string(),
 			per_page: 50,
 			page_no: 0,
+			tags,
+			metadata,
 			result: None
 		};
 		if let Ok(_) = filter.init().await {
@@ -52,6 +73,59 @@ pub async fn list_crate(req: &mut Request, res: &mut Response, depot: &mut Depot
 	}
 }
 
+/// Queries the json items of a crate the key can read (See JsonQueryReq)
+///
+/// Returns http status code CONFLICT for encrypted crates, their data can't be queried
+/// On success, returns http status code OK and the matching items (See JsonQueryHit)
+#[handler]
+pub async fn query_crate(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	if let Ok(query_req) = req.parse_json::<JsonQueryReq>().await {
+		let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+		let mut query = JsonQuery {
+			pub_key: Some(*pub_key),
+			req: query_req,
+			result: None,
+		};
+		match query.init().await {
+			Ok(_) => res.render(serde_json::to_string(&query.result.unwrap()).unwrap()),
+			Err(JsonQueryErr::CrateNotFound) => res.render(StatusCode::UNAUTHORIZED),
+			Err(JsonQueryErr::NotQueryable) => res.render(StatusCode::CONFLICT),
+			Err(e) => {
+				res.status_code(StatusCode::BAD_REQUEST);
+				res.render(e.to_string());
+			}
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Searches item paths and content of the crates the key can read (See SearchReq)
+///
+/// On success, returns http status code OK and the best ranked hits first (See SearchRes)
+#[handler]
+pub async fn search_items(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	if let Ok(search_req) = req.parse_queries::<SearchReq>() {
+		let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+		let mut search = ItemSearch {
+			pub_key: Some(*pub_key),
+			req: search_req,
+			result: None,
+		};
+		match search.init().await {
+			Ok(_) => res.render(serde_json::to_string(&search.result.unwrap()).unwrap()),
+			Err(SearchErr::NotFound) => res.render(StatusCode::UNAUTHORIZED),
+			Err(SearchErr::BadQuery) => res.render(StatusCode::BAD_REQUEST),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Streams the items of crate `id` as they change
+///
+/// When subscribing has a fee, it's charged to the `addr` query parameter, an addr
+/// of the key (See fee::Metered)
 #[handler]
 pub async fn list_crate_stream(req: &mut Request, res: &mut Response, depot: &mut Depot) {
 	let crate_id = req.query::<&str>("id");
@@ -62,6 +136,8 @@ pub async fn list_crate_stream(req: &mut Request, res: &mut Response, depot: &mu
         crate_id: crate_id.unwrap().to_string(),
         per_page: 50,
         page_no: 0,
+        tags: vec![],
+        metadata: HashMap::new(),
         result: None
     };
     let _ = filter.init().await;
@@ -89,7 +165,7 @@ pub struct CrateItemChunk {
 	pub id: Uuid,
 	pub sha2_hash: Vec<u8>,
 	pub crate_item_id: Uuid,
-	pub size_hectobyte: i16,
+	pub size_hectobyte: i32,
 }
 
 /// Gets crate item by key
@@ -134,12 +210,12 @@ and ca.type is not null and ci.id = '{}'::uuid;",
 		{
 			Ok(mut item) => {
 				if item.chunk_count > 0 {
-					let conf = get_config().await;
-					if let Ok(data_file) =
-						read_file(&format!("{}/{}_{}", conf.chunks_location, item.id, 0)).await
-					{
-						item.data_file = Some(data_file);
-					}
+					item.data_file = read_item_blob(item.id).await;
+				}
+				if let Err(e) = unpack_item(&mut item).await {
+					println!("item {} {}", item.id, e);
+					res.render(StatusCode::INTERNAL_SERVER_ERROR);
+					return;
 				}
 				res.render(serde_json::to_string(&item).unwrap())
 			}
@@ -163,13 +239,7 @@ pub async fn add_crate_item(req: &mut Request, res: &mut Response, depot: &mut D
 	}
 	if let Ok(crate_req) = req.parse_json::<AddCrateItemReq>().await {
 		let pub_key = depot.get::<PublicKey>("public_key").unwrap();
-		let mut verify_addr = AddrFilter {
-			name: None,
-			addr: Some(crate_req.addr.clone()),
-			keys: Some(vec!(*pub_key)),
-			result: None,
-		};
-		if let Ok(_) = verify_addr.init().await {
+		if owns_addr(&crate_req.addr, pub_key).await {
 			let mut crate_access = CrateFilter {
 				name: None,
 				addr: Some(vec!(
@@ -196,10 +266,30 @@ pub async fn add_crate_item(req: &mut Request, res: &mut Response, depot: &mut D
 					sha2_hash: crate_req.sha2_hash,
 					data: crate_req.data,
 					scope: scope[1].to_string(),
+					proof: crate_req.proof,
+					metadata: crate_req.metadata,
+					tags: crate_req.tags,
 				};
 				match add_item.add().await {
 					Ok(rr) => res.render(serde_json::to_string(&rr).unwrap()),
 					Err(AddCrateItemErr::PayloadLarge) => res.render(StatusCode::PAYLOAD_TOO_LARGE),
+					Err(AddCrateItemErr::QuotaExceeded) => res.render(StatusCode::PAYMENT_REQUIRED),
+					Err(AddCrateItemErr::StorageFull) => res.render(StatusCode::INSUFFICIENT_STORAGE),
+					Err(AddCrateItemErr::UnknownBlob) => res.render(StatusCode::NOT_FOUND),
+					Err(AddCrateItemErr::UnsupportedMediaType) => res.render(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+					Err(AddCrateItemErr::MediaTypeForbidden) => res.render(StatusCode::FORBIDDEN),
+					Err(e @ AddCrateItemErr::InvalidData(_)) => {
+						res.status_code(StatusCode::UNPROCESSABLE_ENTITY);
+						res.render(e.to_string());
+					}
+					Err(AddCrateItemErr::SchemaViolation(errors)) => {
+						res.status_code(StatusCode::UNPROCESSABLE_ENTITY);
+						res.render(serde_json::to_string(&errors).unwrap());
+					}
+					Err(e @ AddCrateItemErr::BadMetadata(_)) => {
+						res.status_code(StatusCode::BAD_REQUEST);
+						res.render(e.to_string());
+					}
 					Err(AddCrateItemErr::InternalErr) => res.render(StatusCode::INTERNAL_SERVER_ERROR),
 				}
 			} else {
@@ -213,6 +303,159 @@ pub async fn add_crate_item(req: &mut Request, res: &mut Response, depot: &mut D
 	}
 }
 
+/// Patches a json crate item with a JSON Patch or merge patch (See PatchCrateItemReq)
+///
+/// Returns http status code PRECONDITION_FAILED with the current version if the item
+/// isn't at the version the patch was made against
+/// On success, returns http status code OK and the item's new version
+#[handler]
+pub async fn patch_crate_item(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let scope = depot.get::<Vec<String>>("scope").unwrap();
+	if scope[0]!="crate_write" {
+		res.render(StatusCode::BAD_REQUEST);
+		return;
+	}
+	if let Ok(patch_req) = req.parse_json::<PatchCrateItemReq>().await {
+		let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+		if let Err(status) = item_write_access(pub_key, &patch_req.addr, patch_req.id).await {
+			res.render(status);
+			return;
+		}
+		match patch_req.apply().await {
+			Ok(version) => res.render(serde_json::to_string(&version).unwrap()),
+			Err(PatchErr::NotFound) => res.render(StatusCode::NOT_FOUND),
+			Err(PatchErr::NotJson) => res.render(StatusCode::CONFLICT),
+			Err(PatchErr::VersionMismatch(version)) => {
+				res.status_code(StatusCode::PRECONDITION_FAILED);
+				res.render(serde_json::to_string(&version).unwrap());
+			}
+			Err(e @ PatchErr::BadPatch(_)) => {
+				res.status_code(StatusCode::UNPROCESSABLE_ENTITY);
+				res.render(e.to_string());
+			}
+			Err(PatchErr::SchemaViolation(errors)) => {
+				res.status_code(StatusCode::UNPROCESSABLE_ENTITY);
+				res.render(serde_json::to_string(&errors).unwrap());
+			}
+			Err(PatchErr::PayloadLarge) => res.render(StatusCode::PAYLOAD_TOO_LARGE),
+			Err(PatchErr::QuotaExceeded) => res.render(StatusCode::PAYMENT_REQUIRED),
+			Err(PatchErr::StorageFull) => res.render(StatusCode::INSUFFICIENT_STORAGE),
+			Err(PatchErr::InternalErr) => res.render(StatusCode::INTERNAL_SERVER_ERROR),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Sets and removes metadata keys and tags of a crate item (See ItemMetadataReq)
+///
+/// On success, returns http status code OK and the item's metadata and tags
+#[handler]
+pub async fn change_item_metadata(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let scope = depot.get::<Vec<String>>("scope").unwrap();
+	if scope[0]!="crate_write" {
+		res.render(StatusCode::BAD_REQUEST);
+		return;
+	}
+	if let Ok(metadata_req) = req.parse_json::<ItemMetadataReq>().await {
+		let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+		if let Err(status) = item_write_access(pub_key, &metadata_req.addr, metadata_req.id).await {
+			res.render(status);
+			return;
+		}
+		match metadata_req.apply().await {
+			Ok(metadata) => res.render(serde_json::to_string(&metadata).unwrap()),
+			Err(MetadataErr::NotFound) => res.render(StatusCode::NOT_FOUND),
+			Err(e @ MetadataErr::BadMetadata(_)) => {
+				res.status_code(StatusCode::BAD_REQUEST);
+				res.render(e.to_string());
+			}
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+// the addr has to be the key's and able to write to the item's crate
+async fn item_write_access(pub_key: &PublicKey, addr: &ComnAddr, id: Uuid) -> Result<(), StatusCode> {
+	if !owns_addr(addr, pub_key).await {
+		return Err(StatusCode::BAD_REQUEST);
+	}
+	let crate_id = sqlx::query_scalar::<_, Uuid>("SELECT crate_id FROM crate_item WHERE id = $1")
+		.bind(id)
+		.fetch_optional(db().await)
+		.await
+		.unwrap()
+		.ok_or(StatusCode::NOT_FOUND)?;
+	let mut crate_access = CrateFilter {
+		name: None,
+		addr: Some(vec!(
+			SpecialAddr::Registered.value(),
+			SpecialAddr::Public.value(),
+			addr.clone(),
+		)),
+		pub_key: None,
+		crate_id: Some(crate_id.simple().to_string()),
+		access_type: vec!(
+			AccessType::Owner,
+			AccessType::Admin,
+			AccessType::Editor,
+			AccessType::Writer
+		),
+		result: None,
+	};
+	if crate_access.init().await.is_err() {
+		return Err(StatusCode::UNAUTHORIZED);
+	}
+	Ok(())
+}
+
+/// Deletes a crate item, for addrs of the key that can write to its crate
+///
+/// Data no other item points at is removed with it
+#[handler]
+pub async fn delete_crate_item(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let id = req.query::<&str>("id").and_then(|id| Uuid::parse_str(id).ok());
+	if let Some(id) = id {
+		let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+		let crate_id = sqlx::query_scalar::<_, Uuid>("SELECT crate_id FROM crate_item WHERE id = $1")
+			.bind(id)
+			.fetch_optional(db().await)
+			.await
+			.unwrap();
+		if let Some(crate_id) = crate_id {
+			let mut crate_access = CrateFilter {
+				name: None,
+				addr: Some(vec!(SpecialAddr::Registered.value(), SpecialAddr::Public.value())),
+				pub_key: Some(*pub_key),
+				crate_id: Some(crate_id.simple().to_string()),
+				access_type: vec!(
+					AccessType::Owner,
+					AccessType::Admin,
+					AccessType::Editor,
+					AccessType::Writer
+				),
+				result: None,
+			};
+			if let Ok(_) = crate_access.init().await {
+				sqlx::query("DELETE FROM crate_item WHERE id = $1")
+					.bind(id)
+					.execute(db().await)
+					.await
+					.unwrap();
+				collect_garbage().await;
+				res.render(serde_json::to_string(&id).unwrap());
+			} else {
+				res.render(StatusCode::UNAUTHORIZED);
+			}
+		} else {
+			res.render(StatusCode::NOT_FOUND);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
 // pub struct AddFileChunkReq {
 // 	pub id: i16,
 // 	pub crate_item_id: Uuid,
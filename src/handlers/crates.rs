@@ -1,15 +1,23 @@
 use chrono::{DateTime, Utc};
-use salvo::http::{StatusCode};
+use salvo::http::{header, StatusCode};
 use salvo::prelude::{handler, Depot, Response, Request};
 use secp256k1::PublicKey;
 use serde::{Deserialize, Serialize};
 use sqlx::types::{Uuid};
-use sqlx::FromRow;
+use sqlx::{FromRow, PgConnection};
 use crate::{
 	AccessType, SpecialAddr,
 	AddCrateReq, _add_crate, CrateAccess,
 	comn_addr::ComnAddr, db::{db},
-	read::crates::{CrateOwnerFilter, CrateFilter}
+	archive::{ArchiveErr, ExportCrateReq, ImportCrateReq, MAX_ARCHIVE_BYTES, MEDIA_TYPE},
+	read::{crates::{CrateOwnerFilter, CrateFilter}, addr::owns_addr},
+	encryption::master_keys,
+	e2e::{check_envelope, get_envelopes, member_key_version, member_removed, E2eErr, RotateCrateKeyReq},
+	update::json_schema::{check_crate, list_schemas, SchemaErr, SetCrateSchemaReq},
+	update::crates::{
+		list_audit, list_offers, AcceptCrateReq, CancelCrateOfferReq, CrateErr,
+		CrateTransferRes, OfferCrateReq, UpdateCrateReq,
+	},
 };
 use crate::print_current_db;
 
@@ -29,6 +37,16 @@ pub async fn add_crate(req: &mut Request, res: &mut Response, depot: &mut Depot)
 		.bind(crate_req.addr.to_uuid())
 		.fetch_one(&mut *tx)
 		.await {
+			if crate_req.encrypted && master_keys().is_err() {
+				res.render(StatusCode::NOT_IMPLEMENTED);
+				return;
+			}
+			if let Some(envelope) = &crate_req.envelope {
+				if check_envelope(envelope).is_err() {
+					res.render(StatusCode::BAD_REQUEST);
+					return;
+				}
+			}
 			let rr = _add_crate(crate_req).await;
 			res.render(serde_json::to_string(&rr).unwrap());
 		} else {
@@ -139,6 +157,9 @@ pub struct CrateAccessReq {
 	pub give_access: bool,
 	pub access_type: AccessType,
 	pub expires: Option<DateTime<Utc>>,
+	// the crate key sealed to the addr, needed to give access to e2e crates
+	#[serde(default)]
+	pub envelope: Option<Vec<u8>>,
 }
 
 
@@ -156,6 +177,27 @@ pub struct CrateAdmins {
 }
 
 impl CrateAdmins {
+	// owners and admins of a crate with their keys
+	pub async fn of(conn: &mut PgConnection, crate_id: &str) -> Self {
+		CrateAdmins {
+			list: sqlx::query_as::<_, CrateAdmin>(
+					"
+					SELECT a.id as addr, ca.type, k.pub_key
+					FROM addr a
+					LEFT JOIN addr_key ak ON ak.addr_id = a.id
+					LEFT JOIN key k on k.id = ak.key_id
+					JOIN crate_access ca ON ca.addr_id = a.id
+					WHERE ca.type in ('owner', 'admin')
+					AND ca.crate_id = $1::uuid
+					"
+				)
+				.bind(crate_id)
+				.fetch_all(conn)
+				.await
+				.unwrap_or_default(),
+		}
+	}
+
 	pub fn match_pub_key(&self, pub_key_req: Vec<u8>) -> bool {
 		for i in self.list.iter() {
 	      if i.pub_key == Some(pub_key_req.clone()) { return true; }
@@ -207,16 +249,30 @@ pub async fn change_crate_access(req: &mut Request, res: &mut Response, depot: &
 			let entry: CrateAccess;
 
 			if crate_req.give_access {
+				let access_to_addr = ComnAddr::new(&crate_req.access_to_addr).unwrap();
+				let key_version = match member_key_version(&mut *tx, &crate_req.crate_id, &access_to_addr, &crate_req.envelope).await {
+					Ok(key_version) => key_version,
+					Err(E2eErr::Conflict) => {
+						res.render(StatusCode::CONFLICT);
+						return;
+					}
+					Err(_) => {
+						res.render(StatusCode::BAD_REQUEST);
+						return;
+					}
+				};
 				entry = sqlx::query_as::<_, CrateAccess>(
 					"
-					INSERT INTO crate_access(crate_id, addr_id, type, expires)
-					VALUES($1::uuid, $2::uuid, $3, $4) RETURNING *
+					INSERT INTO crate_access(crate_id, addr_id, type, expires, envelope, key_version)
+					VALUES($1::uuid, $2::uuid, $3, $4, $5, $6) RETURNING *
 					"
 				)
 				.bind(crate_req.crate_id)
 				.bind(comn_addr)
 				.bind(crate_req.access_type)
 				.bind(crate_req.expires)
+				.bind(key_version.and(crate_req.envelope))
+				.bind(key_version)
 				.fetch_one(&mut *tx)
 				.await
 				.unwrap();
@@ -233,12 +289,14 @@ pub async fn change_crate_access(req: &mut Request, res: &mut Response, depot: &
 						RETURNING *
 						"
 					)
-					.bind(crate_req.crate_id)
+					.bind(crate_req.crate_id.clone())
 					.bind(comn_addr)
 					.bind(crate_req.access_type)
 					.fetch_one(&mut *tx)
 					.await
 					.unwrap();
+					let access_to_addr = ComnAddr::new(&crate_req.access_to_addr).unwrap();
+					member_removed(&mut *tx, &crate_req.crate_id, &access_to_addr).await;
 				}
 			}
 			// println!("entry {:?}", entry);
@@ -250,4 +308,334 @@ pub async fn change_crate_access(req: &mut Request, res: &mut Response, depot: &
 	} else {
 		res.render(StatusCode::BAD_REQUEST);
 	}
-}
\ No newline at end of file
+}
+/// Gets the crate key envelopes of the key's addrs in an end to end encrypted crate
+///
+/// Returns http status code NOT_FOUND if the crate isn't e2e
+/// On success, returns the crate's key version, whether the key has to be rotated and the envelopes
+#[handler]
+pub async fn get_crate_envelopes(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	if let Some(crate_id) = req.query::<String>("id") {
+		let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+		match get_envelopes(&crate_id, pub_key).await {
+			Ok(envelopes) if !envelopes.envelopes.is_empty() => {
+				res.render(serde_json::to_string(&envelopes).unwrap())
+			}
+			Ok(_) => res.render(StatusCode::UNAUTHORIZED),
+			Err(_) => res.render(StatusCode::NOT_FOUND),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Replaces the crate key of an end to end encrypted crate, for owners and admins
+///
+/// Needs an envelope for every addr with access, sealed with the new key.
+/// Returns http status code CONFLICT if key_version isn't the one after the current
+#[handler]
+pub async fn rotate_crate_key(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Ok(rotate_req) = req.parse_json::<RotateCrateKeyReq>().await {
+		let mut tx = db().await.begin().await.unwrap();
+		let ownership = CrateAdmins {
+			list: sqlx::query_as::<_, CrateAdmin>(
+					"
+					SELECT a.id as addr, ca.type, k.pub_key
+					FROM addr a
+					LEFT JOIN addr_key ak ON ak.addr_id = a.id
+					LEFT JOIN key k on k.id = ak.key_id
+					JOIN crate_access ca ON ca.addr_id = a.id
+					WHERE ca.type in ('owner', 'admin')
+					AND ca.crate_id = $1::uuid
+					"
+				)
+				.bind(rotate_req.crate_id.clone())
+				.fetch_all(&mut *tx)
+				.await
+				.unwrap(),
+		};
+		if !ownership.match_pub_key(pub_key.serialize().to_vec()) {
+			res.render(StatusCode::UNAUTHORIZED);
+			return;
+		}
+		match rotate_req.rotate(&mut *tx).await {
+			Ok(_) => {
+				tx.commit().await.unwrap();
+				res.render(serde_json::to_string(&get_envelopes(&rotate_req.crate_id, pub_key).await.unwrap()).unwrap());
+			}
+			Err(E2eErr::Conflict) => res.render(StatusCode::CONFLICT),
+			Err(E2eErr::NotE2e) => res.render(StatusCode::NOT_FOUND),
+			Err(_) => res.render(StatusCode::BAD_REQUEST),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Lists the JSON Schemas attached to path patterns of a crate, for owners and admins
+#[handler]
+pub async fn get_crate_schemas(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Some(crate_id) = req.query::<Uuid>("id") {
+		let mut conn = db().await.acquire().await.unwrap();
+		let ownership = CrateAdmins::of(&mut conn, &crate_id.to_string()).await;
+		if !ownership.match_pub_key(pub_key.serialize().to_vec()) {
+			res.render(StatusCode::UNAUTHORIZED);
+			return;
+		}
+		res.render(serde_json::to_string(&list_schemas(&mut conn, crate_id).await).unwrap());
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Attaches a JSON Schema to a path pattern of a crate or detaches it (See SetCrateSchemaReq)
+///
+/// Json items added at matching paths have to satisfy it, items already stored
+/// aren't touched, `check_crate_schemas` reports the ones that don't.
+/// Returns http status code BAD_REQUEST with what's wrong if the schema doesn't compile
+#[handler]
+pub async fn set_crate_schema(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Ok(schema_req) = req.parse_json::<SetCrateSchemaReq>().await {
+		let mut conn = db().await.acquire().await.unwrap();
+		let ownership = CrateAdmins::of(&mut conn, &schema_req.crate_id).await;
+		drop(conn);
+		if !ownership.match_pub_key(pub_key.serialize().to_vec()) {
+			res.render(StatusCode::UNAUTHORIZED);
+			return;
+		}
+		match schema_req.apply().await {
+			Ok(crate_schema) => res.render(serde_json::to_string(&crate_schema).unwrap()),
+			Err(SchemaErr::NotFound) => res.render(StatusCode::NOT_FOUND),
+			Err(SchemaErr::E2e) => res.render(StatusCode::CONFLICT),
+			Err(e) => {
+				res.status_code(StatusCode::BAD_REQUEST);
+				res.render(e.to_string());
+			}
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Reports the json items of a crate that don't satisfy the schemas of their paths,
+/// for owners and admins (See SchemaViolation)
+#[handler]
+pub async fn check_crate_schemas(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Some(crate_id) = req.query::<Uuid>("id") {
+		let mut conn = db().await.acquire().await.unwrap();
+		let ownership = CrateAdmins::of(&mut conn, &crate_id.to_string()).await;
+		drop(conn);
+		if !ownership.match_pub_key(pub_key.serialize().to_vec()) {
+			res.render(StatusCode::UNAUTHORIZED);
+			return;
+		}
+		match check_crate(crate_id).await {
+			Ok(violations) => res.render(serde_json::to_string(&violations).unwrap()),
+			Err(_) => res.render(StatusCode::INTERNAL_SERVER_ERROR),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+fn render_err(res: &mut Response, e: CrateErr) {
+	match e {
+		CrateErr::BadData => res.render(StatusCode::BAD_REQUEST),
+		CrateErr::NotFound => res.render(StatusCode::NOT_FOUND),
+		CrateErr::Unauthorized => res.render(StatusCode::UNAUTHORIZED),
+		CrateErr::Conflict => res.render(StatusCode::CONFLICT),
+		CrateErr::Expired => res.render(StatusCode::GONE),
+	}
+}
+
+/// Changes the name, comment or expiry of a crate, for owners and admins (See UpdateCrateReq)
+///
+/// On success, returns http status code OK and the crate as it is now
+#[handler]
+pub async fn update_crate(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Ok(update_req) = req.parse_json::<UpdateCrateReq>().await {
+		if !owns_addr(&update_req.addr, pub_key).await {
+			res.render(StatusCode::BAD_REQUEST);
+			return;
+		}
+		match update_req.apply().await {
+			Ok(updated) => res.render(serde_json::to_string(&updated).unwrap()),
+			Err(e) => render_err(res, e),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Lists the open ownership offers made to or by the addrs of the key
+#[handler]
+pub async fn list_crate_offers(res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	let mut conn = db().await.acquire().await.unwrap();
+	let offers: Vec<CrateTransferRes> = list_offers(&mut conn, &pub_key.serialize()).await
+		.into_iter().map(CrateTransferRes::from).collect();
+	res.render(serde_json::to_string(&offers).unwrap());
+}
+
+/// Offers the ownership of a crate to another addr, for owners (See OfferCrateReq)
+///
+/// The crate changes hands once the addr accepts the offer with `accept_crate_offer`.
+/// Returns http status code CONFLICT if the crate already has an open offer
+#[handler]
+pub async fn offer_crate(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Ok(offer_req) = req.parse_json::<OfferCrateReq>().await {
+		if !owns_addr(&offer_req.from, pub_key).await {
+			res.render(StatusCode::BAD_REQUEST);
+			return;
+		}
+		match offer_req.offer().await {
+			Ok(transfer) => res.render(serde_json::to_string(&CrateTransferRes::from(transfer)).unwrap()),
+			Err(e) => render_err(res, e),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Withdraws or declines an open ownership offer (See CancelCrateOfferReq)
+#[handler]
+pub async fn cancel_crate_offer(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Ok(cancel_req) = req.parse_json::<CancelCrateOfferReq>().await {
+		if !owns_addr(&cancel_req.addr, pub_key).await {
+			res.render(StatusCode::BAD_REQUEST);
+			return;
+		}
+		match cancel_req.cancel().await {
+			Ok(transfer) => res.render(serde_json::to_string(&CrateTransferRes::from(transfer)).unwrap()),
+			Err(e) => render_err(res, e),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Takes the ownership of a crate offered to the addr, signed by it (See AcceptCrateReq)
+///
+/// Returns http status code GONE if the offer has expired
+/// On success, the offering addr is no longer an owner of the crate
+#[handler]
+pub async fn accept_crate_offer(res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	let req_json = depot.get::<String>("req").unwrap();
+
+	if let Ok(accept_req) = serde_json::from_str::<AcceptCrateReq>(req_json) {
+		if !owns_addr(&accept_req.addr, pub_key).await {
+			res.render(StatusCode::BAD_REQUEST);
+			return;
+		}
+		match accept_req.accept().await {
+			Ok(transfer) => res.render(serde_json::to_string(&CrateTransferRes::from(transfer)).unwrap()),
+			Err(e) => render_err(res, e),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Lists the changes made to a crate and its ownership, for owners and admins
+#[handler]
+pub async fn get_crate_audit(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Some(crate_id) = req.query::<Uuid>("id") {
+		let mut conn = db().await.acquire().await.unwrap();
+		let ownership = CrateAdmins::of(&mut conn, &crate_id.to_string()).await;
+		if !ownership.match_pub_key(pub_key.serialize().to_vec()) {
+			res.render(StatusCode::UNAUTHORIZED);
+			return;
+		}
+		res.render(serde_json::to_string(&list_audit(&mut conn, crate_id).await).unwrap());
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+fn render_archive_err(res: &mut Response, e: ArchiveErr) {
+	match e {
+		ArchiveErr::NotFound => res.render(StatusCode::NOT_FOUND),
+		ArchiveErr::Unauthorized => res.render(StatusCode::UNAUTHORIZED),
+		e => {
+			res.status_code(StatusCode::UNPROCESSABLE_ENTITY);
+			res.render(e.to_string());
+		}
+	}
+}
+
+/// Exports a crate as a tar archive, for owners and admins (See ExportCrateReq)
+///
+/// The archive has a manifest.json with the crate, its access list and items
+/// with the SHA-256 of their data, and a file of data per item (See ArchiveManifest)
+/// End to end encrypted crates and archives of more than MAX_ARCHIVE_BYTES can't
+/// be exported, http status code UNPROCESSABLE_ENTITY
+#[handler]
+pub async fn export_crate(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	let crate_id = req.query::<Uuid>("id");
+	let addr = req.query::<String>("addr").and_then(|addr| ComnAddr::parse(&addr));
+	if let (Some(crate_id), Some(addr)) = (crate_id, addr) {
+		if !owns_addr(&addr, pub_key).await {
+			res.render(StatusCode::BAD_REQUEST);
+			return;
+		}
+		match (ExportCrateReq { crate_id, addr }).export().await {
+			Ok(archive) => {
+				let _ = res.add_header(header::CONTENT_TYPE, MEDIA_TYPE, true);
+				let _ = res.add_header(
+					header::CONTENT_DISPOSITION,
+					format!("attachment; filename=\"{}.tar\"", crate_id.simple()),
+					true,
+				);
+				let _ = res.write_body(archive);
+			}
+			Err(e) => render_archive_err(res, e),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Imports a crate archive as a new crate owned by the addr, or into the crate
+/// of `id` when the addr can edit it (See ImportCrateReq)
+///
+/// The body is the archive made by export_crate. Every item is checked against
+/// its hash and added like an upload, nothing is added if one of them can't be.
+/// Returns http status code UNPROCESSABLE_ENTITY with what's wrong in that case
+/// On success, returns the crate, the ids of the items added and the addrs of the
+/// access list that didn't get access, only the importer does (See ImportedCrate)
+#[handler]
+pub async fn import_crate(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	let scope = depot.get::<Vec<String>>("scope").unwrap();
+	if scope[0] != "crate_write" {
+		res.render(StatusCode::BAD_REQUEST);
+		return;
+	}
+	let addr = req.query::<String>("addr").and_then(|addr| ComnAddr::parse(&addr));
+	let Some(addr) = addr else {
+		res.render(StatusCode::BAD_REQUEST);
+		return;
+	};
+	if !owns_addr(&addr, pub_key).await {
+		res.render(StatusCode::BAD_REQUEST);
+		return;
+	}
+	let import_req = ImportCrateReq { addr, crate_id: req.query::<Uuid>("id") };
+	match req.payload_with_max_size(MAX_ARCHIVE_BYTES).await {
+		Ok(archive) => match import_req.import(archive).await {
+			Ok(imported) => res.render(serde_json::to_string(&imported).unwrap()),
+			Err(e) => render_archive_err(res, e),
+		},
+		Err(_) => res.render(StatusCode::PAYLOAD_TOO_LARGE),
+	}
+}
@@ -0,0 +1,91 @@
+use salvo::http::{StatusCode};
+use salvo::prelude::{handler, Depot, Request, Response};
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+use crate::{
+	comn_addr::ComnAddr,
+	read::addr::owns_addr,
+	update::escrow::{
+		EscrowReq, EscrowActionReq, EscrowErr, EscrowRes, list_escrows,
+	},
+};
+
+fn render_err(res: &mut Response, e: EscrowErr) {
+	match e {
+		EscrowErr::AlreadyReported => res.render(StatusCode::ALREADY_REPORTED),
+		EscrowErr::NotFound => res.render(StatusCode::NOT_FOUND),
+		EscrowErr::Unauthorized => res.render(StatusCode::UNAUTHORIZED),
+		EscrowErr::BadData | EscrowErr::LowAmount => res.render(StatusCode::BAD_REQUEST),
+	}
+}
+
+/// Locks the sender's coins in escrow (See EscrowReq)
+///
+/// Returns http status code ALREADY_REPORTED if the sender already used the nonce
+/// Returns http status code BAD_REQUEST if the balance is too low or the request is invalid
+/// Returns http status code NOT_FOUND if the receiver or arbiter isn't an addr
+#[handler]
+pub async fn lock_escrow(res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	let req_json = depot.get::<String>("req").unwrap();
+
+	if let Ok(escrow_req) = serde_json::from_str::<EscrowReq>(req_json) {
+		if owns_addr(&escrow_req.sender, pub_key).await {
+			match escrow_req.lock().await {
+				Ok(escrow) => res.render(serde_json::to_string(&EscrowRes::from(escrow)).unwrap()),
+				Err(e) => render_err(res, e),
+			}
+		} else {
+			res.render(StatusCode::BAD_REQUEST);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Releases an escrow to its receiver or refunds it to its sender (See EscrowActionReq)
+///
+/// Returns http status code UNAUTHORIZED if the signing addr is not allowed to take the action
+/// Returns http status code NOT_FOUND if the escrow is not locked anymore
+#[handler]
+pub async fn settle_escrow(res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	let req_json = depot.get::<String>("req").unwrap();
+
+	if let Ok(action_req) = serde_json::from_str::<EscrowActionReq>(req_json) {
+		if owns_addr(&action_req.addr, pub_key).await {
+			match action_req.apply().await {
+				Ok(escrow) => res.render(serde_json::to_string(&EscrowRes::from(escrow)).unwrap()),
+				Err(e) => render_err(res, e),
+			}
+		} else {
+			res.render(StatusCode::BAD_REQUEST);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListEscrowReq {
+	pub addr: ComnAddr,
+}
+
+#[handler]
+pub async fn list_escrow(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Ok(list_req) = req.parse_queries::<ListEscrowReq>() {
+		if owns_addr(&list_req.addr, pub_key).await {
+			let escrows: Vec<EscrowRes> = list_escrows(&list_req.addr)
+				.await
+				.into_iter()
+				.map(|e| EscrowRes::from(e))
+				.collect();
+			res.render(serde_json::to_string(&escrows).unwrap());
+		} else {
+			res.render(StatusCode::UNAUTHORIZED);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
@@ -0,0 +1,100 @@
+use salvo::http::{StatusCode};
+use salvo::prelude::{handler, Depot, Request, Response};
+use secp256k1::PublicKey;
+use crate::update::item_type::{
+	AddItemTypeReq, ItemTypeAccessReq, ItemTypeErr, ItemTypeExtensionReq,
+	is_registry_admin, list_item_types, remove_item_type,
+};
+
+fn render_err(res: &mut Response, e: ItemTypeErr) {
+	match e {
+		ItemTypeErr::BadData => res.render(StatusCode::BAD_REQUEST),
+		ItemTypeErr::NotFound => res.render(StatusCode::NOT_FOUND),
+		ItemTypeErr::Exists | ItemTypeErr::InUse => res.render(StatusCode::CONFLICT),
+		ItemTypeErr::Forbidden => res.render(StatusCode::FORBIDDEN),
+	}
+}
+
+/// Lists the registered media types with their extensions
+#[handler]
+pub async fn get_item_types(res: &mut Response) {
+	res.render(serde_json::to_string(&list_item_types().await).unwrap());
+}
+
+/// Registers a media type, for keys of the config addr (See AddItemTypeReq)
+///
+/// Returns http status code CONFLICT if it's already registered
+/// On success, returns http status code OK and the media type (See ItemType)
+#[handler]
+pub async fn add_item_type(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if !is_registry_admin(pub_key).await {
+		res.render(StatusCode::UNAUTHORIZED);
+		return;
+	}
+	if let Ok(add_req) = req.parse_json::<AddItemTypeReq>().await {
+		match add_req.add().await {
+			Ok(item_type) => res.render(serde_json::to_string(&item_type).unwrap()),
+			Err(e) => render_err(res, e),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Unregisters a media type, for keys of the config addr
+///
+/// Returns http status code CONFLICT if items of the type are stored
+#[handler]
+pub async fn delete_item_type(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if !is_registry_admin(pub_key).await {
+		res.render(StatusCode::UNAUTHORIZED);
+		return;
+	}
+	if let Some(media_type) = req.query::<String>("media_type") {
+		match remove_item_type(&media_type).await {
+			Ok(_) => res.render(serde_json::to_string(&media_type).unwrap()),
+			Err(e) => render_err(res, e),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Adds or removes an extension of a media type, for keys of the config addr
+#[handler]
+pub async fn change_item_type_extension(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if !is_registry_admin(pub_key).await {
+		res.render(StatusCode::UNAUTHORIZED);
+		return;
+	}
+	if let Ok(extension_req) = req.parse_json::<ItemTypeExtensionReq>().await {
+		match extension_req.apply().await {
+			Ok(item_type) => res.render(serde_json::to_string(&item_type).unwrap()),
+			Err(e) => render_err(res, e),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Gives or takes access of an addr to a media type, for keys of the config addr
+/// (See ItemTypeAccessReq)
+#[handler]
+pub async fn change_item_type_access(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if !is_registry_admin(pub_key).await {
+		res.render(StatusCode::UNAUTHORIZED);
+		return;
+	}
+	if let Ok(access_req) = req.parse_json::<ItemTypeAccessReq>().await {
+		match access_req.apply().await {
+			Ok(_) => res.render(serde_json::to_string(&access_req.media_type).unwrap()),
+			Err(e) => render_err(res, e),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
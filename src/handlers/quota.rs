@@ -0,0 +1,54 @@
+use salvo::http::{StatusCode};
+use salvo::prelude::{handler, Depot, Request, Response};
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+use crate::{
+	comn_addr::ComnAddr,
+	read::addr::owns_addr,
+	update::quota::{BuyQuotaReq, QuotaErr, get_usage},
+};
+
+/// Buys extra storage quota with the addr's coins (See BuyQuotaReq)
+///
+/// Returns http status code ALREADY_REPORTED if the addr already used the nonce
+/// Returns http status code PAYMENT_REQUIRED if the balance is too low
+/// On success, returns http status code OK and the storage usage (See StorageUsage)
+#[handler]
+pub async fn buy_quota(res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	let req_json = depot.get::<String>("req").unwrap();
+
+	if let Ok(buy_req) = serde_json::from_str::<BuyQuotaReq>(req_json) {
+		if owns_addr(&buy_req.addr, pub_key).await {
+			match buy_req.buy().await {
+				Ok(usage) => res.render(serde_json::to_string(&usage).unwrap()),
+				Err(QuotaErr::AlreadyReported) => res.render(StatusCode::ALREADY_REPORTED),
+				Err(QuotaErr::LowAmount) => res.render(StatusCode::PAYMENT_REQUIRED),
+				Err(_) => res.render(StatusCode::BAD_REQUEST),
+			}
+		} else {
+			res.render(StatusCode::BAD_REQUEST);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StorageUsageReq {
+	pub addr: ComnAddr,
+}
+
+#[handler]
+pub async fn storage_usage(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Ok(usage_req) = req.parse_queries::<StorageUsageReq>() {
+		if owns_addr(&usage_req.addr, pub_key).await {
+			res.render(serde_json::to_string(&get_usage(&usage_req.addr).await).unwrap());
+		} else {
+			res.render(StatusCode::UNAUTHORIZED);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
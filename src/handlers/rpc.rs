@@ -0,0 +1,196 @@
+use std::{error::Error, fmt};
+use salvo::prelude::{handler, Request, Response};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use crate::{
+	comn_addr::ComnAddr, db::get_config,
+	eth::{self, EthErr},
+	read::addr::AddrFilter,
+	update::coin::{
+		Transaction, TransactionErr,
+		get_allowance, get_balance, get_details, total_supply,
+	},
+};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcReq {
+	pub jsonrpc: String,
+	pub id: Value,
+	pub method: String,
+	#[serde(default)]
+	pub params: Vec<Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcErrRes {
+	pub code: i64,
+	pub message: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RpcRes {
+	pub jsonrpc: String,
+	pub id: Value,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub result: Option<Value>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub error: Option<RpcErrRes>,
+}
+
+// `message` is either the EIP-191 signed JSON string of TransferMsg
+// or the TransferMsg object itself when signed as EIP-712 typed data
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferParams {
+	pub message: Value,
+	pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferMsg {
+	pub to: String,
+	pub amount: u64,
+	pub nonce: String,
+}
+
+#[derive(Debug)]
+pub enum RpcErr {
+	ParseError,
+	MethodNotFound,
+	InvalidParams,
+	UnknownAddr,
+	Eth(EthErr),
+	Transaction(TransactionErr),
+}
+
+impl Error for RpcErr {}
+
+impl fmt::Display for RpcErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RpcErr::ParseError => write!(f, "Parse error"),
+            RpcErr::MethodNotFound => write!(f, "Method not found"),
+            RpcErr::InvalidParams => write!(f, "Invalid params"),
+            RpcErr::UnknownAddr => write!(f, "address is not registered."),
+            RpcErr::Eth(e) => write!(f, "{}", e),
+            RpcErr::Transaction(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl RpcErr {
+	fn code(&self) -> i64 {
+		match self {
+			RpcErr::ParseError => -32700,
+			RpcErr::MethodNotFound => -32601,
+			RpcErr::InvalidParams | RpcErr::Eth(_) => -32602,
+			RpcErr::UnknownAddr | RpcErr::Transaction(_) => -32000,
+		}
+	}
+}
+
+fn quantity(x: i64) -> Value {
+	json!(format!("{:#x}", x))
+}
+
+fn param<'a>(params: &'a [Value], i: usize) -> Result<&'a Value, RpcErr> {
+	params.get(i).ok_or(RpcErr::InvalidParams)
+}
+
+async fn comn_addr(value: &Value) -> Result<ComnAddr, RpcErr> {
+	let eth_addr = eth::parse_eth_addr(value.as_str().ok_or(RpcErr::InvalidParams)?)
+		.map_err(RpcErr::Eth)?;
+	AddrFilter::from_eth_addr(&eth_addr).await.map_err(|_| RpcErr::UnknownAddr)
+}
+
+async fn transfer(params: TransferParams) -> Result<Value, RpcErr> {
+	let (digest, msg) = match &params.message {
+		Value::String(signed) => (
+			eth::eip191_hash(signed.as_bytes()),
+			serde_json::from_str::<TransferMsg>(signed).map_err(|_| RpcErr::InvalidParams)?,
+		),
+		typed => {
+			let msg = serde_json::from_value::<TransferMsg>(typed.clone())
+				.map_err(|_| RpcErr::InvalidParams)?;
+			let to = eth::parse_eth_addr(&msg.to).map_err(RpcErr::Eth)?;
+			(
+				eth::eip712_transfer_hash(
+					&get_details().await.name,
+					get_config().await.chain_id,
+					&to,
+					msg.amount,
+					&msg.nonce,
+				),
+				msg,
+			)
+		}
+	};
+	let signer = eth::recover(&digest, &params.signature).map_err(RpcErr::Eth)?;
+	let sender = AddrFilter::from_eth_addr(&eth::eth_addr(&signer))
+		.await
+		.map_err(|_| RpcErr::UnknownAddr)?;
+	let transaction = Transaction {
+		amount: msg.amount,
+		receiver: comn_addr(&json!(msg.to)).await?,
+		sender,
+		comment: None,
+		nonce: msg.nonce,
+	};
+	transaction.transfer_coins().await.map_err(RpcErr::Transaction)?;
+	Ok(json!(true))
+}
+
+async fn call(rpc_req: &RpcReq) -> Result<Value, RpcErr> {
+	let params = &rpc_req.params;
+	match rpc_req.method.as_str() {
+		"eth_chainId" => Ok(quantity(get_config().await.chain_id as i64)),
+		"net_version" => Ok(json!(get_config().await.chain_id.to_string())),
+		"name" => Ok(json!(get_details().await.name)),
+		"symbol" => Ok(json!(get_details().await.symbol)),
+		"decimals" => Ok(quantity(0)),
+		"totalSupply" => Ok(quantity(total_supply().await)),
+		"balanceOf" => {
+			let owner = comn_addr(param(params, 0)?).await?;
+			Ok(quantity(get_balance(&owner).await))
+		}
+		"allowance" => {
+			let owner = comn_addr(param(params, 0)?).await?;
+			let spender = comn_addr(param(params, 1)?).await?;
+			Ok(quantity(get_allowance(&owner, &spender).await.amount))
+		}
+		"transfer" => {
+			let transfer_params = serde_json::from_value::<TransferParams>(param(params, 0)?.clone())
+				.map_err(|_| RpcErr::InvalidParams)?;
+			transfer(transfer_params).await
+		}
+		_ => Err(RpcErr::MethodNotFound),
+	}
+}
+
+/// Ethereum style JSON-RPC over the ComnCoin ledger
+///
+/// Addresses are 20 byte ethereum addresses derived from registered keys and
+/// quantities are hex encoded. Errors are returned in the body with http status code OK.
+#[handler]
+pub async fn json_rpc(req: &mut Request, res: &mut Response) {
+	let rpc_res = match req.parse_json::<RpcReq>().await {
+		Ok(rpc_req) => {
+			let result = call(&rpc_req).await;
+			RpcRes {
+				jsonrpc: "2.0".to_string(),
+				id: rpc_req.id,
+				result: result.as_ref().ok().cloned(),
+				error: result.err().map(|e| RpcErrRes { code: e.code(), message: e.to_string() }),
+			}
+		}
+		Err(_) => RpcRes {
+			jsonrpc: "2.0".to_string(),
+			id: Value::Null,
+			result: None,
+			error: Some(RpcErrRes {
+				code: RpcErr::ParseError.code(),
+				message: RpcErr::ParseError.to_string(),
+			}),
+		},
+	};
+	res.render(serde_json::to_string(&rpc_res).unwrap());
+}
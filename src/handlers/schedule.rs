@@ -0,0 +1,80 @@
+use salvo::http::{StatusCode};
+use salvo::prelude::{handler, Depot, Request, Response};
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+use crate::{
+	comn_addr::ComnAddr,
+	read::addr::owns_addr,
+	update::schedule::{
+		StandingOrderReq, CancelStandingOrderReq, StandingOrderErr,
+		StandingOrderRes, list_standing_orders,
+	},
+};
+
+/// Registers a signed standing order that the broker pays out on schedule
+///
+/// Returns http status code ALREADY_REPORTED if the sender already used the nonce
+/// On success, returns http status code OK and the standing order (See StandingOrderRes)
+#[handler]
+pub async fn add_standing_order(res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	let req_json = depot.get::<String>("req").unwrap();
+
+	if let Ok(order_req) = serde_json::from_str::<StandingOrderReq>(req_json) {
+		if owns_addr(&order_req.sender, pub_key).await {
+			match order_req.add().await {
+				Ok(order) => res.render(serde_json::to_string(&StandingOrderRes::from(order)).unwrap()),
+				Err(StandingOrderErr::AlreadyReported) => res.render(StatusCode::ALREADY_REPORTED),
+				Err(StandingOrderErr::BadData) => res.render(StatusCode::BAD_REQUEST),
+				Err(StandingOrderErr::NotFound) => res.render(StatusCode::NOT_FOUND),
+			}
+		} else {
+			res.render(StatusCode::BAD_REQUEST);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+#[handler]
+pub async fn cancel_standing_order(res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	let req_json = depot.get::<String>("req").unwrap();
+
+	if let Ok(cancel_req) = serde_json::from_str::<CancelStandingOrderReq>(req_json) {
+		if owns_addr(&cancel_req.sender, pub_key).await {
+			match cancel_req.cancel().await {
+				Ok(order) => res.render(serde_json::to_string(&StandingOrderRes::from(order)).unwrap()),
+				Err(_) => res.render(StatusCode::NOT_FOUND),
+			}
+		} else {
+			res.render(StatusCode::BAD_REQUEST);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ListStandingOrderReq {
+	pub addr: ComnAddr,
+}
+
+#[handler]
+pub async fn list_standing_order(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Ok(list_req) = req.parse_queries::<ListStandingOrderReq>() {
+		if owns_addr(&list_req.addr, pub_key).await {
+			let orders: Vec<StandingOrderRes> = list_standing_orders(&list_req.addr)
+				.await
+				.into_iter()
+				.map(|o| StandingOrderRes::from(o))
+				.collect();
+			res.render(serde_json::to_string(&orders).unwrap());
+		} else {
+			res.render(StatusCode::UNAUTHORIZED);
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
@@ -0,0 +1,98 @@
+use salvo::http::{StatusCode};
+use salvo::prelude::{handler, Depot, Request, Response};
+use secp256k1::PublicKey;
+use crate::{
+	read::addr::owns_addr,
+	update::item_type::is_registry_admin,
+	add::template::{
+		AddTemplateReq, ApplyTemplateReq, TemplateErr,
+		list_templates, remove_template,
+	},
+};
+
+fn render_err(res: &mut Response, e: TemplateErr) {
+	match e {
+		TemplateErr::NotFound | TemplateErr::UnknownAddr => res.render(StatusCode::NOT_FOUND),
+		e => {
+			res.status_code(StatusCode::UNPROCESSABLE_ENTITY);
+			res.render(e.to_string());
+		}
+	}
+}
+
+// templates come as json, or toml with a content type of application/toml
+async fn parse_template(req: &mut Request) -> Option<AddTemplateReq> {
+	if req.content_type().map_or(false, |mime| mime.subtype() == "toml") {
+		let payload = req.payload().await.ok()?;
+		toml::from_str::<AddTemplateReq>(std::str::from_utf8(payload).ok()?).ok()
+	} else {
+		req.parse_json::<AddTemplateReq>().await.ok()
+	}
+}
+
+/// Lists the crate templates
+#[handler]
+pub async fn get_templates(res: &mut Response) {
+	res.render(serde_json::to_string(&list_templates().await).unwrap());
+}
+
+/// Registers or replaces a crate template, for keys of the config addr (See AddTemplateReq)
+///
+/// Returns http status code UNPROCESSABLE_ENTITY with what's wrong if the template is bad
+#[handler]
+pub async fn add_template(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if !is_registry_admin(pub_key).await {
+		res.render(StatusCode::UNAUTHORIZED);
+		return;
+	}
+	if let Some(add_req) = parse_template(req).await {
+		match add_req.register().await {
+			Ok(template) => res.render(serde_json::to_string(&template).unwrap()),
+			Err(e) => render_err(res, e),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Removes a crate template, for keys of the config addr
+#[handler]
+pub async fn delete_template(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if !is_registry_admin(pub_key).await {
+		res.render(StatusCode::UNAUTHORIZED);
+		return;
+	}
+	if let Some(name) = req.query::<String>("name") {
+		match remove_template(&name).await {
+			Ok(_) => res.render(serde_json::to_string(&name).unwrap()),
+			Err(e) => render_err(res, e),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+/// Makes the crates of a template for an addr of the key, or for any addr when
+/// the key is of the config addr (See ApplyTemplateReq)
+///
+/// Nothing is made if any crate, item or access grant of the template can't be.
+/// Returns http status code UNPROCESSABLE_ENTITY with what's wrong in that case
+/// On success, returns the crates made with the ids of their items (See ProvisionedCrate)
+#[handler]
+pub async fn apply_template(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Ok(apply_req) = req.parse_json::<ApplyTemplateReq>().await {
+		if !owns_addr(&apply_req.addr, pub_key).await && !is_registry_admin(pub_key).await {
+			res.render(StatusCode::UNAUTHORIZED);
+			return;
+		}
+		match apply_req.apply().await {
+			Ok(provisioned) => res.render(serde_json::to_string(&provisioned).unwrap()),
+			Err(e) => render_err(res, e),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
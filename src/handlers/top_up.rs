@@ -0,0 +1,116 @@
+use salvo::prelude::{handler, Depot, Request, Response};
+use salvo::http::{StatusCode};
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::str;
+use crate::{
+	comn_addr::ComnAddr,
+	db::get_config,
+	payment::{apply, PaymentErr, PaymentProvider, stripe::Stripe, webhook::HmacWebhook},
+	read::addr::owns_addr,
+	update::coin::TransactionErr,
+	update::top_up::get_checkout,
+};
+
+/// Verifies a provider webhook and credits or claws back the coins it is about.
+///
+/// Retried events are answered with http status code ALREADY_REPORTED instead
+/// of moving the coins again.
+async fn receive(provider: &dyn PaymentProvider, req: &mut Request, res: &mut Response) {
+	// owned, so the headers can be read along with it
+	let payload = match req.payload().await {
+		Ok(payload_bytes) => str::from_utf8(payload_bytes).unwrap_or_default().to_string(),
+		Err(_) => {
+			res.render(StatusCode::BAD_REQUEST);
+			return;
+		}
+	};
+	let event = match provider.event(req.headers(), &payload) {
+		Ok(event) => event,
+		Err(PaymentErr::Unsupported) => {
+			res.render(StatusCode::METHOD_NOT_ALLOWED);
+			return;
+		}
+		Err(_) => {
+			res.render(StatusCode::BAD_REQUEST);
+			return;
+		}
+	};
+	match apply(provider, event).await {
+		Ok(_) => res.render(StatusCode::OK),
+		Err(TransactionErr::AlreadyReported) => res.render(StatusCode::ALREADY_REPORTED),
+		Err(TransactionErr::BadData) => res.render(StatusCode::BAD_REQUEST),
+		Err(TransactionErr::LowAmount) | Err(TransactionErr::LowAllowance) =>
+			res.render(StatusCode::INTERNAL_SERVER_ERROR),
+	}
+}
+
+#[handler]
+pub async fn stripe_webhook(req: &mut Request, res: &mut Response) {
+	let provider = Stripe { conf: get_config().await.stripe.clone() };
+	receive(&provider, req, res).await;
+}
+
+// top ups from our own billing system, see `payment::webhook`
+#[handler]
+pub async fn payment_webhook(req: &mut Request, res: &mut Response) {
+	let provider = HmacWebhook { conf: get_config().await.webhook.clone() };
+	receive(&provider, req, res).await;
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckoutReq {
+	pub addr: ComnAddr,
+	pub amount: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CheckoutStatusReq {
+	pub id: String,
+}
+
+/// Creates a stripe checkout session buying `amount` coins for an addr of the caller
+///
+/// The addr goes into the session's metadata, so the payer doesn't type it.
+/// Returns http status code BAD_GATEWAY if stripe can't be reached
+/// On success, returns http status code OK and the checkout with its url (See CheckoutRes)
+#[handler]
+pub async fn create_checkout(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Ok(checkout_req) = req.parse_json::<CheckoutReq>().await {
+		if !owns_addr(&checkout_req.addr, pub_key).await {
+			res.render(StatusCode::UNAUTHORIZED);
+			return;
+		}
+		let conf = get_config().await;
+		let provider = Stripe { conf: conf.stripe.clone() };
+		match provider.checkout(&conf.checkout, &checkout_req.addr, checkout_req.amount).await {
+			Ok(checkout) => res.render(serde_json::to_string(&checkout.status().await).unwrap()),
+			Err(PaymentErr::Unavailable) => res.render(StatusCode::BAD_GATEWAY),
+			Err(_) => res.render(StatusCode::BAD_REQUEST),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
+
+// fulfilment of a checkout created by create_checkout, for polling after the redirect
+#[handler]
+pub async fn checkout_status(req: &mut Request, res: &mut Response, depot: &mut Depot) {
+	let pub_key = depot.get::<PublicKey>("public_key").unwrap();
+	if let Ok(status_req) = req.parse_queries::<CheckoutStatusReq>() {
+		match get_checkout("stripe", &status_req.id).await {
+			Some(checkout) => {
+				let addr = ComnAddr::from_uuid(&checkout.addr_id.to_string()).unwrap();
+				if owns_addr(&addr, pub_key).await {
+					res.render(serde_json::to_string(&checkout.status().await).unwrap());
+				} else {
+					res.render(StatusCode::NOT_FOUND);
+				}
+			}
+			None => res.render(StatusCode::NOT_FOUND),
+		}
+	} else {
+		res.render(StatusCode::BAD_REQUEST);
+	}
+}
@@ -1,9 +1,30 @@
 use comn_broker::{app, serve};
+use comn_broker::{db::get_config, encryption::rotate_master_key, store::{migrate, open_store}};
 
 #[tokio::main]
 async fn main() {
 	tracing_subscriber::fmt().init();
 
 	app().await;
+	let args: Vec<String> = std::env::args().collect();
+	if args.len() == 4 && args[1] == "migrate-store" {
+		// copies chunks between backends, i.e. `comn-broker migrate-store local s3`
+		let conf = get_config().await;
+		let from = open_store(conf, &args[2]).expect("unknown store backend");
+		let to = open_store(conf, &args[3]).expect("unknown store backend");
+		match migrate(from.as_ref(), to.as_ref()).await {
+			Ok(copied) => println!("copied {} blobs from {} to {}", copied, args[2], args[3]),
+			Err(e) => println!("migration stopped: {}", e),
+		}
+		return;
+	}
+	if args.len() == 2 && args[1] == "rotate-master-key" {
+		// re-wraps crate data keys with the first of COMN_MASTER_KEYS
+		match rotate_master_key().await {
+			Ok(rotated) => println!("re-wrapped {} crate keys", rotated),
+			Err(e) => println!("rotation stopped: {}", e),
+		}
+		return;
+	}
 	serve().await;
 }
@@ -0,0 +1,188 @@
+pub mod stripe;
+pub mod webhook;
+
+use hmac::{Hmac, Mac};
+use salvo::http::HeaderMap;
+use sha2::Sha256;
+use std::{error::Error, fmt};
+use crate::{
+	comn_addr::ComnAddr,
+	db::PaymentConfig,
+	update::coin::TransactionErr,
+	update::top_up::{TopUpReq, get_top_up},
+};
+
+/// What a verified provider webhook asks of the ledger.
+///
+/// Amounts are in the smallest unit of `currency` and turned into coins with the
+/// provider's configured rates.
+#[derive(Debug, PartialEq)]
+pub enum PaymentEvent {
+	Paid {
+		payment_id: String,
+		// what refunds reference the payment with, if not the payment_id
+		charge_ref: Option<String>,
+		addr: ComnAddr,
+		currency: String,
+		amount: i64,
+	},
+	// total is everything refunded of the payment so far
+	Refunded {
+		payment_ref: String,
+		refund_id: String,
+		currency: String,
+		total: i64,
+	},
+	Failed {
+		payment_ref: String,
+	},
+}
+
+#[derive(Debug)]
+pub enum PaymentErr {
+	BadSignature,
+	BadData,
+	Unsupported,
+	// the provider's api failed
+	Unavailable,
+}
+
+impl Error for PaymentErr {}
+
+impl fmt::Display for PaymentErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaymentErr::BadSignature => write!(f, "webhook signature is not right."),
+            PaymentErr::BadData => write!(f, "webhook payload is not right."),
+            PaymentErr::Unsupported => write!(f, "event is not handled."),
+            PaymentErr::Unavailable => write!(f, "payment provider is unavailable."),
+        }
+    }
+}
+
+pub trait PaymentProvider: Sync {
+	// keeps payment ids of providers apart in top_up and ledger nonces
+	fn name(&self) -> &'static str;
+	fn config(&self) -> &PaymentConfig;
+	fn verify(&self, headers: &HeaderMap, payload: &str) -> Result<(), PaymentErr>;
+	fn parse(&self, payload: &str) -> Result<PaymentEvent, PaymentErr>;
+
+	fn event(&self, headers: &HeaderMap, payload: &str) -> Result<PaymentEvent, PaymentErr> {
+		self.verify(headers, payload)?;
+		self.parse(payload)
+	}
+
+	// coins for an amount in the smallest currency unit, None for currencies without
+	// a positive rate, what's left of the amount isn't worth a coin and is kept
+	fn to_coins(&self, currency: &str, amount: i64) -> Option<i64> {
+		let rate = *self.config().rates.get(&currency.to_lowercase()).filter(|rate| **rate > 0)?;
+		if amount % rate != 0 {
+			println!("{} {} {} of {} isn't worth a coin", self.name(), currency, amount % rate, amount);
+		}
+		amount.checked_div(rate)
+	}
+}
+
+/// Credits or claws back coins for an event of any provider.
+///
+/// Payments and refunds have deterministic ledger nonces, so a redelivered event
+/// is answered with `TransactionErr::AlreadyReported`.
+pub async fn apply(provider: &dyn PaymentProvider, event: PaymentEvent) -> Result<(), TransactionErr> {
+	let conf = provider.config();
+	match event {
+		PaymentEvent::Paid { payment_id, charge_ref, addr, currency, amount } => {
+			match provider.to_coins(&currency, amount) {
+				Some(coins) if coins > 0 => TopUpReq {
+					provider: provider.name().to_string(),
+					payment_id,
+					charge_ref,
+					addr,
+					amount: coins as u64,
+					treasury: conf.treasury.clone(),
+					comment: conf.comment.clone(),
+				}.fulfil().await.map(|_| ()),
+				_ => Err(TransactionErr::BadData),
+			}
+		}
+		PaymentEvent::Refunded { payment_ref, refund_id, currency, total } => {
+			let top_up = get_top_up(provider.name(), &payment_ref).await;
+			match (top_up, provider.to_coins(&currency, total)) {
+				(Some(top_up), Some(coins)) => top_up
+					.claw_back(coins, &refund_id, &conf.treasury, "Refunded.")
+					.await
+					.map(|_| ()),
+				_ => Err(TransactionErr::BadData),
+			}
+		}
+		PaymentEvent::Failed { payment_ref } => {
+			match get_top_up(provider.name(), &payment_ref).await {
+				Some(top_up) => top_up
+					.claw_back(top_up.amount, "failed", &conf.treasury, "Payment failed.")
+					.await
+					.map(|_| ()),
+				// nothing was credited for the payment
+				None => Ok(()),
+			}
+		}
+	}
+}
+
+pub type HmacSha256 = Hmac<Sha256>;
+
+pub fn hmac_sha256(key: &[u8], msg: &[u8]) -> Vec<u8> {
+	// any key length is fine for hmac
+	let mut mac = HmacSha256::new_from_slice(key).unwrap();
+	mac.update(msg);
+	mac.finalize().into_bytes().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	struct Fixed(PaymentConfig);
+
+	impl PaymentProvider for Fixed {
+		fn name(&self) -> &'static str {
+			"fixed"
+		}
+		fn config(&self) -> &PaymentConfig {
+			&self.0
+		}
+		fn verify(&self, _headers: &HeaderMap, _payload: &str) -> Result<(), PaymentErr> {
+			Ok(())
+		}
+		fn parse(&self, _payload: &str) -> Result<PaymentEvent, PaymentErr> {
+			Err(PaymentErr::Unsupported)
+		}
+	}
+
+	#[test]
+	fn coins_at_rates() {
+		let provider = Fixed(PaymentConfig {
+			webhook_secret: String::new(),
+			treasury: ComnAddr::new("≈6D").unwrap(),
+			comment: String::new(),
+			rates: [("usd".to_string(), 100), ("eur".to_string(), 0), ("gbp".to_string(), -100)].into(),
+		});
+		assert_eq!(provider.to_coins("USD", 1050), Some(10));
+		// a misconfigured rate doesn't panic or credit anything
+		assert_eq!(provider.to_coins("eur", 1000), None);
+		assert_eq!(provider.to_coins("gbp", -1000), None);
+		assert_eq!(provider.to_coins("jpy", 1000), None);
+	}
+
+	#[test]
+	fn hmac_rfc4231() {
+		// test case 2 of RFC 4231
+		assert_eq!(
+			hex::encode(hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+			"5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+		);
+		// test case 6, key longer than the block
+		assert_eq!(
+			hex::encode(hmac_sha256(&[0xaa; 131], b"Test Using Larger Than Block-Size Key - Hash Key First")),
+			"60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+		);
+	}
+}
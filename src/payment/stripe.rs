@@ -0,0 +1,181 @@
+use ::stripe::{
+	CheckoutSession, CheckoutSessionMode, CheckoutSessionPaymentStatus, Client,
+	CreateCheckoutSession, CreateCheckoutSessionLineItems, CreateCheckoutSessionLineItemsPriceData,
+	CreateCheckoutSessionLineItemsPriceDataProductData, Currency, EventObject,
+	EventType, Metadata, Webhook, WebhookEvent,
+};
+use salvo::http::HeaderMap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use crate::{
+	comn_addr::ComnAddr,
+	db::{CheckoutConfig, PaymentConfig},
+	payment::{PaymentErr, PaymentEvent, PaymentProvider},
+	update::top_up::Checkout,
+};
+
+// STRIPE_API_BASE when it isn't set
+const API_BASE: &str = "https://api.stripe.com/";
+
+// STRIPE_SECRET_KEY, or the config's, None while neither is set
+fn secret_key(checkout: &CheckoutConfig) -> Option<String> {
+	std::env::var("STRIPE_SECRET_KEY")
+		.ok()
+		.or_else(|| Some(checkout.secret_key.clone()))
+		.filter(|key| !key.is_empty())
+}
+
+// STRIPE_WEBHOOK_SECRET, or the config's, None while neither is set
+fn webhook_secret(conf: &PaymentConfig) -> Option<String> {
+	std::env::var("STRIPE_WEBHOOK_SECRET")
+		.ok()
+		.or_else(|| Some(conf.webhook_secret.clone()))
+		.filter(|secret| !secret.is_empty())
+}
+
+/// Coins bought through Stripe Checkout.
+///
+/// The payer addr is the `addr` metadata of sessions made by `checkout`, or the
+/// `addr` custom field of the manually configured checkout page.
+pub struct Stripe {
+	pub conf: PaymentConfig,
+}
+
+impl Stripe {
+	/// Creates a checkout session paying `amount` coins to `addr`.
+	pub async fn checkout(
+		&self,
+		checkout: &CheckoutConfig,
+		addr: &ComnAddr,
+		amount: i64,
+	) -> Result<Checkout, PaymentErr> {
+		let currency = checkout.currency.to_lowercase();
+		let rate = *self.conf.rates.get(&currency).filter(|rate| **rate > 0).ok_or(PaymentErr::BadData)?;
+		if amount <= 0 || amount > checkout.max_amount {
+			return Err(PaymentErr::BadData);
+		}
+		let secret_key = secret_key(checkout).ok_or(PaymentErr::Unavailable)?;
+		let client = Client::from_url(
+			std::env::var("STRIPE_API_BASE").unwrap_or(API_BASE.to_string()).as_str(),
+			secret_key.as_str(),
+		);
+		let reference = addr.to_uuid();
+		let mut metadata = Metadata::new();
+		metadata.insert("addr".to_string(), addr.to_string());
+
+		let mut params = CreateCheckoutSession::new(&checkout.cancel_url, &checkout.success_url);
+		params.mode = Some(CheckoutSessionMode::Payment);
+		params.client_reference_id = Some(&reference);
+		params.metadata = Some(metadata);
+		params.line_items = Some(vec![CreateCheckoutSessionLineItems {
+			price_data: Some(CreateCheckoutSessionLineItemsPriceData {
+				currency: currency.parse::<Currency>().map_err(|_| PaymentErr::BadData)?,
+				product_data: Some(CreateCheckoutSessionLineItemsPriceDataProductData {
+					name: checkout.product_name.clone(),
+					..Default::default()
+				}),
+				unit_amount: Some(rate),
+				..Default::default()
+			}),
+			quantity: Some(amount as u64),
+			..Default::default()
+		}]);
+		let session = CheckoutSession::create(&client, params)
+			.await
+			.map_err(|_| PaymentErr::Unavailable)?;
+		Ok(Checkout::add(self.name(), session.id.as_str(), addr, amount, session.url).await)
+	}
+}
+
+impl PaymentProvider for Stripe {
+	fn name(&self) -> &'static str {
+		"stripe"
+	}
+
+	fn config(&self) -> &PaymentConfig {
+		&self.conf
+	}
+
+	fn verify(&self, headers: &HeaderMap, payload: &str) -> Result<(), PaymentErr> {
+		let signature = headers
+			.get("stripe-signature")
+			.and_then(|h| h.to_str().ok())
+			.ok_or(PaymentErr::BadSignature)?;
+		let secret = webhook_secret(&self.conf).ok_or(PaymentErr::BadSignature)?;
+		Webhook::construct_event(payload, signature, &secret)
+			.map(|_| ())
+			.map_err(|_| PaymentErr::BadSignature)
+	}
+
+	fn parse(&self, payload: &str) -> Result<PaymentEvent, PaymentErr> {
+		let event = serde_json::from_str::<WebhookEvent>(payload).map_err(|_| PaymentErr::BadData)?;
+		match (event.event_type, event.data.object) {
+			(EventType::CheckoutSessionCompleted, EventObject::CheckoutSession(session))
+			| (EventType::CheckoutSessionAsyncPaymentSucceeded, EventObject::CheckoutSession(session)) => {
+				// sessions paid with delayed methods complete unpaid, they're
+				// fulfilled once async_payment_succeeded comes
+				if session.payment_status != CheckoutSessionPaymentStatus::Paid {
+					return Err(PaymentErr::Unsupported);
+				}
+				Ok(PaymentEvent::Paid {
+					payment_id: session.id.to_string(),
+					charge_ref: session.payment_intent.map(|p| p.id().to_string()),
+					addr: receiver_addr(payload).ok_or(PaymentErr::BadData)?,
+					currency: session.currency.ok_or(PaymentErr::BadData)?.to_string(),
+					// what was paid, after discounts and with taxes
+					amount: session.amount_total.unwrap_or(0),
+				})
+			}
+			(EventType::CheckoutSessionAsyncPaymentFailed, EventObject::CheckoutSession(session)) => {
+				Ok(PaymentEvent::Failed { payment_ref: session.id.to_string() })
+			}
+			(EventType::ChargeRefunded, EventObject::Charge(charge)) => {
+				Ok(PaymentEvent::Refunded {
+					payment_ref: charge.payment_intent.ok_or(PaymentErr::BadData)?.id().to_string(),
+					refund_id: format!("{}:{}", charge.id, charge.amount_refunded),
+					currency: charge.currency.to_string(),
+					total: charge.amount_refunded,
+				})
+			}
+			_ => Err(PaymentErr::Unsupported),
+		}
+	}
+}
+
+fn receiver_addr(payload: &str) -> Option<ComnAddr> {
+    let dd = serde_json::from_str::<CustomField>(payload).ok()?;
+    if let Some(addr) = dd.data.object.metadata.get("addr") {
+        return ComnAddr::new(addr).ok();
+    }
+    let input = dd.data.object.custom_fields.iter().find(|&x| x.key == "addr".to_string())?;
+    let mut receiver_addr = input.text.value.clone().trim().to_string();
+    if !receiver_addr.starts_with('≈') {
+        receiver_addr = "≈".to_owned()+&receiver_addr;
+    }
+    ComnAddr::new(&receiver_addr).ok()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CustomField {
+    pub data: DataL2,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DataL2 {
+    pub object: ObjectL3,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ObjectL3 {
+    #[serde(default)]
+    pub custom_fields: Vec<CustomFieldsL4>,
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CustomFieldsL4 {
+    pub key: String,
+    pub text: TextL5,
+}
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TextL5 {
+    pub value: String,
+}
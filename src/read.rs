@@ -1,3 +1,5 @@
 pub mod crates;
 pub mod addr;
-pub mod crate_item;
\ No newline at end of file
+pub mod crate_item;
+pub mod json_query;
+pub mod search;
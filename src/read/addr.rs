@@ -4,6 +4,7 @@ use sqlx::types::{Uuid};
 use sqlx::FromRow;
 use crate::{db};
 use crate::ComnAddr;
+use crate::eth::{eth_addr, to_checksum};
 use chrono::{DateTime, Utc};
 use secp256k1::PublicKey;
 
@@ -14,6 +15,7 @@ pub struct Addr {
 	pub name: Option<String>,
 	pub comment: Option<String>,
 	pub created: DateTime<Utc>,
+	pub pub_keys: Vec<Vec<u8>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -21,6 +23,7 @@ pub struct AddrRes {
 	pub addr: ComnAddr,
 	pub name: Option<String>,
 	pub created: DateTime<Utc>,
+	pub eth_addrs: Vec<String>,
 }
 
 impl From<Addr> for AddrRes {
@@ -28,7 +31,12 @@ impl From<Addr> for AddrRes {
     Self {
       addr: ComnAddr::from_uuid(&a.id.to_string()).unwrap(),
       name: a.name,
-      created: a.created
+      created: a.created,
+      eth_addrs: a.pub_keys
+        .iter()
+        .filter_map(|k| PublicKey::from_slice(k).ok())
+        .map(|k| to_checksum(&eth_addr(&k)))
+        .collect(),
     }
   }
 }
@@ -96,7 +104,11 @@ impl AddrFilter {
 		}
 		let data = sqlx::query_as::<_, Addr>(
 			"
-			SELECT a.id, a.created, a.name, a.comment
+			SELECT a.id, a.created, a.name, a.comment,
+			ARRAY(
+				SELECT k.pub_key FROM addr_key ak JOIN key k ON k.id = ak.key_id
+				WHERE ak.addr_id = a.id
+			) as pub_keys
 			FROM addr a 
 			WHERE 
 			CASE
@@ -131,7 +143,7 @@ impl AddrFilter {
 			.collect::<Vec<[u8; 33]>>();
 		let data = sqlx::query_as::<_, Addr>(
 			"
-			SELECT nt.id, nt.created, nt.name, nt.comment
+			SELECT nt.id, nt.created, nt.name, nt.comment, nt.pub_keys
 			FROM (
 				SELECT a.*, ARRAY_AGG(k.pub_key) as pub_keys
 				FROM addr a
@@ -159,8 +171,41 @@ impl AddrFilter {
 		Ok(())
 	}
 
+	/// first addr registered with the key behind an ethereum address
+	pub async fn from_eth_addr(eth_addr: &[u8; 20]) -> Result<ComnAddr, AddrFilterErr> {
+		let addr_id = sqlx::query_scalar::<_, Uuid>(
+			"
+			SELECT ak.addr_id
+			FROM key k
+			JOIN addr_key ak ON ak.key_id = k.id
+			WHERE k.eth_addr = $1
+			ORDER BY ak.created
+			LIMIT 1
+			",
+		)
+		.bind(eth_addr.to_vec())
+		.fetch_optional(db::db().await)
+		.await
+		.unwrap();
+		match addr_id {
+			Some(id) => Ok(ComnAddr::from_uuid(&id.to_string()).unwrap()),
+			None => Err(AddrFilterErr::NotFound),
+		}
+	}
+
 	// pub fn get_key() {}
 	// pub fn get_keys() {}
 	// pub async fn verify_key() {}
 	// pub fn fetch_key_using_addr() {}
-}
\ No newline at end of file
+}
+
+/// whether the key is one of the keys of the addr
+pub async fn owns_addr(addr: &ComnAddr, pub_key: &PublicKey) -> bool {
+	let mut verify_addr = AddrFilter {
+		name: None,
+		addr: Some(addr.clone()),
+		keys: Some(vec!(*pub_key)),
+		result: None,
+	};
+	verify_addr.init().await.is_ok()
+}
@@ -1,5 +1,7 @@
 use std::{error::Error, fmt};
+use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
+use sqlx::types::Json;
 
 use secp256k1::PublicKey;
 
@@ -7,7 +9,8 @@ use secp256k1::PublicKey;
 use crate::{
 	db, AccessType, SpecialAddr,
 	ComnAddr, CrateItem,
-	CrateItemRes
+	CrateItemRes,
+	compression::unpack_item,
 };
 use super::crates::{CrateFilter};
 
@@ -42,6 +45,9 @@ pub struct CrateItemFilter {
 	pub crate_id: String,
 	pub per_page: u16,
 	pub page_no: u16,
+	// only items with all of the tags and metadata
+	pub tags: Vec<String>,
+	pub metadata: HashMap<String, String>,
 	pub result: Option<Vec<CrateItem>>
 }
 
@@ -98,7 +104,7 @@ impl CrateItemFilter {
 	async fn fetch_using_key(&mut self) -> Result<(), CrateItemErr> {
 		let mut tx = db::db().await.begin().await.unwrap();
 		let offset = self.page_no * self.per_page;
-		let crate_items = sqlx::query_as::<_, CrateItem>(
+		let mut crate_items = sqlx::query_as::<_, CrateItem>(
 			"
 			SELECT
 			ci.*, it.media_type, NULL as data_file, s.scope_type as scope,
@@ -107,7 +113,7 @@ impl CrateItemFilter {
 			JOIN item_type it ON it.id = ci.type_id
 			JOIN scope s ON s.id = ci.scope_id
 			JOIN crate c ON ci.crate_id = c.id
-			WHERE c.id = $1::uuid
+			WHERE c.id = $1::uuid AND ci.tags @> $4 AND ci.metadata @> $5
 			ORDER BY ci.created DESC
 			OFFSET $2
 			LIMIT $3
@@ -116,12 +122,19 @@ impl CrateItemFilter {
 		.bind(self.crate_id.clone())
 		.bind(offset as i16)
 		.bind(self.per_page as i16)
+		.bind(&self.tags)
+		.bind(Json(&self.metadata))
 		.fetch_all(&mut *tx)
 		.await
 		.unwrap();
 		if crate_items.len()==0 {
 			return Err(CrateItemErr::NotFound);
 		}
+		for item in crate_items.iter_mut() {
+			if unpack_item(item).await.is_err() {
+				return Err(CrateItemErr::BadData);
+			}
+		}
 		self.result = Some(crate_items);
 		Ok(())
 	}
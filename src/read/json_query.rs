@@ -0,0 +1,262 @@
+use std::{error::Error, fmt};
+use serde::{Deserialize, Serialize};
+use secp256k1::PublicKey;
+use sqlx::types::{Json, Uuid};
+use sqlx::{FromRow, Postgres, QueryBuilder};
+use crate::{
+	db, AccessType, SpecialAddr,
+};
+use super::crates::CrateFilter;
+
+// most items a page of query results has
+const MAX_PER_PAGE: u16 = 100;
+
+#[derive(Debug, PartialEq)]
+pub enum JsonQueryErr {
+	BadQuery(String),
+	CrateNotFound,
+	// the data of encrypted crates isn't in data_json
+	NotQueryable,
+}
+
+impl Error for JsonQueryErr {}
+
+impl fmt::Display for JsonQueryErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonQueryErr::BadQuery(e) => write!(f, "query is bad, {}", e),
+            JsonQueryErr::CrateNotFound => write!(f, "Crate doesn't exist or doesn't have access to it."),
+            JsonQueryErr::NotQueryable => write!(f, "items of encrypted crates can't be queried"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum JsonOp {
+	Eq,
+	Ne,
+	Gt,
+	Gte,
+	Lt,
+	Lte,
+	// json containment, i.e. an array field holding the value or an object field all of its keys
+	Contains,
+	Exists,
+}
+
+/// A condition on the value at `field`, a JSON Pointer like `/app/theme`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsonCondition {
+	pub field: String,
+	pub op: JsonOp,
+	#[serde(default)]
+	pub value: serde_json::Value,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsonSort {
+	pub field: String,
+	#[serde(default)]
+	pub desc: bool,
+}
+
+/// Query over the json items of a crate.
+///
+/// Items match when they satisfy every condition and the `jsonpath` predicate,
+/// i.e. `$.amount > 10`. `fields` projects each item's data to the pointers given.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JsonQueryReq {
+	pub crate_id: String,
+	#[serde(default)]
+	pub path_prefix: Option<String>,
+	#[serde(default)]
+	pub filter: Vec<JsonCondition>,
+	#[serde(default)]
+	pub jsonpath: Option<String>,
+	#[serde(default)]
+	pub sort: Vec<JsonSort>,
+	#[serde(default)]
+	pub fields: Option<Vec<String>>,
+	#[serde(default)]
+	pub page_no: u16,
+	pub per_page: u16,
+}
+
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct JsonQueryHit {
+	pub id: Uuid,
+	pub item_path: String,
+	pub version: i32,
+	pub data: Json<serde_json::Value>,
+}
+
+pub struct JsonQuery {
+	pub pub_key: Option<PublicKey>,
+	pub req: JsonQueryReq,
+	pub result: Option<Vec<JsonQueryHit>>,
+}
+
+// keys of a JSON Pointer, `~1` and `~0` unescaped
+fn pointer_keys(pointer: &str) -> Result<Vec<String>, JsonQueryErr> {
+	if pointer.is_empty() {
+		return Ok(vec![]);
+	}
+	if !pointer.starts_with('/') {
+		return Err(JsonQueryErr::BadQuery(format!("{} is not a JSON Pointer", pointer)));
+	}
+	Ok(pointer[1..].split('/').map(|key| key.replace("~1", "/").replace("~0", "~")).collect())
+}
+
+// the value nested under the keys, so `@>` can use the GIN index for equality
+fn nest(keys: &[String], value: serde_json::Value) -> serde_json::Value {
+	let mut nested = serde_json::Value::Null;
+	insert(&mut nested, keys, value);
+	nested
+}
+
+fn insert(target: &mut serde_json::Value, keys: &[String], value: serde_json::Value) {
+	if let Some((key, rest)) = keys.split_first() {
+		if !target.is_object() {
+			*target = serde_json::Value::Object(serde_json::Map::new());
+		}
+		let entry = target.as_object_mut().unwrap().entry(key.clone()).or_insert(serde_json::Value::Null);
+		insert(entry, rest, value);
+	} else {
+		*target = value;
+	}
+}
+
+fn push_condition(qb: &mut QueryBuilder<'_, Postgres>, condition: &JsonCondition) -> Result<(), JsonQueryErr> {
+	let keys = pointer_keys(&condition.field)?;
+	let range = match condition.op {
+		JsonOp::Gt => Some(">"),
+		JsonOp::Gte => Some(">="),
+		JsonOp::Lt => Some("<"),
+		JsonOp::Lte => Some("<="),
+		_ => None,
+	};
+	match (condition.op, range) {
+		(JsonOp::Eq, _) => {
+			qb.push(" AND ci.data_json @> ").push_bind(Json(nest(&keys, condition.value.clone())));
+		}
+		(JsonOp::Ne, _) => {
+			qb.push(" AND NOT ci.data_json @> ").push_bind(Json(nest(&keys, condition.value.clone())));
+		}
+		(JsonOp::Contains, _) => {
+			let value = match &condition.value {
+				serde_json::Value::Object(_) | serde_json::Value::Array(_) => condition.value.clone(),
+				value => serde_json::Value::Array(vec![value.clone()]),
+			};
+			qb.push(" AND ci.data_json #> ").push_bind(keys).push(" @> ").push_bind(Json(value));
+		}
+		(JsonOp::Exists, _) => {
+			qb.push(" AND ci.data_json #> ").push_bind(keys).push(" IS NOT NULL");
+		}
+		// numbers compare as numbers, strings as text
+		(_, Some(op)) => match &condition.value {
+			serde_json::Value::Number(n) => {
+				// postgres may evaluate ANDed conditions in any order, the cast only
+				// runs on numbers inside the CASE
+				qb.push(" AND CASE WHEN jsonb_typeof(ci.data_json #> ").push_bind(keys.clone()).push(") = 'number'");
+				qb.push(" THEN (ci.data_json #>> ").push_bind(keys).push(")::numeric END ");
+				qb.push(op).push(" ").push_bind(n.to_string()).push("::numeric");
+			}
+			serde_json::Value::String(s) => {
+				qb.push(" AND jsonb_typeof(ci.data_json #> ").push_bind(keys.clone()).push(") = 'string'");
+				qb.push(" AND ci.data_json #>> ").push_bind(keys).push(" ");
+				qb.push(op).push(" ").push_bind(s.clone());
+			}
+			_ => return Err(JsonQueryErr::BadQuery(format!("{} compares numbers or strings", condition.field))),
+		},
+		_ => unreachable!(),
+	}
+	Ok(())
+}
+
+/// The item data with only the values at `fields`, nested like they were.
+pub fn project(data: &serde_json::Value, fields: &[String]) -> serde_json::Value {
+	let mut projected = serde_json::Value::Null;
+	for field in fields {
+		if let (Ok(keys), Some(value)) = (pointer_keys(field), data.pointer(field)) {
+			insert(&mut projected, &keys, value.clone());
+		}
+	}
+	projected
+}
+
+impl JsonQuery {
+	pub async fn init(&mut self) -> Result<(), JsonQueryErr> {
+		let mut crate_result = CrateFilter {
+			name: None,
+			addr: Some(vec!(
+				SpecialAddr::Registered.value(),
+				SpecialAddr::Public.value(),
+			)),
+			pub_key: self.pub_key,
+			crate_id: Some(self.req.crate_id.clone()),
+			access_type: vec!(
+				AccessType::Owner,
+				AccessType::Admin,
+				AccessType::Editor,
+				AccessType::Reader
+			),
+			result: None,
+		};
+		if crate_result.init().await.is_err() {
+			return Err(JsonQueryErr::CrateNotFound);
+		}
+		self.fetch().await
+	}
+
+	async fn fetch(&mut self) -> Result<(), JsonQueryErr> {
+		let crate_id = Uuid::parse_str(&self.req.crate_id).map_err(|_| JsonQueryErr::CrateNotFound)?;
+		let mut conn = db::db().await.acquire().await.unwrap();
+		let encrypted = sqlx::query_scalar::<_, bool>(
+			"SELECT c.e2e OR EXISTS(SELECT 1 FROM crate_key ck WHERE ck.crate_id = c.id) FROM crate c WHERE c.id = $1"
+		)
+		.bind(crate_id)
+		.fetch_one(&mut *conn)
+		.await
+		.unwrap();
+		if encrypted {
+			return Err(JsonQueryErr::NotQueryable);
+		}
+
+		let mut qb = QueryBuilder::<Postgres>::new(
+			"SELECT ci.id, ci.item_path, ci.version, ci.data_json AS data FROM crate_item ci
+			WHERE ci.item_storage = 'Json' AND ci.data_json IS NOT NULL AND ci.crate_id = "
+		);
+		qb.push_bind(crate_id);
+		if let Some(path_prefix) = &self.req.path_prefix {
+			qb.push(" AND starts_with(ci.item_path, ").push_bind(path_prefix.clone()).push(")");
+		}
+		for condition in &self.req.filter {
+			push_condition(&mut qb, condition)?;
+		}
+		if let Some(jsonpath) = &self.req.jsonpath {
+			qb.push(" AND ci.data_json @@ ").push_bind(jsonpath.clone()).push("::jsonpath");
+		}
+		qb.push(" ORDER BY ");
+		for sort in &self.req.sort {
+			qb.push("ci.data_json #> ").push_bind(pointer_keys(&sort.field)?);
+			qb.push(if sort.desc { " DESC NULLS LAST, " } else { " ASC NULLS LAST, " });
+		}
+		let per_page = self.req.per_page.min(MAX_PER_PAGE);
+		qb.push("ci.item_path OFFSET ").push_bind(self.req.page_no as i64 * per_page as i64);
+		qb.push(" LIMIT ").push_bind(per_page as i64);
+
+		// a jsonpath that doesn't parse only fails once it's run
+		let mut hits = qb.build_query_as::<JsonQueryHit>()
+			.fetch_all(&mut *conn)
+			.await
+			.map_err(|e| JsonQueryErr::BadQuery(e.to_string()))?;
+		if let Some(fields) = &self.req.fields {
+			for hit in hits.iter_mut() {
+				hit.data = Json(project(&hit.data, fields));
+			}
+		}
+		self.result = Some(hits);
+		Ok(())
+	}
+}
@@ -0,0 +1,174 @@
+use std::{error::Error, fmt};
+use serde::{Deserialize, Serialize};
+use secp256k1::PublicKey;
+use sqlx::types::Uuid;
+use sqlx::FromRow;
+use crate::{
+	db, AccessType, SpecialAddr,
+	blob::read_item_blob,
+	compression::unpack,
+};
+use super::crates::CrateFilter;
+
+// most hits a page of search results has
+const MAX_PER_PAGE: u16 = 50;
+
+const HEADLINE_OPTIONS: &str = "MaxFragments=2, MaxWords=20, MinWords=5, FragmentDelimiter=\" … \"";
+
+#[derive(Debug, PartialEq)]
+pub enum SearchErr {
+	BadQuery,
+	// no crate the key can read
+	NotFound,
+}
+
+impl Error for SearchErr {}
+
+impl fmt::Display for SearchErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SearchErr::BadQuery => write!(f, "search words are missing"),
+            SearchErr::NotFound => write!(f, "no crate to search"),
+        }
+    }
+}
+
+/// Words to look for in the item paths and content of readable crates, in
+/// web search syntax, i.e. `invoice -draft "due date"`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SearchReq {
+	pub q: String,
+	// only items of the crate
+	pub crate_id: Option<String>,
+	#[serde(default)]
+	pub page_no: u16,
+	pub per_page: Option<u16>,
+}
+
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SearchHit {
+	pub id: Uuid,
+	pub crate_id: Uuid,
+	pub item_path: String,
+	pub media_type: String,
+	pub rank: f32,
+	// matching words in context, marked with <b></b>
+	pub snippet: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct SearchRes {
+	pub hits: Vec<SearchHit>,
+	pub total: i64,
+	pub page_no: u16,
+	pub per_page: u16,
+}
+
+#[derive(FromRow)]
+struct SearchRow {
+	#[sqlx(flatten)]
+	hit: SearchHit,
+	// compressed text, its snippet is made once it's decompressed
+	packed_text: Option<Vec<u8>>,
+	compression: Option<String>,
+	// text kept in a file, its snippet is made from the file
+	file_text: bool,
+	total: i64,
+}
+
+pub struct ItemSearch {
+	pub pub_key: Option<PublicKey>,
+	pub req: SearchReq,
+	pub result: Option<SearchRes>,
+}
+
+impl ItemSearch {
+	pub async fn init(&mut self) -> Result<(), SearchErr> {
+		if self.req.q.trim().is_empty() {
+			return Err(SearchErr::BadQuery);
+		}
+		let mut crate_result = CrateFilter {
+			name: None,
+			addr: Some(vec!(
+				SpecialAddr::Registered.value(),
+				SpecialAddr::Public.value(),
+			)),
+			pub_key: self.pub_key,
+			crate_id: self.req.crate_id.clone(),
+			access_type: vec!(
+				AccessType::Owner,
+				AccessType::Admin,
+				AccessType::Editor,
+				AccessType::Reader
+			),
+			result: None,
+		};
+		if crate_result.init().await.is_err() {
+			return Err(SearchErr::NotFound);
+		}
+		let crate_ids: Vec<Uuid> = crate_result.result.unwrap().into_iter().map(|c| c.id).collect();
+		self.fetch(crate_ids).await
+	}
+
+	// encrypted items aren't indexed, their data_text and data_json are empty
+	async fn fetch(&mut self, crate_ids: Vec<Uuid>) -> Result<(), SearchErr> {
+		let per_page = self.req.per_page.unwrap_or(20).min(MAX_PER_PAGE);
+		let mut conn = db::db().await.acquire().await.unwrap();
+		let rows = sqlx::query_as::<_, SearchRow>(
+			"
+			SELECT ci.id, ci.crate_id, ci.item_path, it.media_type,
+			ts_rank(ci.search_vector, q) AS rank,
+			CASE
+				WHEN ci.data_text IS NOT NULL THEN ts_headline('english', ci.data_text, q, $5)
+				WHEN ci.data_json IS NOT NULL THEN ts_headline('english', array_to_string(ARRAY(
+					SELECT v #>> '{}' FROM jsonb_path_query(ci.data_json, 'strict $.** ? (@.type() == \"string\")') v
+				), ' '), q, $5)
+			END AS snippet,
+			CASE WHEN ci.text_vector IS NOT NULL THEN ci.data_bytes END AS packed_text,
+			ci.compression,
+			ci.text_vector IS NOT NULL AND ci.item_storage = 'File' AS file_text,
+			COUNT(*) OVER () AS total
+			FROM crate_item ci
+			JOIN item_type it ON it.id = ci.type_id,
+			websearch_to_tsquery('english', $1) q
+			WHERE ci.crate_id = ANY($2) AND ci.search_vector @@ q
+			ORDER BY rank DESC, ci.updated DESC
+			OFFSET $3
+			LIMIT $4
+			"
+		)
+		.bind(&self.req.q)
+		.bind(crate_ids)
+		.bind(self.req.page_no as i64 * per_page as i64)
+		.bind(per_page as i64)
+		.bind(HEADLINE_OPTIONS)
+		.fetch_all(&mut *conn)
+		.await
+		.unwrap();
+
+		let total = rows.first().map_or(0, |row| row.total);
+		let mut hits = vec![];
+		for row in rows {
+			let mut hit = row.hit;
+			let text = match row.packed_text {
+				Some(packed) => unpack(row.compression.as_deref(), packed).ok(),
+				None if row.file_text => read_item_blob(hit.id).await,
+				None => None,
+			};
+			if let Some(text) = text {
+				hit.snippet = sqlx::query_scalar::<_, String>(
+					"SELECT ts_headline('english', $1, websearch_to_tsquery('english', $2), $3)"
+				)
+				.bind(String::from_utf8_lossy(&text).to_string())
+				.bind(&self.req.q)
+				.bind(HEADLINE_OPTIONS)
+				.fetch_one(&mut *conn)
+				.await
+				.ok();
+			}
+			hits.push(hit);
+		}
+		self.result = Some(SearchRes { hits, total, page_no: self.req.page_no, per_page });
+		Ok(())
+	}
+}
@@ -0,0 +1,111 @@
+use std::{error::Error, fmt};
+use salvo::prelude::async_trait;
+use sha2::{Digest, Sha256};
+use tokio::sync::OnceCell;
+use crate::{compression::unpack, db::{db, get_config, Config}};
+
+pub mod local;
+pub mod s3;
+
+use local::LocalStore;
+use s3::S3Store;
+
+#[derive(Debug, PartialEq)]
+pub enum StoreErr {
+	NotFound,
+	BadKey,
+	Failed,
+	Unavailable,
+}
+
+impl Error for StoreErr {}
+
+impl fmt::Display for StoreErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreErr::NotFound => write!(f, "chunk is not in the store"),
+            StoreErr::BadKey => write!(f, "chunk key is not a plain file name"),
+            StoreErr::Failed => write!(f, "chunk store failed"),
+            StoreErr::Unavailable => write!(f, "chunk store is unavailable"),
+        }
+    }
+}
+
+/// Where chunk data lives, keyed by a plain name like the hex of a blob's hash.
+///
+/// `put` is all or nothing, a key is either missing or has the whole data.
+/// Deleting a missing key is fine.
+#[async_trait]
+pub trait ChunkStore: Send + Sync {
+	fn name(&self) -> &'static str;
+
+	async fn put(&self, key: &str, data: &[u8]) -> Result<(), StoreErr>;
+
+	async fn get(&self, key: &str) -> Result<Vec<u8>, StoreErr>;
+
+	async fn exists(&self, key: &str) -> Result<bool, StoreErr>;
+
+	async fn delete(&self, key: &str) -> Result<(), StoreErr>;
+}
+
+// keys end up in paths and urls, only names without separators are allowed
+pub fn check_key(key: &str) -> Result<(), StoreErr> {
+	if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+		return Err(StoreErr::BadKey);
+	}
+	Ok(())
+}
+
+pub fn open_store(conf: &Config, backend: &str) -> Option<Box<dyn ChunkStore>> {
+	match backend {
+		"local" => Some(Box::new(LocalStore::new(&conf.chunks_location))),
+		"s3" => Some(Box::new(S3Store::new(conf.store.s3.clone()))),
+		_ => None,
+	}
+}
+
+static STORE: OnceCell<Box<dyn ChunkStore>> = OnceCell::const_new();
+
+// the backend in config
+pub async fn chunk_store() -> &'static dyn ChunkStore {
+	STORE
+		.get_or_init(|| async {
+			let conf = get_config().await;
+			match open_store(conf, &conf.store.backend) {
+				Some(store) => store,
+				None => panic!("unknown chunk store {}", conf.store.backend),
+			}
+		})
+		.await
+		.as_ref()
+}
+
+/// Copies every blob from one backend to another, checking each against its hash.
+///
+/// Blobs already in `to` are skipped, so an interrupted migration can be run
+/// again. Nothing is removed from `from`, the backend in config is switched
+/// once this returns. Returns the number of blobs copied.
+pub async fn migrate(from: &dyn ChunkStore, to: &dyn ChunkStore) -> Result<u64, StoreErr> {
+	let blobs = sqlx::query_as::<_, (Vec<u8>, Option<String>)>(
+		"SELECT sha2_hash, compression FROM blob ORDER BY created"
+	)
+	.fetch_all(db().await)
+	.await
+	.unwrap();
+	let mut copied = 0;
+	for (sha2_hash, compression) in blobs {
+		let key = hex::encode(&sha2_hash);
+		if to.exists(&key).await? {
+			continue;
+		}
+		let data = from.get(&key).await?;
+		let plain = unpack(compression.as_deref(), data.clone()).map_err(|_| StoreErr::Failed)?;
+		if Sha256::digest(&plain).as_slice() != sha2_hash.as_slice() {
+			println!("blob {} in {} doesn't match its hash", key, from.name());
+			return Err(StoreErr::Failed);
+		}
+		to.put(&key, &data).await?;
+		copied += 1;
+	}
+	Ok(copied)
+}
@@ -0,0 +1,92 @@
+use salvo::prelude::async_trait;
+use sqlx::types::Uuid;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+use crate::store::{check_key, ChunkStore, StoreErr};
+
+/// Chunks as files under `root`, sharded by the first two byte pairs of the key
+/// so no directory gets too big, i.e. `ab12…` is `{root}/ab/12/ab12…`.
+///
+/// Files are written next to where they go, synced and renamed into place,
+/// so a crash never leaves a partly written chunk under its key.
+pub struct LocalStore {
+	pub root: PathBuf,
+}
+
+impl LocalStore {
+	pub fn new(root: &str) -> Self {
+		Self { root: PathBuf::from(root) }
+	}
+
+	pub fn path(&self, key: &str) -> PathBuf {
+		if key.len() < 4 {
+			return self.root.join(key);
+		}
+		self.root.join(&key[..2]).join(&key[2..4]).join(key)
+	}
+
+	async fn write_synced(path: &Path, data: &[u8]) -> std::io::Result<()> {
+		let mut file = File::create(path).await?;
+		file.write_all(data).await?;
+		file.sync_all().await
+	}
+}
+
+fn io_err(e: std::io::Error) -> StoreErr {
+	if e.kind() == ErrorKind::NotFound {
+		StoreErr::NotFound
+	} else {
+		println!("chunk store io {}", e);
+		StoreErr::Failed
+	}
+}
+
+#[async_trait]
+impl ChunkStore for LocalStore {
+	fn name(&self) -> &'static str {
+		"local"
+	}
+
+	async fn put(&self, key: &str, data: &[u8]) -> Result<(), StoreErr> {
+		check_key(key)?;
+		let path = self.path(key);
+		let dir = path.parent().unwrap();
+		fs::create_dir_all(dir).await.map_err(io_err)?;
+		let tmp = dir.join(format!(".{}.{}.tmp", key, Uuid::new_v4().simple()));
+		if let Err(e) = Self::write_synced(&tmp, data).await {
+			let _ = fs::remove_file(&tmp).await;
+			return Err(io_err(e));
+		}
+		if let Err(e) = fs::rename(&tmp, &path).await {
+			let _ = fs::remove_file(&tmp).await;
+			return Err(io_err(e));
+		}
+		// the rename itself is only durable once the directory is synced
+		File::open(dir).await.map_err(io_err)?.sync_all().await.map_err(io_err)
+	}
+
+	async fn get(&self, key: &str) -> Result<Vec<u8>, StoreErr> {
+		check_key(key)?;
+		fs::read(self.path(key)).await.map_err(io_err)
+	}
+
+	async fn exists(&self, key: &str) -> Result<bool, StoreErr> {
+		check_key(key)?;
+		match fs::metadata(self.path(key)).await {
+			Ok(_) => Ok(true),
+			Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+			Err(e) => Err(io_err(e)),
+		}
+	}
+
+	async fn delete(&self, key: &str) -> Result<(), StoreErr> {
+		check_key(key)?;
+		match fs::remove_file(self.path(key)).await {
+			Ok(_) => Ok(()),
+			Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+			Err(e) => Err(io_err(e)),
+		}
+	}
+}
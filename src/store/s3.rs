@@ -0,0 +1,147 @@
+use chrono::{DateTime, Utc};
+use hyper::{body, client::HttpConnector, Body, Client, Method, Request, Response, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use salvo::prelude::async_trait;
+use sha2::{Digest, Sha256};
+use crate::db::S3Config;
+use crate::payment::hmac_sha256;
+use crate::store::{check_key, ChunkStore, StoreErr};
+
+/// Chunks as objects in a bucket of an S3 compatible service, like MinIO.
+///
+/// Requests are signed with AWS signature version 4 and address the bucket
+/// path style, which every S3 compatible service takes.
+pub struct S3Store {
+	pub conf: S3Config,
+	client: Client<HttpsConnector<HttpConnector>>,
+}
+
+// key for signing requests on a day, see the "Deriving the signing key" section of the sigv4 docs
+pub fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+	let k_date = hmac_sha256(format!("AWS4{}", secret_key).as_bytes(), date.as_bytes());
+	let k_region = hmac_sha256(&k_date, region.as_bytes());
+	let k_service = hmac_sha256(&k_region, service.as_bytes());
+	hmac_sha256(&k_service, b"aws4_request")
+}
+
+impl S3Store {
+	pub fn new(conf: S3Config) -> Self {
+		Self { conf, client: Client::builder().build(HttpsConnector::new()) }
+	}
+
+	fn uri(&self, key: &str) -> Result<Uri, StoreErr> {
+		format!("{}/{}/{}", self.conf.endpoint.trim_end_matches('/'), self.conf.bucket, key)
+			.parse::<Uri>()
+			.map_err(|_| StoreErr::Unavailable)
+	}
+
+	/// `Authorization` header value of a request signing host,
+	/// x-amz-content-sha256 and x-amz-date.
+	pub fn authorization(&self, method: &str, path: &str, host: &str, payload_hash: &str, now: DateTime<Utc>) -> String {
+		let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+		let date = now.format("%Y%m%d").to_string();
+		let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+		let canonical_request = format!(
+			"{}\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\n{}\n{}",
+			method, path, host, payload_hash, amz_date, signed_headers, payload_hash
+		);
+		let scope = format!("{}/{}/s3/aws4_request", date, self.conf.region);
+		let string_to_sign = format!(
+			"AWS4-HMAC-SHA256\n{}\n{}\n{}",
+			amz_date, scope, hex::encode(Sha256::digest(canonical_request.as_bytes()))
+		);
+		let signature = hmac_sha256(
+			&signing_key(&self.conf.secret_key, &date, &self.conf.region, "s3"),
+			string_to_sign.as_bytes(),
+		);
+		format!(
+			"AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+			self.conf.access_key, scope, signed_headers, hex::encode(signature)
+		)
+	}
+
+	async fn send(&self, method: Method, key: &str, data: Option<&[u8]>) -> Result<Response<Body>, StoreErr> {
+		check_key(key)?;
+		let uri = self.uri(key)?;
+		let now = Utc::now();
+		let payload = data.unwrap_or(&[]).to_vec();
+		let payload_hash = hex::encode(Sha256::digest(&payload));
+		let host = match uri.port_u16() {
+			Some(port) => format!("{}:{}", uri.host().unwrap_or(""), port),
+			None => uri.host().unwrap_or("").to_string(),
+		};
+		let authorization = self.authorization(method.as_str(), uri.path(), &host, &payload_hash, now);
+		let req = Request::builder()
+			.method(method.clone())
+			.uri(uri.clone())
+			.header("authorization", authorization)
+			.header("x-amz-content-sha256", payload_hash)
+			.header("x-amz-date", now.format("%Y%m%dT%H%M%SZ").to_string())
+			.body(Body::from(payload))
+			.map_err(|_| StoreErr::BadKey)?;
+		self.client.request(req).await.map_err(|e| {
+			println!("s3 {} {} {}", method, key, e);
+			StoreErr::Unavailable
+		})
+	}
+}
+
+fn status_err(status: StatusCode) -> StoreErr {
+	match status {
+		StatusCode::NOT_FOUND => StoreErr::NotFound,
+		s if s.is_server_error() => StoreErr::Unavailable,
+		_ => StoreErr::Failed,
+	}
+}
+
+#[async_trait]
+impl ChunkStore for S3Store {
+	fn name(&self) -> &'static str {
+		"s3"
+	}
+
+	async fn put(&self, key: &str, data: &[u8]) -> Result<(), StoreErr> {
+		let res = self.send(Method::PUT, key, Some(data)).await?;
+		if !res.status().is_success() {
+			return Err(status_err(res.status()));
+		}
+		Ok(())
+	}
+
+	async fn get(&self, key: &str) -> Result<Vec<u8>, StoreErr> {
+		let res = self.send(Method::GET, key, None).await?;
+		if !res.status().is_success() {
+			return Err(status_err(res.status()));
+		}
+		body::to_bytes(res.into_body()).await.map(|b| b.to_vec()).map_err(|_| StoreErr::Unavailable)
+	}
+
+	async fn exists(&self, key: &str) -> Result<bool, StoreErr> {
+		let res = self.send(Method::HEAD, key, None).await?;
+		match res.status() {
+			s if s.is_success() => Ok(true),
+			StatusCode::NOT_FOUND => Ok(false),
+			s => Err(status_err(s)),
+		}
+	}
+
+	async fn delete(&self, key: &str) -> Result<(), StoreErr> {
+		let res = self.send(Method::DELETE, key, None).await?;
+		match res.status() {
+			s if s.is_success() => Ok(()),
+			StatusCode::NOT_FOUND => Ok(()),
+			s => Err(status_err(s)),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sigv4_signing_key() {
+		let key = signing_key("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY", "20120215", "us-east-1", "iam");
+		assert_eq!(hex::encode(key), "f4780e2d9f65fa895f9c67b32ce1baf0b0d8a43505a000a1a9e090d414db404d");
+	}
+}
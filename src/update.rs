@@ -1 +1,10 @@
-pub mod coin;
\ No newline at end of file
+pub mod coin;
+pub mod schedule;
+pub mod escrow;
+pub mod top_up;
+pub mod quota;
+pub mod item_type;
+pub mod json_schema;
+pub mod json_patch;
+pub mod item_metadata;
+pub mod crates;
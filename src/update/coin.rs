@@ -3,9 +3,9 @@ use std::{error::Error, fmt};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use sqlx::types::{Uuid, Json};
-use sqlx::FromRow;
+use sqlx::{FromRow, PgConnection};
 use crate::{
-	AccessType, AddCrateItemReq, write_file, CrateItemStorage,
+	AccessType, AddCrateItemReq, CrateItemStorage,
 	AddCrateReq, _add_crate, CrateAccess, SpecialAddr,
 	comn_addr::ComnAddr, db::{db, get_config},
 	read::crates::{CrateOwnerFilter, CrateFilterErr, CrateFilter},
@@ -38,11 +38,38 @@ pub struct TransactionQuery {
 	pub receiver: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Approve {
+	pub amount: u64,
+	pub owner: ComnAddr,
+	pub spender: ComnAddr,
+	pub nonce: String,
+}
+
+// spender moves coins out of owner's balance within the approved allowance
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TransferFrom {
+	pub amount: u64,
+	pub owner: ComnAddr,
+	pub spender: ComnAddr,
+	pub receiver: ComnAddr,
+	pub comment: Option<String>,
+	pub nonce: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Allowance {
+	pub owner: ComnAddr,
+	pub spender: ComnAddr,
+	pub amount: i64,
+}
+
 #[derive(Debug)]
 pub enum TransactionErr {
 	AlreadyReported,
 	BadData,
 	LowAmount,
+	LowAllowance,
 }
 
 impl Error for TransactionErr {}
@@ -52,7 +79,8 @@ impl fmt::Display for TransactionErr {
         match self {
             TransactionErr::BadData => write!(f, "addrs are not right."),
             TransactionErr::LowAmount => write!(f, "Amount to transfer is more than balance."),
-            TransactionErr::AlreadyReported => write!(f, "A transaction has already happended with the nonce.")
+            TransactionErr::AlreadyReported => write!(f, "A transaction has already happended with the nonce."),
+            TransactionErr::LowAllowance => write!(f, "Amount to transfer is more than allowance."),
         }
     }
 }
@@ -60,166 +88,360 @@ impl fmt::Display for TransactionErr {
 impl Transaction {
 	pub async fn transfer_coins(&self) -> Result<TransactionQuery, TransactionErr> {
 		let mut tx = db().await.begin().await.unwrap();
-		if let Ok(_nonce) = sqlx::query_scalar::<_, String>(
-			"
-			SELECT ci.data_json ->> $1::text
-			FROM crate_item ci
-			WHERE 
-				ci.id = '000000000000000000000000000011cc'::uuid
-			"
-		)
-		.bind(self.nonce.clone())
-		.fetch_one(&mut *tx)
-		.await {
+		let result = self.transfer_coins_in(&mut tx).await?;
+		tx.commit().await.unwrap();
+		self.record_history(&result).await;
+		Ok(result)
+	}
+
+	/// Moves the coins in the caller's transaction, they only move if it commits.
+	///
+	/// The balances are locked until then, callers locking the allowances too
+	/// lock those first. The history isn't recorded, see `record_history`.
+	pub async fn transfer_coins_in(&self, conn: &mut PgConnection) -> Result<TransactionQuery, TransactionErr> {
+		// above i64::MAX it would turn negative and move coins the other way
+		let amount = i64::try_from(self.amount).map_err(|_| TransactionErr::BadData)?;
+		// locked before the nonce is checked, so a nonce is only spent once
+		sqlx::query("SELECT id FROM crate_item WHERE id = '000000000000000000000000000001cc'::uuid FOR UPDATE")
+			.execute(&mut *conn)
+			.await
+			.unwrap();
+		if nonce_reported(&mut *conn, &self.nonce).await {
 			return Err(TransactionErr::AlreadyReported);
-		} else {
-			let result = sqlx::query_as::<_, TransactionQuery>(
-				"	
-				WITH sender AS (
-					SELECT $1::text
-				), receiver AS (
+		}
+		let result = sqlx::query_as::<_, TransactionQuery>(
+			"	
+			WITH sender AS (
+				SELECT $1::text
+			), receiver AS (
+				SELECT (
+					SELECT a.id
+					from addr a
+					WHERE a.id = $2::uuid
+				)::text
+			), new_sender_amount AS (
+				SELECT COALESCE((
 					SELECT (
-						SELECT a.id
-						from addr a
-						WHERE a.id = $2::uuid
-					)::text
-				), new_sender_amount AS (
-					SELECT ((
-						SELECT (
-							SELECT ci.data_json
-							from crate_item ci
-							WHERE ci.id = '000000000000000000000000000001cc'::uuid
-						)::jsonb ->> (SELECT * from sender)
-					)::int8) - $3::int8
-				)
-				UPDATE crate_item
-				SET
-					data_json =
-					CASE
-						WHEN (select * from new_sender_amount)>=0
-						THEN
-							jsonb_set(
-								jsonb_set(
-									data_json,
-									ARRAY[(SELECT * from receiver)], 
-									(((COALESCE((SELECT ((
-										data_json
-									)::jsonb ->> (SELECT * from receiver)))::numeric,0) + $3::numeric)::text)::jsonb)
-								),
-								ARRAY[(SELECT * from sender)], 
-								((SELECT * from new_sender_amount)::text)::jsonb
-							)
-						ELSE
-							data_json
-					END
-				WHERE 
-					id = '000000000000000000000000000001cc'::uuid
-				RETURNING (SELECT * from new_sender_amount) as amount, (SELECT * from sender) as sender,
-				 (SELECT * from receiver) as receiver
-				"
+						SELECT ci.data_json
+						from crate_item ci
+						WHERE ci.id = '000000000000000000000000000001cc'::uuid
+					)::jsonb ->> (SELECT * from sender)
+				)::int8, 0) - $3::int8
 			)
-			.bind(self.sender.to_uuid())
-			.bind(self.receiver.to_uuid())
-			.bind(self.amount as i64)
-			.fetch_one(&mut *tx)
-			.await
-			.unwrap();
-			tx.commit().await.unwrap();
-			println!("result {:?}", result);
-			if result.amount<0 {
-				return Err(TransactionErr::LowAmount);
-			} else {
-				let transaction_time = chrono::offset::Utc::now();
-				let mut tx = db().await.begin().await.unwrap();		
-				let _updated_json = sqlx::query_scalar::<_, Uuid>(
-					"
-					UPDATE crate_item
-					SET
-						data_json =
+			UPDATE crate_item
+			SET
+				data_json =
+				CASE
+					WHEN (select * from new_sender_amount)>=0
+					THEN
+						jsonb_set(
 							jsonb_set(
 								data_json,
-								$1::text[], 
-								$2
-							)
-					WHERE 
-						id = '000000000000000000000000000011cc'::uuid
-					RETURNING
-						id
+								ARRAY[(SELECT * from receiver)], 
+								(((COALESCE((SELECT ((
+									data_json
+								)::jsonb ->> (SELECT * from receiver)))::numeric,0) + $3::numeric)::text)::jsonb)
+							),
+							ARRAY[(SELECT * from sender)], 
+							((SELECT * from new_sender_amount)::text)::jsonb
+						)
+					ELSE
+						data_json
+				END
+			WHERE 
+				id = '000000000000000000000000000001cc'::uuid
+			RETURNING (SELECT * from new_sender_amount) as amount, (SELECT * from sender) as sender,
+			 (SELECT * from receiver) as receiver
+			"
+		)
+		.bind(self.sender.to_uuid())
+		.bind(self.receiver.to_uuid())
+		.bind(amount)
+		.fetch_one(&mut *conn)
+		.await
+		.unwrap();
+		if result.amount < 0 {
+			return Err(TransactionErr::LowAmount);
+		}
+		report_nonce(&mut *conn, &self.nonce).await;
+		Ok(result)
+	}
+
+	/// Adds the transfer to the sender's and the receiver's history crates, once
+	/// `transfer_coins_in` committed.
+	pub async fn record_history(&self, result: &TransactionQuery) {
+		let transaction_time = chrono::offset::Utc::now();
+		let mut tx = db().await.begin().await.unwrap();
+		let sender_history = TransactionHistory {
+			amount: self.amount,
+			date: transaction_time,
+			credit: false,
+			comment: self.comment.clone(),
+			addr: self.receiver.clone()
+		};
+		let mut sender_crate = CrateFilter {
+			name: Some(ComnAddr::from_uuid(&result.sender).unwrap().to_string()),
+			addr: Some(vec!(SpecialAddr::ComnCoin.value())),
+			pub_key: None,
+			crate_id: None,
+			access_type: vec!(AccessType::Owner),
+			result: None,
+		};
+		let _ = sender_crate.init().await;
+		let sender_crate_id = sender_crate.get_crate().unwrap()[0].id;
+		let _rr = add_transaction_json(sender_history, sender_crate_id).await;
+
+		let receiver_history = TransactionHistory {
+			amount: self.amount,
+			date: transaction_time,
+			credit: true,
+			comment: self.comment.clone(),
+			addr: ComnAddr::from_uuid(&result.sender.to_string()).unwrap()
+		};
+		let mut receiver_crate = CrateOwnerFilter {
+			addr: Some(ComnAddr::from_uuid("000000000000000000000000000000cc").unwrap().to_string()),
+			name: Some(ComnAddr::from_uuid(&result.receiver.to_string()).unwrap().to_string()),
+			crate_ids: None,
+		};
+		let receiver_crate_id = match receiver_crate.get_id().await {
+			Ok(result) => result,
+			Err(CrateFilterErr::NotFound) => {
+				let create_crate_req = AddCrateReq {
+					name: receiver_crate.name.unwrap(),
+					comment: "ComnCoin transaction history".to_string(),
+					addr: ComnAddr::new(&receiver_crate.addr.unwrap()).unwrap(),
+					expires: None,
+					encrypted: false,
+					envelope: None,
+				};
+				let new_crate = _add_crate(create_crate_req).await;
+				sqlx::query_as::<_, CrateAccess>(
+					"
+					INSERT INTO crate_access(crate_id, addr_id, type)
+					VALUES($1::uuid, $2::uuid, $3) RETURNING *
 					"
 				)
-				.bind([self.nonce.to_string()])
-				.bind(Json::from(transaction_time.to_string()))
+				.bind(new_crate.id)
+				.bind(result.receiver.to_string())
+				.bind(AccessType::Reader)
 				.fetch_one(&mut *tx)
 				.await
 				.unwrap();
-				let sender_history = TransactionHistory {
-					amount: self.amount,
-					date: transaction_time,
-					credit: false,
-					comment: self.comment.clone(),
-					addr: self.receiver.clone()
-				};
-				let mut sender_crate = CrateFilter {
-					name: Some(ComnAddr::from_uuid(&result.sender).unwrap().to_string()),
-					addr: Some(vec!(SpecialAddr::ComnCoin.value())),
-					pub_key: None,
-					crate_id: None,
-					access_type: vec!(AccessType::Owner),
-					result: None,
-				};
-				let _ = sender_crate.init().await;
-				let sender_crate_id = sender_crate.get_crate().unwrap()[0].id;
-				let _rr = add_transaction_json(sender_history, sender_crate_id).await;
-
-				let receiver_history = TransactionHistory {
-					amount: self.amount,
-					date: transaction_time,
-					credit: true,
-					comment: self.comment.clone(),
-					addr: ComnAddr::from_uuid(&result.sender.to_string()).unwrap()
-				};
-				let mut receiver_crate = CrateOwnerFilter {
-					addr: Some(ComnAddr::from_uuid("000000000000000000000000000000cc").unwrap().to_string()),
-					name: Some(ComnAddr::from_uuid(&result.receiver.to_string()).unwrap().to_string()),
-					crate_ids: None,
-				};
-				let receiver_crate_id = match receiver_crate.get_id().await {
-					Ok(result) => result,
-					Err(CrateFilterErr::NotFound) => {
-						let create_crate_req = AddCrateReq {
-							name: receiver_crate.name.unwrap(),
-							comment: "ComnCoin transaction history".to_string(),
-							addr: ComnAddr::new(&receiver_crate.addr.unwrap()).unwrap(),
-							expires: None
-						};
-						let new_crate = _add_crate(create_crate_req).await;
-						sqlx::query_as::<_, CrateAccess>(
-							"
-							INSERT INTO crate_access(crate_id, addr_id, type)
-							VALUES($1::uuid, $2::uuid, $3) RETURNING *
-							"
-						)
-						.bind(new_crate.id)
-						.bind(result.receiver.to_string())
-						.bind(AccessType::Reader)
-						.fetch_one(&mut *tx)
-						.await
-						.unwrap();
-						new_crate.id
-					},
-					Err(CrateFilterErr::BadData) | Err(CrateFilterErr::Multiple) 
-						=> panic!("While retriving receiver crate."),
-				};
-				tx.commit().await.unwrap();
-				let receiver_crate_item = add_transaction_json(receiver_history, receiver_crate_id).await;
-				println!("rr {:?}", receiver_crate_item);
+				new_crate.id
+			},
+			Err(CrateFilterErr::BadData) | Err(CrateFilterErr::Multiple) 
+				=> panic!("While retriving receiver crate."),
+		};
+		tx.commit().await.unwrap();
+		let receiver_crate_item = add_transaction_json(receiver_history, receiver_crate_id).await;
+		println!("rr {:?}", receiver_crate_item);
+	}
+}
 
-				Ok(result)
-			}
+impl Approve {
+	pub async fn approve(&self) -> Result<Allowance, TransactionErr> {
+		let approved = i64::try_from(self.amount).map_err(|_| TransactionErr::BadData)?;
+		let mut tx = db().await.begin().await.unwrap();
+		if nonce_reported(&mut tx, &self.nonce).await {
+			return Err(TransactionErr::AlreadyReported);
 		}
+		let amount = sqlx::query_scalar::<_, i64>(
+			"
+			UPDATE crate_item
+			SET
+				data_json =
+					jsonb_set(
+						data_json,
+						ARRAY[$1::text],
+						COALESCE(data_json -> $1::text, '{}'::jsonb)
+							|| jsonb_build_object($2::text, $3::int8)
+					)
+			WHERE
+				id = '000000000000000000000000000021cc'::uuid
+			RETURNING (data_json -> $1::text ->> $2::text)::int8
+			"
+		)
+		.bind(self.owner.to_uuid())
+		.bind(self.spender.to_uuid())
+		.bind(approved)
+		.fetch_one(&mut *tx)
+		.await
+		.unwrap();
+		report_nonce(&mut tx, &self.nonce).await;
+		tx.commit().await.unwrap();
+
+		Ok(Allowance {
+			owner: self.owner.clone(),
+			spender: self.spender.clone(),
+			amount,
+		})
+	}
+}
+
+pub async fn get_allowance(owner: &ComnAddr, spender: &ComnAddr) -> Allowance {
+	let amount = sqlx::query_scalar::<_, i64>(
+		"
+		SELECT COALESCE((ci.data_json -> $1::text ->> $2::text)::int8, 0)
+		FROM crate_item ci
+		WHERE
+			ci.id = '000000000000000000000000000021cc'::uuid
+		"
+	)
+	.bind(owner.to_uuid())
+	.bind(spender.to_uuid())
+	.fetch_one(db().await)
+	.await
+	.unwrap_or(0);
+
+	Allowance {
+		owner: owner.clone(),
+		spender: spender.clone(),
+		amount,
 	}
 }
 
+pub async fn get_balance(addr: &ComnAddr) -> i64 {
+	sqlx::query_scalar::<_, Option<i64>>(
+		"
+		SELECT (ci.data_json ->> $1::text)::int8
+		FROM crate_item ci
+		WHERE
+			ci.id = '000000000000000000000000000001cc'::uuid
+		"
+	)
+	.bind(addr.to_uuid())
+	.fetch_one(db().await)
+	.await
+	.unwrap()
+	.unwrap_or(0)
+}
+
+pub async fn total_supply() -> i64 {
+	sqlx::query_scalar::<_, i64>(
+		"
+		SELECT (ci.data_json ->> 'total')::int8
+		FROM crate_item ci
+		WHERE
+			ci.id = '000000000000000000000000000001cc'::uuid
+		"
+	)
+	.fetch_one(db().await)
+	.await
+	.unwrap()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CoinDetails {
+	pub name: String,
+	pub symbol: String,
+	pub max: i64,
+	pub freeze: bool,
+	pub image: String,
+}
+
+pub async fn get_details() -> CoinDetails {
+	let details = sqlx::query_scalar::<_, serde_json::Value>(
+		"
+		SELECT ci.data_json
+		FROM crate_item ci
+		WHERE
+			ci.id = '000000000000000000000000000000cc'::uuid
+		"
+	)
+	.fetch_one(db().await)
+	.await
+	.unwrap();
+	serde_json::from_value::<CoinDetails>(details).unwrap()
+}
+
+impl TransferFrom {
+	pub async fn transfer_from(&self) -> Result<TransactionQuery, TransactionErr> {
+		// a negative amount would pass the allowance check and raise it
+		let amount = i64::try_from(self.amount).map_err(|_| TransactionErr::BadData)?;
+		let mut tx = db().await.begin().await.unwrap();
+		if nonce_reported(&mut tx, &self.nonce).await {
+			return Err(TransactionErr::AlreadyReported);
+		}
+		// the allowance and the balances move in one transaction, it is only
+		// committed when the coins actually moved.
+		let remaining = sqlx::query_scalar::<_, i64>(
+			"
+			UPDATE crate_item
+			SET
+				data_json =
+					jsonb_set(
+						data_json,
+						ARRAY[$1::text, $2::text],
+						to_jsonb((data_json -> $1::text ->> $2::text)::int8 - $3::int8)
+					)
+			WHERE
+				id = '000000000000000000000000000021cc'::uuid
+				AND COALESCE((data_json -> $1::text ->> $2::text)::int8, 0) >= $3::int8
+			RETURNING (data_json -> $1::text ->> $2::text)::int8
+			"
+		)
+		.bind(self.owner.to_uuid())
+		.bind(self.spender.to_uuid())
+		.bind(amount)
+		.fetch_optional(&mut *tx)
+		.await
+		.unwrap();
+		if remaining.is_none() {
+			return Err(TransactionErr::LowAllowance);
+		}
+
+		let transaction = Transaction {
+			amount: self.amount,
+			receiver: self.receiver.clone(),
+			sender: self.owner.clone(),
+			comment: self.comment.clone(),
+			nonce: self.nonce.clone(),
+		};
+		let result = transaction.transfer_coins_in(&mut tx).await?;
+		tx.commit().await.unwrap();
+		transaction.record_history(&result).await;
+
+		Ok(result)
+	}
+}
+
+async fn nonce_reported(conn: &mut PgConnection, nonce: &str) -> bool {
+	sqlx::query_scalar::<_, String>(
+		"
+		SELECT ci.data_json ->> $1::text
+		FROM crate_item ci
+		WHERE 
+			ci.id = '000000000000000000000000000011cc'::uuid
+		"
+	)
+	.bind(nonce)
+	.fetch_one(&mut *conn)
+	.await
+	.is_ok()
+}
+
+async fn report_nonce(conn: &mut PgConnection, nonce: &str) {
+	sqlx::query(
+		"
+		UPDATE crate_item
+		SET
+			data_json =
+				jsonb_set(
+					data_json,
+					$1::text[], 
+					$2
+				)
+		WHERE 
+			id = '000000000000000000000000000011cc'::uuid
+		"
+	)
+	.bind([nonce.to_string()])
+	.bind(Json::from(chrono::offset::Utc::now().to_string()))
+	.execute(&mut *conn)
+	.await
+	.unwrap();
+}
+
 async fn add_transaction_json(history: TransactionHistory, crate_id: Uuid) -> Uuid {
 	let item_path = "/".to_string() + &history.date.to_string();
 	let history_json = serde_json::to_string(&history).unwrap();
@@ -236,6 +458,9 @@ async fn add_transaction_json(history: TransactionHistory, crate_id: Uuid) -> Uu
 		data: Some(history_data.into()),
 		sha2_hash: sha2_hash,
 		scope: "transaction_history".to_string(),
+		proof: None,
+		metadata: Default::default(),
+		tags: vec![],
 	};
 	let rr = add_item.add().await.unwrap();
 
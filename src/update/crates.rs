@@ -0,0 +1,374 @@
+use chrono::{DateTime, Duration, Utc};
+use std::{error::Error, fmt};
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::types::{Json, Uuid};
+use sqlx::{FromRow, PgConnection};
+use crate::{
+	AccessType, Crate,
+	comn_addr::ComnAddr, db::db,
+	e2e::{member_key_version, member_removed, E2eErr},
+};
+
+// longest a crate name is
+const MAX_NAME_LEN: usize = 256;
+// how long an ownership offer stands when it doesn't say
+const OFFER_DAYS: i64 = 7;
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[sqlx(type_name = "CRATE_TRANSFER_STATUS")]
+#[sqlx(rename_all = "lowercase")]
+pub enum CrateTransferStatus {
+	Offered,
+	Accepted,
+	Cancelled,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CrateErr {
+	BadData,
+	NotFound,
+	Unauthorized,
+	// the crate already has an open offer, or its key has to be rotated first
+	Conflict,
+	Expired,
+}
+
+impl Error for CrateErr {}
+
+impl fmt::Display for CrateErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CrateErr::BadData => write!(f, "name, addrs or expiry are not right."),
+            CrateErr::NotFound => write!(f, "no crate or open offer found"),
+            CrateErr::Unauthorized => write!(f, "addr can't change this crate"),
+            CrateErr::Conflict => write!(f, "crate has an open offer or its key has to be rotated"),
+            CrateErr::Expired => write!(f, "offer has expired"),
+        }
+    }
+}
+
+// tells a field set to null, Some(None), from one left out, None
+fn some<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: Deserialize<'de>,
+{
+	T::deserialize(deserializer).map(Some)
+}
+
+/// Changes the name, comment or expiry of a crate by one of its owners or admins.
+///
+/// Fields left out stay as they are, `comment` and `expires` set to null are cleared.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpdateCrateReq {
+	pub crate_id: Uuid,
+	pub addr: ComnAddr,
+	#[serde(default)]
+	pub name: Option<String>,
+	#[serde(default, deserialize_with = "some")]
+	pub comment: Option<Option<String>>,
+	#[serde(default, deserialize_with = "some")]
+	pub expires: Option<Option<DateTime<Utc>>>,
+}
+
+/// Offer of the ownership of a crate by its owner `from` to `to`.
+///
+/// `envelope` is the crate key sealed to `to`, needed for e2e crates.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OfferCrateReq {
+	pub crate_id: Uuid,
+	pub from: ComnAddr,
+	pub to: ComnAddr,
+	#[serde(default)]
+	pub expires: Option<DateTime<Utc>>,
+	#[serde(default)]
+	pub envelope: Option<Vec<u8>>,
+}
+
+/// Signed by the receiving addr to take the ownership offered to it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AcceptCrateReq {
+	pub id: Uuid,
+	pub addr: ComnAddr,
+}
+
+// addr is the owner withdrawing the offer or the receiver declining it
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CancelCrateOfferReq {
+	pub id: Uuid,
+	pub addr: ComnAddr,
+}
+
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CrateTransfer {
+	pub id: Uuid,
+	pub crate_id: Uuid,
+	pub from_addr: Uuid,
+	pub to_addr: Uuid,
+	#[serde(skip)]
+	pub envelope: Option<Vec<u8>>,
+	pub status: CrateTransferStatus,
+	pub expires: DateTime<Utc>,
+	pub created: DateTime<Utc>,
+	pub updated: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CrateTransferRes {
+	pub id: Uuid,
+	pub crate_id: Uuid,
+	pub from: ComnAddr,
+	pub to: ComnAddr,
+	pub status: CrateTransferStatus,
+	pub expires: DateTime<Utc>,
+	pub created: DateTime<Utc>,
+	pub updated: DateTime<Utc>,
+}
+
+impl From<CrateTransfer> for CrateTransferRes {
+  fn from(a: CrateTransfer) -> Self {
+    Self {
+			id: a.id,
+			crate_id: a.crate_id,
+			from: ComnAddr::from_uuid(&a.from_addr.to_string()).unwrap(),
+			to: ComnAddr::from_uuid(&a.to_addr.to_string()).unwrap(),
+			status: a.status,
+			expires: a.expires,
+			created: a.created,
+			updated: a.updated,
+    }
+  }
+}
+
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CrateAudit {
+	pub id: i64,
+	pub crate_id: Uuid,
+	pub addr_id: Option<Uuid>,
+	pub action: String,
+	pub detail: Json<serde_json::Value>,
+	pub created: DateTime<Utc>,
+}
+
+pub async fn audit(conn: &mut PgConnection, crate_id: Uuid, addr: &ComnAddr, action: &str, detail: serde_json::Value) {
+	sqlx::query("INSERT INTO crate_audit(crate_id, addr_id, action, detail) VALUES($1, $2::uuid, $3, $4)")
+		.bind(crate_id)
+		.bind(addr.to_uuid())
+		.bind(action)
+		.bind(Json(detail))
+		.execute(conn)
+		.await
+		.unwrap();
+}
+
+/// Changes made to a crate, latest first.
+pub async fn list_audit(conn: &mut PgConnection, crate_id: Uuid) -> Vec<CrateAudit> {
+	sqlx::query_as::<_, CrateAudit>("SELECT * FROM crate_audit WHERE crate_id = $1 ORDER BY id DESC")
+		.bind(crate_id)
+		.fetch_all(conn)
+		.await
+		.unwrap()
+}
+
+/// Open offers made to or by the addrs of the key.
+pub async fn list_offers(conn: &mut PgConnection, pub_key: &[u8]) -> Vec<CrateTransfer> {
+	sqlx::query_as::<_, CrateTransfer>(
+		"
+		SELECT DISTINCT ct.* FROM crate_transfer ct
+		JOIN addr_key ak ON ak.addr_id = ct.from_addr OR ak.addr_id = ct.to_addr
+		JOIN key k ON k.id = ak.key_id
+		WHERE k.pub_key = $1 AND ct.status = 'offered' AND ct.expires > now()
+		ORDER BY ct.created DESC
+		"
+	)
+	.bind(pub_key)
+	.fetch_all(conn)
+	.await
+	.unwrap()
+}
+
+// whether addr has one of the access types in the crate
+pub async fn has_access(conn: &mut PgConnection, crate_id: Uuid, addr: &ComnAddr, access_type: &[AccessType]) -> bool {
+	sqlx::query_scalar::<_, bool>(
+		"SELECT EXISTS(SELECT 1 FROM crate_access WHERE crate_id = $1 AND addr_id = $2::uuid AND type = ANY($3))"
+	)
+	.bind(crate_id)
+	.bind(addr.to_uuid())
+	.bind(access_type.to_vec())
+	.fetch_one(conn)
+	.await
+	.unwrap()
+}
+
+impl UpdateCrateReq {
+	pub async fn apply(&self) -> Result<Crate, CrateErr> {
+		if let Some(name) = &self.name {
+			if name.trim().is_empty() || name.len() > MAX_NAME_LEN {
+				return Err(CrateErr::BadData);
+			}
+		}
+		let mut tx = db().await.begin().await.unwrap();
+		if !has_access(&mut *tx, self.crate_id, &self.addr, &[AccessType::Owner, AccessType::Admin]).await {
+			return Err(CrateErr::Unauthorized);
+		}
+		let before = sqlx::query_as::<_, Crate>("SELECT * FROM crate WHERE id = $1 FOR UPDATE")
+			.bind(self.crate_id)
+			.fetch_optional(&mut *tx)
+			.await
+			.unwrap()
+			.ok_or(CrateErr::NotFound)?;
+		let updated = sqlx::query_as::<_, Crate>(
+			"UPDATE crate SET name = $2, comment = $3, expires = $4 WHERE id = $1 RETURNING *"
+		)
+		.bind(self.crate_id)
+		.bind(self.name.as_ref().map(|name| name.trim().to_string()).unwrap_or(before.name.clone()))
+		.bind(self.comment.clone().unwrap_or(before.comment.clone()))
+		.bind(self.expires.unwrap_or(before.expires))
+		.fetch_one(&mut *tx)
+		.await
+		.unwrap();
+		audit(&mut *tx, self.crate_id, &self.addr, "update", serde_json::json!({
+			"before": {"name": before.name, "comment": before.comment, "expires": before.expires},
+			"after": {"name": updated.name, "comment": updated.comment, "expires": updated.expires},
+		})).await;
+		tx.commit().await.unwrap();
+		Ok(updated)
+	}
+}
+
+impl OfferCrateReq {
+	pub async fn offer(&self) -> Result<CrateTransfer, CrateErr> {
+		let expires = self.expires.unwrap_or(Utc::now() + Duration::days(OFFER_DAYS));
+		if expires <= Utc::now() || self.from == self.to {
+			return Err(CrateErr::BadData);
+		}
+		let mut tx = db().await.begin().await.unwrap();
+		if !has_access(&mut *tx, self.crate_id, &self.from, &[AccessType::Owner]).await {
+			return Err(CrateErr::Unauthorized);
+		}
+		// the envelope is checked now, not once the offer is accepted
+		if let Err(e) = member_key_version(&mut *tx, &self.crate_id.simple().to_string(), &self.to, &self.envelope).await {
+			return Err(if e == E2eErr::Conflict { CrateErr::Conflict } else { CrateErr::BadData });
+		}
+		// offers that expired don't hold the crate's one open offer
+		sqlx::query("UPDATE crate_transfer SET status = 'cancelled', updated = now() WHERE crate_id = $1 AND status = 'offered' AND expires <= now()")
+			.bind(self.crate_id)
+			.execute(&mut *tx)
+			.await
+			.unwrap();
+		let transfer = sqlx::query_as::<_, CrateTransfer>(
+			"
+			INSERT INTO crate_transfer(crate_id, from_addr, to_addr, envelope, expires)
+			VALUES($1, $2::uuid, $3::uuid, $4, $5)
+			ON CONFLICT DO NOTHING
+			RETURNING *
+			"
+		)
+		.bind(self.crate_id)
+		.bind(self.from.to_uuid())
+		.bind(self.to.to_uuid())
+		.bind(self.envelope.clone())
+		.bind(expires)
+		.fetch_optional(&mut *tx)
+		.await
+		.map_err(|_| CrateErr::BadData)?
+		.ok_or(CrateErr::Conflict)?;
+		audit(&mut *tx, self.crate_id, &self.from, "transfer_offer", serde_json::json!({
+			"transfer_id": transfer.id, "to": self.to.to_string(), "expires": expires,
+		})).await;
+		tx.commit().await.unwrap();
+		Ok(transfer)
+	}
+}
+
+// the open offer, locked until the transaction ends
+async fn lock_offer(conn: &mut PgConnection, id: Uuid) -> Result<CrateTransfer, CrateErr> {
+	sqlx::query_as::<_, CrateTransfer>("SELECT * FROM crate_transfer WHERE id = $1 AND status = 'offered' FOR UPDATE")
+		.bind(id)
+		.fetch_optional(conn)
+		.await
+		.unwrap()
+		.ok_or(CrateErr::NotFound)
+}
+
+async fn set_status(conn: &mut PgConnection, id: Uuid, status: CrateTransferStatus) {
+	sqlx::query("UPDATE crate_transfer SET status = $2, updated = now() WHERE id = $1")
+		.bind(id)
+		.bind(status)
+		.execute(conn)
+		.await
+		.unwrap();
+}
+
+impl AcceptCrateReq {
+	/// Makes the receiving addr an owner in place of the offering one.
+	///
+	/// The offering addr keeps any other access it has to the crate.
+	pub async fn accept(&self) -> Result<CrateTransfer, CrateErr> {
+		let mut tx = db().await.begin().await.unwrap();
+		let mut transfer = lock_offer(&mut *tx, self.id).await?;
+		if transfer.to_addr.to_string() != self.addr.to_uuid() {
+			return Err(CrateErr::Unauthorized);
+		}
+		if transfer.expires <= Utc::now() {
+			return Err(CrateErr::Expired);
+		}
+		let crate_id = transfer.crate_id.simple().to_string();
+		let from = ComnAddr::from_uuid(&transfer.from_addr.to_string()).unwrap();
+		// the owner could have lost ownership since the offer
+		let removed = sqlx::query("DELETE FROM crate_access WHERE crate_id = $1 AND addr_id = $2 AND type = 'owner'")
+			.bind(transfer.crate_id)
+			.bind(transfer.from_addr)
+			.execute(&mut *tx)
+			.await
+			.unwrap();
+		if removed.rows_affected() == 0 {
+			return Err(CrateErr::Unauthorized);
+		}
+		let key_version = match member_key_version(&mut *tx, &crate_id, &self.addr, &transfer.envelope).await {
+			Ok(key_version) => key_version,
+			Err(E2eErr::Conflict) => return Err(CrateErr::Conflict),
+			Err(_) => return Err(CrateErr::BadData),
+		};
+		sqlx::query(
+			"
+			INSERT INTO crate_access(crate_id, addr_id, type, envelope, key_version)
+			VALUES($1, $2::uuid, 'owner', $3, $4)
+			ON CONFLICT DO NOTHING
+			"
+		)
+		.bind(transfer.crate_id)
+		.bind(self.addr.to_uuid())
+		.bind(key_version.and(transfer.envelope.clone()))
+		.bind(key_version)
+		.execute(&mut *tx)
+		.await
+		.unwrap();
+		member_removed(&mut *tx, &crate_id, &from).await;
+		set_status(&mut *tx, transfer.id, CrateTransferStatus::Accepted).await;
+		audit(&mut *tx, transfer.crate_id, &self.addr, "transfer_accept", serde_json::json!({
+			"transfer_id": transfer.id, "from": from.to_string(),
+		})).await;
+		tx.commit().await.unwrap();
+		transfer.status = CrateTransferStatus::Accepted;
+		Ok(transfer)
+	}
+}
+
+impl CancelCrateOfferReq {
+	pub async fn cancel(&self) -> Result<CrateTransfer, CrateErr> {
+		let mut tx = db().await.begin().await.unwrap();
+		let mut transfer = lock_offer(&mut *tx, self.id).await?;
+		let addr = self.addr.to_uuid();
+		if transfer.from_addr.to_string() != addr && transfer.to_addr.to_string() != addr {
+			return Err(CrateErr::Unauthorized);
+		}
+		set_status(&mut *tx, transfer.id, CrateTransferStatus::Cancelled).await;
+		audit(&mut *tx, transfer.crate_id, &self.addr, "transfer_cancel", serde_json::json!({
+			"transfer_id": transfer.id,
+		})).await;
+		tx.commit().await.unwrap();
+		transfer.status = CrateTransferStatus::Cancelled;
+		Ok(transfer)
+	}
+}
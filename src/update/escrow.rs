@@ -0,0 +1,302 @@
+use chrono::{DateTime, Utc};
+use std::{error::Error, fmt};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use sqlx::FromRow;
+use tokio::time::interval;
+use crate::{
+	SpecialAddr,
+	comn_addr::ComnAddr, db::db,
+	update::coin::{Transaction, TransactionErr},
+};
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+#[sqlx(type_name = "ESCROW_STATUS")]
+#[sqlx(rename_all = "lowercase")]
+pub enum EscrowStatus {
+	Locked,
+	Released,
+	Refunded,
+}
+
+/// Signed request to lock `amount` of the sender's coins for the receiver.
+/// Locked coins are held by the escrow addr until released by the sender or
+/// arbiter, or refunded to the sender at `expires`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EscrowReq {
+	pub sender: ComnAddr,
+	pub receiver: ComnAddr,
+	pub arbiter: Option<ComnAddr>,
+	pub amount: u64,
+	pub comment: Option<String>,
+	pub nonce: String,
+	pub expires: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum EscrowAction {
+	Release,
+	Refund,
+}
+
+// addr is the party signing the action
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EscrowActionReq {
+	pub id: Uuid,
+	pub addr: ComnAddr,
+	pub action: EscrowAction,
+}
+
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Escrow {
+	pub id: Uuid,
+	pub sender: Uuid,
+	pub receiver: Uuid,
+	pub arbiter: Option<Uuid>,
+	pub amount: i64,
+	pub comment: Option<String>,
+	pub nonce: String,
+	pub status: EscrowStatus,
+	pub expires: DateTime<Utc>,
+	pub created: DateTime<Utc>,
+	pub updated: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct EscrowRes {
+	pub id: Uuid,
+	pub sender: ComnAddr,
+	pub receiver: ComnAddr,
+	pub arbiter: Option<ComnAddr>,
+	pub amount: i64,
+	pub comment: Option<String>,
+	pub status: EscrowStatus,
+	pub expires: DateTime<Utc>,
+	pub created: DateTime<Utc>,
+	pub updated: DateTime<Utc>,
+}
+
+impl From<Escrow> for EscrowRes {
+  fn from(a: Escrow) -> Self {
+    Self {
+			id: a.id,
+			sender: ComnAddr::from_uuid(&a.sender.to_string()).unwrap(),
+			receiver: ComnAddr::from_uuid(&a.receiver.to_string()).unwrap(),
+			arbiter: a.arbiter.map(|x| ComnAddr::from_uuid(&x.to_string()).unwrap()),
+			amount: a.amount,
+			comment: a.comment,
+			status: a.status,
+			expires: a.expires,
+			created: a.created,
+			updated: a.updated,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum EscrowErr {
+	AlreadyReported,
+	BadData,
+	LowAmount,
+	NotFound,
+	Unauthorized,
+}
+
+impl Error for EscrowErr {}
+
+impl fmt::Display for EscrowErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EscrowErr::AlreadyReported => write!(f, "An escrow already exists with the nonce."),
+            EscrowErr::BadData => write!(f, "amount, addrs or expiry are not right."),
+            EscrowErr::LowAmount => write!(f, "Amount to lock is more than balance."),
+            EscrowErr::NotFound => write!(f, "no locked escrow found"),
+            EscrowErr::Unauthorized => write!(f, "addr can't settle this escrow"),
+        }
+    }
+}
+
+impl EscrowReq {
+	pub async fn lock(&self) -> Result<Escrow, EscrowErr> {
+		let amount = i64::try_from(self.amount).map_err(|_| EscrowErr::BadData)?;
+		if amount == 0 || self.expires <= Utc::now() || self.sender == self.receiver {
+			return Err(EscrowErr::BadData);
+		}
+		let mut tx = db().await.begin().await.unwrap();
+		let escrow = match sqlx::query_as::<_, Escrow>(
+			"
+			INSERT INTO escrow(sender, receiver, arbiter, amount, comment, nonce, expires)
+			VALUES($1::uuid, $2::uuid, $3::uuid, $4, $5, $6, $7)
+			ON CONFLICT DO NOTHING
+			RETURNING *
+			"
+		)
+		.bind(self.sender.to_uuid())
+		.bind(self.receiver.to_uuid())
+		.bind(self.arbiter.as_ref().map(|x| x.to_uuid()))
+		.bind(amount)
+		.bind(self.comment.clone())
+		.bind(self.nonce.clone())
+		.bind(self.expires)
+		.fetch_optional(&mut *tx)
+		.await
+		{
+			Ok(escrow) => escrow.ok_or(EscrowErr::AlreadyReported)?,
+			// the receiver or arbiter isn't an addr
+			Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => return Err(EscrowErr::NotFound),
+			Err(sqlx::Error::Database(e)) if e.is_check_violation() => return Err(EscrowErr::BadData),
+			Err(e) => panic!("{}", e),
+		};
+
+		let transaction = Transaction {
+			amount: self.amount,
+			receiver: SpecialAddr::Escrow.value(),
+			sender: self.sender.clone(),
+			comment: Some(format!("escrow {} locked", escrow.id)),
+			nonce: escrow.transition_nonce(EscrowStatus::Locked),
+		};
+		// the escrow only exists if the coins moved
+		match transaction.transfer_coins_in(&mut tx).await {
+			Ok(result) => {
+				tx.commit().await.unwrap();
+				transaction.record_history(&result).await;
+				Ok(escrow)
+			}
+			Err(TransactionErr::LowAmount) => Err(EscrowErr::LowAmount),
+			Err(_) => Err(EscrowErr::BadData),
+		}
+	}
+}
+
+impl EscrowActionReq {
+	pub async fn apply(&self) -> Result<Escrow, EscrowErr> {
+		let escrow = get_escrow(self.id).await.ok_or(EscrowErr::NotFound)?;
+		let addr = Uuid::parse_str(&self.addr.to_uuid()).unwrap();
+		let allowed = match self.action {
+			EscrowAction::Release => addr == escrow.sender || Some(addr) == escrow.arbiter,
+			// the receiver may give the coins back
+			EscrowAction::Refund => addr == escrow.receiver || Some(addr) == escrow.arbiter,
+		};
+		if !allowed {
+			return Err(EscrowErr::Unauthorized);
+		}
+		escrow.settle(self.action).await
+	}
+}
+
+impl Escrow {
+	// deterministic per transition so a retried settlement can't move the coins twice
+	pub fn transition_nonce(&self, status: EscrowStatus) -> String {
+		format!("escrow:{}:{:?}", self.id, status)
+	}
+
+	pub async fn settle(&self, action: EscrowAction) -> Result<Escrow, EscrowErr> {
+		let (status, receiver) = match action {
+			EscrowAction::Release => (EscrowStatus::Released, self.receiver),
+			EscrowAction::Refund => (EscrowStatus::Refunded, self.sender),
+		};
+		let mut tx = db().await.begin().await.unwrap();
+		let escrow = sqlx::query_as::<_, Escrow>(
+			"
+			UPDATE escrow
+			SET status = $2, updated = CURRENT_TIMESTAMP
+			WHERE id = $1 AND status = 'locked'
+			RETURNING *
+			"
+		)
+		.bind(self.id)
+		.bind(status)
+		.fetch_optional(&mut *tx)
+		.await
+		.unwrap()
+		.ok_or(EscrowErr::NotFound)?;
+
+		let transaction = Transaction {
+			amount: self.amount as u64,
+			receiver: ComnAddr::from_uuid(&receiver.to_string()).unwrap(),
+			sender: SpecialAddr::Escrow.value(),
+			comment: Some(format!("escrow {} {:?}", self.id, status).to_lowercase()),
+			nonce: self.transition_nonce(status),
+		};
+		match transaction.transfer_coins_in(&mut tx).await {
+			Ok(result) => {
+				tx.commit().await.unwrap();
+				transaction.record_history(&result).await;
+				Ok(escrow)
+			}
+			Err(TransactionErr::AlreadyReported) => {
+				tx.commit().await.unwrap();
+				Ok(escrow)
+			}
+			Err(_) => Err(EscrowErr::BadData),
+		}
+	}
+}
+
+pub async fn get_escrow(id: Uuid) -> Option<Escrow> {
+	sqlx::query_as::<_, Escrow>("SELECT * FROM escrow WHERE id = $1")
+		.bind(id)
+		.fetch_optional(db().await)
+		.await
+		.unwrap()
+}
+
+// escrows where addr is any of the parties
+pub async fn list_escrows(addr: &ComnAddr) -> Vec<Escrow> {
+	sqlx::query_as::<_, Escrow>(
+		"
+		SELECT * FROM escrow
+		WHERE sender = $1::uuid OR receiver = $1::uuid OR arbiter = $1::uuid
+		ORDER BY created DESC
+		"
+	)
+	.bind(addr.to_uuid())
+	.fetch_all(db().await)
+	.await
+	.unwrap()
+}
+
+// coins of addr that are still held by the escrow addr
+pub async fn locked_amount(addr: &ComnAddr) -> i64 {
+	sqlx::query_scalar::<_, i64>(
+		"
+		SELECT COALESCE(SUM(amount), 0)::int8 FROM escrow
+		WHERE sender = $1::uuid AND status = 'locked'
+		"
+	)
+	.bind(addr.to_uuid())
+	.fetch_one(db().await)
+	.await
+	.unwrap()
+}
+
+pub async fn refund_expired() -> Vec<Result<Escrow, EscrowErr>> {
+	let escrows = sqlx::query_as::<_, Escrow>(
+		"
+		SELECT * FROM escrow
+		WHERE status = 'locked' AND expires <= CURRENT_TIMESTAMP
+		ORDER BY expires
+		"
+	)
+	.fetch_all(db().await)
+	.await
+	.unwrap();
+	let mut results = Vec::new();
+	for escrow in escrows {
+		results.push(escrow.settle(EscrowAction::Refund).await);
+	}
+	results
+}
+
+pub async fn worker(period: Duration) {
+	let mut ticker = interval(period);
+	loop {
+		ticker.tick().await;
+		for result in refund_expired().await {
+			if let Err(e) = result {
+				println!("escrow refund failed {}", e);
+			}
+		}
+	}
+}
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use sqlx::types::{Json, Uuid};
+use std::{error::Error, fmt};
+use crate::{comn_addr::ComnAddr, db::db};
+
+// most tags and metadata keys an item has
+const MAX_ENTRIES: usize = 32;
+const MAX_KEY_LEN: usize = 64;
+const MAX_VALUE_LEN: usize = 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum MetadataErr {
+	BadMetadata(String),
+	NotFound,
+}
+
+impl Error for MetadataErr {}
+
+impl fmt::Display for MetadataErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetadataErr::BadMetadata(e) => write!(f, "metadata is bad, {}", e),
+            MetadataErr::NotFound => write!(f, "no item found"),
+        }
+    }
+}
+
+/// Tags trimmed and lowercased, without duplicates, so `Invoice ` and `invoice` match.
+pub fn normalize_tags(tags: &[String]) -> Result<Vec<String>, MetadataErr> {
+	let mut normalized: Vec<String> = vec![];
+	for tag in tags {
+		let tag = tag.trim().to_lowercase();
+		if tag.is_empty() || tag.len() > MAX_KEY_LEN || tag.contains(',') {
+			return Err(MetadataErr::BadMetadata(format!("tag {:?} is empty, too long or has a comma", tag)));
+		}
+		if !normalized.contains(&tag) {
+			normalized.push(tag);
+		}
+	}
+	Ok(normalized)
+}
+
+pub fn check_metadata(metadata: &HashMap<String, String>, tags: &[String]) -> Result<(), MetadataErr> {
+	if metadata.len() > MAX_ENTRIES || tags.len() > MAX_ENTRIES {
+		return Err(MetadataErr::BadMetadata(format!("an item has at most {} tags and keys", MAX_ENTRIES)));
+	}
+	for (key, value) in metadata {
+		if key.is_empty() || key.len() > MAX_KEY_LEN {
+			return Err(MetadataErr::BadMetadata(format!("key {:?} is empty or too long", key)));
+		}
+		if value.len() > MAX_VALUE_LEN {
+			return Err(MetadataErr::BadMetadata(format!("value of {} is too long", key)));
+		}
+	}
+	Ok(())
+}
+
+/// Changes the metadata and tags of an item by addr, its data is left as it is.
+///
+/// Keys set to null are removed, the others are set. Tags are added before
+/// `remove_tags` are taken away.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ItemMetadataReq {
+	pub id: Uuid,
+	pub addr: ComnAddr,
+	#[serde(default)]
+	pub metadata: HashMap<String, Option<String>>,
+	#[serde(default)]
+	pub add_tags: Vec<String>,
+	#[serde(default)]
+	pub remove_tags: Vec<String>,
+}
+
+#[derive(sqlx::FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ItemMetadata {
+	pub metadata: Json<HashMap<String, String>>,
+	pub tags: Vec<String>,
+}
+
+impl ItemMetadataReq {
+	/// Applies the changes, returning the item's metadata and tags as they are now.
+	pub async fn apply(&self) -> Result<ItemMetadata, MetadataErr> {
+		let mut tx = db().await.begin().await.unwrap();
+		let mut item = sqlx::query_as::<_, ItemMetadata>(
+			"SELECT metadata, tags FROM crate_item WHERE id = $1 FOR UPDATE"
+		)
+		.bind(self.id)
+		.fetch_optional(&mut *tx)
+		.await
+		.unwrap()
+		.ok_or(MetadataErr::NotFound)?;
+
+		for (key, value) in &self.metadata {
+			match value {
+				Some(value) => item.metadata.0.insert(key.clone(), value.clone()),
+				None => item.metadata.0.remove(key),
+			};
+		}
+		let add_tags = normalize_tags(&self.add_tags)?;
+		let remove_tags = normalize_tags(&self.remove_tags)?;
+		for tag in add_tags {
+			if !item.tags.contains(&tag) {
+				item.tags.push(tag);
+			}
+		}
+		item.tags.retain(|tag| !remove_tags.contains(tag));
+		check_metadata(&item.metadata, &item.tags)?;
+
+		// metadata isn't item data, the version stays as it is
+		sqlx::query("UPDATE crate_item SET metadata = $2, tags = $3 WHERE id = $1")
+			.bind(self.id)
+			.bind(&item.metadata)
+			.bind(&item.tags)
+			.execute(&mut *tx)
+			.await
+			.unwrap();
+		tx.commit().await.unwrap();
+		Ok(item)
+	}
+}
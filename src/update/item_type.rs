@@ -0,0 +1,282 @@
+use chrono::{DateTime, Utc};
+use secp256k1::PublicKey;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use sqlx::{FromRow, PgConnection};
+use std::{error::Error, fmt};
+use crate::{comn_addr::ComnAddr, db::db, AccessType, SpecialAddr};
+
+/// A registered media type with the file extensions it goes by.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ItemType {
+	pub id: Uuid,
+	pub media_type: String,
+	pub description: Option<String>,
+	pub extensions: Vec<String>,
+	pub created: DateTime<Utc>,
+	pub updated: DateTime<Utc>,
+}
+
+/// Registers a media type. Only `writers` may upload it, anyone can when it's None.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AddItemTypeReq {
+	pub media_type: String,
+	pub description: Option<String>,
+	pub extensions: Vec<String>,
+	pub writers: Option<Vec<ComnAddr>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ItemTypeExtensionReq {
+	pub media_type: String,
+	pub extension: String,
+	// false removes it
+	pub add: bool,
+}
+
+/// Sets what addr can do with a media type, `access_type` None takes it away.
+/// Writer and up may upload items of the type.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ItemTypeAccessReq {
+	pub media_type: String,
+	pub addr: ComnAddr,
+	pub access_type: Option<AccessType>,
+	pub expires: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ItemTypeErr {
+	BadData,
+	NotFound,
+	// already registered
+	Exists,
+	// items of the type are stored
+	InUse,
+	// the addr may not upload items of the type
+	Forbidden,
+}
+
+impl Error for ItemTypeErr {}
+
+impl fmt::Display for ItemTypeErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ItemTypeErr::BadData => write!(f, "media type or extension is not right"),
+            ItemTypeErr::NotFound => write!(f, "media type is not registered"),
+            ItemTypeErr::Exists => write!(f, "media type is already registered"),
+            ItemTypeErr::InUse => write!(f, "there are items of the media type"),
+            ItemTypeErr::Forbidden => write!(f, "addr may not upload items of the media type"),
+        }
+    }
+}
+
+// lowercase type/subtype without parameters, i.e. "Text/Plain; charset=utf-8" is "text/plain"
+pub fn normalize_media_type(media_type: &str) -> Result<String, ItemTypeErr> {
+	let media_type = media_type.split(';').next().unwrap().trim().to_lowercase();
+	match media_type.split_once('/') {
+		Some((top, sub)) if !top.is_empty() && !sub.is_empty() && !sub.contains('/') => Ok(media_type),
+		_ => Err(ItemTypeErr::BadData),
+	}
+}
+
+fn normalize_extension(extension: &str) -> Result<String, ItemTypeErr> {
+	let extension = extension.trim().trim_start_matches('.').to_lowercase();
+	if extension.is_empty() || !extension.chars().all(|c| c.is_ascii_alphanumeric()) {
+		return Err(ItemTypeErr::BadData);
+	}
+	Ok(extension)
+}
+
+// keys of the config addr manage the registry
+pub async fn is_registry_admin(pub_key: &PublicKey) -> bool {
+	sqlx::query_scalar::<_, bool>(
+		"
+		SELECT EXISTS(SELECT 1 FROM addr_key ak JOIN key k ON k.id = ak.key_id
+		WHERE k.pub_key = $1 AND ak.addr_id = $2::uuid)
+		"
+	)
+	.bind(pub_key.serialize())
+	.bind(SpecialAddr::Config.value().to_uuid())
+	.fetch_one(db().await)
+	.await
+	.unwrap()
+}
+
+const SELECT_ITEM_TYPE: &str = "
+	SELECT it.id, it.media_type, it.description, it.created, it.updated,
+	ARRAY(SELECT ite.extension FROM item_type_extension ite WHERE ite.item_type_id = it.id ORDER BY ite.extension) AS extensions
+	FROM item_type it
+";
+
+pub async fn list_item_types() -> Vec<ItemType> {
+	sqlx::query_as::<_, ItemType>(&format!("{} ORDER BY it.media_type", SELECT_ITEM_TYPE))
+		.fetch_all(db().await)
+		.await
+		.unwrap()
+}
+
+pub async fn get_item_type(media_type: &str) -> Result<ItemType, ItemTypeErr> {
+	let media_type = normalize_media_type(media_type)?;
+	sqlx::query_as::<_, ItemType>(&format!("{} WHERE it.media_type = $1", SELECT_ITEM_TYPE))
+		.bind(media_type)
+		.fetch_optional(db().await)
+		.await
+		.unwrap()
+		.ok_or(ItemTypeErr::NotFound)
+}
+
+/// Id of a registered media type `writer` may upload items of.
+///
+/// Writer, editor, admin and owner access of the writer, or of the public and
+/// registered addrs, to the type let it upload.
+pub async fn check_upload(conn: &mut PgConnection, media_type: &str, writer: &ComnAddr) -> Result<Uuid, ItemTypeErr> {
+	let media_type = normalize_media_type(media_type)?;
+	let (id, allowed) = sqlx::query_as::<_, (Uuid, bool)>(
+		"
+		SELECT it.id, EXISTS(
+			SELECT 1 FROM item_type_access ita
+			WHERE ita.item_type_id = it.id AND ita.addr_id = ANY($2)
+			AND ita.type IN ('owner', 'admin', 'editor', 'writer')
+			AND (ita.expires IS NULL OR ita.expires > CURRENT_TIMESTAMP)
+		)
+		FROM item_type it WHERE it.media_type = $1
+		"
+	)
+	.bind(media_type)
+	.bind(vec![
+		Uuid::parse_str(&writer.to_uuid()).unwrap(),
+		Uuid::parse_str(&SpecialAddr::Public.value().to_uuid()).unwrap(),
+		Uuid::parse_str(&SpecialAddr::Registered.value().to_uuid()).unwrap(),
+	])
+	.fetch_optional(conn)
+	.await
+	.unwrap()
+	.ok_or(ItemTypeErr::NotFound)?;
+	if !allowed {
+		return Err(ItemTypeErr::Forbidden);
+	}
+	Ok(id)
+}
+
+impl AddItemTypeReq {
+	pub async fn add(&self) -> Result<ItemType, ItemTypeErr> {
+		let media_type = normalize_media_type(&self.media_type)?;
+		let extensions = self.extensions.iter().map(|e| normalize_extension(e)).collect::<Result<Vec<_>, _>>()?;
+		let mut tx = db().await.begin().await.unwrap();
+		let id = sqlx::query_scalar::<_, Uuid>(
+			"
+			INSERT INTO item_type(media_type, description, extension) VALUES($1, $2, $3)
+			ON CONFLICT (media_type) DO NOTHING RETURNING id
+			"
+		)
+		.bind(&media_type)
+		.bind(&self.description)
+		.bind(extensions.first().cloned().unwrap_or_default())
+		.fetch_optional(&mut *tx)
+		.await
+		.unwrap()
+		.ok_or(ItemTypeErr::Exists)?;
+		for extension in &extensions {
+			add_extension(&mut *tx, id, extension).await;
+		}
+		// public can read, and write too unless writers are given
+		let public_access = if self.writers.is_some() { AccessType::Reader } else { AccessType::Writer };
+		sqlx::query("INSERT INTO item_type_access(item_type_id, addr_id, type) VALUES($1, $2::uuid, $3)")
+			.bind(id)
+			.bind(SpecialAddr::Public.value().to_uuid())
+			.bind(public_access)
+			.execute(&mut *tx)
+			.await
+			.unwrap();
+		for writer in self.writers.iter().flatten() {
+			sqlx::query(
+				"INSERT INTO item_type_access(item_type_id, addr_id, type) VALUES($1, $2::uuid, 'writer') ON CONFLICT DO NOTHING"
+			)
+			.bind(id)
+			.bind(writer.to_uuid())
+			.execute(&mut *tx)
+			.await
+			.map_err(|_| ItemTypeErr::BadData)?;
+		}
+		tx.commit().await.unwrap();
+		get_item_type(&media_type).await
+	}
+}
+
+async fn add_extension(conn: &mut PgConnection, item_type_id: Uuid, extension: &str) {
+	sqlx::query("INSERT INTO item_type_extension(item_type_id, extension) VALUES($1, $2) ON CONFLICT DO NOTHING")
+		.bind(item_type_id)
+		.bind(extension)
+		.execute(conn)
+		.await
+		.unwrap();
+}
+
+/// Unregisters a media type nothing is stored as.
+pub async fn remove_item_type(media_type: &str) -> Result<(), ItemTypeErr> {
+	let item_type = get_item_type(media_type).await?;
+	let mut tx = db().await.begin().await.unwrap();
+	let in_use = sqlx::query_scalar::<_, bool>("SELECT EXISTS(SELECT 1 FROM crate_item WHERE type_id = $1)")
+		.bind(item_type.id)
+		.fetch_one(&mut *tx)
+		.await
+		.unwrap();
+	if in_use {
+		return Err(ItemTypeErr::InUse);
+	}
+	sqlx::query("DELETE FROM item_type WHERE id = $1")
+		.bind(item_type.id)
+		.execute(&mut *tx)
+		.await
+		.unwrap();
+	tx.commit().await.unwrap();
+	Ok(())
+}
+
+impl ItemTypeExtensionReq {
+	pub async fn apply(&self) -> Result<ItemType, ItemTypeErr> {
+		let item_type = get_item_type(&self.media_type).await?;
+		let extension = normalize_extension(&self.extension)?;
+		let mut conn = db().await.acquire().await.unwrap();
+		if self.add {
+			add_extension(&mut conn, item_type.id, &extension).await;
+		} else {
+			sqlx::query("DELETE FROM item_type_extension WHERE item_type_id = $1 AND extension = $2")
+				.bind(item_type.id)
+				.bind(&extension)
+				.execute(&mut *conn)
+				.await
+				.unwrap();
+		}
+		get_item_type(&item_type.media_type).await
+	}
+}
+
+impl ItemTypeAccessReq {
+	pub async fn apply(&self) -> Result<(), ItemTypeErr> {
+		let item_type = get_item_type(&self.media_type).await?;
+		if let Some(access_type) = &self.access_type {
+			sqlx::query(
+				"
+				INSERT INTO item_type_access(item_type_id, addr_id, type, expires) VALUES($1, $2::uuid, $3, $4)
+				ON CONFLICT (item_type_id, addr_id) DO UPDATE SET type = $3, expires = $4
+				"
+			)
+			.bind(item_type.id)
+			.bind(self.addr.to_uuid())
+			.bind(access_type)
+			.bind(self.expires)
+			.execute(db().await)
+			.await
+			.map_err(|_| ItemTypeErr::BadData)?;
+		} else {
+			sqlx::query("DELETE FROM item_type_access WHERE item_type_id = $1 AND addr_id = $2::uuid")
+				.bind(item_type.id)
+				.bind(self.addr.to_uuid())
+				.execute(db().await)
+				.await
+				.unwrap();
+		}
+		Ok(())
+	}
+}
@@ -0,0 +1,154 @@
+use serde::{Deserialize, Serialize};
+use sqlx::types::{Json, Uuid};
+use std::{error::Error, fmt};
+use crate::{
+	comn_addr::ComnAddr, db::{db, get_config},
+	CrateItem, CrateItemStorage,
+	compression::{pack, unpack_item, ZSTD},
+	encryption::{crate_key, seal},
+	update::json_schema::{check_item, SchemaErr},
+	update::quota::{reserve, QuotaErr},
+};
+
+/// RFC 6902 JSON Patch, i.e. `{"json_patch": [{"op": "add", "path": "/a", "value": 1}]}`,
+/// or RFC 7396 merge patch, i.e. `{"merge_patch": {"a": 1, "b": null}}`.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemPatch {
+	JsonPatch(json_patch::Patch),
+	MergePatch(serde_json::Value),
+}
+
+/// Changes a json item in place by addr, if it's still at `version`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PatchCrateItemReq {
+	pub id: Uuid,
+	pub addr: ComnAddr,
+	pub version: i32,
+	pub patch: ItemPatch,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PatchErr {
+	NotFound,
+	// only items stored as json in the db can be patched
+	NotJson,
+	// the item changed since, its current version
+	VersionMismatch(i32),
+	BadPatch(String),
+	SchemaViolation(Vec<String>),
+	PayloadLarge,
+	QuotaExceeded,
+	StorageFull,
+	InternalErr,
+}
+
+impl Error for PatchErr {}
+
+impl fmt::Display for PatchErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchErr::NotFound => write!(f, "no item found"),
+            PatchErr::NotJson => write!(f, "item is not kept as json"),
+            PatchErr::VersionMismatch(version) => write!(f, "item is at version {}", version),
+            PatchErr::BadPatch(e) => write!(f, "patch doesn't apply, {}", e),
+            PatchErr::SchemaViolation(errors) => write!(f, "patched data doesn't match the schema, {}", errors.join(", ")),
+            PatchErr::PayloadLarge => write!(f, "patched data is too large"),
+            PatchErr::QuotaExceeded => write!(f, "Storage quota is exceeded, buy more"),
+            PatchErr::StorageFull => write!(f, "Storage quota is at its max"),
+            PatchErr::InternalErr => write!(f, "internal error"),
+        }
+    }
+}
+
+impl PatchCrateItemReq {
+	/// Applies the patch to the item's data, returning the item's new version.
+	///
+	/// The item row is locked while it's patched, so of concurrent patches made
+	/// against the same version only the first applies. A patch that fails part
+	/// way leaves the item as it was.
+	pub async fn apply(&self) -> Result<i32, PatchErr> {
+		let conf = get_config().await;
+		let mut tx = db().await.begin().await.unwrap();
+		let mut item = sqlx::query_as::<_, CrateItem>(
+			"
+			SELECT ci.*, it.media_type, NULL as data_file, s.scope_type as scope, 0::SMALLINT AS chunk_count
+			FROM crate_item ci
+			JOIN item_type it ON it.id = ci.type_id
+			JOIN scope s ON s.id = ci.scope_id
+			WHERE ci.id = $1
+			FOR UPDATE OF ci
+			"
+		)
+		.bind(self.id)
+		.fetch_optional(&mut *tx)
+		.await
+		.unwrap()
+		.ok_or(PatchErr::NotFound)?;
+		if item.version != self.version {
+			return Err(PatchErr::VersionMismatch(item.version));
+		}
+		if item.item_storage != CrateItemStorage::Json {
+			return Err(PatchErr::NotJson);
+		}
+		unpack_item(&mut item).await.map_err(|_| PatchErr::InternalErr)?;
+		let mut data = item.data_json.take().map(|data| data.0).unwrap_or_default();
+		match &self.patch {
+			ItemPatch::JsonPatch(patch) => {
+				json_patch::patch(&mut data, &patch.0).map_err(|e| PatchErr::BadPatch(e.to_string()))?
+			}
+			ItemPatch::MergePatch(patch) => json_patch::merge(&mut data, patch),
+		}
+		match check_item(&mut *tx, item.crate_id, &item.item_path, &data).await {
+			Ok(_) => {}
+			Err(SchemaErr::Invalid(errors)) => return Err(PatchErr::SchemaViolation(errors)),
+			Err(_) => return Err(PatchErr::InternalErr),
+		}
+
+		let bytes = serde_json::to_vec(&data).unwrap();
+		let size_hectobyte = (bytes.len() / 100) as i32;
+		if size_hectobyte > conf.data_size.max_db {
+			return Err(PatchErr::PayloadLarge);
+		}
+		if size_hectobyte > item.size_hectobyte {
+			match reserve(&mut *tx, item.crate_id, &self.addr, (size_hectobyte - item.size_hectobyte) as i64).await {
+				Ok(_) => {}
+				Err(QuotaErr::Full) => return Err(PatchErr::StorageFull),
+				Err(_) => return Err(PatchErr::QuotaExceeded),
+			}
+		}
+		// encrypted items are compressed and sealed again, like they're added
+		let (data_json, data_bytes, compression, stored_hectobyte) = match crate_key(&mut *tx, item.crate_id).await {
+			Ok(Some(data_key)) => {
+				let packed = pack(&bytes).await;
+				let compression = packed.as_ref().map(|_| ZSTD);
+				let sealed = seal(&data_key, item.id.as_bytes(), packed.as_deref().unwrap_or(&bytes));
+				let stored_hectobyte = (sealed.len() / 100) as i32;
+				(None, Some(sealed), compression, stored_hectobyte)
+			}
+			Ok(None) => (Some(Json(data)), None, None, size_hectobyte),
+			Err(_) => return Err(PatchErr::InternalErr),
+		};
+		// the version is bumped by trg_crate_item_bump_version
+		let version = sqlx::query_scalar::<_, i32>(
+			"
+			UPDATE crate_item
+			SET data_json = $2, data_bytes = $3, compression = $4, size_hectobyte = $5, stored_hectobyte = $6
+			WHERE id = $1 AND version = $7
+			RETURNING version
+			"
+		)
+		.bind(self.id)
+		.bind(data_json)
+		.bind(data_bytes)
+		.bind(compression)
+		.bind(size_hectobyte)
+		.bind(stored_hectobyte)
+		.bind(self.version)
+		.fetch_one(&mut *tx)
+		.await
+		.unwrap();
+		tx.commit().await.unwrap();
+		Ok(version)
+	}
+}
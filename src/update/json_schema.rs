@@ -0,0 +1,234 @@
+use chrono::{DateTime, Utc};
+use jsonschema::JSONSchema;
+use serde::{Deserialize, Serialize};
+use sqlx::types::{Json, Uuid};
+use sqlx::{FromRow, PgConnection};
+use std::{error::Error, fmt};
+use crate::{
+	db::db, CrateItem, CrateItemStorage,
+	blob::read_item_blob,
+	compression::unpack_item,
+};
+
+/// JSON Schema that json items at paths matching `path_pattern` have to satisfy.
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CrateSchema {
+	pub crate_id: Uuid,
+	pub path_pattern: String,
+	pub schema: Json<serde_json::Value>,
+	pub created: DateTime<Utc>,
+	pub updated: DateTime<Utc>,
+}
+
+/// Attaches a schema to a path pattern of a crate, `schema` None detaches it.
+///
+/// Patterns are item paths where a `*` segment matches any one segment and
+/// a `**` segment any number of them, i.e. `/history/*` or `/app/**`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SetCrateSchemaReq {
+	pub crate_id: String,
+	pub path_pattern: String,
+	pub schema: Option<serde_json::Value>,
+}
+
+/// A stored item that doesn't satisfy the schemas of its path.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct SchemaViolation {
+	pub item_id: Uuid,
+	pub item_path: String,
+	pub errors: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SchemaErr {
+	BadPattern,
+	BadSchema(String),
+	NotFound,
+	// end to end encrypted items can't be checked
+	E2e,
+	// the data doesn't satisfy a schema, what's wrong and where
+	Invalid(Vec<String>),
+}
+
+impl Error for SchemaErr {}
+
+impl fmt::Display for SchemaErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SchemaErr::BadPattern => write!(f, "path pattern is not right"),
+            SchemaErr::BadSchema(e) => write!(f, "schema is not right, {}", e),
+            SchemaErr::NotFound => write!(f, "no schema for the path pattern"),
+            SchemaErr::E2e => write!(f, "crate is end to end encrypted"),
+            SchemaErr::Invalid(errors) => write!(f, "data doesn't match the schema, {}", errors.join(", ")),
+        }
+    }
+}
+
+fn check_pattern(pattern: &str) -> Result<(), SchemaErr> {
+	if !pattern.starts_with('/') || pattern.split('/').skip(1).any(|s| s.is_empty()) {
+		return Err(SchemaErr::BadPattern);
+	}
+	Ok(())
+}
+
+// matched[j] is whether the pattern so far matches the first j segments of the
+// path, so a `**` is one pass over the path instead of a branch to backtrack
+fn segments_match(pattern: &[&str], path: &[&str]) -> bool {
+	let mut matched = vec![false; path.len() + 1];
+	matched[0] = true;
+	for p in pattern {
+		if *p == "**" {
+			for j in 1..=path.len() {
+				matched[j] = matched[j] || matched[j - 1];
+			}
+		} else {
+			for j in (1..=path.len()).rev() {
+				matched[j] = matched[j - 1] && (*p == "*" || *p == path[j - 1]);
+			}
+			matched[0] = false;
+		}
+	}
+	matched[path.len()]
+}
+
+pub fn path_matches(pattern: &str, item_path: &str) -> bool {
+	let pattern: Vec<&str> = pattern.split('/').skip(1).collect();
+	let path: Vec<&str> = item_path.split('/').skip(1).collect();
+	segments_match(&pattern, &path)
+}
+
+fn compile(schema: &serde_json::Value) -> Result<JSONSchema, SchemaErr> {
+	JSONSchema::compile(schema).map_err(|e| SchemaErr::BadSchema(e.to_string()))
+}
+
+fn schema_errors(compiled: &JSONSchema, data: &serde_json::Value) -> Vec<String> {
+	match compiled.validate(data) {
+		Ok(_) => vec![],
+		Err(errors) => errors.map(|e| format!("{}: {}", e.instance_path, e)).collect(),
+	}
+}
+
+pub async fn list_schemas(conn: &mut PgConnection, crate_id: Uuid) -> Vec<CrateSchema> {
+	sqlx::query_as::<_, CrateSchema>("SELECT * FROM crate_schema WHERE crate_id = $1 ORDER BY path_pattern")
+		.bind(crate_id)
+		.fetch_all(conn)
+		.await
+		.unwrap()
+}
+
+/// Validates json data going to `item_path` against every schema whose pattern matches.
+pub async fn check_item(conn: &mut PgConnection, crate_id: Uuid, item_path: &str, data: &serde_json::Value) -> Result<(), SchemaErr> {
+	let mut found = vec![];
+	for crate_schema in list_schemas(conn, crate_id).await {
+		if path_matches(&crate_schema.path_pattern, item_path) {
+			found.extend(schema_errors(&compile(&crate_schema.schema)?, data));
+		}
+	}
+	if !found.is_empty() {
+		return Err(SchemaErr::Invalid(found));
+	}
+	Ok(())
+}
+
+impl SetCrateSchemaReq {
+	pub async fn apply(&self) -> Result<Option<CrateSchema>, SchemaErr> {
+		check_pattern(&self.path_pattern)?;
+		let crate_id = Uuid::parse_str(&self.crate_id).map_err(|_| SchemaErr::NotFound)?;
+		let mut conn = db().await.acquire().await.unwrap();
+		if let Some(schema) = &self.schema {
+			compile(schema)?;
+			let e2e = sqlx::query_scalar::<_, bool>("SELECT e2e FROM crate WHERE id = $1")
+				.bind(crate_id)
+				.fetch_optional(&mut *conn)
+				.await
+				.unwrap()
+				.ok_or(SchemaErr::NotFound)?;
+			if e2e {
+				return Err(SchemaErr::E2e);
+			}
+			let crate_schema = sqlx::query_as::<_, CrateSchema>(
+				"
+				INSERT INTO crate_schema(crate_id, path_pattern, schema) VALUES($1, $2, $3)
+				ON CONFLICT (crate_id, path_pattern) DO UPDATE SET schema = $3, updated = CURRENT_TIMESTAMP
+				RETURNING *
+				"
+			)
+			.bind(crate_id)
+			.bind(&self.path_pattern)
+			.bind(Json(schema))
+			.fetch_one(&mut *conn)
+			.await
+			.unwrap();
+			Ok(Some(crate_schema))
+		} else {
+			let removed = sqlx::query("DELETE FROM crate_schema WHERE crate_id = $1 AND path_pattern = $2")
+				.bind(crate_id)
+				.bind(&self.path_pattern)
+				.execute(&mut *conn)
+				.await
+				.unwrap()
+				.rows_affected();
+			if removed == 0 {
+				return Err(SchemaErr::NotFound);
+			}
+			Ok(None)
+		}
+	}
+}
+
+/// Stored json items of a crate that don't satisfy the schemas of their paths,
+/// i.e. ones added before a schema was attached.
+pub async fn check_crate(crate_id: Uuid) -> Result<Vec<SchemaViolation>, SchemaErr> {
+	let mut conn = db().await.acquire().await.unwrap();
+	let schemas = list_schemas(&mut conn, crate_id).await;
+	let mut compiled = vec![];
+	for crate_schema in &schemas {
+		compiled.push((crate_schema.path_pattern.as_str(), compile(&crate_schema.schema)?));
+	}
+	let items = sqlx::query_as::<_, CrateItem>(
+		"
+		SELECT
+		ci.*, it.media_type, NULL as data_file, s.scope_type as scope,
+		(SELECT COUNT(*) FROM crate_item_chunk WHERE crate_item_id = ci.id)::SMALLINT AS chunk_count
+		FROM crate_item ci
+		JOIN item_type it ON it.id = ci.type_id
+		JOIN scope s ON s.id = ci.scope_id
+		WHERE ci.crate_id = $1 AND it.media_type = 'application/json'
+		ORDER BY ci.item_path
+		"
+	)
+	.bind(crate_id)
+	.fetch_all(&mut *conn)
+	.await
+	.unwrap();
+	drop(conn);
+	let mut violations = vec![];
+	for mut item in items {
+		let matching: Vec<&JSONSchema> = compiled.iter()
+			.filter(|(pattern, _)| path_matches(pattern, &item.item_path))
+			.map(|(_, schema)| schema)
+			.collect();
+		if matching.is_empty() {
+			continue;
+		}
+		if item.chunk_count > 0 {
+			item.data_file = read_item_blob(item.id).await;
+		}
+		let data = if unpack_item(&mut item).await.is_err() {
+			None
+		} else if item.item_storage == CrateItemStorage::File {
+			item.data_file.and_then(|data| serde_json::from_slice(&data).ok())
+		} else {
+			item.data_json.map(|data| data.0)
+		};
+		let errors = if let Some(data) = data {
+			matching.iter().flat_map(|schema| schema_errors(schema, &data)).collect()
+		} else {
+			vec!["data can't be read as JSON".to_string()]
+		};
+		if !errors.is_empty() {
+			violations.push(SchemaViolation { item_id: item.id, item_path: item.item_path, errors });
+		}
+	}
+	Ok(violations)
+}
@@ -0,0 +1,216 @@
+use std::{error::Error, fmt};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use sqlx::PgConnection;
+use crate::{
+	SpecialAddr,
+	comn_addr::ComnAddr, db::{db, get_config},
+	update::coin::{Transaction, TransactionErr},
+};
+
+/// Signed request buying `blocks` of `block_hectobyte` extra storage for addr,
+/// paid with `block_price` coins per block to the quota treasury.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BuyQuotaReq {
+	pub addr: ComnAddr,
+	pub blocks: u64,
+	pub nonce: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct StorageUsage {
+	pub addr: ComnAddr,
+	pub used_hectobyte: i64,
+	pub free_hectobyte: i64,
+	pub extra_hectobyte: i64,
+	// free and extra, capped at max_hectobyte
+	pub quota_hectobyte: i64,
+	pub max_hectobyte: i64,
+}
+
+#[derive(Debug)]
+pub enum QuotaErr {
+	AlreadyReported,
+	BadData,
+	LowAmount,
+	// more quota can be bought
+	Exceeded,
+	// quota is at max_hectobyte
+	Full,
+}
+
+impl Error for QuotaErr {}
+
+impl fmt::Display for QuotaErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QuotaErr::AlreadyReported => write!(f, "Quota was already bought with the nonce."),
+            QuotaErr::BadData => write!(f, "blocks or addr are not right."),
+            QuotaErr::LowAmount => write!(f, "Price of the quota is more than balance."),
+            QuotaErr::Exceeded => write!(f, "storage quota is exceeded"),
+            QuotaErr::Full => write!(f, "storage quota is exceeded and at its max"),
+        }
+    }
+}
+
+// hectobytes of items addr pays for, see `reserve`, co-owners of a crate
+// aren't charged for it again
+async fn used_hectobyte(conn: &mut PgConnection, addr_id: Uuid) -> i64 {
+	sqlx::query_scalar::<_, i64>(
+		"
+		SELECT COALESCE(SUM(ci.size_hectobyte), 0)::int8 FROM crate_item ci
+		WHERE $1 = COALESCE((
+			SELECT ca.addr_id FROM crate_access ca
+			WHERE ca.crate_id = ci.crate_id AND ca.type = 'owner'
+			ORDER BY ca.created LIMIT 1
+		), ci.added_by)
+		"
+	)
+	.bind(addr_id)
+	.fetch_one(conn)
+	.await
+	.unwrap()
+}
+
+async fn extra_hectobyte(conn: &mut PgConnection, addr_id: Uuid) -> i64 {
+	sqlx::query_scalar::<_, i64>(
+		"SELECT COALESCE((SELECT extra_hectobyte FROM storage_quota WHERE addr_id = $1), 0)::int8"
+	)
+	.bind(addr_id)
+	.fetch_one(conn)
+	.await
+	.unwrap()
+}
+
+impl StorageUsage {
+	async fn of(conn: &mut PgConnection, addr: &ComnAddr) -> StorageUsage {
+		let conf = &get_config().await.quota;
+		let addr_id = Uuid::parse_str(&addr.to_uuid()).unwrap();
+		let extra = extra_hectobyte(&mut *conn, addr_id).await;
+		StorageUsage {
+			addr: addr.clone(),
+			used_hectobyte: used_hectobyte(&mut *conn, addr_id).await,
+			free_hectobyte: conf.free_hectobyte,
+			extra_hectobyte: extra,
+			quota_hectobyte: (conf.free_hectobyte + extra).min(conf.max_hectobyte),
+			max_hectobyte: conf.max_hectobyte,
+		}
+	}
+}
+
+// the quota row of addr, locked until `conn`'s transaction ends
+async fn lock_quota(conn: &mut PgConnection, addr_id: Uuid) {
+	sqlx::query("INSERT INTO storage_quota(addr_id) VALUES($1) ON CONFLICT DO NOTHING")
+		.bind(addr_id)
+		.execute(&mut *conn)
+		.await
+		.unwrap();
+	sqlx::query("SELECT addr_id FROM storage_quota WHERE addr_id = $1 FOR UPDATE")
+		.bind(addr_id)
+		.execute(&mut *conn)
+		.await
+		.unwrap();
+}
+
+pub async fn get_usage(addr: &ComnAddr) -> StorageUsage {
+	let mut conn = db().await.acquire().await.unwrap();
+	StorageUsage::of(&mut conn, addr).await
+}
+
+/// Checks that `size_hectobyte` more fits the quota of whoever pays for the crate,
+/// its first owner or the writer when nobody owns it.
+///
+/// Locks the payer's quota row until `conn`'s transaction ends, so concurrent
+/// writes can't both squeeze into the last of the quota. The broker's own crates,
+/// like transaction histories, have no quota.
+pub async fn reserve(
+	conn: &mut PgConnection,
+	crate_id: Uuid,
+	writer: &ComnAddr,
+	size_hectobyte: i64,
+) -> Result<(), QuotaErr> {
+	let payer = sqlx::query_scalar::<_, Uuid>(
+		"
+		SELECT COALESCE((
+			SELECT addr_id FROM crate_access
+			WHERE crate_id = $1 AND type = 'owner'
+			ORDER BY created LIMIT 1
+		), $2::uuid)
+		"
+	)
+	.bind(crate_id)
+	.bind(writer.to_uuid())
+	.fetch_one(&mut *conn)
+	.await
+	.unwrap();
+	let payer_addr = ComnAddr::from_uuid(&payer.to_string()).unwrap();
+	if payer_addr == SpecialAddr::ComnCoin.value() || payer_addr == SpecialAddr::Config.value() {
+		return Ok(());
+	}
+	lock_quota(&mut *conn, payer).await;
+
+	let usage = StorageUsage::of(conn, &payer_addr).await;
+	if usage.used_hectobyte + size_hectobyte <= usage.quota_hectobyte {
+		Ok(())
+	} else if usage.quota_hectobyte >= usage.max_hectobyte {
+		Err(QuotaErr::Full)
+	} else {
+		Err(QuotaErr::Exceeded)
+	}
+}
+
+impl BuyQuotaReq {
+	/// Pays for the blocks and adds them to the quota in one transaction.
+	///
+	/// The quota row is locked first, so concurrent buys can't both pass the
+	/// max_hectobyte check, then the balances, like uploads paying a fee do.
+	pub async fn buy(&self) -> Result<StorageUsage, QuotaErr> {
+		let conf = &get_config().await.quota;
+		// blocks come from the request, a wrapped amount would be a transfer the wrong way
+		let blocks = i64::try_from(self.blocks).map_err(|_| QuotaErr::BadData)?;
+		let extra = blocks.checked_mul(conf.block_hectobyte).ok_or(QuotaErr::BadData)?;
+		let amount = blocks
+			.checked_mul(conf.block_price)
+			.and_then(|amount| u64::try_from(amount).ok())
+			.ok_or(QuotaErr::BadData)?;
+		let addr_id = Uuid::parse_str(&self.addr.to_uuid()).unwrap();
+		let mut tx = db().await.begin().await.unwrap();
+		lock_quota(&mut tx, addr_id).await;
+		let total = conf.free_hectobyte
+			.checked_add(extra_hectobyte(&mut tx, addr_id).await)
+			.and_then(|total| total.checked_add(extra))
+			.ok_or(QuotaErr::BadData)?;
+		if blocks == 0 || total > conf.max_hectobyte {
+			return Err(QuotaErr::BadData);
+		}
+		let transaction = Transaction {
+			amount,
+			receiver: conf.treasury.clone(),
+			sender: self.addr.clone(),
+			comment: Some(format!("{} hectobytes of storage", extra)),
+			nonce: format!("quota:{}:{}", self.addr.to_uuid(), self.nonce),
+		};
+		let result = match transaction.transfer_coins_in(&mut tx).await {
+			Ok(result) => result,
+			Err(TransactionErr::AlreadyReported) => return Err(QuotaErr::AlreadyReported),
+			Err(TransactionErr::LowAmount) => return Err(QuotaErr::LowAmount),
+			Err(_) => return Err(QuotaErr::BadData),
+		};
+		sqlx::query(
+			"
+			UPDATE storage_quota
+			SET extra_hectobyte = extra_hectobyte + $2, updated = CURRENT_TIMESTAMP
+			WHERE addr_id = $1
+			"
+		)
+		.bind(addr_id)
+		.bind(extra)
+		.execute(&mut *tx)
+		.await
+		.unwrap();
+		let usage = StorageUsage::of(&mut tx, &self.addr).await;
+		tx.commit().await.unwrap();
+		transaction.record_history(&result).await;
+		Ok(usage)
+	}
+}
@@ -0,0 +1,311 @@
+use chrono::{DateTime, Utc};
+use std::{error::Error, fmt};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use sqlx::{Connection, FromRow, Postgres};
+use tokio::time::interval;
+use crate::{
+	comn_addr::ComnAddr, db::db,
+	update::coin::{Transaction, TransactionErr},
+};
+
+// consecutive failed runs after which an order stops being retried
+const MAX_FAILURES: i32 = 3;
+
+#[derive(sqlx::Type, Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[sqlx(type_name = "STANDING_ORDER_STATUS")]
+#[sqlx(rename_all = "lowercase")]
+pub enum StandingOrderStatus {
+	Active,
+	Cancelled,
+	Completed,
+	Failed,
+}
+
+/// Signed request to pay `amount` every `interval_secs` starting at `start`
+/// until `ends` or `max_count` runs, whichever comes first
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StandingOrderReq {
+	pub sender: ComnAddr,
+	pub receiver: ComnAddr,
+	pub amount: u64,
+	pub comment: Option<String>,
+	pub nonce: String,
+	pub interval_secs: u64,
+	pub start: Option<DateTime<Utc>>,
+	pub ends: Option<DateTime<Utc>>,
+	pub max_count: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CancelStandingOrderReq {
+	pub id: Uuid,
+	pub sender: ComnAddr,
+}
+
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct StandingOrder {
+	pub id: Uuid,
+	pub sender: Uuid,
+	pub receiver: Uuid,
+	pub amount: i64,
+	pub comment: Option<String>,
+	pub nonce: String,
+	pub interval_secs: i64,
+	pub next_run: DateTime<Utc>,
+	pub ends: Option<DateTime<Utc>>,
+	pub max_count: Option<i32>,
+	pub run_count: i32,
+	pub failures: i32,
+	pub status: StandingOrderStatus,
+	pub created: DateTime<Utc>,
+	pub updated: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct StandingOrderRes {
+	pub id: Uuid,
+	pub sender: ComnAddr,
+	pub receiver: ComnAddr,
+	pub amount: i64,
+	pub comment: Option<String>,
+	pub interval_secs: i64,
+	pub next_run: DateTime<Utc>,
+	pub ends: Option<DateTime<Utc>>,
+	pub max_count: Option<i32>,
+	pub run_count: i32,
+	pub failures: i32,
+	pub status: StandingOrderStatus,
+	pub created: DateTime<Utc>,
+}
+
+impl From<StandingOrder> for StandingOrderRes {
+  fn from(a: StandingOrder) -> Self {
+    Self {
+			id: a.id,
+			sender: ComnAddr::from_uuid(&a.sender.to_string()).unwrap(),
+			receiver: ComnAddr::from_uuid(&a.receiver.to_string()).unwrap(),
+			amount: a.amount,
+			comment: a.comment,
+			interval_secs: a.interval_secs,
+			next_run: a.next_run,
+			ends: a.ends,
+			max_count: a.max_count,
+			run_count: a.run_count,
+			failures: a.failures,
+			status: a.status,
+			created: a.created,
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum StandingOrderErr {
+	AlreadyReported,
+	BadData,
+	NotFound,
+}
+
+impl Error for StandingOrderErr {}
+
+impl fmt::Display for StandingOrderErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StandingOrderErr::AlreadyReported => write!(f, "A standing order already exists with the nonce."),
+            StandingOrderErr::BadData => write!(f, "amount, interval and max count should be more than zero and in range."),
+            StandingOrderErr::NotFound => write!(f, "no active standing order or receiver found"),
+        }
+    }
+}
+
+impl StandingOrderReq {
+	pub async fn add(&self) -> Result<StandingOrder, StandingOrderErr> {
+		if self.amount == 0 || self.interval_secs == 0 || self.max_count == Some(0) {
+			return Err(StandingOrderErr::BadData);
+		}
+		let amount = i64::try_from(self.amount).map_err(|_| StandingOrderErr::BadData)?;
+		let interval_secs = i64::try_from(self.interval_secs).map_err(|_| StandingOrderErr::BadData)?;
+		let max_count = self.max_count
+			.map(i32::try_from)
+			.transpose()
+			.map_err(|_| StandingOrderErr::BadData)?;
+		match sqlx::query_as::<_, StandingOrder>(
+			"
+			INSERT INTO standing_order(sender, receiver, amount, comment, nonce,
+				interval_secs, next_run, ends, max_count)
+			VALUES($1::uuid, $2::uuid, $3, $4, $5, $6, $7, $8, $9)
+			ON CONFLICT DO NOTHING
+			RETURNING *
+			"
+		)
+		.bind(self.sender.to_uuid())
+		.bind(self.receiver.to_uuid())
+		.bind(amount)
+		.bind(self.comment.clone())
+		.bind(self.nonce.clone())
+		.bind(interval_secs)
+		.bind(self.start.unwrap_or(Utc::now()))
+		.bind(self.ends)
+		.bind(max_count)
+		.fetch_optional(db().await)
+		.await
+		{
+			Ok(order) => order.ok_or(StandingOrderErr::AlreadyReported),
+			// the receiver isn't an addr
+			Err(sqlx::Error::Database(e)) if e.is_foreign_key_violation() => Err(StandingOrderErr::NotFound),
+			Err(e) => panic!("{}", e),
+		}
+	}
+}
+
+impl CancelStandingOrderReq {
+	pub async fn cancel(&self) -> Result<StandingOrder, StandingOrderErr> {
+		sqlx::query_as::<_, StandingOrder>(
+			"
+			UPDATE standing_order
+			SET status = 'cancelled', updated = CURRENT_TIMESTAMP
+			WHERE id = $1 AND sender = $2::uuid AND status = 'active'
+			RETURNING *
+			"
+		)
+		.bind(self.id)
+		.bind(self.sender.to_uuid())
+		.fetch_optional(db().await)
+		.await
+		.unwrap()
+		.ok_or(StandingOrderErr::NotFound)
+	}
+}
+
+pub async fn list_standing_orders(sender: &ComnAddr) -> Vec<StandingOrder> {
+	sqlx::query_as::<_, StandingOrder>(
+		"SELECT * FROM standing_order WHERE sender = $1::uuid ORDER BY created DESC"
+	)
+	.bind(sender.to_uuid())
+	.fetch_all(db().await)
+	.await
+	.unwrap()
+}
+
+impl StandingOrder {
+	// every run gets its own nonce so a run that is retried after a crash is
+	// reported by the ledger instead of paying twice
+	pub fn run_nonce(&self) -> String {
+		format!("{}:{}:{}", self.id, self.nonce, self.run_count)
+	}
+
+	// runs the order its transaction claimed and commits it, the transfer and
+	// the count move together so a run is neither skipped nor paid twice
+	async fn run(&self, mut tx: sqlx::Transaction<'static, Postgres>) -> Result<StandingOrder, TransactionErr> {
+		let transaction = Transaction {
+			amount: self.amount as u64,
+			receiver: ComnAddr::from_uuid(&self.receiver.to_string()).unwrap(),
+			sender: ComnAddr::from_uuid(&self.sender.to_string()).unwrap(),
+			comment: self.comment.clone(),
+			nonce: self.run_nonce(),
+		};
+		// a refused transfer is rolled back to here, the order stays claimed
+		let mut transfer = tx.begin().await.unwrap();
+		let result = transaction.transfer_coins_in(&mut transfer).await;
+		match result {
+			Ok(_) | Err(TransactionErr::AlreadyReported) => transfer.commit().await.unwrap(),
+			Err(_) => transfer.rollback().await.unwrap(),
+		}
+		match result {
+			Ok(_) | Err(TransactionErr::AlreadyReported) => {
+				let order = sqlx::query_as::<_, StandingOrder>(
+					"
+					UPDATE standing_order
+					SET
+						run_count = run_count + 1,
+						failures = 0,
+						next_run = next_run + interval_secs * interval '1 second',
+						status = CASE
+							WHEN max_count IS NOT NULL AND run_count + 1 >= max_count
+								THEN 'completed'::STANDING_ORDER_STATUS
+							WHEN ends IS NOT NULL AND next_run + interval_secs * interval '1 second' > ends
+								THEN 'completed'::STANDING_ORDER_STATUS
+							ELSE status
+						END,
+						updated = CURRENT_TIMESTAMP
+					WHERE id = $1
+					RETURNING *
+					"
+				)
+				.bind(self.id)
+				.fetch_one(&mut *tx)
+				.await
+				.unwrap();
+				tx.commit().await.unwrap();
+				if let Ok(query) = &result {
+					transaction.record_history(query).await;
+				}
+				Ok(order)
+			}
+			Err(e) => {
+				// the run is skipped until the next interval and keeps its nonce
+				sqlx::query(
+					"
+					UPDATE standing_order
+					SET
+						failures = failures + 1,
+						next_run = next_run + interval_secs * interval '1 second',
+						status = CASE
+							WHEN failures + 1 >= $2 THEN 'failed'::STANDING_ORDER_STATUS
+							ELSE status
+						END,
+						updated = CURRENT_TIMESTAMP
+					WHERE id = $1
+					"
+				)
+				.bind(self.id)
+				.bind(MAX_FAILURES)
+				.execute(&mut *tx)
+				.await
+				.unwrap();
+				tx.commit().await.unwrap();
+				Err(e)
+			}
+		}
+	}
+}
+
+pub async fn run_due_orders() -> Vec<Result<StandingOrder, TransactionErr>> {
+	let mut results = Vec::new();
+	// each order runs at most once a call, even when it is behind
+	let mut claimed: Vec<Uuid> = Vec::new();
+	loop {
+		let mut tx = db().await.begin().await.unwrap();
+		// orders another worker holds are skipped, not waited on and run again
+		let order = sqlx::query_as::<_, StandingOrder>(
+			"
+			SELECT * FROM standing_order
+			WHERE status = 'active' AND next_run <= CURRENT_TIMESTAMP AND id <> ALL($1)
+			ORDER BY next_run
+			LIMIT 1
+			FOR UPDATE SKIP LOCKED
+			"
+		)
+		.bind(&claimed)
+		.fetch_optional(&mut *tx)
+		.await
+		.unwrap();
+		let Some(order) = order else { break };
+		claimed.push(order.id);
+		results.push(order.run(tx).await);
+	}
+	results
+}
+
+pub async fn worker(period: Duration) {
+	let mut ticker = interval(period);
+	loop {
+		ticker.tick().await;
+		for result in run_due_orders().await {
+			if let Err(e) = result {
+				println!("standing order failed {}", e);
+			}
+		}
+	}
+}
@@ -0,0 +1,220 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::types::Uuid;
+use sqlx::FromRow;
+use crate::{
+	comn_addr::ComnAddr, db::db,
+	update::coin::{Transaction, TransactionErr, TransactionQuery},
+};
+
+/// Coins bought from a payment provider, paid out of `treasury`.
+///
+/// `payment_id` identifies the payment at the provider and is the transfer nonce,
+/// so a webhook delivered more than once only credits the coins once.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TopUpReq {
+	pub provider: String,
+	pub payment_id: String,
+	// what refunds reference the payment with, i.e. the stripe payment intent
+	pub charge_ref: Option<String>,
+	pub addr: ComnAddr,
+	pub amount: u64,
+	pub treasury: ComnAddr,
+	pub comment: String,
+}
+
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct TopUp {
+	pub id: Uuid,
+	pub provider: String,
+	pub payment_id: String,
+	pub charge_ref: Option<String>,
+	pub addr_id: Uuid,
+	pub amount: i64,
+	pub clawed_back: i64,
+	pub created: DateTime<Utc>,
+	pub updated: DateTime<Utc>,
+}
+
+impl TopUpReq {
+	pub async fn fulfil(&self) -> Result<TransactionQuery, TransactionErr> {
+		let transaction = Transaction {
+			amount: self.amount,
+			receiver: self.addr.clone(),
+			sender: self.treasury.clone(),
+			comment: Some(self.comment.clone()),
+			nonce: format!("{}:{}", self.provider, self.payment_id),
+		};
+		let result = transaction.transfer_coins().await;
+		if let Ok(_) | Err(TransactionErr::AlreadyReported) = result {
+			sqlx::query(
+				"
+				INSERT INTO top_up(provider, payment_id, charge_ref, addr_id, amount)
+				VALUES($1, $2, $3, $4::uuid, $5)
+				ON CONFLICT DO NOTHING
+				"
+			)
+			.bind(self.provider.clone())
+			.bind(self.payment_id.clone())
+			.bind(self.charge_ref.clone())
+			.bind(self.addr.to_uuid())
+			.bind(self.amount as i64)
+			.execute(db().await)
+			.await
+			.unwrap();
+		}
+		result
+	}
+}
+
+pub async fn get_top_up(provider: &str, payment_ref: &str) -> Option<TopUp> {
+	sqlx::query_as::<_, TopUp>(
+		"
+		SELECT * FROM top_up
+		WHERE provider = $1 AND (payment_id = $2 OR charge_ref = $2)
+		"
+	)
+	.bind(provider)
+	.bind(payment_ref)
+	.fetch_optional(db().await)
+	.await
+	.unwrap()
+}
+
+impl TopUp {
+	/// Moves coins back to the treasury until `total` of this top up is clawed back.
+	///
+	/// `total` is cumulative like a provider's refunded amount, `key` tells the
+	/// refunds of a payment apart and makes the nonce.
+	pub async fn claw_back(
+		&self,
+		total: i64,
+		key: &str,
+		treasury: &ComnAddr,
+		comment: &str,
+	) -> Result<i64, TransactionErr> {
+		// the row stays locked until the coins moved, like the allowance of
+		// transfer_from, so concurrent refunds can't claw back the same coins twice
+		let mut tx = db().await.begin().await.unwrap();
+		let clawed_back = sqlx::query_scalar::<_, i64>("SELECT clawed_back FROM top_up WHERE id = $1 FOR UPDATE")
+			.bind(self.id)
+			.fetch_one(&mut *tx)
+			.await
+			.unwrap();
+		let amount = total.min(self.amount) - clawed_back;
+		if amount <= 0 {
+			return Err(TransactionErr::AlreadyReported);
+		}
+		let transaction = Transaction {
+			amount: amount as u64,
+			receiver: treasury.clone(),
+			sender: ComnAddr::from_uuid(&self.addr_id.to_string()).unwrap(),
+			comment: Some(comment.to_string()),
+			nonce: format!("{}:{}:refund:{}", self.provider, self.payment_id, key),
+		};
+		transaction.transfer_coins().await?;
+		sqlx::query(
+			"
+			UPDATE top_up
+			SET clawed_back = clawed_back + $2, updated = CURRENT_TIMESTAMP
+			WHERE id = $1
+			"
+		)
+		.bind(self.id)
+		.bind(amount)
+		.execute(&mut *tx)
+		.await
+		.unwrap();
+		tx.commit().await.unwrap();
+		Ok(amount)
+	}
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone, Copy)]
+pub enum TopUpStatus {
+	// checkout created, not paid yet
+	Pending,
+	Paid,
+	PartlyRefunded,
+	Refunded,
+}
+
+/// Checkout session created by the broker for `addr`, see `payment::stripe::Stripe::checkout`
+#[derive(FromRow, Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct Checkout {
+	pub id: Uuid,
+	pub provider: String,
+	pub payment_id: String,
+	pub addr_id: Uuid,
+	pub amount: i64,
+	pub url: Option<String>,
+	pub created: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct CheckoutRes {
+	pub id: String,
+	pub addr: ComnAddr,
+	pub amount: i64,
+	pub url: Option<String>,
+	pub status: TopUpStatus,
+	pub clawed_back: i64,
+	pub created: DateTime<Utc>,
+}
+
+impl Checkout {
+	pub async fn add(
+		provider: &str,
+		payment_id: &str,
+		addr: &ComnAddr,
+		amount: i64,
+		url: Option<String>,
+	) -> Checkout {
+		sqlx::query_as::<_, Checkout>(
+			"
+			INSERT INTO checkout(provider, payment_id, addr_id, amount, url)
+			VALUES($1, $2, $3::uuid, $4, $5)
+			RETURNING *
+			"
+		)
+		.bind(provider)
+		.bind(payment_id)
+		.bind(addr.to_uuid())
+		.bind(amount)
+		.bind(url)
+		.fetch_one(db().await)
+		.await
+		.unwrap()
+	}
+
+	// fulfilment of the checkout as told by the provider's webhooks
+	pub async fn status(self) -> CheckoutRes {
+		let top_up = get_top_up(&self.provider, &self.payment_id).await;
+		let (status, clawed_back) = match top_up {
+			None => (TopUpStatus::Pending, 0),
+			Some(t) if t.clawed_back == 0 => (TopUpStatus::Paid, 0),
+			Some(t) if t.clawed_back < t.amount => (TopUpStatus::PartlyRefunded, t.clawed_back),
+			Some(t) => (TopUpStatus::Refunded, t.clawed_back),
+		};
+		CheckoutRes {
+			id: self.payment_id,
+			addr: ComnAddr::from_uuid(&self.addr_id.to_string()).unwrap(),
+			amount: self.amount,
+			url: self.url,
+			status,
+			clawed_back,
+			created: self.created,
+		}
+	}
+}
+
+pub async fn get_checkout(provider: &str, payment_id: &str) -> Option<Checkout> {
+	sqlx::query_as::<_, Checkout>(
+		"SELECT * FROM checkout WHERE provider = $1 AND payment_id = $2"
+	)
+	.bind(provider)
+	.bind(payment_id)
+	.fetch_optional(db().await)
+	.await
+	.unwrap()
+}
@@ -0,0 +1,311 @@
+use once_cell::sync::Lazy;
+use regex_lite::Regex;
+use std::{error::Error, fmt};
+use crate::db::get_config;
+
+#[derive(Debug, PartialEq)]
+pub enum ValidationErr {
+	// the data doesn't start like the media type does, with what it looks like if known
+	Mismatch(Option<&'static str>),
+	// not UTF-8 from the byte at
+	BadUtf8(usize),
+	BadJson(String),
+	// script the broker would have stripped or can't strip, upload the data
+	// to have it sanitized
+	ScriptContent,
+}
+
+impl Error for ValidationErr {}
+
+impl fmt::Display for ValidationErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationErr::Mismatch(Some(sniffed)) => write!(f, "data is {} not the media type given", sniffed),
+            ValidationErr::Mismatch(None) => write!(f, "data is not the media type given"),
+            ValidationErr::BadUtf8(at) => write!(f, "data is not UTF-8 from byte {}", at),
+            ValidationErr::BadJson(e) => write!(f, "data is not JSON, {}", e),
+            ValidationErr::ScriptContent => write!(f, "data has script content"),
+        }
+    }
+}
+
+// magic bytes at an offset, all of a signature's parts have to match
+const SIGNATURES: &[(&str, &[(usize, &[u8])])] = &[
+	("image/png", &[(0, b"\x89PNG\r\n\x1a\n")]),
+	("image/apng", &[(0, b"\x89PNG\r\n\x1a\n")]),
+	("image/jpeg", &[(0, b"\xff\xd8\xff")]),
+	("image/gif", &[(0, b"GIF87a")]),
+	("image/gif", &[(0, b"GIF89a")]),
+	("image/webp", &[(0, b"RIFF"), (8, b"WEBP")]),
+	("image/avif", &[(4, b"ftypavif")]),
+	("image/avif", &[(4, b"ftypavis")]),
+	("video/mp4", &[(4, b"ftyp")]),
+	("application/pdf", &[(0, b"%PDF-")]),
+	("application/zip", &[(0, b"PK\x03\x04")]),
+	("application/zip", &[(0, b"PK\x05\x06")]),
+	("audio/mpeg", &[(0, b"ID3")]),
+	("audio/mpeg", &[(0, b"\xff\xfb")]),
+	("audio/mpeg", &[(0, b"\xff\xf3")]),
+	("audio/mpeg", &[(0, b"\xff\xf2")]),
+	("audio/webm", &[(0, b"\x1a\x45\xdf\xa3")]),
+	("video/webm", &[(0, b"\x1a\x45\xdf\xa3")]),
+	("audio/vorbis", &[(0, b"OggS")]),
+	("font/ttf", &[(0, b"\x00\x01\x00\x00")]),
+	("font/ttf", &[(0, b"true")]),
+	("font/otf", &[(0, b"OTTO")]),
+	("font/woff", &[(0, b"wOFF")]),
+	("font/woff2", &[(0, b"wOF2")]),
+];
+
+fn matches(data: &[u8], parts: &[(usize, &[u8])]) -> bool {
+	parts.iter().all(|(at, magic)| data.get(*at..at + magic.len()) == Some(*magic))
+}
+
+/// Media type the data starts like, the first one it does when several share magic bytes.
+pub fn sniff(data: &[u8]) -> Option<&'static str> {
+	SIGNATURES.iter().find(|(_, parts)| matches(data, parts)).map(|(media_type, _)| *media_type)
+}
+
+// types kept as text, they have to be UTF-8
+fn is_text(media_type: &str) -> bool {
+	media_type.starts_with("text/") || media_type == "application/json" || media_type == "image/svg+xml"
+}
+
+// types a browser runs script in
+fn is_markup(media_type: &str) -> bool {
+	media_type == "text/html" || media_type == "image/svg+xml"
+}
+
+static SCRIPT: Lazy<Vec<Regex>> = Lazy::new(|| {
+	["script", "iframe", "object", "embed"].iter().flat_map(|tag| [
+		Regex::new(&format!(r"(?is)<{}\b[^>]*>.*?</{}\s*>", tag, tag)).unwrap(),
+		Regex::new(&format!(r"(?i)<{}\b[^>]*>", tag)).unwrap(),
+	]).collect()
+});
+// browsers split attributes on `/` as well as whitespace, i.e. `<svg/onload=..>`
+static EVENT_HANDLER: Lazy<Regex> = Lazy::new(|| {
+	Regex::new(r#"(?i)[\s/]+on[a-z]+\s*=\s*("[^"]*"|'[^']*'|[^\s>]+)"#).unwrap()
+});
+static SCRIPT_URL: Lazy<Regex> = Lazy::new(|| {
+	Regex::new(r#"(?i)((?:xlink:)?href|src|action|formaction)\s*=\s*("\s*javascript:[^"]*"|'\s*javascript:[^']*'|javascript:[^\s>]*)"#).unwrap()
+});
+
+/// Markup without script elements, event handler attributes and javascript: urls.
+///
+/// It goes over the markup till nothing more is stripped, so pieces left around
+/// a stripped tag can't make up a new one.
+pub fn sanitize(markup: &str) -> String {
+	let mut markup = markup.to_string();
+	loop {
+		let mut clean = markup.clone();
+		for re in SCRIPT.iter() {
+			clean = re.replace_all(&clean, "").into_owned();
+		}
+		clean = EVENT_HANDLER.replace_all(&clean, "").into_owned();
+		clean = SCRIPT_URL.replace_all(&clean, "$1=\"#\"").into_owned();
+		if clean == markup {
+			return clean;
+		}
+		markup = clean;
+	}
+}
+
+// elements that run script or load documents, whatever their attributes
+const SCRIPT_ELEMENTS: &[&str] = &["script", "iframe", "frame", "object", "embed", "handler"];
+
+// text of an attribute value as a browser reads it, `&#58;` and `&colon;` are `:`
+fn decode_entities(value: &str) -> String {
+	let mut decoded = String::new();
+	let mut rest = value;
+	while let Some(at) = rest.find('&') {
+		decoded.push_str(&rest[..at]);
+		rest = &rest[at + 1..];
+		let (c, len) = if let Some(num) = rest.strip_prefix('#') {
+			let (digits, radix, skip) = match num.strip_prefix(['x', 'X']) {
+				Some(hex) => (hex, 16, 2),
+				None => (num, 10, 1),
+			};
+			let end = digits.find(|c: char| !c.is_digit(radix)).unwrap_or(digits.len());
+			let c = u32::from_str_radix(&digits[..end], radix).ok().and_then(char::from_u32);
+			(c, skip + end)
+		} else {
+			let end = rest.find(|c: char| !c.is_ascii_alphanumeric()).unwrap_or(rest.len());
+			let c = match rest[..end].to_lowercase().as_str() {
+				"colon" => Some(':'),
+				"tab" => Some('\t'),
+				"newline" => Some('\n'),
+				"lpar" => Some('('),
+				"rpar" => Some(')'),
+				_ => None,
+			};
+			(c, end)
+		};
+		match c {
+			Some(c) => {
+				decoded.push(c);
+				rest = &rest[len..];
+				rest = rest.strip_prefix(';').unwrap_or(rest);
+			}
+			None => decoded.push('&'),
+		}
+	}
+	decoded.push_str(rest);
+	decoded
+}
+
+fn is_script_value(value: &str) -> bool {
+	let value: String = decode_entities(value)
+		.chars()
+		.filter(|c| !c.is_whitespace() && !c.is_control())
+		.collect::<String>()
+		.to_lowercase();
+	value.contains("javascript:") || value.contains("vbscript:")
+}
+
+/// Whether markup still has script after it's been sanitized.
+///
+/// Tags are read the way browsers tokenize them, attributes split on whitespace
+/// and `/` with their values decoded, so script that slips past the patterns of
+/// `sanitize` is found here. Any element that runs script, `on*` attribute or
+/// attribute holding a javascript: url counts, svg animation values included.
+pub fn has_script(markup: &str) -> bool {
+	let bytes = markup.as_bytes();
+	let mut at = 0;
+	while let Some(open) = markup[at..].find('<') {
+		at += open + 1;
+		for (start, end) in [("!--", "-->"), ("![CDATA[", "]]>")] {
+			if markup[at..].starts_with(start) {
+				at = markup[at..].find(end).map_or(markup.len(), |e| at + e + end.len());
+				break;
+			}
+		}
+		if at >= markup.len() || !bytes[at].is_ascii_alphabetic() {
+			continue;
+		}
+		let name_end = markup[at..]
+			.find(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>')
+			.map_or(markup.len(), |e| at + e);
+		let name = markup[at..name_end].to_lowercase();
+		if SCRIPT_ELEMENTS.contains(&name.as_str()) {
+			return true;
+		}
+		at = name_end;
+		// attributes till the end of the tag
+		loop {
+			while at < bytes.len() && (bytes[at].is_ascii_whitespace() || bytes[at] == b'/') {
+				at += 1;
+			}
+			if at >= bytes.len() || bytes[at] == b'>' {
+				break;
+			}
+			let attr_end = markup[at + 1..]
+				.find(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>' || c == '=')
+				.map_or(markup.len(), |e| at + 1 + e);
+			let attr = markup[at..attr_end].to_lowercase();
+			if attr.starts_with("on") {
+				return true;
+			}
+			at = attr_end;
+			while at < bytes.len() && bytes[at].is_ascii_whitespace() {
+				at += 1;
+			}
+			if at >= bytes.len() || bytes[at] != b'=' {
+				continue;
+			}
+			at += 1;
+			while at < bytes.len() && bytes[at].is_ascii_whitespace() {
+				at += 1;
+			}
+			let value = match bytes.get(at) {
+				Some(&quote) if quote == b'"' || quote == b'\'' => {
+					let end = markup[at + 1..].find(quote as char).map_or(markup.len(), |e| at + 1 + e);
+					let value = &markup[at + 1..end];
+					at = (end + 1).min(markup.len());
+					value
+				}
+				_ => {
+					let end = markup[at..]
+						.find(|c: char| c.is_ascii_whitespace() || c == '>')
+						.map_or(markup.len(), |e| at + e);
+					let value = &markup[at..end];
+					at = end;
+					value
+				}
+			};
+			if is_script_value(value) {
+				return true;
+			}
+		}
+	}
+	false
+}
+
+/// Checks data is what `media_type` says it is, returning the data to store.
+///
+/// Types with magic bytes have to start with them, text has to be UTF-8 and
+/// JSON has to parse. Html and svg come back sanitized when the config says so,
+/// and are refused if they still have script then.
+pub async fn validate(media_type: &str, data: Vec<u8>) -> Result<Vec<u8>, ValidationErr> {
+	let conf = &get_config().await.validation;
+	if conf.sniff {
+		let mut signatures = SIGNATURES.iter().filter(|(t, _)| *t == media_type).peekable();
+		if signatures.peek().is_some() && !signatures.any(|(_, parts)| matches(&data, parts)) {
+			return Err(ValidationErr::Mismatch(sniff(&data)));
+		}
+	}
+	if !is_text(media_type) {
+		return Ok(data);
+	}
+	let text = String::from_utf8(data).map_err(|e| ValidationErr::BadUtf8(e.utf8_error().valid_up_to()))?;
+	if media_type == "application/json" {
+		if let Err(e) = serde_json::from_str::<serde_json::Value>(&text) {
+			return Err(ValidationErr::BadJson(e.to_string()));
+		}
+	}
+	if conf.sanitize && is_markup(media_type) {
+		let clean = sanitize(&text);
+		if has_script(&clean) {
+			return Err(ValidationErr::ScriptContent);
+		}
+		return Ok(clean.into_bytes());
+	}
+	Ok(text.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn sniff_and_sanitize() {
+		assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\x00\x00"), Some("image/png"));
+		assert_eq!(sniff(b"RIFF\x00\x00\x00\x00WEBPVP8 "), Some("image/webp"));
+		assert_eq!(sniff(b"\x00\x00\x00\x1cftypavif"), Some("image/avif"));
+		assert_eq!(sniff(b"%PDF-1.7"), Some("application/pdf"));
+		assert_eq!(sniff(b"{\"a\": 1}"), None);
+
+		assert_eq!(
+			sanitize(r#"<svg onload="alert(1)"><script>alert(2)</script><a href=" javascript:x">a</a></svg>"#),
+			r##"<svg><a href="#">a</a></svg>"##
+		);
+		assert_eq!(sanitize("<scr<script></script>ipt>alert(1)</script>"), "alert(1)</script>");
+		assert_eq!(sanitize("<p class=\"one\">on=1</p>"), "<p class=\"one\">on=1</p>");
+		assert!(!has_script("<p class=\"one\">on=1, javascript is fun</p>"));
+		assert!(!has_script(r##"<svg><a href="#">a</a><!-- <script> --></svg>"##));
+
+		// attributes split on `/` are stripped too
+		assert_eq!(sanitize("<svg/onload=alert(1)>"), "<svg>");
+		assert_eq!(sanitize("<img/src=x/onerror=alert(1)>"), "<img/src=x>");
+		// what the patterns miss is found once the markup is read like a browser does
+		for bypass in [
+			"<a href=\"javascript&#58;alert(1)\">a</a>",
+			"<a href=\"java&#x09;script&colon;alert(1)\">a</a>",
+			"<a href=\"&#106;avascript:alert(1)\">a</a>",
+			"<svg><animate attributeName=href values=javascript:alert(1) /><a><text>a</text></a></svg>",
+			"<svg><set attributeName=\"href\" to=\"javascript:alert(1)\"/></svg>",
+			"<handler type=\"application/ecmascript\">alert(1)</handler>",
+		] {
+			assert!(has_script(&sanitize(bypass)), "{}", bypass);
+		}
+	}
+}
@@ -0,0 +1,160 @@
+mod common;
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use common::make_auth_header;
+use comn_broker::{
+	compression::{unpack, ZSTD}, db::S3Config,
+	store::{chunk_store, migrate, ChunkStore, StoreErr, local::LocalStore, s3::S3Store},
+	CrateItem,
+};
+use once_cell::sync::Lazy;
+use salvo::http::header::AUTHORIZATION;
+use salvo::http::Method;
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+const S3_MOCK_ADDR: &str = "127.0.0.1:5802";
+
+static OBJECTS: Lazy<Mutex<HashMap<String, Vec<u8>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn s3_config(secret_key: &str) -> S3Config {
+	S3Config {
+		endpoint: format!("http://{}", S3_MOCK_ADDR),
+		bucket: "comn-chunks".to_string(),
+		region: "us-east-1".to_string(),
+		access_key: "minio".to_string(),
+		secret_key: secret_key.to_string(),
+	}
+}
+
+// a bucket of an S3 compatible service, checking signatures like MinIO does
+#[handler]
+async fn mock_object(req: &mut Request, res: &mut Response) {
+	let body = req.payload().await.unwrap().to_vec();
+	let key = req.param::<String>("key").unwrap();
+	let header = |name: &str| {
+		req.headers().get(name).and_then(|h| h.to_str().ok()).unwrap_or("").to_string()
+	};
+	let now = match NaiveDateTime::parse_from_str(&header("x-amz-date"), "%Y%m%dT%H%M%SZ") {
+		Ok(date) => Utc.from_utc_datetime(&date),
+		Err(_) => return res.status_code(StatusCode::FORBIDDEN).render(""),
+	};
+	let payload_hash = hex::encode(Sha256::digest(&body));
+	let expected = S3Store::new(s3_config("minio-secret"))
+		.authorization(req.method().as_str(), req.uri().path(), &header("host"), &payload_hash, now);
+	if header("authorization") != expected || header("x-amz-content-sha256") != payload_hash {
+		return res.status_code(StatusCode::FORBIDDEN).render("");
+	}
+
+	let mut objects = OBJECTS.lock().unwrap();
+	match *req.method() {
+		Method::PUT => {
+			objects.insert(key, body);
+		}
+		Method::GET => match objects.get(&key) {
+			Some(data) => res.write_body(data.clone()).unwrap(),
+			None => res.status_code(StatusCode::NOT_FOUND).render(""),
+		},
+		Method::HEAD => {
+			if !objects.contains_key(&key) {
+				res.status_code(StatusCode::NOT_FOUND);
+			}
+		}
+		Method::DELETE => {
+			objects.remove(&key);
+			res.status_code(StatusCode::NO_CONTENT);
+		}
+		_ => res.status_code(StatusCode::METHOD_NOT_ALLOWED).render(""),
+	}
+}
+
+#[sqlx::test(fixtures("crate_write"), migrator = "comn_broker::MIGRATOR")]
+async fn test_s3_store(_pool: PgPool) -> sqlx::Result<()> {
+	// config is read once per process, so the backend is switched before anything reads it
+	sqlx::query(
+		"
+		UPDATE crate_item SET data_json = jsonb_set(jsonb_set(data_json, '{store,backend}', '\"s3\"'),
+			'{store,s3}', $1)
+		WHERE id = '00000000000000000000000000000000'
+		"
+	)
+	.bind(serde_json::to_value(s3_config("minio-secret")).unwrap())
+	.execute(&_pool)
+	.await?;
+	common::setup(_pool).await;
+	let mock = Router::with_path("comn-chunks/<key>")
+		.get(mock_object)
+		.put(mock_object)
+		.head(mock_object)
+		.delete(mock_object);
+	let acceptor = TcpListener::new(S3_MOCK_ADDR).bind().await;
+	tokio::spawn(Server::new(acceptor).serve(mock));
+
+	let store = chunk_store().await;
+	assert_eq!(store.name(), "s3");
+	let wrong_secret = S3Store::new(s3_config("guessed"));
+	assert_eq!(wrong_secret.put("0a0b", b"data").await, Err(StoreErr::Failed));
+	assert_eq!(store.put("../escape", b"data").await, Err(StoreErr::BadKey));
+	assert_eq!(store.get("0a0b").await, Err(StoreErr::NotFound));
+
+	let data = [7u8; 3_000];
+	let sha2_hash = Sha256::digest(&data).as_slice().to_vec();
+	let key = hex::encode(&sha2_hash);
+	let add_item_req = common::item_req("10000000000000000000000000000000", "≈a", "/a", "application/octet-stream", data.to_vec());
+	let mut res = common::add_item("NewKey", &add_item_req).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let item = res.take_json::<Uuid>().await.unwrap();
+	// the store holds the item's data as it was compressed
+	let stored = |bytes: Vec<u8>| unpack(Some(ZSTD), bytes).unwrap();
+	assert_eq!(OBJECTS.lock().unwrap().get(&key).cloned().map(stored), Some(data.to_vec()));
+
+	let mut res_get = TestClient::get(format!(
+		"http://{}/item?id={}",
+		&std::env::var("BIND_ADDR").unwrap(),
+		item
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_get.take_json::<CrateItem>().await.unwrap().data_file.unwrap(), data.to_vec());
+
+	// to the local store and back, running it again copies nothing
+	let local = LocalStore::new(&format!("/tmp/comn-store-{}", Uuid::new_v4().simple()));
+	assert_eq!(migrate(store, &local).await, Ok(1));
+	assert_eq!(migrate(store, &local).await, Ok(0));
+	assert_eq!(stored(std::fs::read(local.path(&key)).unwrap()), data.to_vec());
+	let shard = local.path(&key).parent().unwrap().to_path_buf();
+	assert_eq!(std::fs::read_dir(&shard).unwrap().count(), 1);
+	OBJECTS.lock().unwrap().clear();
+	assert_eq!(migrate(&local, store).await, Ok(1));
+	assert_eq!(OBJECTS.lock().unwrap().get(&key).cloned().map(stored), Some(data.to_vec()));
+
+	let res_delete = TestClient::delete(format!(
+		"http://{}/item?id={}",
+		&std::env::var("BIND_ADDR").unwrap(),
+		item
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_write", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_delete.status_code.unwrap(), StatusCode::OK);
+	assert!(!store.exists(&key).await.unwrap());
+
+	local.delete(&key).await.unwrap();
+	assert!(!local.exists(&key).await.unwrap());
+	let _ = std::fs::remove_dir_all(&local.root);
+
+	Ok(())
+}
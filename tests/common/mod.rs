@@ -1,9 +1,12 @@
 use comn_broker;
 use comn_broker::auth_token::AuthToken;
-use comn_broker::{db::set_db, utils::default_env};
-use salvo::http::header::HeaderValue;
+use comn_broker::{comn_addr::ComnAddr, db::set_db, utils::default_env, AddCrateItemReq};
+use salvo::http::header::{HeaderValue, AUTHORIZATION};
+use salvo::prelude::Response;
+use salvo::test::TestClient;
 use secp256k1::hashes::sha256;
 use secp256k1::{PublicKey, SecretKey};
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
 
 pub async fn setup(pool: PgPool) {
@@ -31,3 +34,36 @@ pub fn make_auth_header(
 	// println!("{:?}", auth_tok);
 	auth_tok.into()
 }
+
+// request adding the data at item_path of the crate as addr, with its hash
+#[allow(dead_code)]
+pub fn item_req(crate_id: &str, addr: &str, item_path: &str, media_type: &str, data: Vec<u8>) -> AddCrateItemReq {
+	AddCrateItemReq {
+		crate_id: crate_id.to_string(),
+		addr: ComnAddr::new(addr).unwrap(),
+		item_path: item_path.to_string(),
+		media_type: media_type.to_string(),
+		sha2_hash: Sha256::digest(&data).as_slice().to_vec(),
+		data: Some(data),
+		proof: None,
+		metadata: Default::default(),
+		tags: vec![],
+	}
+}
+
+// uploads the item with the key, in the storage scope
+#[allow(dead_code)]
+pub async fn add_item(key: &str, add_item_req: &AddCrateItemReq) -> Response {
+	TestClient::post(format!(
+		"http://{}/item",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(key, "comn.opus.ai", "crate_write,storage", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.json(add_item_req)
+	.send(comn_broker::route())
+	.await
+}
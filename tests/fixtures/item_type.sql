@@ -0,0 +1,9 @@
+WITH k AS (INSERT INTO key(pub_key) VALUES('\x0279b2f72735c1ffb42532a01c3b063b4e051295cf0cfa4c82479f44faea1d7fd4') RETURNING id),
+ak AS (INSERT INTO addr_key(addr_id, key_id) VALUES('00000000-0000-0000-0000-000000000000'::uuid, (SELECT id from k)) RETURNING key_id),
+a_writer AS (INSERT INTO addr(id, name) VALUES('00000000-0000-0000-0000-00000000000a'::uuid, 'onion') RETURNING id),
+k_writer AS (INSERT INTO key(pub_key) VALUES('\x03ba2c0e05c00185b2a793ee99476789572c558c532c62ffbed46e53b2b9a237ab'::bytea) RETURNING id),
+ak_writer AS (INSERT INTO addr_key(addr_id, key_id) VALUES((SELECT id from a_writer), (SELECT id from k_writer)) RETURNING key_id),
+c AS (INSERT INTO crate(id, name) VALUES('50000000-0000-0000-0000-000000000000'::uuid, 'media crate') RETURNING id),
+ca AS (INSERT INTO crate_access(crate_id, addr_id, type) VALUES((SELECT id from c), (SELECT id from a_writer), 'owner') RETURNING crate_id)
+
+SELECT key_id from ak;
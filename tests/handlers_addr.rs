@@ -2,6 +2,7 @@ mod common;
 use common::{get_keys, make_auth_header};
 use comn_broker::{
 	auth_token::{AuthToken}, comn_addr::ComnAddr,
+	eth::backfill_eth_addrs,
 };
 use comn_broker::handlers::{
 	addr::{AddrKey, RegisterKeyReq, RegResp, KeyType}
@@ -238,4 +239,67 @@ async fn test_get_key(_pool: PgPool) -> sqlx::Result<()> {
 		public_key
 	);
 	Ok(())
-}
\ No newline at end of file
+}
+
+#[sqlx::test(fixtures("addr_key"), migrator = "comn_broker::MIGRATOR")]
+async fn test_get_eth_addr(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	backfill_eth_addrs().await;
+	let eth_addr = "0xF4997411ec6A77f8F16Fa5C61C711F4F3c114e50";
+
+	let keys = TestClient::get(format!(
+		"http://{}/key?addr=≈a",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.send(comn_broker::route())
+	.await
+	.take_json::<Vec<AddrKey>>()
+	.await
+	.unwrap();
+	assert_eq!(keys[0].eth_addr.clone().unwrap(), eth_addr);
+
+	let addr_req = TestClient::get(format!(
+		"http://{}/addr?addr=≈a",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.send(comn_broker::route())
+	.await
+	.take_json::<Vec<AddrRes>>()
+	.await
+	.unwrap();
+	assert_eq!(addr_req[0].eth_addrs, vec!(eth_addr.to_string()));
+
+	let eth_req = TestClient::get(format!(
+		"http://{}/addr/eth?eth_addr={}",
+		&std::env::var("BIND_ADDR").unwrap(),
+		eth_addr.to_lowercase()
+	))
+	.send(comn_broker::route())
+	.await
+	.take_json::<Vec<AddrRes>>()
+	.await
+	.unwrap();
+	assert_eq!(
+		eth_req[0].addr.to_string(),
+		ComnAddr::new("≈A").unwrap().to_string()
+	);
+
+	// wrong checksum
+	let res_fail = TestClient::get(format!(
+		"http://{}/addr/eth?eth_addr=0xf4997411ec6A77f8F16Fa5C61C711F4F3c114e50",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::BAD_REQUEST);
+
+	let res_missing = TestClient::get(format!(
+		"http://{}/addr/eth?eth_addr=0x5c2fc6f4e7c679015804b4daa0a7181b22d7cffd",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_missing.status_code.unwrap(), StatusCode::NOT_FOUND);
+
+	Ok(())
+}
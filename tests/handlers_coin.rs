@@ -4,7 +4,7 @@ use comn_broker::{
 	auth_token::{ProtectedReq, Protected}, comn_addr::ComnAddr,
 };
 use comn_broker::update::{
-	coin::{Transaction}
+	coin::{Transaction, Approve, TransferFrom, Allowance}
 };
 use salvo::http::header::AUTHORIZATION;
 use salvo::prelude::*;
@@ -199,4 +199,159 @@ async fn test_transaction(_pool: PgPool) -> sqlx::Result<()> {
 	assert_eq!(bad_res.status_code.unwrap(), StatusCode::BAD_REQUEST);
 	
 	Ok(())
-}
\ No newline at end of file
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_allowance(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	let (owner_key, _public_key) = get_keys("NewKey");
+	let (spender_key, _public_key) = get_keys("Key1");
+
+	// ≈6D lets ≈a spend 500
+	let approve = Approve {
+		amount: 500,
+		owner: ComnAddr::new("≈6D").unwrap(),
+		spender: ComnAddr::new("≈a").unwrap(),
+		nonce: "appr0veNSok98Ingp".to_string(),
+	};
+	let t = serde_json::to_string(&approve).unwrap();
+	let req = ProtectedReq::from(Protected::new(t, owner_key));
+	let res = TestClient::post(format!(
+		"http://{}/comn/approve",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"NewKey",
+			"comn.opus.ai",
+			"approve",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+
+	// spender can't approve on behalf of the owner
+	let mut approve_fail = approve;
+	approve_fail.nonce = "appr0veNSok98Indp".to_string();
+	let t = serde_json::to_string(&approve_fail).unwrap();
+	let req = ProtectedReq::from(Protected::new(t, spender_key));
+	let res_fail = TestClient::post(format!(
+		"http://{}/comn/approve",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"approve",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::BAD_REQUEST);
+
+	// ≈a charges ≈6D within the allowance
+	let mut transfer = TransferFrom {
+		amount: 200,
+		owner: ComnAddr::new("≈6D").unwrap(),
+		spender: ComnAddr::new("≈a").unwrap(),
+		receiver: ComnAddr::new("≈a").unwrap(),
+		comment: Some("charged within allowance".to_string()),
+		nonce: "transferFr0mNSok98Ingp".to_string(),
+	};
+	let t = serde_json::to_string(&transfer).unwrap();
+	let req = ProtectedReq::from(Protected::new(t, spender_key));
+	let res = TestClient::post(format!(
+		"http://{}/comn/transfer_from",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"transfer_from",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+
+	// more than the remaining allowance
+	transfer.amount = 400;
+	transfer.nonce = "transferFr0mNSok98Indp".to_string();
+	let t = serde_json::to_string(&transfer).unwrap();
+	let req = ProtectedReq::from(Protected::new(t, spender_key));
+	let res_fail = TestClient::post(format!(
+		"http://{}/comn/transfer_from",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"transfer_from",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::BAD_REQUEST);
+
+	// above i64::MAX, it would turn negative and raise the allowance
+	transfer.amount = u64::MAX;
+	transfer.nonce = "transferFr0mNSok98Inmx".to_string();
+	let t = serde_json::to_string(&transfer).unwrap();
+	let req = ProtectedReq::from(Protected::new(t, spender_key));
+	let res_fail = TestClient::post(format!(
+		"http://{}/comn/transfer_from",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"transfer_from",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::BAD_REQUEST);
+
+	let allowance = TestClient::get(format!(
+		"http://{}/comn/allowance?owner=≈6D&spender=≈a",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.send(comn_broker::route())
+	.await
+	.take_json::<Allowance>()
+	.await
+	.unwrap();
+	assert_eq!(allowance.amount, 300);
+
+	Ok(())
+}
@@ -213,6 +213,9 @@ async fn test_add_crate_item(_pool: PgPool) -> sqlx::Result<()> {
 			media_type: "text/plain".to_string(),
 			data: Some(data.into()),
 			sha2_hash: sha2_hash,
+			proof: None,
+			metadata: Default::default(),
+			tags: vec![],
 		};
 
 		let mut res = TestClient::post(format!(
@@ -270,9 +273,12 @@ async fn test_add_crate_item(_pool: PgPool) -> sqlx::Result<()> {
 			crate_id: "10000000000000000000000000000000".to_string(),
 			addr: ComnAddr::new("≈a").unwrap(),
 			item_path: "/3k_binary".to_string(),
-			media_type: "image/jpeg".to_string(),
+			media_type: "application/octet-stream".to_string(),
 			data: Some(data.into()),
 			sha2_hash: sha2_hash,
+			proof: None,
+			metadata: Default::default(),
+			tags: vec![],
 		};
 
 		let mut res = TestClient::post(format!(
@@ -318,4 +324,431 @@ async fn test_add_crate_item(_pool: PgPool) -> sqlx::Result<()> {
 	}
 
 	Ok(())
-}
\ No newline at end of file
+}
+#[sqlx::test(fixtures("crate_write"), migrator = "comn_broker::MIGRATOR")]
+async fn test_dedup_blob(_pool: PgPool) -> sqlx::Result<()> {
+	use comn_broker::blob::{get_blob, possession_proof};
+	use rand::prelude::*;
+	common::setup(_pool).await;
+
+	let crate_id = Uuid::parse_str("10000000000000000000000000000000").unwrap();
+	let mut data: [u8; 3_000] = [1; 3_000];
+	rand::thread_rng().fill(&mut data[..]);
+	let mut hasher = Sha256::new();
+	hasher.update(&data);
+	let sha2_hash = hasher.finalize().as_slice().to_vec();
+
+	let add_item = |item_path: &str, data: Option<Vec<u8>>, sha2_hash: Vec<u8>, proof: Option<Vec<u8>>| {
+		let add_item_req = AddCrateItemReq {
+			crate_id: crate_id.simple().to_string(),
+			addr: ComnAddr::new("≈a").unwrap(),
+			item_path: item_path.to_string(),
+			media_type: "application/octet-stream".to_string(),
+			data,
+			sha2_hash,
+			proof,
+			metadata: Default::default(),
+			tags: vec![],
+		};
+		TestClient::post(format!(
+			"http://{}/item",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header(
+				"NewKey",
+				"comn.opus.ai",
+				"crate_write,storage",
+				60 * 60 * 24 * 30,
+				0,
+			),
+			true,
+		)
+		.json(&add_item_req)
+		.send(comn_broker::route())
+	};
+	let delete_item = |id: Uuid| {
+		TestClient::delete(format!(
+			"http://{}/item?id={}",
+			&std::env::var("BIND_ADDR").unwrap(),
+			id
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header("NewKey", "comn.opus.ai", "crate_write", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.send(comn_broker::route())
+	};
+
+	let mut res = add_item("/a", Some(data.to_vec()), sha2_hash.clone(), None).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let first = res.take_json::<Uuid>().await.unwrap();
+
+	// the same data again, proving possession instead of sending it
+	let proof = possession_proof(&crate_id, "/b", &data);
+	let mut res = add_item("/b", None, sha2_hash.clone(), Some(proof.clone())).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let second = res.take_json::<Uuid>().await.unwrap();
+	assert_eq!(get_blob(&sha2_hash).await.unwrap().ref_count, 2);
+
+	let mut res_get = TestClient::get(format!(
+		"http://{}/item?id={}",
+		&std::env::var("BIND_ADDR").unwrap(),
+		second
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_get.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(res_get.take_json::<CrateItem>().await.unwrap().data_file.unwrap(), data.to_vec());
+
+	// a proof is for one item path, a wrong one looks like data the broker doesn't have
+	let res_fail = add_item("/c", None, sha2_hash.clone(), Some(proof)).await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::NOT_FOUND);
+	let res_fail = add_item("/c", None, vec![0; 32], Some(vec![0; 32])).await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::NOT_FOUND);
+
+	assert_eq!(delete_item(first).await.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(get_blob(&sha2_hash).await.unwrap().ref_count, 1);
+	assert_eq!(delete_item(second).await.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(get_blob(&sha2_hash).await, None);
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("crate_write"), migrator = "comn_broker::MIGRATOR")]
+async fn test_compression(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool.clone()).await;
+
+	let add_item = |item_path: &str, media_type: &str, data: Vec<u8>| {
+		let add_item_req = AddCrateItemReq {
+			crate_id: "10000000000000000000000000000000".to_string(),
+			addr: ComnAddr::new("≈a").unwrap(),
+			item_path: item_path.to_string(),
+			media_type: media_type.to_string(),
+			sha2_hash: Sha256::digest(&data).as_slice().to_vec(),
+			data: Some(data),
+			proof: None,
+			metadata: Default::default(),
+			tags: vec![],
+		};
+		TestClient::post(format!(
+			"http://{}/item",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header("NewKey", "comn.opus.ai", "crate_write,storage", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.json(&add_item_req)
+		.send(comn_broker::route())
+	};
+	let get_item = |id: Uuid| {
+		TestClient::get(format!(
+			"http://{}/item?id={}",
+			&std::env::var("BIND_ADDR").unwrap(),
+			id
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header("NewKey", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.send(comn_broker::route())
+	};
+
+	// kept in the db
+	let text = "compressible ".repeat(120);
+	let mut res = add_item("/text", "text/plain", text.clone().into_bytes()).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let text_item = res.take_json::<Uuid>().await.unwrap();
+	let (data_text, compression, size, stored) = sqlx::query_as::<_, (Option<String>, Option<String>, i32, i32)>(
+		"SELECT data_text, compression, size_hectobyte, stored_hectobyte FROM crate_item WHERE id = $1"
+	)
+	.bind(text_item)
+	.fetch_one(&pool)
+	.await?;
+	assert_eq!(data_text, None);
+	assert_eq!(compression.as_deref(), Some("zstd"));
+	assert!(stored < size);
+	let item = get_item(text_item).await.take_json::<CrateItem>().await.unwrap();
+	assert_eq!(item.data_text.unwrap(), text);
+
+	// kept in the chunk store
+	let file = [b'z'; 10_000].to_vec();
+	let mut res = add_item("/file", "application/octet-stream", file.clone()).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let file_item = res.take_json::<Uuid>().await.unwrap();
+	let blob_compression = sqlx::query_scalar::<_, Option<String>>("SELECT compression FROM blob WHERE sha2_hash = $1")
+		.bind(Sha256::digest(&file).as_slice().to_vec())
+		.fetch_one(&pool)
+		.await?;
+	assert_eq!(blob_compression.as_deref(), Some("zstd"));
+	let item = get_item(file_item).await.take_json::<CrateItem>().await.unwrap();
+	assert_eq!(item.data_file.unwrap(), file);
+
+	let res_list = TestClient::get(format!(
+		"http://{}/crate/list?id=10000000000000000000000000000000",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.add_header("accept-encoding", "zstd", true)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_list.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(res_list.headers().get("content-encoding").unwrap(), "zstd");
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("crate_write"), migrator = "comn_broker::MIGRATOR")]
+async fn test_validation(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool.clone()).await;
+
+	let add_item = |item_path: &str, media_type: &str, data: Vec<u8>| {
+		let add_item_req = AddCrateItemReq {
+			crate_id: "10000000000000000000000000000000".to_string(),
+			addr: ComnAddr::new("≈a").unwrap(),
+			item_path: item_path.to_string(),
+			media_type: media_type.to_string(),
+			sha2_hash: Sha256::digest(&data).as_slice().to_vec(),
+			data: Some(data),
+			proof: None,
+			metadata: Default::default(),
+			tags: vec![],
+		};
+		TestClient::post(format!(
+			"http://{}/item",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header("NewKey", "comn.opus.ai", "crate_write,storage", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.json(&add_item_req)
+		.send(comn_broker::route())
+	};
+
+	// a pdf said to be a png
+	let mut res = add_item("/fake.png", "image/png", b"%PDF-1.7 not an image".to_vec()).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNPROCESSABLE_ENTITY);
+	assert!(res.take_string().await.unwrap().contains("application/pdf"));
+	let png = [b"\x89PNG\r\n\x1a\n".as_slice(), &[0u8; 64]].concat();
+	assert_eq!(add_item("/real.png", "image/png", png).await.status_code.unwrap(), StatusCode::OK);
+
+	let mut res = add_item("/bad.json", "application/json", br#"{"a": "#.to_vec()).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNPROCESSABLE_ENTITY);
+	assert!(res.take_string().await.unwrap().contains("JSON"));
+	let mut res = add_item("/bad.txt", "text/plain", b"caf\xe9".to_vec()).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNPROCESSABLE_ENTITY);
+	assert!(res.take_string().await.unwrap().contains("byte 3"));
+
+	// script is stripped and the item kept under the hash of what's stored
+	let svg = br#"<svg onload="alert(1)"><script>alert(2)</script><circle r="1"/></svg>"#.to_vec();
+	let mut res = add_item("/icon.svg", "image/svg+xml", svg).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let svg_item = res.take_json::<Uuid>().await.unwrap();
+	let data_bytes = sqlx::query_scalar::<_, Vec<u8>>("SELECT data_bytes FROM crate_item WHERE id = $1")
+		.bind(svg_item)
+		.fetch_one(&pool)
+		.await?;
+	assert_eq!(data_bytes, br#"<svg><circle r="1"/></svg>"#.to_vec());
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("crate_write"), migrator = "comn_broker::MIGRATOR")]
+async fn test_patch_item(pool: PgPool) -> sqlx::Result<()> {
+	use comn_broker::update::json_patch::{ItemPatch, PatchCrateItemReq};
+	common::setup(pool.clone()).await;
+
+	let data = br#"{"app": {"theme": "dark", "tabs": ["a"]}, "count": 1}"#.to_vec();
+	let add_item_req = AddCrateItemReq {
+		crate_id: "10000000000000000000000000000000".to_string(),
+		addr: ComnAddr::new("≈a").unwrap(),
+		item_path: "/state".to_string(),
+		media_type: "application/json".to_string(),
+		sha2_hash: Sha256::digest(&data).as_slice().to_vec(),
+		data: Some(data),
+		proof: None,
+		metadata: Default::default(),
+		tags: vec![],
+	};
+	let id = TestClient::post(format!(
+		"http://{}/item",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_write,storage", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.json(&add_item_req)
+	.send(comn_broker::route())
+	.await
+	.take_json::<Uuid>()
+	.await
+	.unwrap();
+
+	let patch_item = |version: i32, patch: serde_json::Value| {
+		let patch_req = PatchCrateItemReq {
+			id,
+			addr: ComnAddr::new("≈a").unwrap(),
+			version,
+			patch: serde_json::from_value::<ItemPatch>(patch).unwrap(),
+		};
+		TestClient::patch(format!(
+			"http://{}/item",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header("NewKey", "comn.opus.ai", "crate_write", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.json(&patch_req)
+		.send(comn_broker::route())
+	};
+
+	let mut res = patch_item(1, serde_json::json!({"json_patch": [
+		{"op": "replace", "path": "/count", "value": 2},
+		{"op": "add", "path": "/app/tabs/-", "value": "b"}
+	]})).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(res.take_json::<i32>().await.unwrap(), 2);
+
+	// made against the version before
+	let mut res = patch_item(1, serde_json::json!({"merge_patch": {"count": 5}})).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::PRECONDITION_FAILED);
+	assert_eq!(res.take_json::<i32>().await.unwrap(), 2);
+
+	// a failing test op leaves the item as it was
+	let res = patch_item(2, serde_json::json!({"json_patch": [
+		{"op": "replace", "path": "/count", "value": 9},
+		{"op": "test", "path": "/app/theme", "value": "light"}
+	]})).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNPROCESSABLE_ENTITY);
+
+	let mut res = patch_item(2, serde_json::json!({"merge_patch": {"app": {"theme": null}}})).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(res.take_json::<i32>().await.unwrap(), 3);
+
+	let data_json = sqlx::query_scalar::<_, serde_json::Value>("SELECT data_json FROM crate_item WHERE id = $1")
+		.bind(id)
+		.fetch_one(&pool)
+		.await?;
+	assert_eq!(data_json, serde_json::json!({"app": {"tabs": ["a", "b"]}, "count": 2}));
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("crate_write"), migrator = "comn_broker::MIGRATOR")]
+async fn test_item_metadata(pool: PgPool) -> sqlx::Result<()> {
+	use comn_broker::update::item_metadata::{ItemMetadata, ItemMetadataReq};
+	common::setup(pool.clone()).await;
+
+	let add_item = |item_path: &str, device: &str, tags: Vec<&str>| {
+		let data = format!("notes of {}", item_path).into_bytes();
+		let add_item_req = AddCrateItemReq {
+			crate_id: "10000000000000000000000000000000".to_string(),
+			addr: ComnAddr::new("≈a").unwrap(),
+			item_path: item_path.to_string(),
+			media_type: "text/plain".to_string(),
+			sha2_hash: Sha256::digest(&data).as_slice().to_vec(),
+			data: Some(data),
+			proof: None,
+			metadata: [("device".to_string(), device.to_string())].into(),
+			tags: tags.into_iter().map(String::from).collect(),
+		};
+		TestClient::post(format!(
+			"http://{}/item",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header("NewKey", "comn.opus.ai", "crate_write,storage", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.json(&add_item_req)
+		.send(comn_broker::route())
+	};
+	let list = |query: &str| {
+		TestClient::get(format!(
+			"http://{}/crate/list?id=10000000000000000000000000000000&{}",
+			&std::env::var("BIND_ADDR").unwrap(),
+			query
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header("NewKey", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.send(comn_broker::route())
+	};
+
+	let id = add_item("/notes/1", "phone", vec!["Project-X ", "draft"]).await.take_json::<Uuid>().await.unwrap();
+	add_item("/notes/2", "laptop", vec!["project-x"]).await.take_json::<Uuid>().await.unwrap();
+	let res = add_item("/notes/3", "phone", vec![""]).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::BAD_REQUEST);
+
+	// tags are kept trimmed and lowercased
+	let mut res = list("tags=project-x").await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let items = res.take_json::<Vec<CrateItemRes>>().await.unwrap();
+	assert_eq!(items.len(), 2);
+
+	let mut res = list("tags=Project-X,draft&metadata=%7B%22device%22%3A%22phone%22%7D").await;
+	let items = res.take_json::<Vec<CrateItemRes>>().await.unwrap();
+	assert_eq!(items.len(), 1);
+	assert_eq!(items[0].id, id);
+	assert_eq!(items[0].tags, vec!["project-x", "draft"]);
+	assert_eq!(items[0].metadata.0.get("device").unwrap(), "phone");
+
+	let metadata_req = ItemMetadataReq {
+		id,
+		addr: ComnAddr::new("≈a").unwrap(),
+		metadata: [
+			("device".to_string(), None),
+			("project".to_string(), Some("x".to_string())),
+		].into(),
+		add_tags: vec!["done".to_string()],
+		remove_tags: vec!["draft".to_string()],
+	};
+	let mut res = TestClient::patch(format!(
+		"http://{}/item/metadata",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_write", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.json(&metadata_req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let metadata = res.take_json::<ItemMetadata>().await.unwrap();
+	assert_eq!(metadata.metadata.0, [("project".to_string(), "x".to_string())].into());
+	assert_eq!(metadata.tags, vec!["project-x", "done"]);
+
+	// editing metadata leaves the item's data and version as they were
+	let version = sqlx::query_scalar::<_, i32>("SELECT version FROM crate_item WHERE id = $1")
+		.bind(id)
+		.fetch_one(&pool)
+		.await?;
+	assert_eq!(version, 1);
+
+	Ok(())
+}
@@ -1,15 +1,22 @@
 mod common;
-use common::{make_auth_header};
+use common::{get_keys, make_auth_header};
 use comn_broker::{
-	comn_addr::ComnAddr, AddCrateReq, Crate, AccessType,
+	auth_token::{Protected, ProtectedReq},
+	comn_addr::ComnAddr, AddCrateReq, AddCrateItemReq, Crate, AccessType,
+	archive::{ArchiveManifest, ImportedCrate, MEDIA_TYPE},
+};
+use comn_broker::update::crates::{
+	AcceptCrateReq, CrateAudit, CrateTransferRes, CrateTransferStatus, OfferCrateReq,
 };
 use comn_broker::handlers::{
 	crates::{CrateAccessReq}
 };
-use salvo::http::header::AUTHORIZATION;
+use salvo::http::header::{AUTHORIZATION, CONTENT_TYPE};
 use salvo::prelude::*;
 use salvo::test::{ResponseExt, TestClient};
+use sha2::{Digest, Sha256};
 use sqlx::PgPool;
+use std::io::Read;
 use std::time::SystemTime;
 use uuid::Uuid;
 
@@ -22,6 +29,8 @@ async fn test_add_crate(_pool: PgPool) -> sqlx::Result<()> {
 		comment: "".to_string(),
 		addr: ComnAddr::new("≈a").unwrap(),
 		expires: Some(SystemTime::now().into()),
+		encrypted: false,
+		envelope: None,
 	};
 	let mut res = TestClient::post(format!(
 		"http://{}/crate",
@@ -182,6 +191,7 @@ async fn test_crate_access(_pool: PgPool) -> sqlx::Result<()> {
 		give_access: true,
 		access_type: AccessType::Writer,
 		expires: None,
+		envelope: None,
 	};
 	println!("access req{:?}", access_req);
 	// adding write access
@@ -279,4 +289,312 @@ async fn test_crate_access(_pool: PgPool) -> sqlx::Result<()> {
 	assert_eq!(res4.status_code.unwrap(), StatusCode::OK);
 
 	Ok(())
-}
\ No newline at end of file
+}
+
+#[sqlx::test(fixtures("crate_transfer"), migrator = "comn_broker::MIGRATOR")]
+async fn test_update_crate(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+
+	let update_crate = |key: &'static str, update_req: serde_json::Value| {
+		TestClient::patch(format!(
+			"http://{}/crate",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header(key, "comn.opus.ai", "crate_write", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.json(&update_req)
+		.send(comn_broker::route())
+	};
+
+	let mut res = update_crate("NewKey", serde_json::json!({
+		"crate_id": "10000000000000000000000000000000",
+		"addr": "≈a",
+		"name": "renamed",
+		"comment": "kept for a year",
+	})).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let updated = res.take_json::<Crate>().await.unwrap();
+	assert_eq!(updated.name, "renamed");
+	assert_eq!(updated.comment, Some("kept for a year".to_string()));
+
+	// left out fields stay, null clears them
+	let mut res = update_crate("NewKey", serde_json::json!({
+		"crate_id": "10000000000000000000000000000000",
+		"addr": "≈a",
+		"comment": null,
+	})).await;
+	let updated = res.take_json::<Crate>().await.unwrap();
+	assert_eq!(updated.name, "renamed");
+	assert_eq!(updated.comment, None);
+
+	// the addr of Key1 has no access to the crate
+	let to = ComnAddr::from_uuid("00000000-0000-0000-0000-00000000000b").unwrap();
+	let res = update_crate("Key1", serde_json::json!({
+		"crate_id": "10000000000000000000000000000000",
+		"addr": to,
+		"name": "taken",
+	})).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNAUTHORIZED);
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("crate_transfer"), migrator = "comn_broker::MIGRATOR")]
+async fn test_crate_transfer(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool.clone()).await;
+	let crate_id = Uuid::parse_str("10000000000000000000000000000000").unwrap();
+	let from = ComnAddr::new("≈a").unwrap();
+	let to = ComnAddr::from_uuid("00000000-0000-0000-0000-00000000000b").unwrap();
+
+	let offer_req = OfferCrateReq {
+		crate_id,
+		from: from.clone(),
+		to: to.clone(),
+		expires: None,
+		envelope: None,
+	};
+	let offer_crate = || {
+		TestClient::post(format!(
+			"http://{}/crate/transfer",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header("NewKey", "comn.opus.ai", "crate_write", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.json(&offer_req)
+		.send(comn_broker::route())
+	};
+	let mut res = offer_crate().await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let offer = res.take_json::<CrateTransferRes>().await.unwrap();
+	assert_eq!(offer.status, CrateTransferStatus::Offered);
+
+	// one open offer at a time
+	let res = offer_crate().await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::CONFLICT);
+
+	// the receiver sees the offer
+	let mut res = TestClient::get(format!(
+		"http://{}/crate/transfer",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("Key1", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.send(comn_broker::route())
+	.await;
+	let offers = res.take_json::<Vec<CrateTransferRes>>().await.unwrap();
+	assert_eq!(offers.len(), 1);
+	assert_eq!(offers[0].id, offer.id);
+
+	let accept_crate = |key: &'static str, addr: &ComnAddr| {
+		let (secret_key, _public_key) = get_keys(key);
+		let accept_req = AcceptCrateReq { id: offer.id, addr: addr.clone() };
+		let protected = Protected::new(serde_json::to_string(&accept_req).unwrap(), secret_key);
+		TestClient::post(format!(
+			"http://{}/crate/transfer/accept",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header(key, "comn.opus.ai", "crate_write", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.json(&ProtectedReq::from(protected))
+		.send(comn_broker::route())
+	};
+	// only the receiving addr can accept
+	let res = accept_crate("NewKey", &from).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNAUTHORIZED);
+
+	let mut res = accept_crate("Key1", &to).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(res.take_json::<CrateTransferRes>().await.unwrap().status, CrateTransferStatus::Accepted);
+
+	let owners = sqlx::query_scalar::<_, Uuid>(
+		"SELECT addr_id FROM crate_access WHERE crate_id = $1 AND type = 'owner'"
+	)
+	.bind(crate_id)
+	.fetch_all(&pool)
+	.await?;
+	assert_eq!(owners, vec![Uuid::parse_str(&to.to_uuid()).unwrap()]);
+
+	let res = accept_crate("Key1", &to).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::NOT_FOUND);
+
+	// the new owner reads what happened
+	let mut res = TestClient::get(format!(
+		"http://{}/crate/audit?id={}",
+		&std::env::var("BIND_ADDR").unwrap(),
+		crate_id
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("Key1", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let actions: Vec<String> = res.take_json::<Vec<CrateAudit>>().await.unwrap()
+		.into_iter().map(|entry| entry.action).collect();
+	assert_eq!(actions, vec!["transfer_accept", "transfer_offer"]);
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("crate_transfer"), migrator = "comn_broker::MIGRATOR")]
+async fn test_crate_archive(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool.clone()).await;
+	let crate_id = Uuid::parse_str("10000000000000000000000000000000").unwrap();
+	let text = "text of an archived crate".as_bytes();
+	let json = r#"{"theme":"dark"}"#.as_bytes();
+	for (item_path, media_type, data) in [("/notes.txt", "text/plain", text), ("/settings", "application/json", json)] {
+		let add_item_req = AddCrateItemReq {
+			crate_id: crate_id.simple().to_string(),
+			addr: ComnAddr::new("≈a").unwrap(),
+			item_path: item_path.to_string(),
+			media_type: media_type.to_string(),
+			data: Some(data.into()),
+			sha2_hash: Sha256::digest(data).as_slice().to_vec(),
+			proof: None,
+			metadata: Default::default(),
+			tags: vec!["archived".to_string()],
+		};
+		let res = TestClient::post(format!(
+			"http://{}/item",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header("NewKey", "comn.opus.ai", "crate_write,storage", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.json(&add_item_req)
+		.send(comn_broker::route())
+		.await;
+		assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	}
+
+	let export_crate = |key: &str, addr: &str| {
+		TestClient::get(format!(
+			"http://{}/crate/export?id={}&addr={}",
+			&std::env::var("BIND_ADDR").unwrap(),
+			crate_id.simple(),
+			addr
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header(key, "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.send(comn_broker::route())
+	};
+	// only owners and admins export
+	let res = export_crate("Key1", "≈C").await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNAUTHORIZED);
+	let mut res = export_crate("NewKey", "≈a").await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(res.headers().get(CONTENT_TYPE).unwrap(), MEDIA_TYPE);
+	let archive = res.take_bytes(None).await.unwrap().to_vec();
+
+	let mut tar = tar::Archive::new(archive.as_slice());
+	let mut entry = tar.entries().unwrap().next().unwrap().unwrap();
+	assert_eq!(entry.path().unwrap().to_str(), Some("manifest.json"));
+	let mut manifest = vec![];
+	entry.read_to_end(&mut manifest).unwrap();
+	let manifest = serde_json::from_slice::<ArchiveManifest>(&manifest).unwrap();
+	assert_eq!(manifest.crate_.id, crate_id);
+	assert_eq!(manifest.access.len(), 1);
+	assert_eq!(manifest.items.len(), 2);
+	assert_eq!(manifest.items[0].item_path, "/notes.txt");
+	assert_eq!(manifest.items[0].sha2_hash, hex::encode(Sha256::digest(text)));
+	assert_eq!(manifest.items[1].tags, vec!["archived".to_string()]);
+
+	let import_crate = |key: &str, query: String, archive: Vec<u8>| {
+		TestClient::post(format!(
+			"http://{}/crate/import?{}",
+			&std::env::var("BIND_ADDR").unwrap(),
+			query
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header(key, "comn.opus.ai", "crate_write,storage", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.bytes(archive)
+		.add_header(CONTENT_TYPE, MEDIA_TYPE, true)
+		.send(comn_broker::route())
+	};
+	let crate_count = || sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM crate").fetch_one(&pool);
+
+	// data that doesn't match its hash isn't imported, nor is the crate
+	let crates_before = crate_count().await?;
+	let mut tampered = archive.clone();
+	let at = tampered.windows(text.len()).position(|window| window == text).unwrap();
+	tampered[at] = b'T';
+	let mut res = import_crate("Key1", "addr=≈C".to_string(), tampered).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNPROCESSABLE_ENTITY);
+	assert!(res.take_string().await.unwrap().contains("/notes.txt"));
+	assert_eq!(crate_count().await?, crates_before);
+
+	// addrs in the manifest are checked, not trusted
+	let added_by = "\"added_by\": \"≈A\"".as_bytes();
+	let mut bad_addr = archive.clone();
+	let at = bad_addr.windows(added_by.len()).position(|window| window == added_by).unwrap();
+	bad_addr[at + added_by.len() - 2] = b'!';
+	let mut res = import_crate("Key1", "addr=≈C".to_string(), bad_addr).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNPROCESSABLE_ENTITY);
+	assert!(res.take_string().await.unwrap().contains("≈! is not a comn address"));
+	assert_eq!(crate_count().await?, crates_before);
+
+	// a new crate for the importer only, the exporter isn't given access
+	let mut res = import_crate("Key1", "addr=≈C".to_string(), archive.clone()).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let imported = res.take_json::<ImportedCrate>().await.unwrap();
+	assert_ne!(imported.crate_.id, crate_id);
+	assert_eq!(imported.crate_.name, "test_crate");
+	assert_eq!(imported.items.len(), 2);
+	assert_eq!(imported.skipped_access, vec![ComnAddr::new("≈A").unwrap()]);
+	let owners = sqlx::query_scalar::<_, i64>(
+		"SELECT COUNT(*) FROM crate_access WHERE crate_id = $1 AND type = 'owner'"
+	)
+	.bind(imported.crate_.id)
+	.fetch_one(&pool)
+	.await?;
+	assert_eq!(owners, 1);
+	let notes = sqlx::query_scalar::<_, Option<String>>(
+		"SELECT data_text FROM crate_item WHERE id = $1"
+	)
+	.bind(imported.items[0])
+	.fetch_one(&pool)
+	.await?;
+	assert_eq!(notes.as_deref().map(str::as_bytes), Some(text));
+
+	// into a crate the addr can't edit
+	let res = import_crate("Key1", format!("addr=≈C&id={}", crate_id.simple()), archive.clone()).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNAUTHORIZED);
+	// nor over the items already in it
+	let mut res = import_crate("NewKey", format!("addr=≈a&id={}", crate_id.simple()), archive.clone()).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNPROCESSABLE_ENTITY);
+	assert!(res.take_string().await.unwrap().contains("already in the crate"));
+	sqlx::query("DELETE FROM crate_item WHERE crate_id = $1").bind(imported.crate_.id).execute(&pool).await?;
+	let mut res = import_crate("Key1", format!("addr=≈C&id={}", imported.crate_.id.simple()), archive.clone()).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(res.take_json::<ImportedCrate>().await.unwrap().crate_.id, imported.crate_.id);
+
+	// nor into one keeping only ciphertext
+	sqlx::query("UPDATE crate SET e2e = true WHERE id = $1").bind(crate_id).execute(&pool).await?;
+	let mut res = import_crate("NewKey", format!("addr=≈a&id={}", crate_id.simple()), archive).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNPROCESSABLE_ENTITY);
+	assert!(res.take_string().await.unwrap().contains("end to end"));
+
+	Ok(())
+}
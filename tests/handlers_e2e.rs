@@ -0,0 +1,171 @@
+mod common;
+use common::{get_keys, make_auth_header};
+use comn_broker::{
+	comn_addr::ComnAddr, AccessType, AddCrateItemReq, AddCrateReq, Crate, CrateItem, CrateItemStorage,
+	e2e::{open_envelope, seal_envelope, CrateEnvelopes, RotateCrateKeyReq},
+	handlers::crates::CrateAccessReq,
+};
+use salvo::http::header::AUTHORIZATION;
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+async fn post<T: Serialize>(path: &str, key: &str, scope: &str, body: &T) -> Response {
+	TestClient::post(format!(
+		"http://{}/{}",
+		&std::env::var("BIND_ADDR").unwrap(),
+		path
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(key, "comn.opus.ai", scope, 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.json(body)
+	.send(comn_broker::route())
+	.await
+}
+
+async fn get_envelopes(crate_id: Uuid, key: &str) -> Response {
+	TestClient::get(format!(
+		"http://{}/crate/envelope?id={}",
+		&std::env::var("BIND_ADDR").unwrap(),
+		crate_id.simple()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(key, "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.send(comn_broker::route())
+	.await
+}
+
+// Key1 has ≈a, NewKey has ≈6D
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_e2e_crate(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	let (key1_secret, key1) = get_keys("Key1");
+	let (new_key_secret, new_key) = get_keys("NewKey");
+	let crate_key = [1u8; 32];
+
+	let mut crate_req = AddCrateReq {
+		name: "sealed".to_string(),
+		comment: "".to_string(),
+		addr: ComnAddr::new("≈a").unwrap(),
+		expires: None,
+		encrypted: false,
+		envelope: Some(vec![0; 93]),
+	};
+	let res_fail = post("crate", "Key1", "crate_write", &crate_req).await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::BAD_REQUEST);
+	crate_req.envelope = Some(seal_envelope(&key1, &crate_key));
+	let mut res = post("crate", "Key1", "crate_write", &crate_req).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let new_crate = res.take_json::<Crate>().await.unwrap();
+	assert!(new_crate.e2e);
+	assert_eq!(new_crate.key_version, 1);
+
+	// access comes with the member's envelope
+	let mut access_req = CrateAccessReq {
+		crate_id: new_crate.id.simple().to_string(),
+		access_to_addr: "≈6D".to_string(),
+		give_access: true,
+		access_type: AccessType::Reader,
+		expires: None,
+		envelope: None,
+	};
+	let res_fail = post("crate/access", "Key1", "change_crate_access", &access_req).await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::BAD_REQUEST);
+	access_req.envelope = Some(seal_envelope(&new_key, &crate_key));
+	let res = post("crate/access", "Key1", "change_crate_access", &access_req).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+
+	let mut res = get_envelopes(new_crate.id, "NewKey").await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let envelopes = res.take_json::<CrateEnvelopes>().await.unwrap();
+	assert_eq!(envelopes.envelopes.len(), 1);
+	assert_eq!(envelopes.envelopes[0].addr, ComnAddr::new("≈6D").unwrap());
+	assert_eq!(open_envelope(&new_key_secret, &envelopes.envelopes[0].envelope), Ok(crate_key));
+
+	// removing the member needs a new crate key before anyone else gets in
+	access_req.give_access = false;
+	let res = post("crate/access", "Key1", "change_crate_access", &access_req).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let envelopes = get_envelopes(new_crate.id, "Key1").await.take_json::<CrateEnvelopes>().await.unwrap();
+	assert!(envelopes.rotate_key);
+	access_req.give_access = true;
+	let res_fail = post("crate/access", "Key1", "change_crate_access", &access_req).await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::CONFLICT);
+
+	let new_crate_key = [3u8; 32];
+	let mut rotate_req = RotateCrateKeyReq {
+		crate_id: new_crate.id.simple().to_string(),
+		key_version: 1,
+		envelopes: HashMap::from([("≈a".to_string(), seal_envelope(&key1, &new_crate_key))]),
+	};
+	let res_fail = post("crate/envelope", "NewKey", "rotate_crate_key", &rotate_req).await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::UNAUTHORIZED);
+	let res_fail = post("crate/envelope", "Key1", "rotate_crate_key", &rotate_req).await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::CONFLICT);
+	rotate_req.key_version = 2;
+	rotate_req.envelopes.insert("≈6D".to_string(), seal_envelope(&new_key, &new_crate_key));
+	let res_fail = post("crate/envelope", "Key1", "rotate_crate_key", &rotate_req).await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::BAD_REQUEST);
+	rotate_req.envelopes.remove("≈6D");
+	let mut res = post("crate/envelope", "Key1", "rotate_crate_key", &rotate_req).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let envelopes = res.take_json::<CrateEnvelopes>().await.unwrap();
+	assert_eq!(envelopes.key_version, 2);
+	assert!(!envelopes.rotate_key);
+	assert_eq!(envelopes.envelopes[0].key_version, 2);
+	assert_eq!(open_envelope(&key1_secret, &envelopes.envelopes[0].envelope), Ok(new_crate_key));
+
+	let res_fail = get_envelopes(new_crate.id, "NewKey").await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::UNAUTHORIZED);
+
+	// items are the client's ciphertext whatever their media type, kept as uploaded
+	for (item_path, media_type) in [("/settings", "application/json"), ("/notes", "text/plain")] {
+		let ciphertext: Vec<u8> = (0..200u8).map(|b| b.wrapping_mul(73) ^ 0xa5).collect();
+		let add_item_req = AddCrateItemReq {
+			crate_id: new_crate.id.simple().to_string(),
+			addr: ComnAddr::new("≈a").unwrap(),
+			item_path: item_path.to_string(),
+			media_type: media_type.to_string(),
+			data: Some(ciphertext.clone()),
+			sha2_hash: Sha256::digest(&ciphertext).as_slice().to_vec(),
+			proof: None,
+			metadata: Default::default(),
+			tags: vec![],
+		};
+		let mut res = post("item", "Key1", "crate_write,storage", &add_item_req).await;
+		assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+		let id = res.take_json::<Uuid>().await.unwrap();
+
+		let mut res = TestClient::get(format!(
+			"http://{}/item?id={}",
+			&std::env::var("BIND_ADDR").unwrap(),
+			id.simple()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header("Key1", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.send(comn_broker::route())
+		.await;
+		assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+		let item = res.take_json::<CrateItem>().await.unwrap();
+		assert_eq!(item.media_type, media_type);
+		assert_eq!(item.item_storage, CrateItemStorage::Bytes);
+		assert_eq!(item.data_bytes, Some(ciphertext));
+		assert_eq!(item.data_json, None);
+		assert_eq!(item.data_text, None);
+	}
+
+	Ok(())
+}
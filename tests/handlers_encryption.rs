@@ -0,0 +1,136 @@
+mod common;
+use common::make_auth_header;
+use comn_broker::{
+	comn_addr::ComnAddr, encryption::rotate_master_key,
+	AddCrateReq, Crate, CrateItem, CrateItemRes,
+};
+use salvo::http::header::AUTHORIZATION;
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use sha2::{Digest, Sha256};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+const MASTER_KEY_1: &str = "1:000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const MASTER_KEY_2: &str = "2:1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+async fn add_item(crate_id: Uuid, item_path: &str, media_type: &str, data: Vec<u8>) -> Uuid {
+	let add_item_req = common::item_req(&crate_id.simple().to_string(), "≈a", item_path, media_type, data);
+	let mut res = common::add_item("NewKey", &add_item_req).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	res.take_json::<Uuid>().await.unwrap()
+}
+
+async fn get_item(id: Uuid) -> CrateItem {
+	TestClient::get(format!(
+		"http://{}/item?id={}",
+		&std::env::var("BIND_ADDR").unwrap(),
+		id
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.send(comn_broker::route())
+	.await
+	.take_json::<CrateItem>()
+	.await
+	.unwrap()
+}
+
+#[sqlx::test(fixtures("addr_key"), migrator = "comn_broker::MIGRATOR")]
+async fn test_encrypted_crate(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool.clone()).await;
+	std::env::set_var("COMN_MASTER_KEYS", MASTER_KEY_1);
+
+	let crate_req = AddCrateReq {
+		name: "sealed".to_string(),
+		comment: "".to_string(),
+		addr: ComnAddr::new("≈a").unwrap(),
+		expires: None,
+		encrypted: true,
+		envelope: None,
+	};
+	let mut res = TestClient::post(format!(
+		"http://{}/crate",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_write", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.json(&crate_req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let crate_id = res.take_json::<Crate>().await.unwrap().id;
+
+	let json = br#"{"secret": 42}"#.to_vec();
+	let file = [9u8; 3_000].to_vec();
+	let json_item = add_item(crate_id, "/secret.json", "application/json", json.clone()).await;
+	let file_item = add_item(crate_id, "/secret.bin", "application/octet-stream", file.clone()).await;
+
+	// nothing in the db or the chunk store is plain
+	let (data_json, data_bytes, encrypted) = sqlx::query_as::<_, (Option<serde_json::Value>, Option<Vec<u8>>, bool)>(
+		"SELECT data_json, data_bytes, encrypted FROM crate_item WHERE id = $1"
+	)
+	.bind(json_item)
+	.fetch_one(&pool)
+	.await?;
+	assert_eq!(data_json, None);
+	assert!(encrypted);
+	assert!(!data_bytes.unwrap().windows(json.len()).any(|w| w == json.as_slice()));
+	let plain_blob = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM blob WHERE sha2_hash = $1")
+		.bind(Sha256::digest(&file).as_slice().to_vec())
+		.fetch_one(&pool)
+		.await?;
+	assert_eq!(plain_blob, 0);
+
+	let item = get_item(json_item).await;
+	assert_eq!(item.data_json.unwrap().0, serde_json::json!({"secret": 42}));
+	assert_eq!(get_item(file_item).await.data_file.unwrap(), file);
+
+	let mut res_list = TestClient::get(format!(
+		"http://{}/crate/list?id={}",
+		&std::env::var("BIND_ADDR").unwrap(),
+		crate_id
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.send(comn_broker::route())
+	.await;
+	let items = res_list.take_json::<Vec<CrateItemRes>>().await.unwrap();
+	let listed = items.iter().find(|i| i.id == json_item).unwrap();
+	assert_eq!(listed.data_json.clone().unwrap().0, serde_json::json!({"secret": 42}));
+
+	// the new master key wraps the data key, then the old one can go
+	std::env::set_var("COMN_MASTER_KEYS", format!("{},{}", MASTER_KEY_2, MASTER_KEY_1));
+	assert_eq!(rotate_master_key().await, Ok(1));
+	assert_eq!(rotate_master_key().await, Ok(0));
+	std::env::set_var("COMN_MASTER_KEYS", MASTER_KEY_2);
+	assert_eq!(get_item(json_item).await.data_json.unwrap().0, serde_json::json!({"secret": 42}));
+	assert_eq!(get_item(file_item).await.data_file.unwrap(), file);
+
+	// without a master key there are no encrypted crates
+	std::env::remove_var("COMN_MASTER_KEYS");
+	let res_fail = TestClient::post(format!(
+		"http://{}/crate",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_write", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.json(&crate_req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::NOT_IMPLEMENTED);
+
+	Ok(())
+}
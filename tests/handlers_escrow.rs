@@ -0,0 +1,216 @@
+mod common;
+use common::{get_keys, make_auth_header};
+use comn_broker::{
+	auth_token::{ProtectedReq, Protected}, comn_addr::ComnAddr,
+};
+use comn_broker::handlers::coin::Balance;
+use comn_broker::update::{
+	escrow::{
+		EscrowReq, EscrowActionReq, EscrowAction, EscrowRes, EscrowStatus,
+		refund_expired,
+	},
+};
+use chrono::{Duration, Utc};
+use salvo::http::header::AUTHORIZATION;
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use sqlx::PgPool;
+
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_escrow_release(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	let (sender_key, _public_key) = get_keys("NewKey");
+	let (receiver_key, _public_key) = get_keys("Key1");
+
+	let escrow_req = EscrowReq {
+		sender: ComnAddr::new("≈6D").unwrap(),
+		receiver: ComnAddr::new("≈a").unwrap(),
+		arbiter: None,
+		amount: 100,
+		comment: Some("paid on delivery".to_string()),
+		nonce: "escr0wNSok98Ingp".to_string(),
+		expires: Utc::now() + Duration::days(7),
+	};
+	let t = serde_json::to_string(&escrow_req).unwrap();
+	let req = ProtectedReq::from(Protected::new(t, sender_key));
+	let mut res = TestClient::post(format!(
+		"http://{}/comn/escrow",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"NewKey",
+			"comn.opus.ai",
+			"escrow",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let escrow = res.take_json::<EscrowRes>().await.unwrap();
+	assert_eq!(escrow.status, EscrowStatus::Locked);
+
+	// neither an amount above i64::MAX nor an unknown receiver locks any coins
+	let lock_escrow = |escrow_req: &EscrowReq| {
+		let t = serde_json::to_string(escrow_req).unwrap();
+		let req = ProtectedReq::from(Protected::new(t, sender_key));
+		TestClient::post(format!(
+			"http://{}/comn/escrow",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header(
+				"NewKey",
+				"comn.opus.ai",
+				"escrow",
+				60 * 60 * 24 * 30,
+				0,
+			),
+			true,
+		)
+		.json(&req)
+		.send(comn_broker::route())
+	};
+	let mut bad_req = EscrowReq {
+		amount: u64::MAX,
+		nonce: "escr0wNSok98Inmx".to_string(),
+		..escrow_req
+	};
+	assert_eq!(lock_escrow(&bad_req).await.status_code.unwrap(), StatusCode::BAD_REQUEST);
+	bad_req.amount = 100;
+	bad_req.receiver = ComnAddr::new("≈9Z").unwrap();
+	assert_eq!(lock_escrow(&bad_req).await.status_code.unwrap(), StatusCode::NOT_FOUND);
+
+	let balance = TestClient::get(format!(
+		"http://{}/comn/balance?addr=≈6D",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"NewKey",
+			"comn.opus.ai",
+			"account_amount",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.send(comn_broker::route())
+	.await
+	.take_json::<Balance>()
+	.await
+	.unwrap();
+	assert_eq!(balance.available, 100000000000 - 100);
+	assert_eq!(balance.locked, 100);
+
+	// receiver can't release to itself
+	let mut action_req = EscrowActionReq {
+		id: escrow.id,
+		addr: ComnAddr::new("≈a").unwrap(),
+		action: EscrowAction::Release,
+	};
+	let t = serde_json::to_string(&action_req).unwrap();
+	let req = ProtectedReq::from(Protected::new(t, receiver_key));
+	let res_fail = TestClient::post(format!(
+		"http://{}/comn/escrow/settle",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"escrow",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::UNAUTHORIZED);
+
+	action_req.addr = ComnAddr::new("≈6D").unwrap();
+	let t = serde_json::to_string(&action_req).unwrap();
+	let req = ProtectedReq::from(Protected::new(t, sender_key));
+	let mut res = TestClient::post(format!(
+		"http://{}/comn/escrow/settle",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"NewKey",
+			"comn.opus.ai",
+			"escrow",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(res.take_json::<EscrowRes>().await.unwrap().status, EscrowStatus::Released);
+
+	let received = TestClient::get(format!(
+		"http://{}/comn",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"account_amount",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.send(comn_broker::route())
+	.await
+	.take_json::<i64>()
+	.await
+	.unwrap();
+	assert_eq!(received, 100);
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_escrow_refund_at_expiry(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool.clone()).await;
+
+	let escrow = EscrowReq {
+		sender: ComnAddr::new("≈6D").unwrap(),
+		receiver: ComnAddr::new("≈a").unwrap(),
+		arbiter: None,
+		amount: 50,
+		comment: None,
+		nonce: "expiredNSok98Ingp".to_string(),
+		expires: Utc::now() + Duration::days(1),
+	}.lock().await.unwrap();
+	assert!(refund_expired().await.is_empty());
+
+	sqlx::query("UPDATE escrow SET expires = CURRENT_TIMESTAMP - interval '1 second' WHERE id = $1")
+		.bind(escrow.id)
+		.execute(&pool)
+		.await?;
+	let refunds = refund_expired().await;
+	assert_eq!(refunds.len(), 1);
+	assert_eq!(refunds[0].as_ref().unwrap().status, EscrowStatus::Refunded);
+	assert!(refund_expired().await.is_empty());
+
+	Ok(())
+}
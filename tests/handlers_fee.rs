@@ -0,0 +1,129 @@
+mod common;
+use common::{get_keys, make_auth_header};
+use comn_broker::{
+	auth_token::{ProtectedReq, Protected}, comn_addr::ComnAddr,
+	fee::refund_pending,
+	update::coin::Transaction,
+};
+use salvo::http::header::AUTHORIZATION;
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use sqlx::PgPool;
+
+async fn transfer_from_a(amount: u64, nonce: &str) -> StatusCode {
+	transfer_from("≈a", amount, nonce).await
+}
+
+// signed with Key1, whatever the sender
+async fn transfer_from(sender: &str, amount: u64, nonce: &str) -> StatusCode {
+	let (key1, _public_key) = get_keys("Key1");
+	let transaction = Transaction {
+		amount,
+		receiver: ComnAddr::new("≈6D").unwrap(),
+		sender: ComnAddr::new(sender).unwrap(),
+		comment: None,
+		nonce: nonce.to_string(),
+	};
+	let t = serde_json::to_string(&transaction).unwrap();
+	let req = ProtectedReq::from(Protected::new(t, key1));
+	TestClient::post(format!(
+		"http://{}/comn/transaction",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"transaction",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&req)
+	.send(comn_broker::route())
+	.await
+	.status_code
+	.unwrap()
+}
+
+async fn coins_of_a() -> i64 {
+	TestClient::get(format!(
+		"http://{}/comn",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"account_amount",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.send(comn_broker::route())
+	.await
+	.take_json::<i64>()
+	.await
+	.unwrap()
+}
+
+// config is read once per process, so this is the only test in the file
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_transaction_fee(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool.clone()).await;
+	sqlx::query(
+		"
+		UPDATE crate_item
+		SET data_json = jsonb_set(data_json, '{fees,schedule}', '{\"transaction\": {\"amount\": 2}}')
+		WHERE id = '00000000000000000000000000000000'
+		"
+	)
+	.execute(&pool)
+	.await?;
+	Transaction {
+		amount: 100,
+		receiver: ComnAddr::new("≈a").unwrap(),
+		sender: ComnAddr::new("≈6D").unwrap(),
+		comment: None,
+		nonce: "feeNSok98Ingp".to_string(),
+	}.transfer_coins().await.unwrap();
+
+	assert_eq!(transfer_from_a(10, "fee1NSok98Ingp").await, StatusCode::OK);
+	assert_eq!(coins_of_a().await, 88);
+
+	// the fee is paid back when the transfer fails
+	assert_eq!(transfer_from_a(1000, "fee2NSok98Ingp").await, StatusCode::BAD_REQUEST);
+	assert_eq!(coins_of_a().await, 88);
+
+	let statuses = sqlx::query_scalar::<_, String>(
+		"SELECT status::text FROM fee WHERE operation = 'transaction' ORDER BY created"
+	)
+	.fetch_all(&pool)
+	.await?;
+	assert_eq!(statuses, vec!["charged", "refunded"]);
+
+	// the fee is charged to the request's addr, which has to be the key's
+	assert_eq!(transfer_from("≈6D", 10, "fee3NSok98Ingp").await, StatusCode::BAD_REQUEST);
+	assert_eq!(coins_of_a().await, 88);
+
+	// a fee left held by a request that never finished is paid back
+	sqlx::query(
+		"UPDATE fee SET status = 'held', updated = CURRENT_TIMESTAMP - interval '2 hours' WHERE status = 'charged'"
+	)
+	.execute(&pool)
+	.await?;
+	assert!(refund_pending().await.iter().all(|result| result.is_ok()));
+	assert_eq!(coins_of_a().await, 90);
+	let statuses = sqlx::query_scalar::<_, String>(
+		"SELECT status::text FROM fee WHERE operation = 'transaction' ORDER BY created"
+	)
+	.fetch_all(&pool)
+	.await?;
+	assert_eq!(statuses, vec!["refunded", "refunded"]);
+
+	Ok(())
+}
@@ -0,0 +1,117 @@
+mod common;
+use common::make_auth_header;
+use comn_broker::{comn_addr::ComnAddr, AccessType};
+use comn_broker::update::item_type::{AddItemTypeReq, ItemType, ItemTypeAccessReq};
+use salvo::http::header::AUTHORIZATION;
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use sqlx::PgPool;
+
+async fn add_item(item_path: &str, media_type: &str) -> StatusCode {
+	let data = "<p>comn</p>".as_bytes().to_vec();
+	let add_item_req = common::item_req("50000000000000000000000000000000", "≈a", item_path, media_type, data);
+	common::add_item("Key1", &add_item_req).await.status_code.unwrap()
+}
+
+#[sqlx::test(fixtures("item_type"), migrator = "comn_broker::MIGRATOR")]
+async fn test_item_type_registry(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool).await;
+
+	// not registered yet
+	assert_eq!(add_item("/model.glb", "model/gltf-binary").await, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+
+	let add_req = AddItemTypeReq {
+		media_type: "Model/glTF-Binary".to_string(),
+		description: Some("binary glTF".to_string()),
+		extensions: vec![".GLB".to_string()],
+		writers: None,
+	};
+	// only keys of the config addr manage the registry
+	let res = TestClient::post(format!(
+		"http://{}/item_type",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("Key1", "comn.opus.ai", "item_type", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.json(&add_req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNAUTHORIZED);
+
+	for expected in [StatusCode::OK, StatusCode::CONFLICT] {
+		let mut res = TestClient::post(format!(
+			"http://{}/item_type",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header("NewKey", "comn.opus.ai", "item_type", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.json(&add_req)
+		.send(comn_broker::route())
+		.await;
+		assert_eq!(res.status_code.unwrap(), expected);
+		if expected == StatusCode::OK {
+			let item_type = res.take_json::<ItemType>().await.unwrap();
+			assert_eq!(item_type.media_type, "model/gltf-binary");
+			assert_eq!(item_type.extensions, vec!["glb".to_string()]);
+		}
+	}
+
+	let item_types = TestClient::get(format!(
+		"http://{}/item_type",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.send(comn_broker::route())
+	.await
+	.take_json::<Vec<ItemType>>()
+	.await
+	.unwrap();
+	let jpeg = item_types.iter().find(|t| t.media_type == "image/jpeg").unwrap();
+	assert_eq!(jpeg.extensions, vec!["jpeg".to_string(), "jpg".to_string()]);
+
+	assert_eq!(add_item("/model.glb", "model/gltf-binary").await, StatusCode::OK);
+
+	// html is registered but only uploaded by addrs given access
+	assert_eq!(add_item("/index.html", "text/html").await, StatusCode::FORBIDDEN);
+	let access_req = ItemTypeAccessReq {
+		media_type: "text/html".to_string(),
+		addr: ComnAddr::new("≈a").unwrap(),
+		access_type: Some(AccessType::Writer),
+		expires: None,
+	};
+	let res = TestClient::post(format!(
+		"http://{}/item_type/access",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "item_type", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.json(&access_req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(add_item("/index.html", "text/html; charset=utf-8").await, StatusCode::OK);
+
+	// a type with items stored stays registered
+	let res = TestClient::delete(format!(
+		"http://{}/item_type?media_type=model/gltf-binary",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "item_type", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::CONFLICT);
+
+	Ok(())
+}
@@ -0,0 +1,101 @@
+mod common;
+use common::make_auth_header;
+use comn_broker::read::json_query::{JsonQueryHit, JsonQueryReq};
+use salvo::http::header::AUTHORIZATION;
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use serde_json::json;
+use sqlx::PgPool;
+
+const CRATE_ID: &str = "10000000000000000000000000000000";
+
+async fn add_json_item(pool: &PgPool, item_path: &str, data: serde_json::Value) -> sqlx::Result<()> {
+	sqlx::query(
+		"INSERT INTO crate_item(added_by, crate_id, scope_id, item_path, item_storage, data_json, type_id, size_hectobyte)
+		VALUES('0000000000000000000000000000000a', $1::uuid, (SELECT id from scope where scope_type = 'storage'),
+			$2, 'Json', $3, (SELECT id from item_type where media_type = 'application/json'), 0)"
+	)
+	.bind(CRATE_ID)
+	.bind(item_path)
+	.bind(data)
+	.execute(pool)
+	.await?;
+	Ok(())
+}
+
+async fn query(query: serde_json::Value) -> salvo::Response {
+	let mut query_req = json!({"crate_id": CRATE_ID, "per_page": 10});
+	json_patch::merge(&mut query_req, &query);
+	TestClient::post(format!(
+		"http://{}/crate/query",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.json(&serde_json::from_value::<JsonQueryReq>(query_req).unwrap())
+	.send(comn_broker::route())
+	.await
+}
+
+async fn paths(query_req: serde_json::Value) -> Vec<String> {
+	let mut res = query(query_req).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	res.take_json::<Vec<JsonQueryHit>>().await.unwrap().into_iter().map(|hit| hit.item_path).collect()
+}
+
+#[sqlx::test(fixtures("crate_write"), migrator = "comn_broker::MIGRATOR")]
+async fn test_json_query(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool.clone()).await;
+	add_json_item(&pool, "/records/1", json!({"kind": "sale", "amount": 30, "tags": ["eu", "card"], "who": {"name": "ada"}})).await?;
+	add_json_item(&pool, "/records/2", json!({"kind": "sale", "amount": 5, "tags": ["us"], "who": {"name": "bob"}})).await?;
+	add_json_item(&pool, "/records/3", json!({"kind": "refund", "amount": 12, "tags": ["eu"]})).await?;
+	add_json_item(&pool, "/settings", json!({"kind": "sale"})).await?;
+
+	assert_eq!(
+		paths(json!({"path_prefix": "/records/", "filter": [{"field": "/kind", "op": "eq", "value": "sale"}]})).await,
+		vec!["/records/1", "/records/2"]
+	);
+	assert_eq!(
+		paths(json!({"filter": [{"field": "/amount", "op": "gte", "value": 12}], "sort": [{"field": "/amount", "desc": true}]})).await,
+		vec!["/records/1", "/records/3"]
+	);
+	assert_eq!(
+		paths(json!({"filter": [
+			{"field": "/tags", "op": "contains", "value": "eu"},
+			{"field": "/who/name", "op": "exists"}
+		]})).await,
+		vec!["/records/1"]
+	);
+	assert_eq!(paths(json!({"jsonpath": "$.amount < 10"})).await, vec!["/records/2"]);
+	assert_eq!(
+		paths(json!({"filter": [{"field": "/kind", "op": "ne", "value": "sale"}]})).await,
+		vec!["/records/3"]
+	);
+
+	let mut res = query(json!({"filter": [{"field": "/amount", "op": "eq", "value": 5}], "fields": ["/who/name", "/missing"]})).await;
+	let hits = res.take_json::<Vec<JsonQueryHit>>().await.unwrap();
+	assert_eq!(hits[0].data.0, json!({"who": {"name": "bob"}}));
+	assert_eq!(hits[0].version, 1);
+
+	// paged after sorting
+	assert_eq!(
+		paths(json!({"path_prefix": "/records/", "sort": [{"field": "/amount"}], "per_page": 2, "page_no": 1})).await,
+		vec!["/records/1"]
+	);
+
+	// amounts that aren't numbers are skipped, never cast
+	add_json_item(&pool, "/notes", json!({"amount": "a lot"})).await?;
+	assert_eq!(
+		paths(json!({"filter": [{"field": "/amount", "op": "lt", "value": 10}]})).await,
+		vec!["/records/2"]
+	);
+
+	assert_eq!(query(json!({"filter": [{"field": "amount", "op": "gt", "value": 1}]})).await.status_code.unwrap(), StatusCode::BAD_REQUEST);
+	assert_eq!(query(json!({"filter": [{"field": "/amount", "op": "gt", "value": true}]})).await.status_code.unwrap(), StatusCode::BAD_REQUEST);
+	assert_eq!(query(json!({"jsonpath": "$.amount <"})).await.status_code.unwrap(), StatusCode::BAD_REQUEST);
+
+	Ok(())
+}
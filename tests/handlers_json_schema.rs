@@ -0,0 +1,102 @@
+mod common;
+use common::make_auth_header;
+use comn_broker::update::json_schema::{path_matches, CrateSchema, SchemaViolation, SetCrateSchemaReq};
+use salvo::http::header::AUTHORIZATION;
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use serde_json::json;
+use sqlx::PgPool;
+
+const CRATE_ID: &str = "10000000000000000000000000000000";
+
+async fn add_item(item_path: &str, data: serde_json::Value) -> salvo::Response {
+	let data = serde_json::to_vec(&data).unwrap();
+	let add_item_req = common::item_req(CRATE_ID, "≈a", item_path, "application/json", data);
+	common::add_item("NewKey", &add_item_req).await
+}
+
+async fn set_schema(schema: Option<serde_json::Value>) -> salvo::Response {
+	let schema_req = SetCrateSchemaReq {
+		crate_id: CRATE_ID.to_string(),
+		path_pattern: "/history/*".to_string(),
+		schema,
+	};
+	TestClient::post(format!(
+		"http://{}/crate/schema",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_write", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.json(&schema_req)
+	.send(comn_broker::route())
+	.await
+}
+
+#[sqlx::test(fixtures("crate_access"), migrator = "comn_broker::MIGRATOR")]
+async fn test_json_schema(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool).await;
+
+	assert!(path_matches("/history/*", "/history/1"));
+	assert!(!path_matches("/history/*", "/history/1/note"));
+	assert!(path_matches("/app/**", "/app/state/ui"));
+	assert!(path_matches("/app/**", "/app"));
+	assert!(path_matches("/**/ui/*", "/app/state/ui/dark"));
+	assert!(!path_matches("/**/ui/*", "/app/state/ui"));
+	// many `**` don't make matching blow up
+	let pattern = format!("{}/x", "/**".repeat(30));
+	let path = "/a".repeat(40);
+	assert!(!path_matches(&pattern, &path));
+	assert!(path_matches(&pattern, &format!("{}/x", path)));
+
+	// added before there's a schema
+	let mut res = add_item("/history/1", json!({"amount": "ten"})).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let old_item = res.take_json::<uuid::Uuid>().await.unwrap();
+
+	let res = set_schema(Some(json!({"type": "object", "properties": {"amount": {"type": "nonsense"}}}))).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::BAD_REQUEST);
+	let schema = json!({
+		"type": "object",
+		"properties": {"amount": {"type": "integer"}},
+		"required": ["amount"]
+	});
+	let mut res = set_schema(Some(schema.clone())).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let crate_schema = res.take_json::<CrateSchema>().await.unwrap();
+	assert_eq!(crate_schema.schema.0, schema);
+
+	let mut res = add_item("/history/2", json!({"amount": "eleven"})).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNPROCESSABLE_ENTITY);
+	let errors = res.take_json::<Vec<String>>().await.unwrap();
+	assert_eq!(errors.len(), 1);
+	assert!(errors[0].starts_with("/amount"));
+	assert_eq!(add_item("/history/2", json!({"amount": 11})).await.status_code.unwrap(), StatusCode::OK);
+	// other paths aren't checked
+	assert_eq!(add_item("/notes", json!({"amount": "any"})).await.status_code.unwrap(), StatusCode::OK);
+
+	let mut res = TestClient::get(format!(
+		"http://{}/crate/schema/check?id={}",
+		&std::env::var("BIND_ADDR").unwrap(),
+		CRATE_ID
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let violations = res.take_json::<Vec<SchemaViolation>>().await.unwrap();
+	assert_eq!(violations.len(), 1);
+	assert_eq!(violations[0].item_id, old_item);
+
+	assert_eq!(set_schema(None).await.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(set_schema(None).await.status_code.unwrap(), StatusCode::NOT_FOUND);
+	assert_eq!(add_item("/history/3", json!({"amount": "twelve"})).await.status_code.unwrap(), StatusCode::OK);
+
+	Ok(())
+}
@@ -0,0 +1,195 @@
+mod common;
+use common::{get_keys, make_auth_header};
+use comn_broker::{
+	auth_token::{ProtectedReq, Protected}, comn_addr::ComnAddr,
+};
+use comn_broker::update::{
+	coin::Transaction,
+	quota::{BuyQuotaReq, StorageUsage, get_usage},
+};
+use salvo::http::header::AUTHORIZATION;
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use sqlx::PgPool;
+
+const CRATE_ID: &str = "40000000000000000000000000000000";
+
+// crate owned by ≈a, already holding `size_hectobyte` of data
+async fn add_owned_crate(pool: &PgPool, size_hectobyte: i32) -> sqlx::Result<()> {
+	sqlx::query("INSERT INTO crate(id, name) VALUES($1::uuid, 'quota crate')")
+		.bind(CRATE_ID)
+		.execute(pool)
+		.await?;
+	sqlx::query(
+		"INSERT INTO crate_access(crate_id, addr_id, type)
+		VALUES($1::uuid, '0000000000000000000000000000000a', 'owner')"
+	)
+	.bind(CRATE_ID)
+	.execute(pool)
+	.await?;
+	add_sized_item(pool, "/big", size_hectobyte).await
+}
+
+async fn add_sized_item(pool: &PgPool, item_path: &str, size_hectobyte: i32) -> sqlx::Result<()> {
+	sqlx::query(
+		"INSERT INTO crate_item(added_by, crate_id, scope_id, item_path, item_storage, data_text, type_id, size_hectobyte)
+		VALUES('0000000000000000000000000000000a', $1::uuid, (SELECT id from scope where scope_type = 'storage'),
+			$2, 'Text', '', (SELECT id from item_type where media_type = 'text/plain'), $3)"
+	)
+	.bind(CRATE_ID)
+	.bind(item_path)
+	.bind(size_hectobyte)
+	.execute(pool)
+	.await?;
+	Ok(())
+}
+
+async fn add_item(item_path: &str) -> StatusCode {
+	let data = [7u8; 3_000];
+	let add_item_req = common::item_req(CRATE_ID, "≈a", item_path, "application/octet-stream", data.to_vec());
+	common::add_item("Key1", &add_item_req).await.status_code.unwrap()
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_storage_quota(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool.clone()).await;
+	let (key1, _public_key) = get_keys("Key1");
+	Transaction {
+		amount: 100,
+		receiver: ComnAddr::new("≈a").unwrap(),
+		sender: ComnAddr::new("≈6D").unwrap(),
+		comment: None,
+		nonce: "quotaNSok98Ingp".to_string(),
+	}.transfer_coins().await.unwrap();
+	// 10 hectobytes short of the free tier
+	add_owned_crate(&pool, 99_990).await?;
+	// a later co-owner isn't charged for the crate too
+	sqlx::query("INSERT INTO addr(id, name) VALUES('0000000000000000000000000000000b', 'co-owner')")
+		.execute(&pool)
+		.await?;
+	sqlx::query(
+		"INSERT INTO crate_access(crate_id, addr_id, type, created)
+		VALUES($1::uuid, '0000000000000000000000000000000b', 'owner', CURRENT_TIMESTAMP + interval '1 second')"
+	)
+	.bind(CRATE_ID)
+	.execute(&pool)
+	.await?;
+	assert_eq!(get_usage(&ComnAddr::new("≈C").unwrap()).await.used_hectobyte, 0);
+
+	// the 30 hectobyte item doesn't fit
+	assert_eq!(add_item("/3k_binary").await, StatusCode::PAYMENT_REQUIRED);
+
+	let usage = TestClient::get(format!(
+		"http://{}/comn/storage?addr=≈a",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"storage",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.send(comn_broker::route())
+	.await
+	.take_json::<StorageUsage>()
+	.await
+	.unwrap();
+	assert_eq!(usage.used_hectobyte, 99_990);
+	assert_eq!(usage.quota_hectobyte, 100_000);
+
+	// amounts that would overflow are refused, not wrapped
+	for blocks in [u64::MAX, i64::MAX as u64, u64::MAX / 100_000 + 1] {
+		let buy_req = BuyQuotaReq {
+			addr: ComnAddr::new("≈a").unwrap(),
+			blocks,
+			nonce: format!("buyOverflow{}", blocks),
+		};
+		let t = serde_json::to_string(&buy_req).unwrap();
+		let req = ProtectedReq::from(Protected::new(t, key1));
+		let res = TestClient::post(format!(
+			"http://{}/comn/storage",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header("Key1", "comn.opus.ai", "storage", 60 * 60 * 24 * 30, 0),
+			true,
+		)
+		.json(&req)
+		.send(comn_broker::route())
+		.await;
+		assert_eq!(res.status_code.unwrap(), StatusCode::BAD_REQUEST);
+	}
+
+	let buy_req = BuyQuotaReq {
+		addr: ComnAddr::new("≈a").unwrap(),
+		blocks: 1,
+		nonce: "buyNSok98Ingp".to_string(),
+	};
+	for expected in [StatusCode::OK, StatusCode::ALREADY_REPORTED] {
+		let t = serde_json::to_string(&buy_req).unwrap();
+		let req = ProtectedReq::from(Protected::new(t, key1));
+		let mut res = TestClient::post(format!(
+			"http://{}/comn/storage",
+			&std::env::var("BIND_ADDR").unwrap()
+		))
+		.add_header(
+			AUTHORIZATION,
+			&make_auth_header(
+				"Key1",
+				"comn.opus.ai",
+				"storage",
+				60 * 60 * 24 * 30,
+				0,
+			),
+			true,
+		)
+		.json(&req)
+		.send(comn_broker::route())
+		.await;
+		assert_eq!(res.status_code.unwrap(), expected);
+		if expected == StatusCode::OK {
+			let usage = res.take_json::<StorageUsage>().await.unwrap();
+			assert_eq!(usage.extra_hectobyte, 100_000);
+			assert_eq!(usage.quota_hectobyte, 200_000);
+		}
+	}
+
+	let balance = TestClient::get(format!(
+		"http://{}/comn",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"account_amount",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.send(comn_broker::route())
+	.await
+	.take_json::<i64>()
+	.await
+	.unwrap();
+	assert_eq!(balance, 90);
+
+	assert_eq!(add_item("/3k_binary").await, StatusCode::OK);
+
+	// nothing more can be bought past max_hectobyte
+	sqlx::query("UPDATE storage_quota SET extra_hectobyte = 99900000")
+		.execute(&pool)
+		.await?;
+	add_sized_item(&pool, "/huge", 99_900_000).await?;
+	assert_eq!(add_item("/3k_binary_2").await, StatusCode::INSUFFICIENT_STORAGE);
+
+	Ok(())
+}
@@ -0,0 +1,102 @@
+mod common;
+use common::get_keys;
+use comn_broker::eth::{backfill_eth_addrs, eip191_hash, eip712_transfer_hash, parse_eth_addr};
+use comn_broker::handlers::rpc::{RpcReq, RpcRes, TransferMsg, TransferParams};
+use salvo::test::{ResponseExt, TestClient};
+use secp256k1::{Message, SecretKey, SECP256K1};
+use serde_json::{json, Value};
+use sqlx::PgPool;
+
+// eth addresses of the NewKey and Key1 test keys
+const NEW_KEY_ETH: &str = "0xF4997411ec6A77f8F16Fa5C61C711F4F3c114e50";
+const KEY1_ETH: &str = "0x5c2FC6f4E7C679015804b4DaA0a7181b22d7cFfd";
+
+async fn rpc(method: &str, params: Vec<Value>) -> RpcRes {
+	let rpc_req = RpcReq {
+		jsonrpc: "2.0".to_string(),
+		id: json!(1),
+		method: method.to_string(),
+		params,
+	};
+	TestClient::post(format!(
+		"http://{}/rpc",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.json(&rpc_req)
+	.send(comn_broker::route())
+	.await
+	.take_json::<RpcRes>()
+	.await
+	.unwrap()
+}
+
+fn sign(digest: [u8; 32], secret_key: SecretKey) -> String {
+	let (recid, sig) = SECP256K1
+		.sign_ecdsa_recoverable(&Message::from_slice(&digest).unwrap(), &secret_key)
+		.serialize_compact();
+	hex::encode([&sig[..], &[recid.to_i32() as u8 + 27]].concat())
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_rpc_metadata(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	backfill_eth_addrs().await;
+
+	assert_eq!(rpc("eth_chainId", vec!()).await.result.unwrap(), json!("0x539"));
+	assert_eq!(rpc("symbol", vec!()).await.result.unwrap(), json!("cc"));
+	assert_eq!(rpc("totalSupply", vec!()).await.result.unwrap(), json!("0x174876e800"));
+	assert_eq!(
+		rpc("balanceOf", vec!(json!(NEW_KEY_ETH))).await.result.unwrap(),
+		json!("0x174876e800")
+	);
+	assert_eq!(rpc("eth_sendTransaction", vec!()).await.error.unwrap().code, -32601);
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_rpc_transfer(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	backfill_eth_addrs().await;
+	let (secret_key, _public_key) = get_keys("NewKey");
+
+	// EIP-191 personal message
+	let msg = serde_json::to_string(&TransferMsg {
+		to: KEY1_ETH.to_string(),
+		amount: 100,
+		nonce: "eip191NSok98Ingp".to_string(),
+	}).unwrap();
+	let params = TransferParams {
+		signature: sign(eip191_hash(msg.as_bytes()), secret_key),
+		message: json!(msg),
+	};
+	let res = rpc("transfer", vec!(json!(params))).await;
+	assert_eq!(res.result.unwrap(), json!(true));
+
+	// replaying the same signed message
+	let res = rpc("transfer", vec!(json!(params))).await;
+	assert_eq!(res.error.unwrap().code, -32000);
+
+	// EIP-712 typed data
+	let msg = TransferMsg {
+		to: KEY1_ETH.to_string(),
+		amount: 50,
+		nonce: "eip712NSok98Ingp".to_string(),
+	};
+	let digest = eip712_transfer_hash(
+		"comncoin", 1337, &parse_eth_addr(KEY1_ETH).unwrap(), msg.amount, &msg.nonce,
+	);
+	let params = TransferParams {
+		signature: sign(digest, secret_key),
+		message: json!(msg),
+	};
+	let res = rpc("transfer", vec!(json!(params))).await;
+	assert_eq!(res.result.unwrap(), json!(true));
+
+	assert_eq!(
+		rpc("balanceOf", vec!(json!(KEY1_ETH))).await.result.unwrap(),
+		json!("0x96")
+	);
+
+	Ok(())
+}
@@ -0,0 +1,248 @@
+mod common;
+use common::{get_keys, make_auth_header};
+use comn_broker::{
+	auth_token::{ProtectedReq, Protected}, comn_addr::ComnAddr,
+};
+use comn_broker::update::{
+	schedule::{
+		StandingOrderReq, CancelStandingOrderReq, StandingOrderRes,
+		StandingOrderErr, StandingOrderStatus, run_due_orders,
+	},
+};
+use chrono::{Duration, Utc};
+use salvo::http::header::AUTHORIZATION;
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use sqlx::PgPool;
+
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_standing_order(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	let (secret_key, _public_key) = get_keys("NewKey");
+
+	let order_req = StandingOrderReq {
+		sender: ComnAddr::new("≈6D").unwrap(),
+		receiver: ComnAddr::new("≈a").unwrap(),
+		amount: 25,
+		comment: Some("monthly".to_string()),
+		nonce: "sch3duleNSok98Ingp".to_string(),
+		interval_secs: 60 * 60 * 24 * 30,
+		start: Some(Utc::now() - Duration::seconds(1)),
+		ends: None,
+		max_count: Some(2),
+	};
+	let t = serde_json::to_string(&order_req).unwrap();
+	let req = ProtectedReq::from(Protected::new(t, secret_key));
+	let mut res = TestClient::post(format!(
+		"http://{}/comn/schedule",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"NewKey",
+			"comn.opus.ai",
+			"schedule",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let order = res.take_json::<StandingOrderRes>().await.unwrap();
+
+	// only the first run is due
+	let runs = run_due_orders().await;
+	assert_eq!(runs.len(), 1);
+	assert_eq!(runs[0].as_ref().unwrap().run_count, 1);
+	assert_eq!(run_due_orders().await.len(), 0);
+
+	let balance = TestClient::get(format!(
+		"http://{}/comn",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"account_amount",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.send(comn_broker::route())
+	.await
+	.take_json::<i64>()
+	.await
+	.unwrap();
+	assert_eq!(balance, 25);
+
+	// receiver can't cancel the sender's order
+	let cancel_req = CancelStandingOrderReq {
+		id: order.id,
+		sender: ComnAddr::new("≈6D").unwrap(),
+	};
+	let (receiver_key, _public_key) = get_keys("Key1");
+	let t = serde_json::to_string(&cancel_req).unwrap();
+	let req = ProtectedReq::from(Protected::new(t, receiver_key));
+	let res_fail = TestClient::post(format!(
+		"http://{}/comn/schedule/cancel",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"schedule",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::BAD_REQUEST);
+
+	let t = serde_json::to_string(&cancel_req).unwrap();
+	let req = ProtectedReq::from(Protected::new(t, secret_key));
+	let res = TestClient::post(format!(
+		"http://{}/comn/schedule/cancel",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"NewKey",
+			"comn.opus.ai",
+			"schedule",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&req)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+
+	let orders = TestClient::get(format!(
+		"http://{}/comn/schedule?addr=≈6D",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"NewKey",
+			"comn.opus.ai",
+			"schedule",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.send(comn_broker::route())
+	.await
+	.take_json::<Vec<StandingOrderRes>>()
+	.await
+	.unwrap();
+	assert_eq!(orders.len(), 1);
+	assert_eq!(orders[0].status, StandingOrderStatus::Cancelled);
+	assert_eq!(orders[0].run_count, 1);
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_standing_order_low_balance(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+
+	// ≈a has no coins, every run fails until the order gives up
+	let order = StandingOrderReq {
+		sender: ComnAddr::new("≈a").unwrap(),
+		receiver: ComnAddr::new("≈6D").unwrap(),
+		amount: 10,
+		comment: None,
+		nonce: "l0wBalanceNSok98Ingp".to_string(),
+		interval_secs: 1,
+		start: Some(Utc::now() - Duration::seconds(10)),
+		ends: None,
+		max_count: None,
+	}.add().await.unwrap();
+	assert_eq!(order.status, StandingOrderStatus::Active);
+
+	for _ in 0..3 {
+		let runs = run_due_orders().await;
+		assert_eq!(runs.len(), 1);
+		assert!(runs[0].is_err());
+	}
+	assert_eq!(run_due_orders().await.len(), 0);
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_standing_order_overlapping_workers(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	StandingOrderReq {
+		sender: ComnAddr::new("≈6D").unwrap(),
+		receiver: ComnAddr::new("≈a").unwrap(),
+		amount: 25,
+		comment: None,
+		nonce: "0verlapNSok98Ingp".to_string(),
+		interval_secs: 60 * 60,
+		start: Some(Utc::now() - Duration::seconds(1)),
+		ends: None,
+		max_count: None,
+	}.add().await.unwrap();
+
+	// two workers ticking at once run the due order once between them
+	let (first, second) = tokio::join!(run_due_orders(), run_due_orders());
+	let runs: Vec<_> = first.into_iter().chain(second).collect();
+	assert_eq!(runs.len(), 1);
+	assert_eq!(runs[0].as_ref().unwrap().run_count, 1);
+	assert_eq!(run_due_orders().await.len(), 0);
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_standing_order_bad_data(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	let mut order_req = StandingOrderReq {
+		sender: ComnAddr::new("≈6D").unwrap(),
+		receiver: ComnAddr::new("≈ZZZZ").unwrap(),
+		amount: 25,
+		comment: None,
+		nonce: "sch3duleBadNSok98Ingp".to_string(),
+		interval_secs: 60,
+		start: None,
+		ends: None,
+		max_count: None,
+	};
+	// the receiver isn't an addr
+	assert!(matches!(order_req.add().await, Err(StandingOrderErr::NotFound)));
+
+	// values the ledger can't hold
+	order_req.receiver = ComnAddr::new("≈a").unwrap();
+	order_req.amount = u64::MAX;
+	assert!(matches!(order_req.add().await, Err(StandingOrderErr::BadData)));
+	order_req.amount = 25;
+	order_req.interval_secs = u64::MAX;
+	assert!(matches!(order_req.add().await, Err(StandingOrderErr::BadData)));
+	order_req.interval_secs = 60;
+	order_req.max_count = Some(u32::MAX);
+	assert!(matches!(order_req.add().await, Err(StandingOrderErr::BadData)));
+
+	order_req.max_count = Some(1);
+	assert!(order_req.add().await.is_ok());
+
+	Ok(())
+}
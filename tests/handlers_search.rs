@@ -0,0 +1,79 @@
+mod common;
+use common::make_auth_header;
+use comn_broker::read::search::SearchRes;
+use salvo::http::header::AUTHORIZATION;
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use sqlx::PgPool;
+
+async fn add_item(item_path: &str, media_type: &str, data: Vec<u8>) {
+	let add_item_req = common::item_req("10000000000000000000000000000000", "≈a", item_path, media_type, data);
+	let res = common::add_item("NewKey", &add_item_req).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+}
+
+async fn search(query: &str) -> SearchRes {
+	let mut res = TestClient::get(format!(
+		"http://{}/search?{}",
+		&std::env::var("BIND_ADDR").unwrap(),
+		query
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header("NewKey", "comn.opus.ai", "crate_read", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	res.take_json::<SearchRes>().await.unwrap()
+}
+
+#[sqlx::test(fixtures("crate_write"), migrator = "comn_broker::MIGRATOR")]
+async fn test_search(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool.clone()).await;
+
+	add_item("/notes/meeting.txt", "text/plain", b"The invoice for the garden shed is due on Friday.".to_vec()).await;
+	// kept compressed or in a file, out of data_text
+	let long = format!("{} The lighthouse keeper counted ships. {}", "filler words here. ".repeat(80), "more filler. ".repeat(80));
+	add_item("/notes/story.txt", "text/plain", long.into_bytes()).await;
+	add_item("/records/1", "application/json", br#"{"title": "Quarterly invoices", "amount": 10}"#.to_vec()).await;
+	// a crate the key can't read
+	sqlx::query("INSERT INTO crate(id, name) VALUES('20000000000000000000000000000000', 'other')")
+		.execute(&pool)
+		.await?;
+	sqlx::query(
+		"INSERT INTO crate_item(added_by, crate_id, scope_id, item_path, item_storage, data_text, type_id, size_hectobyte)
+		VALUES('0000000000000000000000000000000a', '20000000000000000000000000000000', (SELECT id from scope where scope_type = 'storage'),
+			'/secret.txt', 'Text', 'another invoice', (SELECT id from item_type where media_type = 'text/plain'), 0)"
+	)
+	.execute(&pool)
+	.await?;
+
+	// stemmed, so invoices finds invoice
+	let res = search("q=invoice").await;
+	assert_eq!(res.total, 2);
+	let mut paths: Vec<&str> = res.hits.iter().map(|hit| hit.item_path.as_str()).collect();
+	paths.sort();
+	assert_eq!(paths, vec!["/notes/meeting.txt", "/records/1"]);
+	let meeting = res.hits.iter().find(|hit| hit.item_path == "/notes/meeting.txt").unwrap();
+	assert!(meeting.snippet.as_ref().unwrap().contains("<b>invoice</b>"));
+
+	let res = search("q=lighthouse").await;
+	assert_eq!(res.hits[0].item_path, "/notes/story.txt");
+	assert!(res.hits[0].snippet.as_ref().unwrap().contains("<b>lighthouse</b>"));
+
+	// paths are searched and weigh more
+	let res = search("q=notes").await;
+	assert_eq!(res.total, 2);
+
+	let res = search("q=invoice%20-garden&per_page=1").await;
+	assert_eq!(res.total, 1);
+	assert_eq!(res.hits[0].item_path, "/records/1");
+
+	let res = search("q=notes&per_page=1&page_no=1").await;
+	assert_eq!(res.total, 2);
+	assert_eq!(res.hits.len(), 1);
+
+	Ok(())
+}
@@ -0,0 +1,140 @@
+mod common;
+use common::make_auth_header;
+use comn_broker::comn_addr::ComnAddr;
+use comn_broker::add::template::{ApplyTemplateReq, CrateTemplate, ProvisionedCrate};
+use salvo::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use sqlx::PgPool;
+use uuid::Uuid;
+
+async fn add_template(key: &str, toml: String) -> Response {
+	TestClient::post(format!(
+		"http://{}/template",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(key, "comn.opus.ai", "crate_write", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.text(toml)
+	.add_header(CONTENT_TYPE, "application/toml", true)
+	.send(comn_broker::route())
+	.await
+}
+
+async fn apply_template(key: &str, apply_req: &ApplyTemplateReq) -> Response {
+	TestClient::post(format!(
+		"http://{}/template/apply",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(key, "comn.opus.ai", "crate_write", 60 * 60 * 24 * 30, 0),
+		true,
+	)
+	.json(apply_req)
+	.send(comn_broker::route())
+	.await
+}
+
+async fn crate_count(pool: &PgPool) -> sqlx::Result<i64> {
+	sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM crate").fetch_one(pool).await
+}
+
+#[sqlx::test(fixtures("item_type"), migrator = "comn_broker::MIGRATOR")]
+async fn test_template(pool: PgPool) -> sqlx::Result<()> {
+	common::setup(pool.clone()).await;
+	let starter = format!(r#"
+name = "starter"
+description = "crates of new users"
+
+[[crates]]
+name = "{{{{addr}}}} notes"
+comment = "notes of {{{{project}}}}"
+
+[[crates.items]]
+item_path = "/readme.txt"
+text = "Welcome {{{{addr}}}}"
+tags = ["welcome"]
+
+[[crates.items]]
+item_path = "/settings"
+json = {{ theme = "dark", owner = "{{{{addr}}}}" }}
+
+[[crates.access]]
+addr = "{}"
+access_type = "Reader"
+
+[[crates]]
+name = "inbox"
+"#, ComnAddr::from_uuid("fffffffffffffffffffffffffffffffe").unwrap());
+
+	// only keys of the config addr register templates
+	let res = add_template("Key1", starter.clone()).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNAUTHORIZED);
+	let mut res = add_template("NewKey", starter).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	assert_eq!(res.take_json::<CrateTemplate>().await.unwrap().name, "starter");
+
+	let addr = ComnAddr::new("≈a").unwrap();
+	let crates_before = crate_count(&pool).await?;
+	// every placeholder needs a value, nothing is made otherwise
+	let mut apply_req = ApplyTemplateReq {
+		name: "starter".to_string(),
+		addr: addr.clone(),
+		vars: Default::default(),
+	};
+	let res = apply_template("Key1", &apply_req).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNPROCESSABLE_ENTITY);
+	assert_eq!(crate_count(&pool).await?, crates_before);
+
+	apply_req.vars.insert("project".to_string(), "garden".to_string());
+	let mut res = apply_template("Key1", &apply_req).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let provisioned = res.take_json::<Vec<ProvisionedCrate>>().await.unwrap();
+	assert_eq!(provisioned.len(), 2);
+	assert_eq!(provisioned[0].crate_.name, format!("{} notes", addr));
+	assert_eq!(provisioned[0].crate_.comment, Some("notes of garden".to_string()));
+	assert_eq!(provisioned[0].items.len(), 2);
+
+	let settings = sqlx::query_scalar::<_, serde_json::Value>(
+		"SELECT data_json FROM crate_item WHERE crate_id = $1 AND item_path = '/settings'"
+	)
+	.bind(provisioned[0].crate_.id)
+	.fetch_one(&pool)
+	.await?;
+	assert_eq!(settings, serde_json::json!({"theme": "dark", "owner": addr.to_string()}));
+	let access = sqlx::query_scalar::<_, Uuid>(
+		"SELECT addr_id FROM crate_access WHERE crate_id = $1 ORDER BY type"
+	)
+	.bind(provisioned[0].crate_.id)
+	.fetch_all(&pool)
+	.await?;
+	assert_eq!(access.len(), 2);
+
+	// a grant to an addr that doesn't exist undoes the crates made before it
+	let broken = r#"
+name = "broken"
+
+[[crates]]
+name = "first"
+
+[[crates]]
+name = "second"
+
+[[crates.access]]
+addr = "≈ZZZZ"
+access_type = "Editor"
+"#;
+	let res = add_template("NewKey", broken.to_string()).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let crates_before = crate_count(&pool).await?;
+	apply_req.name = "broken".to_string();
+	let res = apply_template("Key1", &apply_req).await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::UNPROCESSABLE_ENTITY);
+	assert_eq!(crate_count(&pool).await?, crates_before);
+
+	Ok(())
+}
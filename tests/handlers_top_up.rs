@@ -0,0 +1,298 @@
+mod common;
+use common::make_auth_header;
+use chrono::Utc;
+use salvo::http::header::AUTHORIZATION;
+use salvo::prelude::*;
+use salvo::test::{ResponseExt, TestClient};
+use comn_broker::payment::webhook::sign;
+use comn_broker::update::top_up::{CheckoutRes, TopUpStatus};
+use sqlx::PgPool;
+
+const WEBHOOK_SECRET: &str = "whsec_test_stripe";
+const STRIPE_MOCK_ADDR: &str = "127.0.0.1:5801";
+const BILLING_SECRET: &str = "whsec_test_billing";
+
+// stripe signs the `stripe-signature` header the same way
+fn stripe_signature(payload: &str) -> String {
+	std::env::set_var("STRIPE_WEBHOOK_SECRET", WEBHOOK_SECRET);
+	sign(WEBHOOK_SECRET, Utc::now().timestamp(), payload)
+}
+
+async fn send_event(payload: &str) -> StatusCode {
+	TestClient::post(format!(
+		"http://{}/stripe_webhook",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header("stripe-signature", stripe_signature(payload), true)
+	.text(payload.to_string())
+	.send(comn_broker::route())
+	.await
+	.status_code
+	.unwrap()
+}
+
+async fn send_billing_event(payload: &str, signature: String) -> StatusCode {
+	TestClient::post(format!(
+		"http://{}/payment_webhook",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header("comn-signature", signature, true)
+	.text(payload.to_string())
+	.send(comn_broker::route())
+	.await
+	.status_code
+	.unwrap()
+}
+
+// the part of the stripe api creating checkout sessions
+#[handler]
+async fn mock_create_session(req: &mut Request, res: &mut Response) {
+	let addr = req.form::<String>("metadata[addr]").await;
+	let quantity = req.form::<String>("line_items[0][quantity]").await;
+	if addr.as_deref() == Some("≈a") && quantity.as_deref() == Some("25") {
+		res.render(Text::Json(include_str!("fixtures/stripe/checkout_session.json")));
+	} else {
+		res.status_code(StatusCode::BAD_REQUEST);
+	}
+}
+
+async fn checkout_status(id: &str) -> CheckoutRes {
+	TestClient::get(format!(
+		"http://{}/comn/checkout?id={}",
+		&std::env::var("BIND_ADDR").unwrap(),
+		id
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"checkout",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.send(comn_broker::route())
+	.await
+	.take_json::<CheckoutRes>()
+	.await
+	.unwrap()
+}
+
+async fn coins_of_a() -> i64 {
+	TestClient::get(format!(
+		"http://{}/comn",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"account_amount",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.send(comn_broker::route())
+	.await
+	.take_json::<i64>()
+	.await
+	.unwrap()
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_stripe_webhook(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	let completed = include_str!("fixtures/stripe/checkout_session_completed.json");
+
+	// 1000 cents paid after a 200 cent discount, at 100 cents per coin
+	assert_eq!(send_event(completed).await, StatusCode::OK);
+	assert_eq!(coins_of_a().await, 10);
+
+	// stripe retrying the same event
+	assert_eq!(send_event(completed).await, StatusCode::ALREADY_REPORTED);
+	assert_eq!(coins_of_a().await, 10);
+
+	// bad signature
+	let res_fail = TestClient::post(format!(
+		"http://{}/stripe_webhook",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header("stripe-signature", "t=1,v1=00", true)
+	.text(completed.to_string())
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::BAD_REQUEST);
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_stripe_webhook_delayed_payment(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	let paid = include_str!("fixtures/stripe/checkout_session_completed.json");
+
+	// delayed payment methods complete the session before the money arrives
+	let unpaid = paid.replace(r#""payment_status": "paid""#, r#""payment_status": "unpaid""#);
+	assert_eq!(send_event(&unpaid).await, StatusCode::METHOD_NOT_ALLOWED);
+	assert_eq!(coins_of_a().await, 0);
+
+	let succeeded = paid
+		.replace("evt_test_checkout_completed", "evt_test_async_payment_succeeded")
+		.replace("checkout.session.completed", "checkout.session.async_payment_succeeded");
+	assert_eq!(send_event(&succeeded).await, StatusCode::OK);
+	assert_eq!(coins_of_a().await, 10);
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_stripe_webhook_refunds(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+
+	assert_eq!(
+		send_event(include_str!("fixtures/stripe/checkout_session_completed.json")).await,
+		StatusCode::OK
+	);
+	assert_eq!(coins_of_a().await, 10);
+
+	// half of the charge is refunded
+	let refunded = include_str!("fixtures/stripe/charge_refunded.json");
+	assert_eq!(send_event(refunded).await, StatusCode::OK);
+	assert_eq!(coins_of_a().await, 5);
+	assert_eq!(send_event(refunded).await, StatusCode::ALREADY_REPORTED);
+	assert_eq!(coins_of_a().await, 5);
+
+	// the rest is clawed back when the payment fails
+	assert_eq!(
+		send_event(include_str!("fixtures/stripe/checkout_session_async_payment_failed.json")).await,
+		StatusCode::OK
+	);
+	assert_eq!(coins_of_a().await, 0);
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_payment_webhook(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	let now = Utc::now().timestamp();
+	let paid = r#"{"type": "paid", "payment_id": "inv_1", "addr": "≈a", "currency": "USD", "amount": 2000}"#;
+
+	// nothing is accepted until the secret is set
+	std::env::remove_var("COMN_WEBHOOK_SECRET");
+	assert_eq!(
+		send_billing_event(paid, sign("", now, paid)).await,
+		StatusCode::BAD_REQUEST
+	);
+	std::env::set_var("COMN_WEBHOOK_SECRET", BILLING_SECRET);
+	// signed with another secret
+	assert_eq!(
+		send_billing_event(paid, sign(WEBHOOK_SECRET, now, paid)).await,
+		StatusCode::BAD_REQUEST
+	);
+	// replayed signature
+	assert_eq!(
+		send_billing_event(paid, sign(BILLING_SECRET, now - 3600, paid)).await,
+		StatusCode::BAD_REQUEST
+	);
+	assert_eq!(coins_of_a().await, 0);
+
+	assert_eq!(send_billing_event(paid, sign(BILLING_SECRET, now, paid)).await, StatusCode::OK);
+	assert_eq!(coins_of_a().await, 20);
+	assert_eq!(
+		send_billing_event(paid, sign(BILLING_SECRET, now, paid)).await,
+		StatusCode::ALREADY_REPORTED
+	);
+	assert_eq!(coins_of_a().await, 20);
+
+	let refunded = r#"{"type": "refunded", "payment_id": "inv_1", "refund_id": "rf_1", "currency": "usd", "amount_refunded": 500}"#;
+	assert_eq!(send_billing_event(refunded, sign(BILLING_SECRET, now, refunded)).await, StatusCode::OK);
+	assert_eq!(coins_of_a().await, 15);
+
+	let failed = r#"{"type": "failed", "payment_id": "inv_1"}"#;
+	assert_eq!(send_billing_event(failed, sign(BILLING_SECRET, now, failed)).await, StatusCode::OK);
+	assert_eq!(coins_of_a().await, 0);
+
+	// unknown currency
+	let euro = r#"{"type": "paid", "payment_id": "inv_2", "addr": "≈a", "currency": "eur", "amount": 2000}"#;
+	assert_eq!(
+		send_billing_event(euro, sign(BILLING_SECRET, now, euro)).await,
+		StatusCode::BAD_REQUEST
+	);
+
+	Ok(())
+}
+
+#[sqlx::test(fixtures("transaction"), migrator = "comn_broker::MIGRATOR")]
+async fn test_checkout(_pool: PgPool) -> sqlx::Result<()> {
+	common::setup(_pool).await;
+	std::env::set_var("STRIPE_API_BASE", format!("http://{}/", STRIPE_MOCK_ADDR));
+	std::env::set_var("STRIPE_SECRET_KEY", "sk_test_mock");
+	let mock = Router::with_path("v1/checkout/sessions").post(mock_create_session);
+	let acceptor = TcpListener::new(STRIPE_MOCK_ADDR).bind().await;
+	tokio::spawn(Server::new(acceptor).serve(mock));
+
+	// Key1 doesn't own ≈6D
+	let res_fail = TestClient::post(format!(
+		"http://{}/comn/checkout",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"checkout",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&serde_json::json!({"addr": "≈6D", "amount": 25}))
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res_fail.status_code.unwrap(), StatusCode::UNAUTHORIZED);
+
+	let mut res = TestClient::post(format!(
+		"http://{}/comn/checkout",
+		&std::env::var("BIND_ADDR").unwrap()
+	))
+	.add_header(
+		AUTHORIZATION,
+		&make_auth_header(
+			"Key1",
+			"comn.opus.ai",
+			"checkout",
+			60 * 60 * 24 * 30,
+			0,
+		),
+		true,
+	)
+	.json(&serde_json::json!({"addr": "≈a", "amount": 25}))
+	.send(comn_broker::route())
+	.await;
+	assert_eq!(res.status_code.unwrap(), StatusCode::OK);
+	let checkout = res.take_json::<CheckoutRes>().await.unwrap();
+	assert_eq!(checkout.id, "cs_test_2");
+	assert_eq!(checkout.url.as_deref(), Some("https://checkout.stripe.com/c/pay/cs_test_2"));
+	assert_eq!(checkout.status, TopUpStatus::Pending);
+
+	// the session pays to its metadata addr, whatever the custom field says
+	let completed = include_str!("fixtures/stripe/checkout_session_completed.json")
+		.replace("cs_test_1", "cs_test_2")
+		.replace("\"metadata\": {}", "\"metadata\": {\"addr\": \"≈a\"}")
+		.replace("\" a \"", "\" 6D \"")
+		.replace("1000", "2500");
+	assert_eq!(send_event(&completed).await, StatusCode::OK);
+	assert_eq!(coins_of_a().await, 25);
+
+	let checkout = checkout_status("cs_test_2").await;
+	assert_eq!(checkout.status, TopUpStatus::Paid);
+	assert_eq!(checkout.amount, 25);
+
+	Ok(())
+}